@@ -0,0 +1,143 @@
+// SPDX-License-Identifier: Apache-2.0
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use actix_web::body::MessageBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{header, StatusCode};
+use futures_util::future::LocalBoxFuture;
+use crate::config::access_log::AccessLogConfig;
+use crate::config::app::UpstreamConfig;
+
+/// Decides which requests of an upstream end up in the access logs
+#[derive(Debug)]
+pub struct AccessLogSampler {
+    config: AccessLogConfig,
+    requests: AtomicU64,
+}
+
+impl AccessLogSampler {
+    pub fn new(config: AccessLogConfig) -> Self {
+        AccessLogSampler {
+            config,
+            requests: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether the request which got the given response status has to be logged
+    pub fn should_log(&self, status: StatusCode) -> bool {
+        if self.config.always_log_errors && (status.is_client_error() || status.is_server_error()) {
+            return true;
+        }
+
+        // The errors logged above don't count towards the sampling
+        let sample_rate = self.config.sample_rate.max(1);
+        self.requests.fetch_add(1, Ordering::Relaxed).is_multiple_of(sample_rate)
+    }
+}
+
+/// Access logs middleware sampling the requests per upstream, selected via the Host header
+#[derive(Clone)]
+pub struct AccessLog {
+    samplers: Arc<HashMap<String, AccessLogSampler>>,
+}
+
+impl AccessLog {
+    pub fn new(upstreams: &HashMap<String, UpstreamConfig>) -> Self {
+        let samplers = upstreams.iter()
+            .map(|(host, upstream)| (host.clone(), AccessLogSampler::new(upstream.access_log.clone())))
+            .collect();
+
+        AccessLog {
+            samplers: Arc::new(samplers),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for AccessLog
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+        B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = AccessLogMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AccessLogMiddleware {
+            service,
+            samplers: self.samplers.clone(),
+        }))
+    }
+}
+
+pub struct AccessLogMiddleware<S> {
+    service: S,
+    samplers: Arc<HashMap<String, AccessLogSampler>>,
+}
+
+impl<S, B> Service<ServiceRequest> for AccessLogMiddleware<S>
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+        B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let started = Instant::now();
+        let host = req.headers().get(header::HOST).and_then(|host| host.to_str().ok()).unwrap_or("").to_string();
+        let peer = req.connection_info().realip_remote_addr().unwrap_or("-").to_string();
+        let request_line = format!("{} {} {:?}", req.method(), req.uri(), req.version());
+        let samplers = self.samplers.clone();
+
+        let response = self.service.call(req);
+
+        Box::pin(async move {
+            let response = response.await?;
+            let status = response.status();
+
+            // Requests which don't match any upstream are always logged
+            let log = samplers.get(&host).map(|sampler| sampler.should_log(status)).unwrap_or(true);
+            if log {
+                tracing::info!("{} \"{}\" {} {} {:.6}", peer, request_line, status.as_u16(), host, started.elapsed().as_secs_f64());
+            }
+
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::http::StatusCode;
+    use crate::api::access_log::AccessLogSampler;
+    use crate::config::access_log::AccessLogConfig;
+
+    #[test]
+    fn sampling_rate_test() {
+        let sampler = AccessLogSampler::new(AccessLogConfig { sample_rate: 10, always_log_errors: true });
+
+        let logged = (0..1000).filter(|_| sampler.should_log(StatusCode::OK)).count();
+        assert!((90..=110).contains(&logged), "logged {} requests out of 1000", logged);
+
+        // Errors are logged regardless of the sampling
+        assert!((0..100).all(|_| sampler.should_log(StatusCode::NOT_FOUND)));
+        assert!((0..100).all(|_| sampler.should_log(StatusCode::BAD_GATEWAY)));
+    }
+
+    #[test]
+    fn sampling_errors_test() {
+        let sampler = AccessLogSampler::new(AccessLogConfig { sample_rate: 10, always_log_errors: false });
+
+        let logged = (0..1000).filter(|_| sampler.should_log(StatusCode::INTERNAL_SERVER_ERROR)).count();
+        assert!((90..=110).contains(&logged), "logged {} errors out of 1000", logged);
+    }
+}
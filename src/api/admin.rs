@@ -0,0 +1,931 @@
+// SPDX-License-Identifier: Apache-2.0
+use std::collections::BTreeMap;
+use std::time::{Duration, UNIX_EPOCH};
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web::http::header;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use crate::api::state::AppState;
+use crate::config::admin::AdminConfig;
+use crate::config::app::{AppConfig, MirrorConfig};
+use crate::error::error_kind::ErrorKind;
+use crate::error::registry::RegistryError;
+use crate::metrics;
+use crate::models::types::{MimeType, UpstreamHost};
+use crate::registry::digest::Digest;
+use crate::registry::repository::Repository;
+use crate::repository::active_reads::Eviction;
+use crate::repository::blob_refs;
+use crate::repository::filesystem::FilesystemStorage;
+
+/// Admin token of a registry request, which can't use the Authorization header meant for upstream
+pub const ADMIN_TOKEN_HEADER: &str = "x-admin-token";
+
+/// Timeout in milliseconds of the upstream request of an admin registry request
+pub const UPSTREAM_TIMEOUT_HEADER: &str = "x-upstream-timeout-ms";
+
+/// What was removed from the cache by a repository purge
+#[derive(Serialize, Debug)]
+pub struct PurgeSummary {
+    pub name: String,
+    pub manifests: usize,
+    pub blobs: usize,
+}
+
+/// Query of a repository purge
+#[derive(Deserialize, Debug)]
+pub struct PurgeQuery {
+    /// Only purge what was pulled from this upstream host, instead of from every upstream
+    pub upstream: Option<String>,
+}
+
+/// How many blobs are verified between two progress reports
+const VERIFY_PROGRESS_INTERVAL: usize = 100;
+
+/// Query of a cache verification
+#[derive(Deserialize, Debug)]
+pub struct VerifyQuery {
+    /// Remove the corrupted blobs and the index records of the missing manifests, instead of reporting them only
+    #[serde(default)]
+    pub delete: bool,
+}
+
+/// How many of the most pulled container images the statistics list, unless the query says otherwise
+const STATS_DEFAULT_TOP: u32 = 10;
+
+/// Query of the cache statistics
+#[derive(Deserialize, Debug)]
+pub struct StatsQuery {
+    /// How many of the most pulled container images to list
+    #[serde(default = "stats_default_top")]
+    pub top: u32,
+}
+
+fn stats_default_top() -> u32 {
+    STATS_DEFAULT_TOP
+}
+
+/// Pulls of a container image through the cache, since it was first pulled
+#[derive(Serialize, Debug)]
+pub struct RepositoryPulls {
+    pub repository: String,
+    pub pulls: i64,
+}
+
+/// Usage statistics of the cache
+#[derive(Serialize, Debug)]
+pub struct CacheStats {
+    /// The most pulled container images, the most pulled first
+    pub most_pulled: Vec<RepositoryPulls>,
+}
+
+/// What the cache holds, for the dashboards
+#[derive(Serialize, Debug)]
+pub struct CacheSummary {
+    /// Container image names with at least a manifest indexed
+    pub repositories: i64,
+
+    /// Indexed manifests, one per tag or digest pulled, upstream and media type
+    pub tags: i64,
+
+    /// Bytes of the indexed manifests
+    pub manifest_bytes: i64,
+
+    /// Stored blobs, manifests included, each counted once whatever the images sharing it
+    pub blobs: u64,
+
+    /// Bytes of the stored blobs
+    pub bytes: u64,
+}
+
+/// Where the requests for an upstream host are sent
+#[derive(Serialize, Debug)]
+pub struct UpstreamRoute {
+    pub registry: String,
+    pub schema: String,
+    pub port: u16,
+    pub path_prefix: Option<String>,
+    pub mirrors: Vec<MirrorConfig>,
+
+    /// Whether the credentials of config.yaml replace the ones of the clients, they are never listed
+    pub credentials: bool,
+}
+
+/// A problem found by a cache verification, its progress or its summary, streamed as a line of JSON
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum VerifyReport {
+    /// The content of the blob does not match the digest it is stored under
+    Mismatch { digest: String, actual: String, path: String, deleted: bool },
+
+    /// A manifest digest is indexed but not stored in any storage folder
+    Missing { digest: String, deleted: bool },
+
+    /// Blobs verified so far
+    Progress { verified: usize, total: usize },
+
+    /// What the verification found
+    Summary { verified: usize, mismatches: usize, missing: usize, deleted: usize },
+}
+
+/// A tag, or digest, of a container image as indexed by the cache
+#[derive(Serialize, Debug)]
+pub struct CachedTag {
+    pub tag: String,
+    /// Host of the upstream the tag was pulled from, the same tag can be cached from several upstreams
+    pub upstream: UpstreamHost,
+    pub reference: Option<String>,
+    pub size: i32,
+    pub mime: MimeType,
+    /// Seconds since the unix epoch the manifest was last read from the disk, none when it is not stored anymore
+    pub last_accessed: Option<u64>,
+}
+
+/// List the tags of a container image held by the cache, with their digest, size and media type
+pub async fn repository_tags(name: web::Path<String>, req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse, RegistryError> {
+
+    authorize(&req, &state.app_config)?;
+
+    // Validate the name
+    let repository = Repository::new_with_limits(&name.into_inner(), &state.app_config.repository_names)?;
+
+    let storages = state.storage.storages();
+
+    let mut tags = Vec::new();
+    for manifest in state.manifests.list_by_name(&repository.name).await? {
+        let last_accessed = match &manifest.reference {
+            Some(digest) => last_accessed(&storages, digest).await,
+            None => None,
+        };
+
+        tags.push(CachedTag {
+            tag: manifest.tag,
+            upstream: manifest.upstream,
+            reference: manifest.reference.map(|digest| digest.to_string()),
+            size: manifest.size,
+            mime: manifest.mime,
+            last_accessed,
+        });
+    }
+
+    Ok(HttpResponse::Ok().json(tags))
+}
+
+/// Remove every tag and blob of a container image from the cache,
+/// the blobs still used by other container images are left in place
+pub async fn purge_repository(name: web::Path<String>, query: web::Query<PurgeQuery>, req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse, RegistryError> {
+
+    authorize(&req, &state.app_config)?;
+
+    if !state.app_config.admin.allow_delete {
+        return Err(RegistryError::new(ErrorKind::Forbidden).with_error("config.yaml admin->allow_delete is disabled"));
+    }
+    started(&state)?;
+
+    // Validate the name
+    let repository = Repository::new_with_limits(&name.into_inner(), &state.app_config.repository_names)?;
+
+    // Drop the index first, so that no client is served a manifest whose blobs are being removed
+    let upstream = query.upstream.as_deref();
+    let manifests = state.manifests.delete_by_name(upstream, &repository.name).await?;
+
+    // The manifests, configs and layers no other container image, nor upstream, references anymore
+    let released = state.manifests.release_name(upstream, &repository.name).await?;
+
+    // The storage folder of the upstream, or all of them: the blobs of a repository can come from several upstreams
+    let storages = match upstream {
+        Some(upstream) => vec![state.storage.for_upstream(upstream)],
+        None => state.storage.storages(),
+    };
+
+    let mut blobs = 0;
+    for digest in &released {
+        blobs += blob_refs::unlink(&storages, &state.manifests, digest).await;
+    }
+
+    // The other upstreams may still hold the container image
+    match state.manifests.size_for_name(&repository.name).await? {
+        0 => if let Err(e) = metrics::CACHE_REPOSITORY_BYTES.remove_label_values(&[&repository.name]) {
+            tracing::debug!("no repository bytes metric for {}: {}", repository.name, e.to_string());
+        },
+        total => metrics::CACHE_REPOSITORY_BYTES.with_label_values(&[&repository.name]).set(total),
+    }
+
+    tracing::info!("Purged {} manifests and {} blobs of {}", manifests.len(), blobs, repository.name);
+
+    Ok(HttpResponse::Ok().json(PurgeSummary {
+        name: repository.name,
+        manifests: manifests.len(),
+        blobs,
+    }))
+}
+
+/// Recompute the digest of every stored blob and look for the indexed manifests which are not stored anymore.
+/// The problems, the progress and the summary are streamed as JSON lines while the verification runs
+pub async fn verify_cache(query: web::Query<VerifyQuery>, req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse, RegistryError> {
+
+    authorize(&req, &state.app_config)?;
+
+    if query.delete && !state.app_config.admin.allow_delete {
+        return Err(RegistryError::new(ErrorKind::Forbidden).with_error("config.yaml admin->allow_delete is disabled"));
+    }
+    if query.delete {
+        started(&state)?;
+    }
+
+    let (reports, reports_rx) = mpsc::unbounded_channel();
+    let delete = query.delete;
+    tokio::spawn(async move {
+        let summary = verify(&state, delete, &reports).await;
+        tracing::info!("Cache verification done: {:?}", summary);
+        let _ = reports.send(summary);
+    });
+
+    let lines = UnboundedReceiverStream::new(reports_rx).map(|report| {
+        let mut line = serde_json::to_vec(&report)?;
+        line.push(b'\n');
+        Ok::<_, serde_json::Error>(web::Bytes::from(line))
+    });
+
+    Ok(HttpResponse::Ok().content_type("application/x-ndjson").streaming(lines))
+}
+
+/// Verify the blobs one at a time, then the indexed manifests, sending the problems and the progress as they are found.
+/// Returns the summary
+async fn verify(state: &AppState, delete: bool, reports: &mpsc::UnboundedSender<VerifyReport>) -> VerifyReport {
+    let (mut verified, mut mismatches, mut missing, mut deleted) = (0, 0, 0, 0);
+
+    // Walking the folders is blocking IO, only the paths are kept in memory
+    let storage = state.storage.clone();
+    let blobs = match tokio::task::spawn_blocking(move || storage.blobs()).await {
+        Ok(Ok(blobs)) => blobs,
+        Ok(Err(e)) => {
+            tracing::error!("Failed to walk the storage folder: {}", e.to_string());
+            Vec::new()
+        }
+        Err(e) => {
+            tracing::error!("Failed to run the storage folder walk: {}", e.to_string());
+            Vec::new()
+        }
+    };
+
+    let total = blobs.len();
+    for blob in blobs {
+        // Removed in the meantime, e.g. evicted
+        let Ok(file) = tokio::fs::File::open(&blob.path).await else { continue };
+
+        let actual = match Digest::hash_digest_file(blob.digest.algo, file.into_std().await).await {
+            Ok(actual) => actual,
+            Err(e) => {
+                tracing::error!("Failed to hash blob {}: {}", blob.path.display(), e);
+                continue;
+            }
+        };
+        verified += 1;
+
+        if actual != blob.digest {
+            tracing::warn!("Blob {} is stored under {} but its digest is {}", blob.path.display(), blob.digest, actual);
+            mismatches += 1;
+
+            // Same as an eviction, a client might still be reading it
+            let removed = delete && match state.storage.evict(blob.path.clone()) {
+                Ok(Eviction::Removed) => {
+                    metrics::CACHE_DISK_BYTES.sub(blob.size as i64);
+                    metrics::CACHE_BLOB_COUNT.dec();
+                    true
+                }
+                Ok(_) => false,
+                Err(e) => {
+                    tracing::error!("Failed to remove blob {}: {}", blob.path.display(), e.to_string());
+                    false
+                }
+            };
+            if removed {
+                deleted += 1;
+                blob_refs::forget_type(&state.storage, &state.manifests, &blob.digest).await;
+            }
+
+            let _ = reports.send(VerifyReport::Mismatch {
+                digest: blob.digest.to_string(),
+                actual: actual.to_string(),
+                path: blob.path.display().to_string(),
+                deleted: removed,
+            });
+        }
+
+        if verified % VERIFY_PROGRESS_INTERVAL == 0 {
+            let _ = reports.send(VerifyReport::Progress { verified, total });
+        }
+    }
+
+    // The indexed manifests pointing to nothing
+    let storages = state.storage.storages();
+    let references = state.manifests.references().await.unwrap_or_else(|e| {
+        tracing::error!("Failed to load the indexed manifests: {}", e);
+        Vec::new()
+    });
+    for digest in references {
+        if is_stored(&storages, &digest).await {
+            continue;
+        }
+        missing += 1;
+
+        let removed = delete && match state.manifests.delete_by_reference(&digest).await {
+            Ok(_) => true,
+            Err(e) => {
+                tracing::error!("Failed to remove the index records of {}: {}", digest, e);
+                false
+            }
+        };
+        if removed {
+            deleted += 1;
+        }
+
+        let _ = reports.send(VerifyReport::Missing { digest: digest.to_string(), deleted: removed });
+    }
+
+    VerifyReport::Summary { verified, mismatches, missing, deleted }
+}
+
+/// List the blobs and manifests whose persistence failed, with the reason of the last failure
+pub async fn dead_letters(req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse, RegistryError> {
+
+    authorize(&req, &state.app_config)?;
+
+    Ok(HttpResponse::Ok().json(state.manifests.dead_letters().await?))
+}
+
+/// Usage statistics of the cache, e.g. which container images are worth pinning or prefetching
+pub async fn stats(query: web::Query<StatsQuery>, req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse, RegistryError> {
+
+    authorize(&req, &state.app_config)?;
+
+    let most_pulled = state.manifests.most_pulled(query.top).await?.into_iter()
+        .map(|(repository, pulls)| RepositoryPulls { repository, pulls })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(CacheStats { most_pulled }))
+}
+
+/// Count the repositories and the tags indexed, and the unique blobs stored with their bytes
+pub async fn summary(req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse, RegistryError> {
+
+    authorize(&req, &state.app_config)?;
+
+    let (repositories, tags, manifest_bytes) = state.manifests.summary().await?;
+
+    // Walking the folder is blocking IO
+    let storage = state.storage.clone();
+    let (bytes, blobs) = tokio::task::spawn_blocking(move || storage.driver().usage()).await
+        .map_err(|e| RegistryError::new(ErrorKind::InternalError).with_error(e.to_string()))?
+        .map_err(|e| RegistryError::new(ErrorKind::InternalError).with_error(e.to_string()))?;
+
+    Ok(HttpResponse::Ok().json(CacheSummary { repositories, tags, manifest_bytes, blobs, bytes }))
+}
+
+/// The upstream each Host header is routed to, as the live config has it, i.e. after the config reloads
+pub async fn upstreams(req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse, RegistryError> {
+
+    authorize(&req, &state.app_config)?;
+
+    let routes = state.live().upstreams.iter()
+        .map(|(host, upstream)| (host.clone(), UpstreamRoute {
+            registry: upstream.registry.clone(),
+            schema: upstream.schema.clone(),
+            port: upstream.port,
+            path_prefix: upstream.path_prefix.clone(),
+            mirrors: upstream.mirrors.clone(),
+            credentials: upstream.credentials.is_some(),
+        }))
+        .collect::<BTreeMap<String, UpstreamRoute>>();
+
+    Ok(HttpResponse::Ok().json(routes))
+}
+
+/// The blobs are only removed once the startup completed: until the blob references of a cache which predates them
+/// are recorded, blobs still used by a container image would look unreferenced
+fn started(state: &AppState) -> Result<(), RegistryError> {
+    if state.readiness.is_started() {
+        return Ok(());
+    }
+    Err(RegistryError::new(ErrorKind::Unavailable)
+        .with_context("the cache is starting up")
+        .with_retry_after(state.app_config.readiness.interval))
+}
+
+/// Check the bearer token of an admin request
+fn authorize(req: &HttpRequest, config: &AppConfig) -> Result<(), RegistryError> {
+    // The admin API is disabled
+    let Some(token) = &config.admin.token else {
+        return Err(RegistryError::new(ErrorKind::NotFound));
+    };
+
+    let bearer = req.headers().get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match bearer {
+        Some(bearer) if constant_time_eq(bearer.as_bytes(), token.as_bytes()) => Ok(()),
+        _ => Err(RegistryError::unauthorized(config.realm())),
+    }
+}
+
+/// The timeout of the upstream request asked by a registry request presenting the admin token,
+/// ignored unless `admin.allow_upstream_timeout` is enabled
+pub fn upstream_timeout(req: &HttpRequest, config: &AdminConfig) -> Option<Duration> {
+    let timeout = req.headers().get(UPSTREAM_TIMEOUT_HEADER)?;
+
+    let authorized = config.allow_upstream_timeout && config.token.as_ref().is_some_and(|token| {
+        req.headers().get(ADMIN_TOKEN_HEADER)
+            .is_some_and(|presented| constant_time_eq(presented.as_bytes(), token.as_bytes()))
+    });
+    if !authorized {
+        tracing::warn!("Ignored the {} header of an unauthorized request", UPSTREAM_TIMEOUT_HEADER);
+        return None;
+    }
+
+    match timeout.to_str().ok().and_then(|timeout| timeout.parse().ok()) {
+        Some(timeout) => Some(Duration::from_millis(timeout)),
+        None => {
+            tracing::warn!("Ignored the invalid {} header: {:?}", UPSTREAM_TIMEOUT_HEADER, timeout);
+            None
+        }
+    }
+}
+
+/// Compare the tokens without leaking via the timing how much of them matches
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Access time, same as the eviction sees it, of the manifest in the first storage it is found in
+async fn last_accessed(storages: &[FilesystemStorage], digest: &Digest) -> Option<u64> {
+    for storage in storages {
+        if let Ok(metadata) = tokio::fs::metadata(storage.digest_path(digest)).await {
+            let accessed = metadata.accessed().or_else(|_| metadata.modified()).ok()?;
+            return accessed.duration_since(UNIX_EPOCH).ok().map(|accessed| accessed.as_secs());
+        }
+    }
+    None
+}
+
+/// Whether the content is stored in any of the storages
+async fn is_stored(storages: &[FilesystemStorage], digest: &Digest) -> bool {
+    for storage in storages {
+        if storage.driver().is_stored(digest) {
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::{test, web, App};
+    use actix_web::http::{header, StatusCode};
+    use sha2::{Digest as Sha2Digest, Sha256};
+    use crate::api::{live_config, routes};
+    use crate::api::state::AppState;
+    use crate::config::app::AppConfig;
+    use crate::models::dead_letter::DeadLetterRecord;
+    use crate::registry::digest::Digest;
+    use crate::registry::repository::Repository;
+    use crate::repository::blob_refs;
+
+    const MIME: &str = "application/vnd.oci.image.manifest.v1+json";
+
+    fn digest(data: &str) -> Digest {
+        Digest::parse(&format!("sha256:{}", hex::encode(Sha256::digest(data.as_bytes())))).unwrap()
+    }
+
+    /// Store the manifest with the given layers, and the layers themselves, then index the manifest and what it references for the tag
+    async fn cache_image(state: &AppState, name: &str, layers: &[&str]) -> Digest {
+        cache_image_from(state, "localhost", name, layers).await
+    }
+
+    /// Same as `cache_image`, for the tag of the upstream
+    async fn cache_image_from(state: &AppState, upstream: &str, name: &str, layers: &[&str]) -> Digest {
+        let descriptors = layers.iter().map(|layer| {
+            std::fs::write(state.storage.digest_path(&digest(layer)), layer).unwrap();
+            format!(r#"{{"mediaType":"application/vnd.oci.image.layer.v1.tar+gzip","digest":"{}","size":{}}}"#, digest(layer), layer.len())
+        }).collect::<Vec<String>>();
+        for layer in layers {
+            state.manifests.persist_blob_type(&digest(layer), &"application/vnd.oci.image.layer.v1.tar+gzip".parse().unwrap()).await.unwrap();
+        }
+        let manifest = format!(r#"{{"schemaVersion":2,"mediaType":"{}","layers":[{}]}}"#, MIME, descriptors.join(","));
+
+        let manifest_digest = digest(&manifest);
+        std::fs::write(state.storage.digest_path(&manifest_digest), &manifest).unwrap();
+        let repository = Repository::new_with_reference(name, "latest").unwrap();
+        state.manifests.persist(upstream, &repository, manifest_digest.clone(), manifest.len() as i32, &MIME.parse().unwrap(), None).await.unwrap();
+        state.manifests.reference_blobs(upstream, &repository, &manifest_digest, &blob_refs::referenced_digests(&manifest_digest, manifest.as_bytes())).await.unwrap();
+        manifest_digest
+    }
+
+    #[actix_web::test]
+    async fn purge_repository_test() {
+        let folder = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(folder.path().join("sha256")).unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.admin.token = Some("secret".to_string());
+        config.admin.allow_delete = true;
+        let (state, _commands) = AppState::for_test(config).await;
+
+        // The base layer is shared with another image
+        let nginx = cache_image(&state, "library/nginx", &["base layer", "nginx layer"]).await;
+        let debian = cache_image(&state, "library/debian", &["base layer"]).await;
+        cache_image_from(&state, "mirror.local", "library/nginx", &["base layer", "nginx layer"]).await;
+
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(state.clone()))
+            .service(web::scope("/admin").configure(routes::admin_api_config))).await;
+
+        // Admin token required, without a realm nothing tells where to authenticate
+        let req = test::TestRequest::delete().uri("/admin/cache/library/nginx")
+            .insert_header((header::AUTHORIZATION, "Bearer wrong"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, resp.status());
+        assert!(!resp.headers().contains_key(header::WWW_AUTHENTICATE));
+        assert!(state.storage.digest_path(&nginx).exists());
+
+        // Not until the blob references are backfilled
+        let purge = || test::TestRequest::delete().uri("/admin/cache/library/nginx")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_request();
+        let resp = test::call_service(&app, purge()).await;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, resp.status());
+        assert!(resp.headers().contains_key(header::RETRY_AFTER));
+        assert!(state.storage.digest_path(&nginx).exists());
+        state.readiness.started();
+
+        // Purging one upstream keeps the blobs the other one still references
+        let req = test::TestRequest::delete().uri("/admin/cache/library/nginx?upstream=mirror.local")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        let summary: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(serde_json::json!({"name": "library/nginx", "manifests": 1, "blobs": 0}), summary);
+        assert!(state.storage.digest_path(&nginx).exists());
+        assert!(state.storage.digest_path(&digest("nginx layer")).exists());
+
+        let resp = test::call_service(&app, purge()).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        let summary: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(serde_json::json!({"name": "library/nginx", "manifests": 1, "blobs": 2}), summary);
+
+        // Only what belongs to nginx alone is gone
+        assert!(!state.storage.digest_path(&nginx).exists());
+        assert!(!state.storage.digest_path(&digest("nginx layer")).exists());
+        assert!(state.storage.digest_path(&digest("base layer")).exists());
+        assert!(state.storage.digest_path(&debian).exists());
+        assert!(state.manifests.blob_type(&digest("nginx layer")).await.unwrap().is_none());
+        assert!(state.manifests.blob_type(&digest("base layer")).await.unwrap().is_some());
+        assert!(state.manifests.get("localhost", &Repository::new_with_reference("library/nginx", "latest").unwrap(), &[]).await.unwrap().is_none());
+        assert!(state.manifests.get("localhost", &Repository::new_with_reference("library/debian", "latest").unwrap(), &[]).await.unwrap().is_some());
+    }
+
+    #[actix_web::test]
+    async fn dead_letters_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.admin.token = Some("secret".to_string());
+        let (state, _commands) = AppState::for_test(config).await;
+
+        let layer = digest("layer");
+        state.manifests.persist_dead_letter(&DeadLetterRecord {
+            upstream: "localhost:8080".to_string(),
+            name: "library/nginx".to_string(),
+            reference: layer.to_string(),
+            digest: layer.clone(),
+            mime: None,
+            reason: "Digest mismatch".to_string(),
+            attempts: 1,
+            failed_at: 100,
+        }).await.unwrap();
+
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(state))
+            .service(web::scope("/admin").configure(routes::admin_api_config))).await;
+
+        let req = test::TestRequest::get().uri("/admin/dead-letters").to_request();
+        assert_eq!(StatusCode::UNAUTHORIZED, test::call_service(&app, req).await.status());
+
+        let req = test::TestRequest::get().uri("/admin/dead-letters")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        let dead_letters: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(serde_json::json!([{
+            "upstream": "localhost:8080",
+            "name": "library/nginx",
+            "reference": layer.to_string(),
+            "digest": layer.to_string(),
+            "mime": null,
+            "reason": "Digest mismatch",
+            "attempts": 1,
+            "failed_at": 100,
+        }]), dead_letters);
+    }
+
+    #[actix_web::test]
+    async fn stats_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.admin.token = Some("secret".to_string());
+        let (state, _commands) = AppState::for_test(config).await;
+
+        for name in ["library/nginx", "library/alpine", "library/nginx", "library/redis"] {
+            state.pull_stats.record(name);
+        }
+        state.pull_stats.flush().await;
+
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(state))
+            .service(web::scope("/admin").configure(routes::admin_api_config))).await;
+
+        let req = test::TestRequest::get().uri("/admin/stats").to_request();
+        assert_eq!(StatusCode::UNAUTHORIZED, test::call_service(&app, req).await.status());
+
+        let stats = |uri: &'static str| {
+            let req = test::TestRequest::get().uri(uri)
+                .insert_header((header::AUTHORIZATION, "Bearer secret"))
+                .to_request();
+            test::call_service(&app, req)
+        };
+
+        let resp = stats("/admin/stats").await;
+        assert_eq!(StatusCode::OK, resp.status());
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(serde_json::json!({"most_pulled": [
+            {"repository": "library/nginx", "pulls": 2},
+            {"repository": "library/alpine", "pulls": 1},
+            {"repository": "library/redis", "pulls": 1},
+        ]}), body);
+
+        let body: serde_json::Value = test::read_body_json(stats("/admin/stats?top=1").await).await;
+        assert_eq!(serde_json::json!({"most_pulled": [{"repository": "library/nginx", "pulls": 2}]}), body);
+    }
+
+    #[actix_web::test]
+    async fn summary_test() {
+        let folder = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(folder.path().join("sha256")).unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.admin.token = Some("secret".to_string());
+        let (state, _commands) = AppState::for_test(config).await;
+
+        // The base layer is shared, and stored once
+        let nginx = cache_image(&state, "library/nginx", &["base layer", "nginx layer"]).await;
+        let debian = cache_image(&state, "library/debian", &["base layer"]).await;
+        let stable = Repository::new_with_reference("library/nginx", "stable").unwrap();
+        state.manifests.persist("localhost", &stable, nginx.clone(), 100, &MIME.parse().unwrap(), None).await.unwrap();
+
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(state.clone()))
+            .service(web::scope("/admin").configure(routes::admin_api_config))).await;
+
+        let req = test::TestRequest::get().uri("/admin/summary").to_request();
+        assert_eq!(StatusCode::UNAUTHORIZED, test::call_service(&app, req).await.status());
+
+        let req = test::TestRequest::get().uri("/admin/summary")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        let summary: serde_json::Value = test::read_body_json(resp).await;
+
+        let stored = [nginx, debian, digest("base layer"), digest("nginx layer")].iter()
+            .map(|digest| std::fs::metadata(state.storage.digest_path(digest)).unwrap().len())
+            .sum::<u64>();
+        let indexed = state.manifests.size_by_name().await.unwrap().iter().map(|(_, size)| size).sum::<i64>();
+        assert_eq!(serde_json::json!({
+            "repositories": 2,
+            "tags": 3,
+            "manifest_bytes": indexed,
+            "blobs": 4,
+            "bytes": stored,
+        }), summary);
+    }
+
+    #[actix_web::test]
+    async fn upstreams_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.admin.token = Some("secret".to_string());
+        config.upstreams.push(serde_json::from_value(serde_json::json!({
+            "host": "docker.local", "registry": "registry-1.docker.io", "port": 443, "schema": "https",
+            "credentials": { "username": "mirror", "password": { "value": "s3cret" } },
+        })).unwrap());
+        let (state, _commands) = AppState::for_test(config.clone()).await;
+
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(state.clone()))
+            .service(web::scope("/admin").configure(routes::admin_api_config))).await;
+
+        let req = test::TestRequest::get().uri("/admin/upstreams").to_request();
+        assert_eq!(StatusCode::UNAUTHORIZED, test::call_service(&app, req).await.status());
+
+        let upstreams = || test::call_service(&app, test::TestRequest::get().uri("/admin/upstreams")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_request());
+
+        // The credentials are not listed
+        let resp = upstreams().await;
+        assert_eq!(StatusCode::OK, resp.status());
+        let body = test::read_body(resp).await;
+        assert!(!std::str::from_utf8(&body).unwrap().contains("s3cret"));
+        let routes: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(serde_json::json!({
+            "registry": "registry-1.docker.io",
+            "schema": "https",
+            "port": 443,
+            "path_prefix": null,
+            "mirrors": [],
+            "credentials": true,
+        }), routes["docker.local"]);
+
+        // Once config.yaml is reloaded
+        let mut reloaded = config.clone();
+        reloaded.upstreams[0].registry = "mirror.gcr.io".to_string();
+        assert!(live_config::reload(&state, Ok(reloaded)));
+        let routes: serde_json::Value = test::read_body_json(upstreams().await).await;
+        assert_eq!("mirror.gcr.io", routes["docker.local"]["registry"]);
+    }
+
+    #[actix_web::test]
+    async fn purge_repository_disallowed_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.admin.token = Some("secret".to_string());
+        config.auth.realm = Some("https://auth.cache.local/token".to_string());
+        let (state, _commands) = AppState::for_test(config).await;
+
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(state))
+            .service(web::scope("/admin").configure(routes::admin_api_config))).await;
+
+        let req = test::TestRequest::delete().uri("/admin/cache/library/nginx")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_request();
+        assert_eq!(StatusCode::FORBIDDEN, test::call_service(&app, req).await.status());
+
+        // The challenge tells where to authenticate
+        let req = test::TestRequest::delete().uri("/admin/cache/library/nginx")
+            .insert_header((header::AUTHORIZATION, "Bearer wrong"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, resp.status());
+        assert_eq!("Bearer realm=\"https://auth.cache.local/token\"", resp.headers().get(header::WWW_AUTHENTICATE).unwrap());
+    }
+
+    #[actix_web::test]
+    async fn repository_tags_test() {
+        let folder = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(folder.path().join("sha256")).unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.admin.token = Some("secret".to_string());
+        let (state, _commands) = AppState::for_test(config).await;
+
+        let nginx = cache_image(&state, "library/nginx", &["nginx layer"]).await;
+        cache_image(&state, "library/debian", &["base layer"]).await;
+
+        // A tag whose manifest was evicted since
+        let evicted = digest("evicted manifest");
+        let stable = Repository::new_with_reference("library/nginx", "stable").unwrap();
+        state.manifests.persist("localhost", &stable, evicted.clone(), 16, &MIME.parse().unwrap(), None).await.unwrap();
+
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(state))
+            .service(web::scope("/admin").configure(routes::admin_api_config))).await;
+
+        let req = test::TestRequest::get().uri("/admin/repositories/library/nginx").to_request();
+        assert_eq!(StatusCode::UNAUTHORIZED, test::call_service(&app, req).await.status());
+
+        let req = test::TestRequest::get().uri("/admin/repositories/library/nginx")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        let tags: serde_json::Value = test::read_body_json(resp).await;
+
+        let tags = tags.as_array().unwrap();
+        assert_eq!(2, tags.len());
+        assert_eq!("latest", tags[0]["tag"]);
+        assert_eq!("localhost", tags[0]["upstream"]);
+        assert_eq!(nginx.to_string(), tags[0]["reference"]);
+        assert_eq!(MIME, tags[0]["mime"]);
+        assert!(tags[0]["size"].as_i64().unwrap() > 0);
+        assert!(tags[0]["last_accessed"].as_u64().unwrap() > 0);
+        assert_eq!(serde_json::json!({
+            "tag": "stable",
+            "upstream": "localhost",
+            "reference": evicted.to_string(),
+            "size": 16,
+            "mime": MIME,
+            "last_accessed": null,
+        }), tags[1]);
+    }
+
+    #[actix_web::test]
+    async fn repository_name_limits_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.admin.token = Some("secret".to_string());
+        config.admin.allow_delete = true;
+        config.repository_names.max_components = 2;
+        let (state, _commands) = AppState::for_test(config).await;
+        state.readiness.started();
+
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(state))
+            .service(web::scope("/admin").configure(routes::admin_api_config))).await;
+
+        for req in [test::TestRequest::get().uri("/admin/repositories/mycorp/team/app"), test::TestRequest::delete().uri("/admin/cache/mycorp/team/app")] {
+            let resp = test::call_service(&app, req.insert_header((header::AUTHORIZATION, "Bearer secret")).to_request()).await;
+            assert_eq!(StatusCode::NOT_FOUND, resp.status());
+            assert!(std::str::from_utf8(&test::read_body(resp).await).unwrap().contains("NAME_INVALID"));
+        }
+
+        // Within the limits
+        let req = test::TestRequest::get().uri("/admin/repositories/library/nginx")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_request();
+        assert_eq!(StatusCode::OK, test::call_service(&app, req).await.status());
+    }
+
+    #[actix_web::test]
+    async fn verify_cache_test() {
+        let folder = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(folder.path().join("sha256")).unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.admin.token = Some("secret".to_string());
+        let (state, _commands) = AppState::for_test(config.clone()).await;
+
+        cache_image(&state, "library/nginx", &["nginx layer", "rotten layer"]).await;
+        let rotten = state.storage.digest_path(&digest("rotten layer"));
+        std::fs::write(&rotten, "bit rot").unwrap();
+
+        // A tag whose manifest was evicted since
+        let evicted = digest("evicted manifest");
+        let stable = Repository::new_with_reference("library/nginx", "stable").unwrap();
+        state.manifests.persist("localhost", &stable, evicted.clone(), 16, &MIME.parse().unwrap(), None).await.unwrap();
+
+        let verify = |state: AppState, uri: &'static str| async move {
+            let app = test::init_service(App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/admin").configure(routes::admin_api_config))).await;
+            let req = test::TestRequest::post().uri(uri)
+                .insert_header((header::AUTHORIZATION, "Bearer secret"))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            let status = resp.status();
+            let body = test::read_body(resp).await;
+            let reports = std::str::from_utf8(&body).unwrap().lines()
+                .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+                .collect::<Vec<serde_json::Value>>();
+            (status, reports)
+        };
+
+        // Dry run
+        let (status, reports) = verify(state.clone(), "/admin/verify").await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(serde_json::json!([{
+            "type": "mismatch",
+            "digest": digest("rotten layer").to_string(),
+            "actual": digest("bit rot").to_string(),
+            "path": rotten.display().to_string(),
+            "deleted": false,
+        }, {
+            "type": "missing",
+            "digest": evicted.to_string(),
+            "deleted": false,
+        }, {
+            "type": "summary",
+            "verified": 3,
+            "mismatches": 1,
+            "missing": 1,
+            "deleted": 0,
+        }]), serde_json::Value::Array(reports));
+        assert!(rotten.exists());
+
+        // Removing requires admin.allow_delete
+        assert_eq!(StatusCode::FORBIDDEN, verify(state.clone(), "/admin/verify?delete=true").await.0);
+
+        // Nor until the blob references are backfilled
+        config.admin.allow_delete = true;
+        let state = AppState { app_config: config, ..state };
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, verify(state.clone(), "/admin/verify?delete=true").await.0);
+        assert!(rotten.exists());
+
+        state.readiness.started();
+        let (_, reports) = verify(state.clone(), "/admin/verify?delete=true").await;
+        assert_eq!(serde_json::json!({"type": "summary", "verified": 3, "mismatches": 1, "missing": 1, "deleted": 2}), reports[2]);
+        assert!(!rotten.exists());
+        assert_eq!(1, state.manifests.list_by_name("library/nginx").await.unwrap().len());
+    }
+}
@@ -0,0 +1,189 @@
+// SPDX-License-Identifier: Apache-2.0
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use parking_lot::Mutex;
+use crate::config::circuit_breaker::CircuitBreakerConfig;
+use crate::metrics;
+use crate::models::types::UpstreamHost;
+
+/// State of the circuit of an upstream, the value of the upstream_circuit_state metric
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CircuitState {
+    /// The upstream is called
+    Closed = 0,
+
+    /// The upstream kept failing, it is not called until the cooldown elapsed
+    Open = 1,
+
+    /// The cooldown elapsed, a single request probes whether the upstream is back
+    HalfOpen = 2,
+}
+
+struct Circuit {
+    state: CircuitState,
+
+    /// Consecutive failures since the first one of the window
+    failures: u32,
+    first_failure: Instant,
+
+    /// When the circuit was opened, or the probe of the half-open circuit started
+    since: Instant,
+}
+
+/// A circuit breaker per upstream, and per mirror by its base URL: after too many consecutive failures it is skipped for a while
+pub struct CircuitBreakers {
+    circuits: Mutex<HashMap<UpstreamHost, Circuit>>,
+
+    /// Disabled when not set
+    failures: Option<u32>,
+    window: Duration,
+    cooldown: Duration,
+}
+
+impl CircuitBreakers {
+
+    pub fn new(config: &CircuitBreakerConfig) -> Self {
+        CircuitBreakers {
+            circuits: Default::default(),
+            failures: config.failures,
+            window: Duration::from_secs(config.window),
+            cooldown: Duration::from_secs(config.cooldown),
+        }
+    }
+
+    /// Whether the upstream can be called. Once the cooldown elapsed the caller probes the upstream,
+    /// the other ones are turned away until the probe is done, or it takes longer than another cooldown
+    pub fn allow(&self, upstream: &str) -> bool {
+        let mut circuits = self.circuits.lock();
+        let Some(circuit) = circuits.get_mut(upstream) else { return true };
+
+        match circuit.state {
+            CircuitState::Closed => true,
+            _ if circuit.since.elapsed() < self.cooldown => false,
+            _ => {
+                tracing::info!("Probing upstream {} after a cooldown of {:?}", upstream, self.cooldown);
+                circuit.since = Instant::now();
+                set_state(upstream, circuit, CircuitState::HalfOpen);
+                true
+            }
+        }
+    }
+
+    /// Whether the upstream is skipped, i.e. its circuit is open, or half-open while another request probes it.
+    /// Unlike `allow`, it does not start a probe
+    pub fn is_open(&self, upstream: &str) -> bool {
+        self.circuits.lock().get(upstream)
+            .is_some_and(|circuit| circuit.state != CircuitState::Closed && circuit.since.elapsed() < self.cooldown)
+    }
+
+    /// The upstream answered
+    pub fn success(&self, upstream: &str) {
+        let mut circuits = self.circuits.lock();
+        if let Some(mut circuit) = circuits.remove(upstream) {
+            if circuit.state != CircuitState::Closed {
+                tracing::info!("Upstream {} is back, closing its circuit", upstream);
+                set_state(upstream, &mut circuit, CircuitState::Closed);
+            }
+        }
+    }
+
+    /// The upstream could not be reached, timed out or answered with a server error
+    pub fn failure(&self, upstream: &str) {
+        let Some(failures) = self.failures else { return };
+
+        let mut circuits = self.circuits.lock();
+        let now = Instant::now();
+        let circuit = circuits.entry(upstream.to_string()).or_insert_with(|| Circuit {
+            state: CircuitState::Closed,
+            failures: 0,
+            first_failure: now,
+            since: now,
+        });
+
+        // The failures are only consecutive within the window
+        if now.duration_since(circuit.first_failure) > self.window {
+            circuit.failures = 0;
+            circuit.first_failure = now;
+        }
+        circuit.failures += 1;
+
+        let open = match circuit.state {
+            CircuitState::Closed => circuit.failures >= failures,
+            CircuitState::Open => false,
+            CircuitState::HalfOpen => true,
+        };
+        if open {
+            tracing::warn!("Upstream {} failed {} times, opening its circuit for {:?}", upstream, circuit.failures, self.cooldown);
+            circuit.since = now;
+            set_state(upstream, circuit, CircuitState::Open);
+        }
+    }
+
+    /// The state of the circuit of the upstream
+    #[cfg(test)]
+    pub fn state(&self, upstream: &str) -> CircuitState {
+        self.circuits.lock().get(upstream).map(|circuit| circuit.state).unwrap_or(CircuitState::Closed)
+    }
+}
+
+fn set_state(upstream: &str, circuit: &mut Circuit, state: CircuitState) {
+    circuit.state = state;
+    metrics::UPSTREAM_CIRCUIT_STATE.with_label_values(&[upstream]).set(state as i64);
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use crate::api::circuit_breaker::{CircuitBreakers, CircuitState};
+    use crate::config::circuit_breaker::CircuitBreakerConfig;
+
+    #[test]
+    fn circuit_breaker_test() {
+        let mut breakers = CircuitBreakers::new(&CircuitBreakerConfig { failures: Some(3), ..Default::default() });
+
+        // A success in between resets the failures
+        breakers.failure("docker.io");
+        breakers.failure("docker.io");
+        breakers.success("docker.io");
+        breakers.failure("docker.io");
+        breakers.failure("docker.io");
+        assert!(breakers.allow("docker.io"));
+
+        // Opened by the consecutive failures, for this upstream only
+        breakers.failure("docker.io");
+        assert_eq!(CircuitState::Open, breakers.state("docker.io"));
+        assert!(!breakers.allow("docker.io"));
+        assert!(breakers.allow("quay.io"));
+
+        // A single probe once the cooldown elapsed, a failed one opens the circuit again
+        breakers.cooldown = Duration::from_millis(20);
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(breakers.allow("docker.io"));
+        assert_eq!(CircuitState::HalfOpen, breakers.state("docker.io"));
+        assert!(!breakers.allow("docker.io"));
+        breakers.failure("docker.io");
+        assert_eq!(CircuitState::Open, breakers.state("docker.io"));
+
+        // A successful probe closes it
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(breakers.allow("docker.io"));
+        breakers.success("docker.io");
+        assert_eq!(CircuitState::Closed, breakers.state("docker.io"));
+        assert!(breakers.allow("docker.io"));
+
+        // The failures must happen within the window
+        breakers.window = Duration::from_millis(20);
+        breakers.failure("docker.io");
+        breakers.failure("docker.io");
+        std::thread::sleep(Duration::from_millis(30));
+        breakers.failure("docker.io");
+        assert_eq!(CircuitState::Closed, breakers.state("docker.io"));
+
+        // Disabled
+        let breakers = CircuitBreakers::new(&CircuitBreakerConfig::default());
+        for _ in 0..100 {
+            breakers.failure("docker.io");
+        }
+        assert!(breakers.allow("docker.io"));
+    }
+}
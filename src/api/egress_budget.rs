@@ -0,0 +1,169 @@
+// SPDX-License-Identifier: Apache-2.0
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use parking_lot::Mutex;
+use crate::api::registry::AnsweringMirror;
+use crate::config::egress_budget::EgressBudgetConfig;
+use crate::metrics;
+use crate::models::types::UpstreamHost;
+
+/// Bytes fetched from an upstream in the current period and in the previous one.
+/// The bytes of the sliding window are the ones of the current period, plus the share of the previous one
+/// the window still overlaps
+struct Usage {
+    /// Start of the current period
+    since: Instant,
+    current: u64,
+    previous: u64,
+}
+
+impl Usage {
+
+    /// Move on to the period of now
+    fn roll(&mut self, now: Instant, period: Duration) {
+        let elapsed = now.duration_since(self.since);
+        if elapsed >= period * 2 {
+            self.previous = 0;
+            self.current = 0;
+            self.since = now;
+        } else if elapsed >= period {
+            self.previous = self.current;
+            self.current = 0;
+            self.since += period;
+        }
+    }
+
+    /// Bytes fetched within the sliding window ending now
+    fn used(&self, now: Instant, period: Duration) -> u64 {
+        let overlap = 1.0 - (now.duration_since(self.since).as_secs_f64() / period.as_secs_f64()).min(1.0);
+        self.current + (self.previous as f64 * overlap) as u64
+    }
+}
+
+/// The bytes fetched from each upstream, and each mirror by its base URL, over a sliding window, one is not called anymore once they reach its budget
+pub struct EgressBudgets {
+    usage: Mutex<HashMap<UpstreamHost, Usage>>,
+
+    /// Unlimited when not set
+    budget: Option<u64>,
+    period: Duration,
+}
+
+impl EgressBudgets {
+
+    pub fn new(config: &EgressBudgetConfig) -> Self {
+        EgressBudgets {
+            usage: Default::default(),
+            budget: config.upstream_egress_budget_bytes,
+            period: Duration::from_secs(config.period_secs),
+        }
+    }
+
+    /// Whether the upstream can be called, i.e. some of its budget is left
+    pub fn allow(&self, upstream: &str) -> bool {
+        self.remaining(upstream).is_none_or(|remaining| remaining > 0)
+    }
+
+    /// Bytes which can still be fetched from the upstream, none when there is no budget
+    pub fn remaining(&self, upstream: &str) -> Option<u64> {
+        let budget = self.budget?;
+
+        let now = Instant::now();
+        let mut usage = self.usage.lock();
+        let remaining = match usage.get_mut(upstream) {
+            Some(usage) => {
+                usage.roll(now, self.period);
+                budget.saturating_sub(usage.used(now, self.period))
+            }
+            None => budget,
+        };
+        metrics::UPSTREAM_EGRESS_BUDGET_REMAINING.with_label_values(&[upstream]).set(remaining as i64);
+        Some(remaining)
+    }
+
+    /// Count the bytes received from the upstream
+    pub fn record(&self, upstream: &str, bytes: u64) {
+        let Some(budget) = self.budget else { return };
+
+        let now = Instant::now();
+        let mut usage = self.usage.lock();
+        let usage = usage.entry(upstream.to_string()).or_insert_with(|| Usage { since: now, current: 0, previous: 0 });
+        usage.roll(now, self.period);
+        let spent = usage.used(now, self.period) >= budget;
+        usage.current = usage.current.saturating_add(bytes);
+
+        let remaining = budget.saturating_sub(usage.used(now, self.period));
+        metrics::UPSTREAM_EGRESS_BUDGET_REMAINING.with_label_values(&[upstream]).set(remaining as i64);
+        if remaining == 0 && !spent {
+            tracing::warn!("Egress budget of upstream {} is spent, only the cached content is served until the window slides", upstream);
+        }
+    }
+
+    /// The body of the upstream response, its bytes counted as they are received.
+    /// The ones of a mirror answering instead of the upstream count against the budget of the mirror, by its base URL
+    pub fn counted(self: &Arc<Self>, upstream: &str, response: reqwest::Response) -> impl Stream<Item = reqwest::Result<Bytes>> {
+        let budgets = self.clone();
+        let upstream = response.extensions().get::<AnsweringMirror>()
+            .map_or_else(|| upstream.to_string(), |AnsweringMirror(mirror)| mirror.clone());
+        response.bytes_stream().inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                budgets.record(&upstream, chunk.len() as u64);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+    use crate::api::egress_budget::{EgressBudgets, Usage};
+    use crate::config::egress_budget::EgressBudgetConfig;
+
+    #[test]
+    fn egress_budget_test() {
+        let budgets = EgressBudgets::new(&EgressBudgetConfig { upstream_egress_budget_bytes: Some(100), period_secs: 60 });
+        assert_eq!(Some(100), budgets.remaining("docker.io"));
+
+        // Per upstream
+        budgets.record("docker.io", 60);
+        assert_eq!(Some(40), budgets.remaining("docker.io"));
+        assert!(budgets.allow("docker.io"));
+        budgets.record("docker.io", 60);
+        assert_eq!(Some(0), budgets.remaining("docker.io"));
+        assert!(!budgets.allow("docker.io"));
+        assert!(budgets.allow("quay.io"));
+
+        // Unlimited
+        let budgets = EgressBudgets::new(&EgressBudgetConfig::default());
+        budgets.record("docker.io", u64::MAX);
+        assert!(budgets.allow("docker.io"));
+        assert_eq!(None, budgets.remaining("docker.io"));
+    }
+
+    #[test]
+    fn sliding_window_test() {
+        let period = Duration::from_secs(60);
+        let start = Instant::now();
+        let mut usage = Usage { since: start, current: 100, previous: 0 };
+        assert_eq!(100, usage.used(start, period));
+
+        // Half of the previous period is still within the window
+        let now = start + Duration::from_secs(90);
+        usage.roll(now, period);
+        usage.current += 10;
+        assert_eq!(60, usage.used(now, period));
+
+        // Then none of it
+        let now = start + Duration::from_secs(120);
+        usage.roll(now, period);
+        assert_eq!(10, usage.used(now, period));
+
+        // Nothing fetched for a while
+        let now = start + Duration::from_secs(300);
+        usage.roll(now, period);
+        assert_eq!(0, usage.used(now, period));
+    }
+}
@@ -0,0 +1,135 @@
+// SPDX-License-Identifier: Apache-2.0
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use crate::config::streaming::{InFlightPolicy, StreamingConfig};
+use crate::error::error_kind::ErrorKind;
+use crate::error::registry::RegistryError;
+use crate::metrics;
+
+/// Global cap on the upstream responses streamed at the same time,
+/// each of them holds a duplex buffer and a persist channel
+pub struct InFlightLimiter {
+    /// Not set when there is no limit
+    semaphore: Option<Arc<Semaphore>>,
+    policy: InFlightPolicy,
+}
+
+impl InFlightLimiter {
+    pub fn new(config: &StreamingConfig) -> Self {
+        InFlightLimiter {
+            semaphore: config.max_in_flight.map(|max_in_flight| Arc::new(Semaphore::new(max_in_flight))),
+            policy: config.in_flight_policy.clone(),
+        }
+    }
+
+    /// Reserve a slot for an upstream stream, which is released once the permit is dropped
+    pub async fn acquire(&self) -> Result<InFlightPermit, RegistryError> {
+        let permit = match &self.semaphore {
+            Some(semaphore) => Some(match self.policy {
+                InFlightPolicy::Wait => semaphore.clone().acquire_owned().await
+                    .map_err(|e| RegistryError::new(ErrorKind::InternalError).with_error(e.to_string()))?,
+                InFlightPolicy::Reject => semaphore.clone().try_acquire_owned()
+                    .map_err(|e| RegistryError::new(ErrorKind::Unavailable).with_error(format!("Too many upstream streams in flight: {}", e)))?,
+            }),
+            None => None,
+        };
+
+        metrics::UPSTREAM_STREAMS_IN_FLIGHT.inc();
+        Ok(InFlightPermit { _permit: permit })
+    }
+}
+
+/// A slot of the in-flight upstream streams
+pub struct InFlightPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for InFlightPermit {
+    fn drop(&mut self) {
+        metrics::UPSTREAM_STREAMS_IN_FLIGHT.dec();
+    }
+}
+
+/// An upstream request, from before it is sent until its response is fully received or dropped
+pub struct UpstreamRequestGuard;
+
+impl UpstreamRequestGuard {
+    pub fn start() -> Self {
+        metrics::UPSTREAM_IN_FLIGHT.inc();
+        UpstreamRequestGuard
+    }
+}
+
+impl Drop for UpstreamRequestGuard {
+    fn drop(&mut self) {
+        metrics::UPSTREAM_IN_FLIGHT.dec();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use actix_web::{test, web, App, HttpResponse, HttpServer};
+    use actix_web::http::{header, StatusCode};
+    use bytes::Bytes;
+    use futures_util::StreamExt;
+    use crate::api::routes;
+    use crate::api::state::AppState;
+    use crate::config::app::{AppConfig, UpstreamConfig};
+    use crate::config::streaming::InFlightPolicy;
+    use crate::metrics;
+
+    const DIGEST: &str = "sha256:05c6e08f1d9fdafa03147fcb8f82f124c76d2f70e3d989dc8aadb5e7d7450bec";
+
+    /// Upstream streaming its response slowly
+    async fn upstream() -> HttpResponse {
+        HttpResponse::Ok().streaming(futures_util::stream::iter(["slow ", "blob"]).then(|chunk| async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            Ok::<_, actix_web::Error>(Bytes::from_static(chunk.as_bytes()))
+        }))
+    }
+
+    #[actix_web::test]
+    async fn in_flight_limit_test() {
+        let server = HttpServer::new(|| App::new().default_service(web::to(upstream)))
+            .workers(1)
+            .bind(("127.0.0.1", 0)).unwrap();
+        let address = server.addrs()[0];
+        let server = server.run();
+        let server_handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        // Two upstreams sharing a single in-flight stream
+        let folder = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.streaming.max_in_flight = Some(1);
+        config.streaming.in_flight_policy = InFlightPolicy::Reject;
+        for host in ["first.local", "second.local"] {
+            config.upstreams.push(UpstreamConfig::for_test(host, &format!("http://{}", address)));
+        }
+        let (state, _commands) = AppState::for_test(config).await;
+
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(state))
+            .service(web::scope("/v2").configure(routes::registry_api_config))).await;
+
+        let request = |host: &str| test::TestRequest::get().uri(&format!("/v2/library/nginx/blobs/{}", DIGEST))
+            .insert_header((header::HOST, host.to_string()))
+            .to_request();
+
+        // The first upstream is still streaming, so the other one has to wait
+        let first = test::call_service(&app, request("first.local")).await;
+        assert_eq!(StatusCode::OK, first.status());
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, test::call_service(&app, request("second.local")).await.status());
+        assert!(metrics::UPSTREAM_IN_FLIGHT.get() >= 1);
+
+        // The slot is released once the first response is fully streamed
+        assert_eq!("slow blob", test::read_body(first).await);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let second = test::call_service(&app, request("second.local")).await;
+        assert_eq!(StatusCode::OK, second.status());
+        assert_eq!("slow blob", test::read_body(second).await);
+
+        server_handle.stop(true).await;
+    }
+}
@@ -0,0 +1,169 @@
+// SPDX-License-Identifier: Apache-2.0
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use actix_web::web;
+use tokio::signal::unix::{signal, SignalKind};
+use crate::api::repository_policy::RepositoryPolicy;
+use crate::api::state::AppState;
+use crate::api::upstream_clients::UpstreamClients;
+use crate::config::app::{AppConfig, UpstreamConfig, UserAgentConfig};
+use crate::error::registry::RegistryError;
+
+/// The parts of config.yaml applied without a restart, swapped as a whole when config.yaml is reloaded:
+/// the upstreams, the timeouts, the User-Agent and the allowed/denied repositories
+pub struct LiveConfig {
+    pub upstreams: HashMap<String, UpstreamConfig>,
+    pub clients: UpstreamClients,
+
+    /// Which container images can be pulled
+    pub repository_policy: RepositoryPolicy,
+
+    /// User-Agent of the upstream requests, unless the upstream sets its own
+    pub user_agent: UserAgentConfig,
+
+    /// Overall deadline of the registry requests, api->request_timeout_secs
+    pub request_timeout: Option<Duration>,
+
+    /// Longest wait for the next chunk of an upstream response, streaming->chunk_timeout
+    pub chunk_timeout: Duration,
+}
+
+impl LiveConfig {
+    pub fn new(config: &AppConfig) -> Self {
+        LiveConfig {
+            upstreams: config.upstreams(),
+            clients: UpstreamClients::new(&config.upstreams),
+            repository_policy: RepositoryPolicy::new(&config.allowed_repositories, &config.denied_repositories),
+            user_agent: config.user_agent.clone(),
+            request_timeout: config.api.request_timeout_secs.map(Duration::from_secs),
+            chunk_timeout: Duration::from_secs(config.streaming.chunk_timeout),
+        }
+    }
+}
+
+/// Reload config.yaml on every SIGHUP
+pub async fn reload_on_hangup(state: web::Data<AppState>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            tracing::error!("Failed to listen for SIGHUP, config.yaml can't be reloaded: {}", e);
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        tracing::info!("SIGHUP received, reloading config.yaml");
+        reload(&state, AppConfig::load());
+    }
+}
+
+/// Swap the live parts of the config for the ones of the new config, once it is valid.
+/// The other changes are only logged, they need a restart. Returns whether the new config was applied
+pub fn reload(state: &AppState, config: Result<AppConfig, RegistryError>) -> bool {
+    let config = match config.map_err(|e| vec![e.to_string()]).and_then(|config| config.validate().map(|_| config)) {
+        Ok(config) => config,
+        Err(errors) => {
+            for error in errors {
+                tracing::error!("{}", error);
+            }
+            tracing::error!("invalid config.yaml, the current config is kept");
+            return false;
+        }
+    };
+
+    for section in restart_required(&state.app_config, &config) {
+        tracing::warn!("config.yaml {} changed, restart the cache to apply it", section);
+    }
+
+    state.live.store(Arc::new(LiveConfig::new(&config)));
+    tracing::info!("config.yaml reloaded");
+    true
+}
+
+/// The sections of config.yaml which changed since the startup, and are not applied until a restart
+fn restart_required(current: &AppConfig, new: &AppConfig) -> Vec<String> {
+    let mut changed: Vec<String> = match (startup_sections(current), startup_sections(new)) {
+        (serde_json::Value::Object(current), serde_json::Value::Object(new)) => new.iter()
+            .filter(|(section, value)| current.get(*section) != Some(value))
+            .map(|(section, _)| section.clone())
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    // The storage folders of the upstreams are only created and used from the startup
+    let storage_folder = |config: &AppConfig, host: &str| config.upstreams.iter()
+        .find(|upstream| upstream.host == host)
+        .and_then(|upstream| upstream.storage_folder.clone());
+    if new.upstreams.iter().any(|upstream| storage_folder(current, &upstream.host) != upstream.storage_folder) {
+        changed.push("upstreams->storage_folder".to_string());
+    }
+
+    changed
+}
+
+/// The config without the parts applied by a reload
+fn startup_sections(config: &AppConfig) -> serde_json::Value {
+    let mut config = config.clone();
+    config.upstreams.clear();
+    config.allowed_repositories.clear();
+    config.denied_repositories.clear();
+    config.user_agent = Default::default();
+    config.api.request_timeout_secs = None;
+    config.streaming.chunk_timeout = 0;
+
+    serde_json::to_value(config).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use crate::api::live_config::{reload, restart_required};
+    use crate::api::state::AppState;
+    use crate::config::app::{AppConfig, UpstreamConfig};
+    use crate::error::error_kind::ErrorKind;
+    use crate::error::registry::RegistryError;
+
+    fn upstream(host: &str) -> UpstreamConfig {
+        UpstreamConfig::for_test(host, "https://registry-1.docker.io")
+    }
+
+    #[tokio::test]
+    async fn reload_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.upstreams.push(upstream("docker.local"));
+        let (state, _commands) = AppState::for_test(config.clone()).await;
+        assert!(state.live().upstreams.contains_key("docker.local"));
+        assert!(state.live().repository_policy.allows("mycorp/app"));
+
+        // Upstreams, timeouts and allowed/denied repositories are applied
+        let mut reloaded = config.clone();
+        reloaded.upstreams.push(upstream("quay.local"));
+        reloaded.denied_repositories.push("mycorp/**".to_string());
+        reloaded.api.request_timeout_secs = Some(30);
+        reloaded.streaming.chunk_timeout = 5;
+        assert!(reload(&state, Ok(reloaded.clone())));
+        let live = state.live();
+        assert!(live.upstreams.contains_key("quay.local"));
+        assert!(!live.repository_policy.allows("mycorp/app"));
+        assert_eq!(Some(Duration::from_secs(30)), live.request_timeout);
+        assert_eq!(Duration::from_secs(5), live.chunk_timeout);
+        assert!(restart_required(&config, &reloaded).is_empty());
+
+        // An invalid config is not applied
+        let mut invalid = config.clone();
+        invalid.streaming.chunk_timeout = 0;
+        assert!(!reload(&state, Ok(invalid)));
+        assert!(!reload(&state, Err(RegistryError::new(ErrorKind::InternalError))));
+        assert!(state.live().upstreams.contains_key("quay.local"));
+
+        // Applied, though the changes which need a restart are not
+        let mut restart = reloaded.clone();
+        restart.api.port = Some("9090".to_string());
+        restart.storage.deduplicate = true;
+        restart.upstreams[1].storage_folder = Some("quay".to_string());
+        assert!(reload(&state, Ok(restart.clone())));
+        assert_eq!(vec!["api", "storage", "upstreams->storage_folder"], restart_required(&config, &restart));
+    }
+}
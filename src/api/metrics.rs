@@ -1,11 +1,22 @@
 // SPDX-License-Identifier: Apache-2.0
-use actix_web::{get, HttpResponse, HttpResponseBuilder};
-use actix_web::http::StatusCode;
+use actix_web::{get, HttpRequest, HttpResponse, HttpResponseBuilder};
+use actix_web::http::{header, StatusCode};
 use prometheus::{Encoder, TextEncoder};
 use crate::error::registry::RegistryError;
+use crate::metrics::exemplars;
 
 #[get("/metrics")]
-pub(crate) async fn metrics_handler() -> Result<HttpResponse, RegistryError>  {
+pub(crate) async fn metrics_handler(req: HttpRequest) -> Result<HttpResponse, RegistryError>  {
+
+    // The scrapers asking for OpenMetrics get the exemplars linking the latency histograms to the traces
+    let openmetrics = req.headers().get_all(header::ACCEPT)
+        .filter_map(|accept| accept.to_str().ok())
+        .any(|accept| accept.contains("application/openmetrics-text"));
+    if openmetrics {
+        return Ok(HttpResponseBuilder::new(StatusCode::OK)
+            .content_type(exemplars::OPENMETRICS_CONTENT_TYPE)
+            .body(exemplars::encode(&prometheus::gather())));
+    }
 
     let encoder = TextEncoder::new();
 
@@ -24,4 +35,27 @@ pub(crate) async fn metrics_handler() -> Result<HttpResponse, RegistryError>  {
 
 
     Ok(HttpResponseBuilder::new(StatusCode::OK).body(res_custom))
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::{test, App};
+    use actix_web::http::header;
+    use crate::api::metrics::metrics_handler;
+
+    #[actix_web::test]
+    async fn openmetrics_test() {
+        let app = test::init_service(App::new().service(metrics_handler)).await;
+
+        let req = test::TestRequest::get().uri("/metrics")
+            .insert_header((header::ACCEPT, "application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!("application/openmetrics-text; version=1.0.0; charset=utf-8", resp.headers().get(header::CONTENT_TYPE).unwrap());
+        assert!(std::str::from_utf8(&test::read_body(resp).await).unwrap().ends_with("# EOF\n"));
+
+        // The Prometheus text format otherwise
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/metrics").to_request()).await;
+        assert!(!std::str::from_utf8(&test::read_body(resp).await).unwrap().contains("# EOF"));
+    }
+}
@@ -1,6 +1,19 @@
 // SPDX-License-Identifier: Apache-2.0
+mod access_log;
+mod admin;
+mod circuit_breaker;
+pub mod egress_budget;
+mod in_flight;
+mod live_config;
+mod negative_cache;
+mod pull_stats;
+mod readiness;
 pub mod registry;
+mod repository_policy;
+mod request_tracing;
 pub mod server;
+mod single_flight;
 mod state;
+pub mod upstream_clients;
 pub mod routes;
 mod metrics;
@@ -0,0 +1,137 @@
+// SPDX-License-Identifier: Apache-2.0
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+use crate::config::negative_cache::NegativeCacheConfig;
+use crate::metrics;
+use crate::models::types::UpstreamHost;
+use crate::registry::repository::Repository;
+
+/// Upstream, container image name and reference, i.e. tag or digest, of a blob or manifest,
+/// and the hash of the credentials of the client: upstream can answer a 404 to a client which is not allowed to pull
+/// a private container image, and not to another one which is
+type NegativeCacheKey = (UpstreamHost, String, String, Option<[u8; 32]>);
+
+#[derive(Default)]
+struct Entries {
+    /// When each of them expires
+    expires: HashMap<NegativeCacheKey, Instant>,
+
+    /// The same, the first to expire first: they all live for the same TTL
+    queue: VecDeque<(Instant, NegativeCacheKey)>,
+}
+
+/// The blobs and manifests upstream recently answered a 404 for, until their TTL expires
+pub struct NegativeCache {
+    entries: Mutex<Entries>,
+
+    /// Disabled when not set
+    ttl: Option<Duration>,
+    max_entries: usize,
+}
+
+impl NegativeCache {
+
+    pub fn new(config: &NegativeCacheConfig) -> Self {
+        NegativeCache {
+            entries: Default::default(),
+            ttl: config.ttl.map(Duration::from_secs),
+            max_entries: config.max_entries,
+        }
+    }
+
+    /// Whether upstream did not have the blob or manifest a moment ago for a client with these credentials,
+    /// in which case asking it again is pointless
+    pub fn is_missing(&self, upstream: &str, repository: &Repository, credentials: Option<&[u8]>) -> bool {
+        if self.ttl.is_none() {
+            return false;
+        }
+
+        let mut entries = self.entries.lock();
+        let key = key(upstream, repository, credentials);
+        match entries.expires.get(&key) {
+            Some(expires) if *expires > Instant::now() => {
+                metrics::NEGATIVE_CACHE_HITS.inc();
+                true
+            }
+            Some(_) => {
+                entries.expires.remove(&key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Upstream answered a 404 for the blob or manifest to a client with these credentials
+    pub fn missing(&self, upstream: &str, repository: &Repository, credentials: Option<&[u8]>) {
+        let Some(ttl) = self.ttl else { return };
+
+        let mut entries = self.entries.lock();
+        let now = Instant::now();
+
+        // Forget the expired ones, unless they were remembered again since
+        while entries.queue.front().is_some_and(|(expires, _)| *expires <= now) {
+            let (expires, key) = entries.queue.pop_front().expect("the front entry exists");
+            if entries.expires.get(&key) == Some(&expires) {
+                entries.expires.remove(&key);
+            }
+        }
+
+        if entries.expires.len() >= self.max_entries {
+            return;
+        }
+        let key = key(upstream, repository, credentials);
+        entries.expires.insert(key.clone(), now + ttl);
+        entries.queue.push_back((now + ttl, key));
+    }
+}
+
+fn key(upstream: &str, repository: &Repository, credentials: Option<&[u8]>) -> NegativeCacheKey {
+    (upstream.to_string(), repository.name.clone(), repository.reference.clone(), credentials.map(|credentials| Sha256::digest(credentials).into()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use crate::api::negative_cache::NegativeCache;
+    use crate::config::negative_cache::NegativeCacheConfig;
+    use crate::registry::repository::Repository;
+
+    #[test]
+    fn negative_cache_test() {
+        let cache = NegativeCache::new(&NegativeCacheConfig { ttl: Some(60), max_entries: 2 });
+        let missing = Repository::new_with_reference("library/nginx", "missing").unwrap();
+        let latest = Repository::new_with_reference("library/nginx", "latest").unwrap();
+
+        // Per upstream and reference
+        cache.missing("docker.io", &missing, None);
+        assert!(cache.is_missing("docker.io", &missing, None));
+        assert!(!cache.is_missing("quay.io", &missing, None));
+        assert!(!cache.is_missing("docker.io", &latest, None));
+
+        // And per credentials, another client may be allowed to pull it
+        assert!(!cache.is_missing("docker.io", &missing, Some(b"Bearer token")));
+
+        // Up to the maximum amount of entries
+        cache.missing("quay.io", &missing, None);
+        cache.missing("docker.io", &latest, None);
+        assert!(!cache.is_missing("docker.io", &latest, None));
+
+        // Forgotten once expired, which makes room for the newer ones
+        let mut cache = NegativeCache::new(&NegativeCacheConfig { ttl: Some(60), max_entries: 2 });
+        cache.ttl = Some(Duration::from_millis(20));
+        cache.missing("docker.io", &missing, Some(b"Bearer token"));
+        cache.missing("quay.io", &missing, None);
+        std::thread::sleep(Duration::from_millis(30));
+        cache.missing("docker.io", &latest, None);
+        assert!(cache.is_missing("docker.io", &latest, None));
+        assert!(!cache.is_missing("docker.io", &missing, Some(b"Bearer token")));
+        assert_eq!(1, cache.entries.lock().queue.len());
+
+        // Disabled
+        let cache = NegativeCache::new(&NegativeCacheConfig::default());
+        cache.missing("docker.io", &missing, None);
+        assert!(!cache.is_missing("docker.io", &missing, None));
+    }
+}
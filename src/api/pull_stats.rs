@@ -0,0 +1,118 @@
+// SPDX-License-Identifier: Apache-2.0
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use parking_lot::Mutex;
+use crate::config::pull_stats::PullStatsConfig;
+use crate::handlers::command::blob::service::ManifestService;
+use crate::metrics;
+
+/// Label of the `pulls_total` metric the container images beyond `pull_stats.max_metric_repositories` are counted under
+const OTHER_REPOSITORIES: &str = "other";
+
+/// Counts the pulls of the container images in memory, so that serving a manifest does not wait for a database write.
+/// They are written at once every `pull_stats.flush_interval`, and when the cache shuts down
+pub struct PullStats {
+    manifests: Arc<ManifestService>,
+
+    /// Pulls of each container image since the last flush
+    pending: Mutex<HashMap<String, u64>>,
+
+    /// The container images with their own `pulls_total` label
+    labelled: Mutex<HashSet<String>>,
+
+    flush_interval: Duration,
+    max_labels: usize,
+}
+
+impl PullStats {
+
+    pub fn new(manifests: Arc<ManifestService>, config: &PullStatsConfig) -> Self {
+        PullStats {
+            manifests,
+            pending: Default::default(),
+            labelled: Default::default(),
+            flush_interval: Duration::from_secs(config.flush_interval),
+            max_labels: config.max_metric_repositories,
+        }
+    }
+
+    /// Count a pull of the container image
+    pub fn record(&self, name: &str) {
+        metrics::PULLS_TOTAL.with_label_values(&[self.label(name)]).inc();
+        *self.pending.lock().entry(name.to_string()).or_default() += 1;
+    }
+
+    /// The `pulls_total` label of the container image: its name, unless too many container images already have their own
+    fn label<'a>(&self, name: &'a str) -> &'a str {
+        let mut labelled = self.labelled.lock();
+        if labelled.contains(name) || (labelled.len() < self.max_labels && labelled.insert(name.to_string())) {
+            name
+        } else {
+            OTHER_REPOSITORIES
+        }
+    }
+
+    /// Periodically write the pulls counted since the last flush
+    pub async fn start(self: Arc<Self>) {
+        let mut ticker = tokio::time::interval(self.flush_interval);
+        loop {
+            ticker.tick().await;
+            self.flush().await;
+        }
+    }
+
+    /// Write the pulls counted since the last flush. They are kept for the next one when the write fails
+    pub async fn flush(&self) {
+        let pulls = std::mem::take(&mut *self.pending.lock());
+        if pulls.is_empty() {
+            return;
+        }
+
+        if let Err(e) = self.manifests.record_pulls(&pulls).await {
+            tracing::error!("Failed to count the pulls of {} container images: {}", pulls.len(), e);
+            let mut pending = self.pending.lock();
+            for (name, count) in pulls {
+                *pending.entry(name).or_default() += count;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::api::pull_stats::PullStats;
+    use crate::config::pull_stats::PullStatsConfig;
+    use crate::db::db_pulls::DBPulls;
+    use crate::db::pool::DBPool;
+    use crate::handlers::command::blob::service::ManifestService;
+    use crate::metrics;
+
+    #[tokio::test]
+    async fn pull_stats_test() {
+        let pool = DBPool::default().await;
+        DBPulls::create_table(&pool).await;
+        let manifests = ManifestService::from_pool(pool);
+        let stats = PullStats::new(manifests.clone(), &PullStatsConfig { max_metric_repositories: 1, ..Default::default() });
+        let other = metrics::PULLS_TOTAL.with_label_values(&["other"]).get();
+
+        // Counted in memory until flushed
+        for name in ["stats/nginx", "stats/alpine", "stats/nginx"] {
+            stats.record(name);
+        }
+        assert!(manifests.most_pulled(10).await.unwrap().is_empty());
+
+        stats.flush().await;
+        assert_eq!(vec![("stats/nginx".to_string(), 2), ("stats/alpine".to_string(), 1)], manifests.most_pulled(10).await.unwrap());
+
+        // Added to the previous ones
+        stats.record("stats/alpine");
+        stats.flush().await;
+        assert_eq!(vec![("stats/alpine".to_string(), 2), ("stats/nginx".to_string(), 2)], manifests.most_pulled(10).await.unwrap());
+
+        // Only the first container image has its own label
+        assert_eq!(2, metrics::PULLS_TOTAL.with_label_values(&["stats/nginx"]).get());
+        assert_eq!(0, metrics::PULLS_TOTAL.with_label_values(&["stats/alpine"]).get());
+        assert_eq!(other + 2, metrics::PULLS_TOTAL.with_label_values(&["other"]).get());
+    }
+}
@@ -0,0 +1,266 @@
+// SPDX-License-Identifier: Apache-2.0
+use std::future::{ready, Ready};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use actix_web::{get, web, HttpResponse};
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header;
+use futures_util::future::LocalBoxFuture;
+use tokio::time::Instant;
+use crate::api::state::AppState;
+use crate::api::upstream_clients::UpstreamClients;
+use crate::config::app::UpstreamConfig;
+use crate::config::readiness::{ReadinessConfig, StartupPolicy};
+use crate::error::error_kind::ErrorKind;
+use crate::error::registry::RegistryError;
+
+/// Whether the cache is ready to receive traffic, as reported by /readyz
+#[derive(Default)]
+pub struct Readiness {
+    /// The startup tasks completed, the registry requests are answered with a 503 until then
+    started: AtomicBool,
+
+    /// The startup policy is satisfied
+    ready: AtomicBool,
+}
+
+impl Readiness {
+
+    pub fn is_started(&self) -> bool {
+        self.started.load(Ordering::Relaxed)
+    }
+
+    /// Both started and satisfying the startup policy
+    pub fn is_ready(&self) -> bool {
+        self.is_started() && self.ready.load(Ordering::Relaxed)
+    }
+
+    /// The startup tasks completed, the registry requests get served from now on
+    pub fn started(&self) {
+        self.started.store(true, Ordering::Relaxed);
+    }
+
+    /// Mark the cache ready according to the startup policy: right away,
+    /// or once one of the upstreams answers, waiting at most for the configured timeout
+    pub async fn start(self: Arc<Self>, clients: UpstreamClients, upstreams: Vec<UpstreamConfig>, config: ReadinessConfig) {
+        if config.startup == StartupPolicy::Wait && !upstreams.is_empty() {
+            let deadline = Instant::now() + Duration::from_secs(config.timeout);
+            loop {
+                if let Some(upstream) = Readiness::reachable(&clients, &upstreams).await {
+                    tracing::info!("Upstream {} is reachable, ready to receive traffic", upstream.host);
+                    break;
+                }
+
+                if Instant::now() >= deadline {
+                    tracing::warn!("No upstream answered within {} seconds, ready to receive traffic anyway", config.timeout);
+                    break;
+                }
+
+                tokio::time::sleep(Duration::from_secs(config.interval)).await;
+            }
+        }
+
+        self.ready.store(true, Ordering::Relaxed);
+    }
+
+    /// Probe all the upstreams at once, returning one which answered.
+    /// Any HTTP response counts, e.g. a 401 asking for authentication
+    async fn reachable<'a>(clients: &UpstreamClients, upstreams: &'a [UpstreamConfig]) -> Option<&'a UpstreamConfig> {
+        let probes = upstreams.iter().map(|upstream| async move {
+            match clients.for_upstream(&upstream.host).get(format!("{}{}", upstream.base_url(), upstream.upstream_path("/v2/"))).send().await {
+                Ok(_) => Some(upstream),
+                Err(e) => {
+                    tracing::debug!("Upstream {} is not reachable yet: {}", upstream.host, e.to_string());
+                    None
+                }
+            }
+        });
+
+        futures_util::future::join_all(probes).await.into_iter().flatten().next()
+    }
+}
+
+#[get("/readyz")]
+pub(crate) async fn readyz_handler(state: web::Data<AppState>) -> HttpResponse {
+    let waiting_for = if !state.readiness.is_started() {
+        "starting up"
+    } else if !state.readiness.is_ready() {
+        "waiting for the upstreams"
+    } else {
+        return HttpResponse::Ok().body("ready");
+    };
+
+    HttpResponse::ServiceUnavailable()
+        .insert_header((header::RETRY_AFTER, state.app_config.readiness.interval))
+        .body(waiting_for)
+}
+
+/// Answers the requests with a 503 and a Retry-After until the startup tasks completed,
+/// instead of serving them from a cache which is not set up yet
+#[derive(Clone)]
+pub struct StartupGate {
+    readiness: Arc<Readiness>,
+
+    /// Seconds sent as Retry-After
+    retry_after: u64,
+}
+
+impl StartupGate {
+    pub fn new(readiness: Arc<Readiness>, retry_after: u64) -> Self {
+        StartupGate { readiness, retry_after }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for StartupGate
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+        B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = StartupGateMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(StartupGateMiddleware {
+            service,
+            gate: self.clone(),
+        }))
+    }
+}
+
+pub struct StartupGateMiddleware<S> {
+    service: S,
+    gate: StartupGate,
+}
+
+impl<S, B> Service<ServiceRequest> for StartupGateMiddleware<S>
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+        B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if !self.gate.readiness.is_started() {
+            let error = RegistryError::new(ErrorKind::Unavailable)
+                .with_context("the cache is starting up")
+                .with_retry_after(self.gate.retry_after);
+            let response = req.error_response(error).map_into_right_body();
+            return Box::pin(async move { Ok(response) });
+        }
+
+        let response = self.service.call(req);
+        Box::pin(async move { Ok(response.await?.map_into_left_body()) })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use actix_web::{test, web, App, HttpResponse, HttpServer};
+    use actix_web::http::{header, StatusCode};
+    use crate::api::readiness::{readyz_handler, StartupGate};
+    use crate::api::state::AppState;
+    use crate::config::app::{AppConfig, UpstreamConfig};
+    use crate::config::readiness::{ReadinessConfig, StartupPolicy};
+
+    fn upstream_config(address: SocketAddr) -> UpstreamConfig {
+        UpstreamConfig::for_test("localhost", &format!("http://{}", address))
+    }
+
+    #[actix_web::test]
+    async fn readiness_test() {
+        // Nothing listens on the port yet
+        let address = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+
+        let folder = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.upstreams.push(upstream_config(address));
+        let (state, _commands) = AppState::for_test(config.clone()).await;
+        let readiness = state.readiness.clone();
+        readiness.started();
+
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(state))
+            .service(readyz_handler)).await;
+        let readyz = || test::TestRequest::get().uri("/readyz").to_request();
+
+        let waiting = ReadinessConfig { startup: StartupPolicy::Wait, timeout: 30, interval: 1 };
+        tokio::spawn(readiness.clone().start(reqwest::Client::new().into(), config.upstreams.clone(), waiting.clone()));
+
+        // Not ready while the upstream is unreachable
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, test::call_service(&app, readyz()).await.status());
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, test::call_service(&app, readyz()).await.status());
+
+        // Ready once the upstream answers, even if it requires authentication
+        let server = HttpServer::new(|| App::new().default_service(web::to(HttpResponse::Unauthorized)))
+            .workers(1)
+            .bind(address).unwrap()
+            .run();
+        let server_handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        for _ in 0..50 {
+            if readiness.is_ready() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(StatusCode::OK, test::call_service(&app, readyz()).await.status());
+        server_handle.stop(true).await;
+
+        // Ready anyway once the timeout elapsed
+        let (state, _commands) = AppState::for_test(config.clone()).await;
+        state.readiness.started();
+        let timeout = ReadinessConfig { timeout: 1, ..waiting };
+        tokio::time::timeout(Duration::from_secs(5), state.readiness.clone().start(reqwest::Client::new().into(), config.upstreams.clone(), timeout)).await
+            .expect("ready after the timeout");
+        assert!(state.readiness.is_ready());
+
+        // Ready right away
+        let (state, _commands) = AppState::for_test(config.clone()).await;
+        state.readiness.started();
+        state.readiness.clone().start(reqwest::Client::new().into(), config.upstreams, ReadinessConfig::default()).await;
+        assert!(state.readiness.is_ready());
+    }
+
+    #[actix_web::test]
+    async fn startup_gate_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let (state, _commands) = AppState::for_test(AppConfig::with_storage_folder(folder.path().to_str().unwrap())).await;
+        let readiness = state.readiness.clone();
+        readiness.clone().start(reqwest::Client::new().into(), Vec::new(), ReadinessConfig::default()).await;
+
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(state))
+            .service(readyz_handler)
+            .service(web::scope("/v2")
+                .wrap(StartupGate::new(readiness.clone(), 5))
+                .route("/", web::get().to(HttpResponse::Ok)))).await;
+
+        // Neither the registry requests nor /readyz until the startup tasks completed
+        let response = test::call_service(&app, test::TestRequest::get().uri("/v2/").to_request()).await;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+        assert_eq!("5", response.headers().get(header::RETRY_AFTER).unwrap());
+        let body = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+        assert!(body.contains("UNAVAILABLE"), "{}", body);
+
+        let response = test::call_service(&app, test::TestRequest::get().uri("/readyz").to_request()).await;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+        assert!(response.headers().contains_key(header::RETRY_AFTER));
+
+        readiness.started();
+        assert_eq!(StatusCode::OK, test::call_service(&app, test::TestRequest::get().uri("/v2/").to_request()).await.status());
+        assert_eq!(StatusCode::OK, test::call_service(&app, test::TestRequest::get().uri("/readyz").to_request()).await.status());
+    }
+}
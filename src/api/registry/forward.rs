@@ -1,15 +1,22 @@
 // SPDX-License-Identifier: Apache-2.0
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use actix_web::{
    http::Method, web, HttpRequest, HttpResponse
 };
+use actix_web::error::PayloadError;
+use actix_web::http::header;
 use futures_util::{StreamExt as _};
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::UnboundedReceiverStream;
-use crate::api::registry::build_upstream_req;
+use crate::api::in_flight::UpstreamRequestGuard;
+use crate::api::registry::{allowed_repository, build_upstream_req, check_max_size, content_length, execute_upstream, relay_headers, relayed_body, upstream_allowed, upstream_host, within_deadline, UpstreamError, UpstreamRequestKind};
 use crate::api::state::AppState;
+use crate::config::app::StorageConfig;
 use crate::error::error_kind::ErrorKind;
 use crate::error::registry::RegistryError;
 use crate::metrics;
+use crate::registry::repository::Repository;
 
 
 /// Forward the request to upstream
@@ -20,15 +27,57 @@ pub async fn forward(req: HttpRequest, mut payload: web::Payload,
     // Increase the requests counter
     metrics::INCOMING_REQUESTS.inc();
 
+    // The requests for a container image, e.g. the pushes, the routes without a name are not about any
+    if let Some(name) = req.match_info().get("name") {
+        Repository::new_with_limits(name, &state.app_config.repository_names)?;
+        allowed_repository(name, &state)?;
+    }
+
+    // Nothing is written upstream through the cache unless allowed
+    if is_push(&req, &method) && !state.app_config.push_passthrough {
+        tracing::warn!("Rejected push {} {}", method, req.uri());
+        return Err(RegistryError::new(ErrorKind::Unsupported)
+            .with_error(format!("{} {} is a push, the cache is for pulls only", method, req.path()))
+            .with_allow("GET, HEAD"));
+    }
+
+    // Pushed blobs and manifests are subject to the same limits as the cached ones
+    let max_size = max_body_size(&req, &state.app_config.storage);
+    let request_length = req.headers().get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    check_max_size(request_length, max_size)?;
+
+    // The upstream keeps failing
+    if !upstream_allowed(&req, &state) {
+        return Err(RegistryError::new(ErrorKind::Unavailable).with_error(format!("upstream {} is failing", upstream_host(&req))));
+    }
+
     // Build the upstream URL
     let upstream_request = build_upstream_req(&req, method, &state)?;
 
     // Create a new channel
     let (tx, rx) = mpsc::unbounded_channel();
 
+    // Set once a body without a Content-Length grows over the limit
+    let overflow = Arc::new(AtomicBool::new(false));
+    let payload_overflow = overflow.clone();
+
     // Start a new task where we forward a possible payload
     actix_web::rt::spawn(async move {
+        let mut size = 0;
         while let Some(chunk) = payload.next().await {
+            if let Ok(ref chunk) = chunk {
+                size += chunk.len() as u64;
+            }
+
+            // Abort the upstream request
+            if max_size.is_some_and(|max_size| size > max_size) {
+                payload_overflow.store(true, Ordering::Relaxed);
+                let _ = tx.send(Err(PayloadError::Overflow));
+                break;
+            }
+
             tx.send(chunk).unwrap();
         }
     });
@@ -37,28 +86,408 @@ pub async fn forward(req: HttpRequest, mut payload: web::Payload,
     let upstream_request = upstream_request.body(reqwest::Body::wrap_stream(UnboundedReceiverStream::new(rx)));
 
     // Build the upstream request
-    let upstream_request = upstream_request.build().map_err(|e| RegistryError::new(ErrorKind::NotFound).with_error(e.to_string()))?;
+    let upstream_request = upstream_request.build().map_err(|e| RegistryError::new(ErrorKind::InternalError).with_error(e.to_string()))?;
 
     // Logging
     log::info!("Upstream: {} {}", upstream_request.method(), upstream_request.url());
 
-    // Execute the request against the upstream
-    let res = state.client.execute(upstream_request).await
-        .map_err(|e| RegistryError::new(ErrorKind::NotFound).with_error(e.to_string()))?;
+    // Execute the request against the upstream, within the deadline of the request
+    let upstream_guard = UpstreamRequestGuard::start();
+    let upstream = upstream_host(&req);
+    let res = within_deadline(&req, &state, async {
+        execute_upstream(&upstream, UpstreamRequestKind::Forward, upstream_request, &state).await
+            .map_err(|e| if overflow.load(Ordering::Relaxed) {
+                metrics::CACHE_OVERSIZED.inc();
+                RegistryError::new(ErrorKind::MaxPayloadError).with_error(e.to_string())
+            } else {
+                upstream_error(e)
+            })
+    }).await??;
 
     // Build the response for the client
     let mut client_resp = HttpResponse::build(res.status());
-    // Remove `Connection` as per
-    // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Connection#Directives
-    for (header_name, header_value) in res.headers().iter().filter(|(h, _)| *h != "connection") {
-        client_resp.insert_header((header_name.clone(), header_value.clone()));
-        tracing::info!("Response header: {}: {:?}", header_name, header_value);
-    }
+    relay_headers(&mut client_resp, res.headers(), &state.app_config.response_headers);
+    let content_length = content_length(res.headers());
 
     metrics::UPSTREAM_RESPONSES.inc();
     metrics::RESPONSE_CODE_COLLECTOR.with_label_values(&[res.status().as_str(), req.method().as_ref(), ""]).inc();
 
-    Ok(client_resp.streaming(res.bytes_stream()))
+    // Still in flight until the response is fully streamed
+    Ok(relayed_body(client_resp, content_length, state.egress_budgets.counted(&upstream_host(&req), res).map(move |chunk| {
+        let _upstream_guard = &upstream_guard;
+        chunk
+    })))
+
+
+}
+
+/// Handle the blob uploads, the first step of any push, including the upload status requests.
+/// Rejected with a 405 since the cache is read-only, unless the pushes are forwarded to upstream
+pub async fn blob_uploads(req: HttpRequest, payload: web::Payload,
+                          method: Method,
+                          state: web::Data<AppState>) -> Result<HttpResponse, RegistryError> {
+
+    if state.app_config.push_passthrough {
+        return forward(req, payload, method, state).await;
+    }
+
+    // Increase the requests counter
+    metrics::INCOMING_REQUESTS.inc();
+
+    tracing::warn!("Rejected blob upload {} {}", method, req.uri());
+    Err(RegistryError::new(ErrorKind::Unsupported)
+        .with_error("Blob uploads are not supported, this is a read-only pull-through cache")
+        .with_allow(""))
+}
+
+/// The error of an upstream request which got no response, an upstream error response is relayed as it is
+fn upstream_error(e: UpstreamError) -> RegistryError {
+    let kind = if e.is_timeout() { ErrorKind::GatewayTimeout } else { ErrorKind::BadGateway };
+    e.into_registry_error(kind)
+}
 
+/// Whether the request writes a manifest or a blob, e.g. an upload or a delete
+fn is_push(req: &HttpRequest, method: &Method) -> bool {
+    let content = req.path().contains("/manifests/") || req.path().contains("/blobs/");
+    content && method != Method::GET && method != Method::HEAD
+}
 
-}
\ No newline at end of file
+/// The maximum size of the body of a client request, depending on whether it is a manifest or a blob
+fn max_body_size(req: &HttpRequest, config: &StorageConfig) -> Option<u64> {
+    if req.path().contains("/manifests/") {
+        config.max_manifest_bytes
+    } else {
+        config.max_blob_bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use actix_web::{test, web, App, HttpRequest, HttpResponse, HttpServer};
+    use actix_web::http::{header, StatusCode};
+    use crate::api::routes;
+    use crate::api::state::AppState;
+    use crate::config::app::{AppConfig, UpstreamConfig, UserAgentConfig};
+    use crate::config::credentials::{CredentialsConfig, Secret};
+    use crate::metrics;
+
+    #[actix_web::test]
+    async fn forward_max_size_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.storage.max_blob_bytes = Some(16);
+        config.push_passthrough = true;
+        let (state, _commands) = AppState::for_test(config).await;
+
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(state))
+            .service(web::scope("/v2").configure(routes::registry_api_config))).await;
+
+        // The pushes declaring a size over the limit never reach upstream
+        let req = test::TestRequest::patch().uri("/v2/library/nginx/blobs/uploads/b2a2e9b6")
+            .insert_header((header::CONTENT_LENGTH, 27))
+            .set_payload("blob content over the limit")
+            .to_request();
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, test::call_service(&app, req).await.status());
+
+        // Within the limit the request is forwarded, and there is no upstream for this host
+        let req = test::TestRequest::patch().uri("/v2/library/nginx/blobs/uploads/b2a2e9b6")
+            .set_payload("blob content")
+            .to_request();
+        assert_eq!(StatusCode::NOT_FOUND, test::call_service(&app, req).await.status());
+    }
+
+    #[actix_web::test]
+    async fn push_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+
+        for push_passthrough in [false, true] {
+            config.push_passthrough = push_passthrough;
+            let (state, _commands) = AppState::for_test(config.clone()).await;
+
+            let app = test::init_service(App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/v2").configure(routes::registry_api_config))).await;
+
+            // With the methods allowed on the resource
+            let pushes = [
+                (test::TestRequest::post().uri("/v2/library/nginx/blobs/uploads/"), ""),
+                (test::TestRequest::patch().uri("/v2/library/nginx/blobs/uploads/b2a2e9b6"), ""),
+                (test::TestRequest::put().uri("/v2/library/nginx/manifests/latest"), "GET, HEAD"),
+                (test::TestRequest::delete().uri("/v2/library/nginx/manifests/latest"), "GET, HEAD"),
+                (test::TestRequest::delete().uri("/v2/library/nginx/blobs/sha256:05c6e08f1d9fdafa03147fcb8f82f124c76d2f70e3d989dc8aadb5e7d7450bec"), "GET, HEAD"),
+            ];
+            for (push, allow) in pushes {
+                let resp = test::call_service(&app, push.to_request()).await;
+                if push_passthrough {
+                    // Forwarded, and there is no upstream for this host
+                    assert_eq!(StatusCode::NOT_FOUND, resp.status());
+                } else {
+                    assert_eq!(StatusCode::METHOD_NOT_ALLOWED, resp.status());
+                    assert_eq!(allow, resp.headers().get(header::ALLOW).unwrap());
+                    assert!(std::str::from_utf8(&test::read_body(resp).await).unwrap().contains("UNSUPPORTED"));
+                }
+            }
+
+            // Even the upload status, a GET, is part of a push
+            let resp = test::call_service(&app, test::TestRequest::get().uri("/v2/library/nginx/blobs/uploads/b2a2e9b6").to_request()).await;
+            if push_passthrough {
+                assert_eq!(StatusCode::NOT_FOUND, resp.status());
+            } else {
+                assert_eq!(StatusCode::METHOD_NOT_ALLOWED, resp.status());
+                assert_eq!("", resp.headers().get(header::ALLOW).unwrap());
+                assert!(std::str::from_utf8(&test::read_body(resp).await).unwrap().contains("read-only pull-through cache"));
+            }
+
+            // Anything else is forwarded
+            let resp = test::call_service(&app, test::TestRequest::get().uri("/v2/").to_request()).await;
+            assert_eq!(StatusCode::NOT_FOUND, resp.status());
+        }
+    }
+
+    #[actix_web::test]
+    async fn denied_repository_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.push_passthrough = true;
+        config.denied_repositories.push("mycorp/**".to_string());
+        let (state, _commands) = AppState::for_test(config).await;
+
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(state))
+            .service(web::scope("/v2").configure(routes::registry_api_config))).await;
+
+        // Never forwarded upstream
+        let requests = [
+            test::TestRequest::post().uri("/v2/mycorp/team/app/blobs/uploads/"),
+            test::TestRequest::put().uri("/v2/mycorp/app/manifests/latest"),
+            test::TestRequest::delete().uri("/v2/mycorp/app/blobs/sha256:05c6e08f1d9fdafa03147fcb8f82f124c76d2f70e3d989dc8aadb5e7d7450bec"),
+            test::TestRequest::post().uri("/v2/mycorp/app/tags/list"),
+        ];
+        for request in requests {
+            let resp = test::call_service(&app, request.to_request()).await;
+            assert_eq!(StatusCode::NOT_FOUND, resp.status());
+            assert!(std::str::from_utf8(&test::read_body(resp).await).unwrap().contains("NAME_UNKNOWN"));
+        }
+
+        // The other container images are, and there is no upstream for this host
+        let resp = test::call_service(&app, test::TestRequest::post().uri("/v2/library/nginx/blobs/uploads/").to_request()).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+        assert!(!std::str::from_utf8(&test::read_body(resp).await).unwrap().contains("NAME_UNKNOWN"));
+    }
+
+    /// Slow upstream, telling which admin headers reached it
+    async fn slow_upstream(req: HttpRequest) -> HttpResponse {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let leaked = req.headers().contains_key("x-admin-token") || req.headers().contains_key("x-upstream-timeout-ms");
+        HttpResponse::Ok().insert_header(("x-leaked", leaked.to_string())).finish()
+    }
+
+    #[actix_web::test]
+    async fn upstream_timeout_header_test() {
+        let server = HttpServer::new(|| App::new().default_service(web::to(slow_upstream)))
+            .bind(("127.0.0.1", 0)).unwrap();
+        let address = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+
+        let status = |allow_upstream_timeout: bool, token: &'static str| async move {
+            let folder = tempfile::tempdir().unwrap();
+            let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+            config.admin.token = Some("secret".to_string());
+            config.admin.allow_upstream_timeout = allow_upstream_timeout;
+            config.upstreams.push(UpstreamConfig::for_test("localhost", &format!("http://{}", address)));
+            let (state, _commands) = AppState::for_test(config).await;
+
+            let app = test::init_service(App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/v2").configure(routes::registry_api_config))).await;
+
+            let req = test::TestRequest::get().uri("/v2/")
+                .insert_header((header::HOST, "localhost"))
+                .insert_header(("x-admin-token", token))
+                .insert_header(("x-upstream-timeout-ms", "100"))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            if resp.status() == StatusCode::OK {
+                assert_eq!("false", resp.headers().get("x-leaked").unwrap());
+            }
+            resp.status()
+        };
+
+        // Honored: the upstream request times out
+        assert_eq!(StatusCode::GATEWAY_TIMEOUT, status(true, "secret").await);
+
+        // Ignored without the admin token, or when not enabled
+        assert_eq!(StatusCode::OK, status(true, "guess").await);
+        assert_eq!(StatusCode::OK, status(false, "secret").await);
+    }
+
+    #[actix_web::test]
+    async fn upstream_requests_total_test() {
+        let server = HttpServer::new(|| App::new()
+            .route("/v2/missing", web::get().to(HttpResponse::NotFound))
+            .route("/v2/failing", web::get().to(HttpResponse::InternalServerError))
+            .route("/v2/slow", web::get().to(slow_upstream))
+            .default_service(web::to(HttpResponse::Ok)))
+            .bind(("127.0.0.1", 0)).unwrap();
+        let address = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+
+        // Nothing listens on the port of the unreachable upstream
+        let unreachable = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap().local_addr().unwrap();
+
+        let folder = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.admin.token = Some("secret".to_string());
+        config.admin.allow_upstream_timeout = true;
+        for (host, address) in [("results.localhost", address), ("unreachable.localhost", unreachable)] {
+            config.upstreams.push(UpstreamConfig::for_test(host, &format!("http://{}", address)));
+        }
+        let (state, _commands) = AppState::for_test(config).await;
+
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(state))
+            .service(web::scope("/v2").configure(routes::registry_api_config))).await;
+
+        let mut statuses = Vec::new();
+        for (host, uri) in [("results.localhost", "/v2/"), ("results.localhost", "/v2/missing"), ("results.localhost", "/v2/failing"),
+                            ("results.localhost", "/v2/slow"), ("unreachable.localhost", "/v2/")] {
+            let req = test::TestRequest::get().uri(uri)
+                .insert_header((header::HOST, host))
+                .insert_header(("x-admin-token", "secret"))
+                .insert_header(("x-upstream-timeout-ms", "100"))
+                .to_request();
+            statuses.push(test::call_service(&app, req).await.status());
+        }
+
+        // Only the upstream 404 is a 404, a failed upstream request is told apart from it
+        assert_eq!(vec![StatusCode::OK, StatusCode::NOT_FOUND, StatusCode::INTERNAL_SERVER_ERROR,
+                        StatusCode::GATEWAY_TIMEOUT, StatusCode::BAD_GATEWAY], statuses);
+
+        let count = |host: &str, result: &str| metrics::UPSTREAM_REQUESTS_TOTAL.with_label_values(&[host, result]).get();
+        for result in ["success", "client_error", "server_error", "timeout"] {
+            assert_eq!(1, count("results.localhost", result), "{}", result);
+        }
+        assert_eq!(0, count("results.localhost", "connect_error"));
+        assert_eq!(1, count("unreachable.localhost", "connect_error"));
+    }
+
+    #[actix_web::test]
+    async fn user_agent_test() {
+        // Upstream answering with the User-Agent of the request
+        let server = HttpServer::new(|| App::new().default_service(web::to(|req: HttpRequest| async move {
+            req.headers().get_all(header::USER_AGENT).map(|value| value.to_str().unwrap().to_string()).collect::<Vec<_>>().join(", ")
+        }))).bind(("127.0.0.1", 0)).unwrap();
+        let address = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+
+        let user_agent = |global: UserAgentConfig, upstream: Option<UserAgentConfig>| async move {
+            let folder = tempfile::tempdir().unwrap();
+            let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+            config.user_agent = global;
+            config.upstreams.push(UpstreamConfig { user_agent: upstream, ..UpstreamConfig::for_test("localhost", &format!("http://{}", address)) });
+            let (state, _commands) = AppState::for_test(config).await;
+
+            let app = test::init_service(App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/v2").configure(routes::registry_api_config))).await;
+
+            let req = test::TestRequest::get().uri("/v2/")
+                .insert_header((header::HOST, "localhost"))
+                .insert_header((header::USER_AGENT, "containerd/1.7.2"))
+                .to_request();
+            String::from_utf8(test::call_and_read_body(&app, req).await.to_vec()).unwrap()
+        };
+        let config = |value: &str, append: bool| UserAgentConfig { value: Some(value.to_string()), append };
+
+        // Forwarded verbatim when not configured
+        assert_eq!("containerd/1.7.2", user_agent(Default::default(), None).await);
+
+        // Replaced or appended to
+        assert_eq!("registry-cache/1.2.3", user_agent(config("registry-cache/1.2.3", false), None).await);
+        assert_eq!("containerd/1.7.2 registry-cache/1.2.3", user_agent(config("registry-cache/1.2.3", true), None).await);
+
+        // The one of the upstream wins over the global one
+        assert_eq!("mirror/2.0", user_agent(config("registry-cache/1.2.3", true), Some(config("mirror/2.0", false))).await);
+        assert_eq!("containerd/1.7.2", user_agent(config("registry-cache/1.2.3", false), Some(Default::default())).await);
+    }
+
+    #[actix_web::test]
+    async fn response_headers_test() {
+        let server = HttpServer::new(|| App::new().default_service(web::to(|| async {
+            HttpResponse::Ok()
+                .insert_header(("docker-distribution-api-version", "registry/2.0"))
+                .insert_header((header::SET_COOKIE, "session=upstream"))
+                .insert_header(("x-registry-internal", "node-7"))
+                .finish()
+        }))).bind(("127.0.0.1", 0)).unwrap();
+        let address = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+
+        let relayed = |strip: Vec<&'static str>, allow: Vec<&'static str>| async move {
+            let folder = tempfile::tempdir().unwrap();
+            let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+            config.response_headers.strip.extend(strip.into_iter().map(String::from));
+            config.response_headers.allow = allow.into_iter().map(String::from).collect();
+            config.upstreams.push(UpstreamConfig::for_test("localhost", &format!("http://{}", address)));
+            let (state, _commands) = AppState::for_test(config).await;
+
+            let app = test::init_service(App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/v2").configure(routes::registry_api_config))).await;
+
+            let req = test::TestRequest::get().uri("/v2/").insert_header((header::HOST, "localhost")).to_request();
+            let resp = test::call_service(&app, req).await;
+            let mut names = ["docker-distribution-api-version", "set-cookie", "x-registry-internal"].into_iter()
+                .filter(|name| resp.headers().contains_key(*name))
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+
+        assert_eq!(vec!["docker-distribution-api-version", "set-cookie", "x-registry-internal"], relayed(vec![], vec![]).await);
+        assert_eq!(vec!["docker-distribution-api-version", "x-registry-internal"], relayed(vec!["Set-Cookie"], vec![]).await);
+        assert_eq!(vec!["docker-distribution-api-version"], relayed(vec!["set-cookie"], vec!["docker-distribution-api-version", "set-cookie"]).await);
+    }
+
+    #[actix_web::test]
+    async fn upstream_credentials_test() {
+        // Upstream answering with the Authorization of the request
+        let server = HttpServer::new(|| App::new().default_service(web::to(|req: HttpRequest| async move {
+            req.headers().get_all(header::AUTHORIZATION).map(|value| value.to_str().unwrap().to_string()).collect::<Vec<_>>().join(", ")
+        }))).bind(("127.0.0.1", 0)).unwrap();
+        let address = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+
+        let authorization = |credentials: Option<CredentialsConfig>, client: Option<&'static str>| async move {
+            let folder = tempfile::tempdir().unwrap();
+            let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+            config.upstreams.push(UpstreamConfig { credentials, ..UpstreamConfig::for_test("localhost", &format!("http://{}", address)) });
+            let (state, _commands) = AppState::for_test(config).await;
+
+            let app = test::init_service(App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/v2").configure(routes::registry_api_config))).await;
+
+            let mut req = test::TestRequest::get().uri("/v2/").insert_header((header::HOST, "localhost"));
+            if let Some(client) = client {
+                req = req.insert_header((header::AUTHORIZATION, client));
+            }
+            String::from_utf8(test::call_and_read_body(&app, req.to_request()).await.to_vec()).unwrap()
+        };
+        let basic = CredentialsConfig {
+            username: Some("mirror".to_string()),
+            password: Some(Secret::Value("s3cret".to_string())),
+            token: None,
+        };
+
+        // Forwarded from the client when the upstream has no credentials
+        assert_eq!("Bearer client", authorization(None, Some("Bearer client")).await);
+
+        // Otherwise the ones of the cache replace it
+        assert_eq!("Basic bWlycm9yOnMzY3JldA==", authorization(Some(basic.clone()), Some("Bearer client")).await);
+        assert_eq!("Basic bWlycm9yOnMzY3JldA==", authorization(Some(basic), None).await);
+    }
+}
@@ -2,18 +2,28 @@
 use actix_web::{
     http::Method, web, HttpRequest, HttpResponse
 };
-use actix_web::http::header::HeaderValue;
-use futures_util::{pin_mut, StreamExt as _, TryStreamExt};
-use tokio::io::AsyncWriteExt;
-use tokio::sync::mpsc;
+use actix_web::body::SizedStream;
+use actix_web::http::header::{self, HeaderName};
+use bytes::{Bytes, BytesMut};
+use futures_util::{pin_mut, StreamExt};
+use tokio::sync::oneshot;
+use tracing::Instrument;
+use crate::api::in_flight::UpstreamRequestGuard;
 use crate::api::registry::blobs::RepositoryRequest;
-use crate::api::registry::{build_upstream_req, serve_from_cache, validate_repository};
+use crate::api::registry::{accepted_media_types, build_upstream_req, client_stream, content_length, content_type, etag_matches, exceeds_max_size, execute_upstream, fan_out, identity_encoding, is_missing, next_chunk, not_modified, relay_headers, relayed_body, remember_missing, serve_from_cache, upstream_allowed, upstream_host, validate_repository, within_deadline, FanOutEnd, UpstreamError, UpstreamRequestKind};
 use crate::api::state::AppState;
+use crate::config::app::{ConcurrentManifestsPolicy, UpstreamErrorPolicy};
+use crate::dead_letters::unix_now;
 use crate::error::error_kind::ErrorKind;
 use crate::error::registry::RegistryError;
 use crate::metrics;
+use crate::priming::Primer;
+use crate::models::chunks::chunk_channel;
 use crate::models::commands::RegistryCommand;
+use crate::models::manifest_record::ManifestRecord;
+use crate::models::types::{MimeType, UpstreamHost};
 use crate::registry::digest::Digest;
+use crate::registry::manifest::Manifest;
 use crate::registry::repository::Repository;
 
 
@@ -26,125 +36,530 @@ pub async fn get_manifests(manifest_request: web::Path<RepositoryRequest>,
     // Increase the requests counter
     metrics::INCOMING_REQUESTS.inc();
 
+    // Get the repository from the request, before asking upstream for a container image which is not allowed
+    let manifest_repository = validate_repository(manifest_request, &state).await?;
+
+    // Slow upstream and slow disk alike, the client does not wait forever
+    let response = match within_deadline(&req, &state, serve_manifest(manifest_repository.clone(), req.clone(), method.clone(), state.clone())).await {
+        Ok(response) => response,
+        Err(e) => deadline_exceeded(req, manifest_repository.clone(), &state, e).await,
+    };
+
+    // Popularity of the container images, whether the manifest came from upstream or from the cache
+    if method == Method::GET && response.as_ref().is_ok_and(|response| response.status().is_success()) {
+        state.pull_stats.record(&manifest_repository.name);
+    }
+
+    response
+}
+
+/// Serve the manifest from upstream while it is being cached, or from the cache when upstream is failing
+async fn serve_manifest(manifest_repository: Repository, req: HttpRequest, method: Method, state: web::Data<AppState>) -> Result<HttpResponse, RegistryError> {
+
+    // A manifest addressed by digest never changes, so there is no need to ask upstream
+    // whether the copy of the client is still valid
+    if let Some(ref digest) = manifest_repository.digest {
+        if etag_matches(&req, digest) && state.storage.for_upstream(&upstream_host(&req)).driver().is_stored(digest) {
+            return Ok(not_modified(&req, digest));
+        }
+    }
+
+    // Upstream did not have it a moment ago
+    if is_missing(&req, &manifest_repository, &state) {
+        return Err(RegistryError::new(ErrorKind::RegistryManifestUnknown)
+            .with_error(format!("{}:{} was not found upstream", manifest_repository.name, manifest_repository.reference)));
+    }
+
+    // The client only needs the headers, which the cache has as well
+    if method == Method::HEAD {
+        return head_manifest(req, manifest_repository, &state).await;
+    }
+
+    // The upstream keeps failing, serve the manifest from the cache right away, the stale tags included:
+    // they are revalidated by the probe of the half-open circuit
+    if !upstream_allowed(&req, &state) {
+        return handle_upstream_error(req, manifest_repository, &state, true).await;
+    }
+
+    // Identical pulls share a single upstream request
+    if state.app_config.storage.concurrent_manifests == ConcurrentManifestsPolicy::Coalesce {
+        return coalesced_manifest(manifest_repository, req, state).await;
+    }
+
+    // Held until the upstream response is fully streamed
+    let in_flight = state.in_flight.acquire().await?;
+
     // Build the upstream URL
     let upstream_request = build_upstream_req(&req, method, &state)?;
 
     // Build the upstream request
-    let upstream_request = upstream_request.build().map_err(|e| RegistryError::new(ErrorKind::NotFound).with_error(e.to_string()))?;
+    let mut upstream_request = upstream_request.build().map_err(|e| RegistryError::new(ErrorKind::NotFound).with_error(e.to_string()))?;
+    identity_encoding(&mut upstream_request);
+
+    // Upstream only sends the manifest again when the tag moved
+    let revalidated = revalidated_manifest(&req, &manifest_repository, &state).await;
+    if let Some((_, ref digest)) = revalidated {
+        if_none_match(&mut upstream_request, digest);
+    }
 
     // Log the upstream request
     log::info!("Upstream: {} {}", upstream_request.method(), upstream_request.url());
 
-    // Execute the request against the upstream
-    let upstream_response = state.client.execute(upstream_request).await;
+    // Keep the upstream URL around, the platform manifest of an image index is fetched from the same upstream
+    let upstream_url = upstream_request.url().clone();
 
-    // In case we get a timeout, from upstream, then serve the manifest from the cache, if present
-    if let Err(ref e) = upstream_response {
+    // Execute the request against the upstream
+    let upstream_guard = UpstreamRequestGuard::start();
+    let upstream_response = match execute_upstream(&upstream_host(&req), UpstreamRequestKind::Manifest, upstream_request, &state).await {
+        Ok(upstream_response) => upstream_response,
 
-        // if we got a timeout error, then serve it from cache, if present
-        if e.is_timeout() {
-            return handle_upstream_error(req, manifest_request, &state).await;
+        // In case of a timeout, or a connection error, serve the manifest from the cache, if present
+        Err(e) if serves_from_cache(&e, &state) => {
+            tracing::warn!("Upstream request for manifest {}:{} failed: {}", manifest_repository.name, manifest_repository.reference, e.to_string());
+            let circuit_open = matches!(e, UpstreamError::CircuitOpen);
+            return handle_upstream_error(req, manifest_repository, &state, circuit_open).await;
         }
-    }
+        Err(e) => return Err(RegistryError::new(ErrorKind::Unavailable).with_error(e.to_string())),
+    };
+    remember_missing(&req, &manifest_repository, &upstream_response, &state);
 
-    // If we got here, we can safely unwrap
-    let upstream_response = upstream_response.unwrap();
+    // The cached manifest is still the one of the tag
+    if let Some((mime, digest)) = revalidated.filter(|_| upstream_response.status() == reqwest::StatusCode::NOT_MODIFIED) {
+        not_modified_upstream(&req, &manifest_repository, &mime, &digest, &state).await;
+        return serve_from_cache(req, Repository::new_with_reference(&manifest_repository.name, &digest.to_string())?, Some(mime), &state).await;
+    }
 
     // If we got an upstream error, try to serve the manifest from the cache, if present
     if upstream_response.status().is_server_error() {
-        return handle_upstream_error(req, manifest_request, &state).await;
+        return handle_upstream_error(req, manifest_repository, &state, false).await;
     }
 
     // Otherwise pipe the request upstream and store the manifest in cache
 
-    // ---------------------------------------------------------------------------------------------
-    // Get the repository from the request
-    let manifest_repository = validate_repository(manifest_request).await?;
+    // A manifest larger than the maximum is only relayed to the client, without being stored
+    let oversized = upstream_response.status().is_success()
+        && exceeds_max_size(upstream_response.content_length(), state.app_config.storage.max_manifest_bytes);
 
     // ---------------------------------------------------------------------------------------------
     // Get the manifest digest from the upstream response
-    let manifest_digest = upstream_response.headers().get("docker-content-digest").cloned()
-        .unwrap_or_else(|| HeaderValue::from_str("").unwrap()).to_str().unwrap_or("").to_string();
-
-    // Parse it
-    let manifest_digest = if manifest_digest.is_empty() {
-        None
-    } else {
-        Digest::parse(&manifest_digest).ok()
-    };
+    let manifest_digest = manifest_digest(upstream_response.headers(), &manifest_repository);
 
     // ---------------------------------------------------------------------------------------------
     // Get the content-type from the upstream response
-    let content_type = upstream_response.headers().get("content-type").cloned()
-        .unwrap_or_else(|| HeaderValue::from_static("")).to_str().unwrap_or("").to_string();
+    let content_type = content_type(upstream_response.headers());
 
     // ---------------------------------------------------------------------------------------------
 
     // Build the response for the client
     let mut client_resp = HttpResponse::build(upstream_response.status());
-
-    // Remove `Connection` as per
-    // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Connection#Directives
-    for (header_name, header_value) in upstream_response.headers().iter().filter(|(h, _)| *h != "connection") {
-        client_resp.insert_header((header_name.clone(), header_value.clone()));
-        // tracing::info!("Response header: {}: {:?}", header_name, header_value);
-    }
+    relay_headers(&mut client_resp, upstream_response.headers(), &state.app_config.response_headers);
+    let content_length = content_length(upstream_response.headers());
 
     // Status code
     let status = upstream_response.status().to_string();
 
     // Create the client response channel
-    let (mut response_tx, response_rx) = tokio::io::duplex(8192); //mpsc::unbounded_channel();
-    let stream = tokio_util::codec::FramedRead::new(response_rx, tokio_util::codec::BytesCodec::new()).map_ok(|b| b.freeze());
+    let (response_tx, response_rx) = tokio::io::duplex(state.app_config.streaming.buffer_size);
+    let (failed_tx, failed_rx) = oneshot::channel();
+    let stream = client_stream(response_rx, failed_rx);
+
+    // For the logs
+    let manifest = format!("manifest {}/{}", manifest_repository.name, manifest_repository.reference);
 
-    // Create the persistence channels
-    let (persist_tx,persist_rx) = mpsc::unbounded_channel();
+    // How long the upstream can stall
+    let chunk_timeout = state.live().chunk_timeout;
 
-    // Ask the bus to store the data
-    let persist_command = RegistryCommand::PersistManifest(manifest_repository, manifest_digest, 0, content_type, persist_rx);
-    state.command_bus.publish(persist_command).await;
+    // Whether the manifest is still cached once the client went away
+    let finish_cache_on_disconnect = state.app_config.streaming.finish_cache_on_disconnect;
+
+    // Prime the cache with the configured platform in case of an image index
+    let priming = state.primer.clone()
+        .filter(|_| upstream_response.status().is_success() && Primer::is_index(&content_type))
+        .map(|primer| {
+            let authorization = req.headers().get(header::AUTHORIZATION)
+                .and_then(|value| reqwest::header::HeaderValue::from_bytes(value.as_bytes()).ok());
+            (primer, upstream_host(&req), upstream_url, manifest_repository.name.clone(), authorization)
+        });
+
+    // Only a successful response carries the manifest, e.g. a 304 Not Modified has an empty body
+    let persist_tx = if upstream_response.status().is_success() && state.app_config.storage.cache_manifests && !oversized {
+
+        // Create the persistence channels
+        let (persist_tx, persist_rx) = chunk_channel(&state.app_config.streaming.persist_channel);
+
+        // Ask the bus to store the data
+        let persist_command = RegistryCommand::PersistManifest(upstream_host(&req), manifest_repository, manifest_digest, content_type, persist_rx);
+        state.command_bus.publish(persist_command).await;
+
+        Some(persist_tx)
+    } else {
+        None
+    };
+
+    // Counted against the egress budget of the upstream
+    let upstream_body = state.egress_budgets.counted(&upstream_host(&req), upstream_response);
 
     // Consume the stream and send it to 2 channels:
     // - the response channel to send to the client
     // - the persist channel to persist the blob
     let _handle = tokio::spawn(async move {
-        let stream = upstream_response.bytes_stream();
-        pin_mut!(stream);
-
-        while let Some(chunk) = stream.next().await {
-            if let Ok(ref chunk) = chunk {
-                if let Err(e) = persist_tx.send(chunk.clone()) {
-                    tracing::error!("Failed to send manifest blob chunk for persistence: {}", e.to_string());
-                }
-                if let Err(e) = response_tx.write_all(chunk).await {
-                    tracing::error!("Failed to send manifest blob chunk for client response: {}", e.to_string());
-                }
+        // The image index is small, keep it around for the priming
+        let mut index = Vec::new();
+        let keep_index = priming.is_some();
+        let upstream_body = upstream_body.inspect(|chunk| {
+            if let (true, Ok(chunk)) = (keep_index, chunk) {
+                index.extend_from_slice(chunk);
             }
+        });
+
+        // Once it returns the client and the persistence streams are closed, so that neither of them waits for the priming
+        let end = fan_out(&manifest, upstream_body, persist_tx, response_tx, failed_tx, chunk_timeout, finish_cache_on_disconnect).await;
+
+        // Nor the other upstream streams
+        drop(in_flight);
+        drop(upstream_guard);
+
+        // Nor is an incomplete image index, or one nobody is waiting for anymore, primed
+        if let Some((primer, upstream, upstream_url, name, authorization)) = priming.filter(|_| end == FanOutEnd::Complete) {
+            primer.prime(&upstream, &upstream_url, &name, authorization, &index).await;
         }
-    });
+    }.instrument(tracing::info_span!("stream_response")));
 
     metrics::UPSTREAM_RESPONSES.inc();
     metrics::RESPONSE_CODE_COLLECTOR.with_label_values(&[status.as_str(), req.method().as_ref(), ""]).inc();
 
-    Ok(client_resp.streaming(stream))
+    Ok(relayed_body(client_resp, content_length, stream))
+}
+
+
+/// What identifies the identical manifest pulls
+#[derive(Hash, Eq, PartialEq, Clone)]
+pub struct ManifestFlightKey {
+    upstream: UpstreamHost,
+    name: String,
+    reference: String,
+
+    /// The credentials, the accepted media types and the If-None-Match of the client, which is relayed, change what upstream answers
+    authorization: Option<Vec<u8>>,
+    accept: Vec<Vec<u8>>,
+    if_none_match: Vec<Vec<u8>>,
+}
+
+/// A manifest fully received from upstream, shared by the coalesced pulls
+#[derive(Clone)]
+pub enum FetchedManifest {
+    Response {
+        status: reqwest::StatusCode,
+        headers: reqwest::header::HeaderMap,
+        body: Bytes,
+    },
+
+    /// Upstream timed out or failed, the pulls are served from the cache
+    Unavailable,
+
+    /// Upstream was not called, its circuit is open, the pulls are served from the cache even when the tag is stale
+    CircuitOpen,
+
+    /// Upstream confirmed the cached manifest, of this media type and digest, is still the one of the tag
+    NotModified(MimeType, Digest),
+}
+
+/// Serve the manifest from a single upstream request shared with the identical concurrent pulls,
+/// so that they all get, and store, the same digest even when upstream is moving the tag
+async fn coalesced_manifest(repository: Repository, req: HttpRequest, state: web::Data<AppState>) -> Result<HttpResponse, RegistryError> {
+
+    let key = ManifestFlightKey {
+        upstream: upstream_host(&req),
+        name: repository.name.clone(),
+        reference: repository.reference.clone(),
+        authorization: req.headers().get(header::AUTHORIZATION).map(|value| value.as_bytes().to_vec()),
+        accept: req.headers().get_all(header::ACCEPT).map(|value| value.as_bytes().to_vec()).collect(),
+        if_none_match: req.headers().get_all(header::IF_NONE_MATCH).map(|value| value.as_bytes().to_vec()).collect(),
+    };
+
+    let fetched = state.manifest_flights.run(key, || fetch_manifest(&req, &repository, &state)).await?;
+
+    match fetched {
+        FetchedManifest::Unavailable => handle_upstream_error(req, repository, &state, false).await,
+        FetchedManifest::CircuitOpen => handle_upstream_error(req, repository, &state, true).await,
+        FetchedManifest::NotModified(mime, digest) => {
+            serve_from_cache(req, Repository::new_with_reference(&repository.name, &digest.to_string())?, Some(mime), &state).await
+        }
+        FetchedManifest::Response { status, headers, body } => {
+            // The body is whole, actix sizes it
+            let mut client_resp = HttpResponse::build(status);
+            relay_headers(&mut client_resp, &headers, &state.app_config.response_headers);
+
+            metrics::RESPONSE_CODE_COLLECTOR.with_label_values(&[status.as_str(), req.method().as_ref(), ""]).inc();
+
+            Ok(client_resp.body(body))
+        }
+    }
+}
+
+/// Receive the whole manifest from upstream and hand it over to the persistence and the priming
+async fn fetch_manifest(req: &HttpRequest, repository: &Repository, state: &web::Data<AppState>) -> Result<FetchedManifest, RegistryError> {
+
+    // Held until the upstream response is fully received
+    let in_flight = state.in_flight.acquire().await?;
+
+    let mut upstream_request = build_upstream_req(req, Method::GET, state)?
+        .build().map_err(|e| RegistryError::new(ErrorKind::NotFound).with_error(e.to_string()))?;
+    identity_encoding(&mut upstream_request);
+
+    // Upstream only sends the manifest again when the tag moved
+    let revalidated = revalidated_manifest(req, repository, state).await;
+    if let Some((_, ref digest)) = revalidated {
+        if_none_match(&mut upstream_request, digest);
+    }
+
+    log::info!("Upstream: {} {}", upstream_request.method(), upstream_request.url());
+
+    let upstream_url = upstream_request.url().clone();
+
+    let upstream_guard = UpstreamRequestGuard::start();
+    let upstream_response = match execute_upstream(&upstream_host(req), UpstreamRequestKind::Manifest, upstream_request, state).await {
+        Ok(upstream_response) => upstream_response,
+        Err(e) if serves_from_cache(&e, state) => {
+            tracing::warn!("Upstream request for manifest {}:{} failed: {}", repository.name, repository.reference, e.to_string());
+            if matches!(e, UpstreamError::CircuitOpen) {
+                return Ok(FetchedManifest::CircuitOpen);
+            }
+            return Ok(FetchedManifest::Unavailable);
+        }
+        Err(e) => return Err(RegistryError::new(ErrorKind::Unavailable).with_error(e.to_string())),
+    };
+    remember_missing(req, repository, &upstream_response, state);
+
+    let status = upstream_response.status();
+    if let Some((mime, digest)) = revalidated.filter(|_| status == reqwest::StatusCode::NOT_MODIFIED) {
+        not_modified_upstream(req, repository, &mime, &digest, state).await;
+        return Ok(FetchedManifest::NotModified(mime, digest));
+    }
+    if status.is_server_error() {
+        return Ok(FetchedManifest::Unavailable);
+    }
+
+    let headers = upstream_response.headers().clone();
+    let chunk_timeout = state.live().chunk_timeout;
+
+    // The same bytes go to every client
+    let mut body = BytesMut::new();
+    let stream = state.egress_budgets.counted(&upstream_host(req), upstream_response);
+    pin_mut!(stream);
+    while let Some(chunk) = next_chunk(&mut stream, chunk_timeout).await
+        .map_err(|e| RegistryError::new(ErrorKind::Unavailable).with_error(e.to_string()))? {
+        body.extend_from_slice(&chunk);
+    }
+    let body = body.freeze();
+    drop(in_flight);
+    drop(upstream_guard);
+
+    metrics::UPSTREAM_RESPONSES.inc();
+
+    // Only a successful response carries the manifest
+    if status.is_success() {
+        let content_type = content_type(&headers);
+
+        // A manifest larger than the maximum is only relayed to the clients, without being stored
+        let oversized = exceeds_max_size(Some(body.len() as u64), state.app_config.storage.max_manifest_bytes);
+        if state.app_config.storage.cache_manifests && !oversized {
+            let (persist_tx, persist_rx) = chunk_channel(&state.app_config.streaming.persist_channel);
+            let persist_command = RegistryCommand::PersistManifest(upstream_host(req), repository.clone(), manifest_digest(&headers, repository), content_type.clone(), persist_rx);
+            state.command_bus.publish(persist_command).await;
+            if let Err(e) = persist_tx.send(body.clone()).await {
+                tracing::error!("Failed to send manifest for persistence: {}", e.to_string());
+            }
+        }
+
+        // Prime the cache with the configured platform in case of an image index
+        if let Some(primer) = state.primer.clone().filter(|_| Primer::is_index(&content_type)) {
+            let authorization = req.headers().get(header::AUTHORIZATION)
+                .and_then(|value| reqwest::header::HeaderValue::from_bytes(value.as_bytes()).ok());
+            let (upstream, name, index) = (upstream_host(req), repository.name.clone(), body.clone());
+            tokio::spawn(async move {
+                primer.prime(&upstream, &upstream_url, &name, authorization, &index).await;
+            });
+        }
+    }
+
+    Ok(FetchedManifest::Response { status, headers, body })
 }
 
+/// The media type and the digest of the cached manifest to revalidate upstream, so that upstream answers with a 304
+/// instead of the manifest while the tag did not move. None when the client sent its own If-None-Match,
+/// which is relayed, or the manifest is not cached
+async fn revalidated_manifest(req: &HttpRequest, repository: &Repository, state: &web::Data<AppState>) -> Option<(MimeType, Digest)> {
+    if !state.app_config.storage.cache_manifests || req.headers().contains_key(header::IF_NONE_MATCH) {
+        return None;
+    }
+
+    let manifest = match state.manifests.get(&upstream_host(req), repository, &accepted_media_types(req)).await {
+        Ok(manifest) => manifest?,
+        Err(e) => {
+            tracing::error!("Failed to look up the cached manifest {}:{}: {}", repository.name, repository.reference, e);
+            return None;
+        }
+    };
+
+    // The manifest has been evicted
+    let digest = manifest.reference.filter(|digest| state.storage.for_upstream(&upstream_host(req)).driver().is_stored(digest))?;
+    Some((manifest.mime, digest))
+}
+
+/// Ask upstream for the manifest only if it is not the cached one anymore, upstream uses the digest as the etag
+fn if_none_match(upstream_request: &mut reqwest::Request, digest: &Digest) {
+    if let Ok(etag) = reqwest::header::HeaderValue::from_str(&format!("\"{}\"", digest)) {
+        upstream_request.headers_mut().insert(reqwest::header::IF_NONE_MATCH, etag);
+    }
+}
+
+/// Upstream answered the revalidation with a 304: the cached manifest is marked as refreshed, it is not stored again
+async fn not_modified_upstream(req: &HttpRequest, repository: &Repository, mime: &MimeType, digest: &Digest, state: &web::Data<AppState>) {
+    metrics::UPSTREAM_RESPONSES.inc();
+    metrics::UPSTREAM_NOT_MODIFIED.inc();
+    tracing::info!("{}:{} did not move upstream, serving {} from the cache", repository.name, repository.reference, digest);
+
+    if let Err(e) = state.manifests.refresh(&upstream_host(req), repository, mime, digest).await {
+        tracing::error!("Failed to refresh the cached manifest {}:{}: {}", repository.name, repository.reference, e);
+    }
+}
+
+/// Answer a HEAD request from the manifest record, without any body.
+/// Upstream is only asked when the manifest is not cached, and the response is not persisted
+async fn head_manifest(req: HttpRequest, repository: Repository, state: &web::Data<AppState>) -> Result<HttpResponse, RegistryError> {
 
-/// Handles the client request in case the upstream timed out or returned an error
-async fn handle_upstream_error(req: HttpRequest, manifest_request: web::Path<RepositoryRequest>, state: &web::Data<AppState>) -> Result<HttpResponse, RegistryError> {
+    if let Some(response) = cached_manifest_head(&req, &repository, state).await? {
+        return Ok(response);
+    }
 
-    // parse the name from the request
-    let repository = validate_repository(manifest_request).await?;
+    if !upstream_allowed(&req, state) {
+        return Err(RegistryError::new(ErrorKind::RegistryManifestUnknown));
+    }
 
-    // Load the manifest record
-    let manifest_record = state.manifests.get(&repository).await?;
+    let upstream_request = build_upstream_req(&req, Method::HEAD, state)?
+        .build().map_err(|e| RegistryError::new(ErrorKind::NotFound).with_error(e.to_string()))?;
+
+    log::info!("Upstream: {} {}", upstream_request.method(), upstream_request.url());
+
+    let _upstream_guard = UpstreamRequestGuard::start();
+    let upstream_response = match execute_upstream(&upstream_host(&req), UpstreamRequestKind::Manifest, upstream_request, state).await {
+        Ok(upstream_response) => upstream_response,
+        Err(e) if serves_from_cache(&e, state) => {
+            tracing::warn!("Upstream request for manifest {}:{} failed: {}", repository.name, repository.reference, e.to_string());
+            return Err(RegistryError::new(ErrorKind::RegistryManifestUnknown));
+        }
+        Err(e) => return Err(RegistryError::new(ErrorKind::Unavailable).with_error(e.to_string())),
+    };
+    remember_missing(&req, &repository, &upstream_response, state);
+
+    let mut client_resp = HttpResponse::build(upstream_response.status());
+    relay_headers(&mut client_resp, upstream_response.headers(), &state.app_config.response_headers);
+
+    metrics::UPSTREAM_RESPONSES.inc();
+    metrics::RESPONSE_CODE_COLLECTOR.with_label_values(&[upstream_response.status().as_str(), req.method().as_ref(), ""]).inc();
+
+    // The content length of the manifest, not of the empty body
+    Ok(match content_length(upstream_response.headers()) {
+        Some(content_length) => client_resp.body(SizedStream::new(content_length, futures_util::stream::empty::<Result<Bytes, std::io::Error>>())),
+        None => client_resp.finish(),
+    })
+}
+
+/// The headers of the cached manifest: digest, size and media type as recorded in the database.
+/// None when the manifests are not cached, the ones indexed before are left to the upstream failures
+async fn cached_manifest_head(req: &HttpRequest, repository: &Repository, state: &web::Data<AppState>) -> Result<Option<HttpResponse>, RegistryError> {
+
+    if !state.app_config.storage.cache_manifests {
+        return Ok(None);
+    }
+
+    let manifest = match state.manifests.get(&upstream_host(req), repository, &accepted_media_types(req)).await? {
+        Some(manifest) if !is_stale(repository, &manifest, state) => manifest,
+        _ => return Ok(None),
+    };
+
+    // The manifest has been evicted, or is cached for another upstream
+    let digest = match manifest.reference {
+        Some(digest) if state.storage.for_upstream(&upstream_host(req)).driver().is_stored(&digest) => digest,
+        _ => return Ok(None),
+    };
+
+    metrics::CACHED_RESPONSES.inc();
+    metrics::RESPONSE_CODE_COLLECTOR.with_label_values(&["200", req.method().as_str(), &repository.name]).inc();
+
+    log::info!("*** Cached: {} {}", req.method(), req.uri());
+
+    // The body is never sent for a HEAD request, its size is the Content-Length
+    let response = HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, manifest.mime.to_string()))
+        .insert_header((HeaderName::from_static("docker-content-digest"), digest.to_string()))
+        .insert_header((header::ETAG, digest.to_string()))
+        .body(SizedStream::new(manifest.size as u64, futures_util::stream::empty::<Result<Bytes, std::io::Error>>()));
+
+    Ok(Some(response))
+}
+
+/// Whether the cached tag was not refreshed from upstream for longer than storage->manifest_ttl_secs,
+/// a manifest addressed by digest never goes stale
+fn is_stale(repository: &Repository, manifest: &ManifestRecord, state: &AppState) -> bool {
+    repository.digest.is_none() && manifest.is_stale(state.app_config.storage.manifest_ttl_secs, unix_now())
+}
+
+/// Whether the manifest is served from the cache after the upstream request failed
+fn serves_from_cache(e: &UpstreamError, state: &web::Data<AppState>) -> bool {
+    matches!(e, UpstreamError::CircuitOpen) || e.is_timeout() || state.app_config.storage.upstream_error == UpstreamErrorPolicy::Cache
+}
+
+/// The digest of the manifest, as sent by upstream or, when it did not, the one it was pulled by.
+/// The persistence verifies that the manifest hashes to it, and computes it when there is none
+fn manifest_digest(headers: &reqwest::header::HeaderMap, repository: &Repository) -> Option<Digest> {
+    headers.get("docker-content-digest")
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty())
+        .and_then(|value| Digest::parse(value).ok())
+        .or_else(|| repository.digest.clone())
+}
+
+/// Serve the cached manifest to a client whose request exceeded its deadline, the deadline error otherwise
+async fn deadline_exceeded(req: HttpRequest, repository: Repository, state: &web::Data<AppState>, e: RegistryError) -> Result<HttpResponse, RegistryError> {
+    let cached = state.manifests.get(&upstream_host(&req), &repository, &accepted_media_types(&req)).await?;
+    match cached {
+        Some(manifest) if !is_stale(&repository, &manifest, state) => handle_upstream_error(req, repository, state, false).await,
+        None if unindexed_manifest(&req, &repository, state).await.is_some() => handle_upstream_error(req, repository, state, false).await,
+        _ => Err(e),
+    }
+}
+
+/// The media type of a manifest addressed by digest which is stored but not indexed, e.g. the cache stopped
+/// between storing and indexing it. A manifest is indexed only once stored, never the other way around
+async fn unindexed_manifest(req: &HttpRequest, repository: &Repository, state: &web::Data<AppState>) -> Option<Option<MimeType>> {
+    let digest = repository.digest.as_ref()?;
+    let data = state.storage.for_upstream(&upstream_host(req)).driver().content(digest).await.ok()?;
+
+    // Content addressed, so the file is the manifest the client asks for, whatever its media type
+    Some(Manifest::parse(&data).ok().and_then(|manifest| manifest.media_type).and_then(|media_type| media_type.parse().ok()))
+}
+
+/// Handles the client request in case the upstream timed out or returned an error. A stale tag fails,
+/// unless `serve_stale` as upstream was not called at all: its circuit is open and another request probes it
+async fn handle_upstream_error(req: HttpRequest, repository: Repository, state: &web::Data<AppState>, serve_stale: bool) -> Result<HttpResponse, RegistryError> {
+
+    // Load the manifest record of the variant the client accepts
+    let accepted = accepted_media_types(&req);
+    let manifest_record = state.manifests.get(&upstream_host(&req), &repository, &accepted).await?;
 
     match manifest_record {
+        Some(manifest) if !serve_stale && is_stale(&repository, &manifest, state) => {
+            Err(RegistryError::new(ErrorKind::Unavailable)
+                .with_error(format!("{}:{} is stale in the cache and upstream is unreachable", repository.name, repository.reference)))
+        }
         Some(manifest) => {
+            tracing::info!("Serving {}:{} from the cache as {}, the client accepts {:?}", repository.name, repository.reference, manifest.mime, accepted);
 
             // It means we don't have a blob cache for this specific tag
             // We can't do anything at this stage so return an error
-            if let None = manifest.reference {
+            if manifest.reference.is_none() {
                 return Err(RegistryError::new(ErrorKind::RegistryManifestUnknown));
             }
 
@@ -155,8 +570,865 @@ async fn handle_upstream_error(req: HttpRequest, manifest_request: web::Path<Rep
             serve_from_cache(req, manifest_repository,Some(manifest.mime), state).await
         },
         None => {
+            // Stored but not indexed
+            if let Some(mime) = unindexed_manifest(&req, &repository, state).await {
+                tracing::warn!("Serving {}@{} from the storage, it is not indexed", repository.name, repository.reference);
+                return serve_from_cache(req, repository, mime, state).await;
+            }
+
+            // Cached, but not as a media type the client can handle
+            if !accepted.is_empty() && state.manifests.get(&upstream_host(&req), &repository, &[]).await?.is_some() {
+                tracing::warn!("No cached variant of {}:{} matches the media types the client accepts {:?}", repository.name, repository.reference, accepted);
+            }
             Err(RegistryError::new(ErrorKind::RegistryManifestUnknown))
         }
     }
 
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use actix_web::{middleware, test, web, App, HttpRequest, HttpResponse, HttpServer};
+    use actix_web::http::header;
+    use actix_web::http::StatusCode;
+    use actix_web::http::Method;
+    use futures_util::StreamExt;
+    use sha2::{Digest as Sha2Digest, Sha256};
+    use crate::api::circuit_breaker::CircuitState;
+    use crate::api::routes;
+    use crate::api::state::AppState;
+    use crate::config::app::{AppConfig, ConcurrentManifestsPolicy, UpstreamConfig, UpstreamErrorPolicy};
+    use crate::handlers::command::blob::persist::BlobPersistHandler;
+    use crate::pubsub::subscriber::CommandSubscriberTrait;
+    use crate::registry::digest::Digest;
+    use crate::registry::repository::Repository;
+
+    const DIGEST: &str = "sha256:05c6e08f1d9fdafa03147fcb8f82f124c76d2f70e3d989dc8aadb5e7d7450bec";
+    const MANIFEST: &str = r#"{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","layers":[]}"#;
+    const MIME: &str = "application/vnd.oci.image.manifest.v1+json";
+
+    /// Upstream answering slowly with a different manifest at every request, as if the tag kept moving
+    async fn moving_tag(hits: web::Data<AtomicUsize>) -> HttpResponse {
+        let hit = hits.fetch_add(1, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let manifest = MANIFEST.replace("[]", &format!(r#"[],"annotations":{{"hit":"{}"}}"#, hit));
+        HttpResponse::Ok()
+            .insert_header((header::CONTENT_TYPE, MIME))
+            .insert_header(("docker-content-digest", format!("sha256:{}", hex::encode(Sha256::digest(manifest.as_bytes())))))
+            .body(manifest)
+    }
+
+    #[actix_web::test]
+    async fn if_none_match_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let (state, _commands) = AppState::for_test(AppConfig::with_storage_folder(folder.path().to_str().unwrap())).await;
+
+        let digest = Digest::parse(DIGEST).unwrap();
+        let path = state.storage.digest_path(&digest);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, b"cached content").unwrap();
+
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(state))
+            .service(web::scope("/v2").configure(routes::registry_api_config))).await;
+
+        // Manifest by digest: answered without asking upstream
+        let req = test::TestRequest::get().uri(&format!("/v2/library/nginx/manifests/{}", DIGEST))
+            .insert_header((header::IF_NONE_MATCH, format!("\"{}\"", DIGEST)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::NOT_MODIFIED, resp.status());
+        assert_eq!(DIGEST, resp.headers().get(header::ETAG).unwrap());
+        assert!(test::read_body(resp).await.is_empty());
+
+        // Cached blob
+        let req = test::TestRequest::get().uri(&format!("/v2/library/nginx/blobs/{}", DIGEST))
+            .insert_header((header::IF_NONE_MATCH, format!("W/\"sha256:other\", \"{}\"", DIGEST)))
+            .to_request();
+        assert_eq!(StatusCode::NOT_MODIFIED, test::call_service(&app, req).await.status());
+
+        // The client has a different version of the blob
+        let req = test::TestRequest::get().uri(&format!("/v2/library/nginx/blobs/{}", DIGEST))
+            .insert_header((header::IF_NONE_MATCH, "\"sha256:other\""))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!("cached content", test::read_body(resp).await);
+    }
+
+    /// Upstream answering a 304 when asked for the manifest it has, recording the If-None-Match of the requests
+    async fn conditional_upstream(req: HttpRequest, requests: web::Data<parking_lot::Mutex<Vec<Option<String>>>>) -> HttpResponse {
+        let etag = format!("\"sha256:{}\"", hex::encode(Sha256::digest(MANIFEST.as_bytes())));
+        let if_none_match = req.headers().get(header::IF_NONE_MATCH).map(|value| value.to_str().unwrap().to_string());
+        requests.lock().push(if_none_match.clone());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        match if_none_match {
+            Some(if_none_match) if if_none_match == etag => HttpResponse::NotModified().insert_header((header::ETAG, etag)).finish(),
+            _ => HttpResponse::Ok()
+                .insert_header((header::CONTENT_TYPE, MIME))
+                .insert_header((header::ETAG, etag.as_str()))
+                .insert_header(("docker-content-digest", etag.trim_matches('"')))
+                .body(MANIFEST),
+        }
+    }
+
+    #[actix_web::test]
+    async fn conditional_revalidation_test() {
+        let requests = web::Data::new(parking_lot::Mutex::new(Vec::new()));
+        let upstream_requests = requests.clone();
+        let server = HttpServer::new(move || App::new().app_data(upstream_requests.clone()).default_service(web::to(conditional_upstream)))
+            .bind(("127.0.0.1", 0)).unwrap();
+        let address = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+        let digest = format!("sha256:{}", hex::encode(Sha256::digest(MANIFEST.as_bytes())));
+
+        for policy in [ConcurrentManifestsPolicy::Independent, ConcurrentManifestsPolicy::Coalesce] {
+            requests.lock().clear();
+            let folder = tempfile::tempdir().unwrap();
+            let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+            config.storage.concurrent_manifests = policy;
+            config.upstreams.push(UpstreamConfig::for_test("localhost", &format!("http://{}", address)));
+            let (state, mut commands) = AppState::for_test(config.clone()).await;
+            let handler = BlobPersistHandler::new(state.storage.clone(), state.manifests.clone(), config.storage.clone());
+
+            let app = test::init_service(App::new()
+                .app_data(web::Data::new(state))
+                .service(web::scope("/v2").configure(routes::registry_api_config))).await;
+            let pull = |if_none_match: Option<&str>| {
+                let mut req = test::TestRequest::get().uri("/v2/library/nginx/manifests/latest")
+                    .insert_header((header::HOST, "localhost"))
+                    .insert_header((header::ACCEPT, MIME));
+                if let Some(if_none_match) = if_none_match {
+                    req = req.insert_header((header::IF_NONE_MATCH, if_none_match));
+                }
+                req.to_request()
+            };
+
+            // Not cached yet, pulled as it is
+            assert_eq!(MANIFEST, test::call_and_read_body(&app, pull(None)).await);
+            handler.run(commands.recv().await.unwrap()).await.expect("the manifest is persisted");
+
+            // Revalidated, upstream does not send it again and it is not stored again
+            let not_modified = crate::metrics::UPSTREAM_NOT_MODIFIED.get();
+            let resp = test::call_service(&app, pull(None)).await;
+            assert_eq!(StatusCode::OK, resp.status());
+            assert_eq!(MIME, resp.headers().get(header::CONTENT_TYPE).unwrap());
+            assert_eq!(digest, resp.headers().get("docker-content-digest").unwrap().to_str().unwrap());
+            assert_eq!(MANIFEST, test::read_body(resp).await);
+            assert!(commands.try_recv().is_err());
+            assert!(crate::metrics::UPSTREAM_NOT_MODIFIED.get() > not_modified);
+
+            // The If-None-Match of the client is relayed, and so is the 304 of upstream
+            let etag = format!("\"{}\"", digest);
+            assert_eq!(StatusCode::NOT_MODIFIED, test::call_service(&app, pull(Some(&etag))).await.status());
+
+            // A pull without If-None-Match does not get the 304 of a concurrent conditional pull
+            let (conditional, unconditional) = futures_util::future::join(test::call_service(&app, pull(Some(&etag))), test::call_service(&app, pull(None))).await;
+            assert_eq!(StatusCode::NOT_MODIFIED, conditional.status());
+            assert_eq!(StatusCode::OK, unconditional.status());
+            assert_eq!(MANIFEST, test::read_body(unconditional).await);
+
+            assert_eq!(vec![None, Some(etag.clone()), Some(etag.clone()), Some(etag.clone()), Some(etag)], *requests.lock());
+        }
+    }
+
+    #[actix_web::test]
+    async fn coalesced_manifest_test() {
+        let hits = web::Data::new(AtomicUsize::new(0));
+        let upstream_hits = hits.clone();
+        let server = HttpServer::new(move || App::new().app_data(upstream_hits.clone()).default_service(web::to(moving_tag)))
+            .bind(("127.0.0.1", 0)).unwrap();
+        let address = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+
+        let folder = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(folder.path().join("sha256")).unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.storage.concurrent_manifests = ConcurrentManifestsPolicy::Coalesce;
+        config.upstreams.push(UpstreamConfig::for_test("localhost", &format!("http://{}", address)));
+        let (state, mut commands) = AppState::for_test(config.clone()).await;
+
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(state.clone()))
+            .service(web::scope("/v2").configure(routes::registry_api_config))).await;
+
+        let pull = || async {
+            let req = test::TestRequest::get().uri("/v2/library/nginx/manifests/latest")
+                .insert_header((header::HOST, "localhost"))
+                .insert_header((header::ACCEPT, MIME))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(StatusCode::OK, resp.status());
+            test::read_body(resp).await
+        };
+
+        let pulls = futures_util::future::join_all((0..5).map(|_| pull())).await;
+
+        // A single upstream request, every client got the same manifest
+        assert_eq!(1, hits.load(Ordering::SeqCst));
+        assert!(pulls.iter().all(|body| *body == pulls[0]));
+
+        // Stored once, for the digest the clients got
+        let handler = BlobPersistHandler::new(state.storage.clone(), state.manifests.clone(), config.storage.clone());
+        handler.run(commands.recv().await.unwrap()).await.expect("the manifest is persisted");
+        assert!(commands.try_recv().is_err());
+
+        let record = state.manifests.get("localhost", &Repository::new_with_reference("library/nginx", "latest").unwrap(), &[]).await.unwrap().unwrap();
+        assert_eq!(format!("sha256:{}", hex::encode(Sha256::digest(&pulls[0]))), record.reference.unwrap().to_string());
+
+        // The next pull starts a new upstream request
+        pull().await;
+        assert_eq!(2, hits.load(Ordering::SeqCst));
+    }
+
+    #[actix_web::test]
+    async fn denied_repository_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.allowed_repositories = vec!["library/*".to_string()];
+        let (state, _commands) = AppState::for_test(config).await;
+
+        // Even cached content is not served
+        let digest = Digest::parse(DIGEST).unwrap();
+        let path = state.storage.digest_path(&digest);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, b"cached content").unwrap();
+
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(state))
+            .service(web::scope("/v2").configure(routes::registry_api_config))).await;
+
+        // Denied before reaching upstream, there is no upstream configured for this host anyway
+        for uri in [format!("/v2/mycorp/app/manifests/{}", DIGEST), format!("/v2/mycorp/app/blobs/{}", DIGEST)] {
+            let req = test::TestRequest::get().uri(&uri)
+                .insert_header((header::IF_NONE_MATCH, format!("\"{}\"", DIGEST)))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(StatusCode::NOT_FOUND, resp.status());
+            assert!(std::str::from_utf8(&test::read_body(resp).await).unwrap().contains("NAME_UNKNOWN"));
+        }
+
+        // Allowed
+        let req = test::TestRequest::get().uri(&format!("/v2/library/nginx/blobs/{}", DIGEST)).to_request();
+        assert_eq!(StatusCode::OK, test::call_service(&app, req).await.status());
+    }
+
+    /// Upstream accepting the connections, then closing them while the client waits for the response headers
+    async fn closing_upstream() -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = [0; 1024];
+                let _ = tokio::io::AsyncReadExt::read(&mut socket, &mut request).await;
+            }
+        });
+        address
+    }
+
+    fn upstream_config(address: std::net::SocketAddr, schema: &str) -> UpstreamConfig {
+        UpstreamConfig::for_test("localhost", &format!("{}://{}", schema, address))
+    }
+
+    /// State with only the latest tag cached
+    async fn cached_latest(config: AppConfig) -> AppState {
+        let (state, _commands) = AppState::for_test(config).await;
+
+        let digest = Digest::parse(&format!("sha256:{}", hex::encode(Sha256::digest(MANIFEST.as_bytes())))).unwrap();
+        let path = state.storage.digest_path(&digest);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, MANIFEST).unwrap();
+        let latest = Repository::new_with_reference("library/nginx", "latest").unwrap();
+        state.manifests.persist("localhost", &latest, digest, MANIFEST.len() as i32, &MIME.parse().unwrap(), None).await.unwrap();
+        state
+    }
+
+    /// Pull the tag, returns the response status and body
+    async fn pull(state: AppState, tag: &str) -> (StatusCode, bytes::Bytes) {
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(state))
+            .service(web::scope("/v2").configure(routes::registry_api_config))).await;
+
+        let req = test::TestRequest::get().uri(&format!("/v2/library/nginx/manifests/{}", tag))
+            .insert_header((header::HOST, "localhost"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        (resp.status(), test::read_body(resp).await)
+    }
+
+    #[actix_web::test]
+    async fn upstream_connection_closed_test() {
+        let address = closing_upstream().await;
+        let observed = crate::metrics::UPSTREAM_RESPONSE_TIME.with_label_values(&["localhost", "manifest"]).get_sample_count();
+
+        for (policy, coalesce) in [(UpstreamErrorPolicy::Cache, ConcurrentManifestsPolicy::Independent),
+                                   (UpstreamErrorPolicy::Cache, ConcurrentManifestsPolicy::Coalesce),
+                                   (UpstreamErrorPolicy::Error, ConcurrentManifestsPolicy::Independent)] {
+            let folder = tempfile::tempdir().unwrap();
+            let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+            config.storage.upstream_error = policy.clone();
+            config.storage.concurrent_manifests = coalesce;
+            config.upstreams.push(upstream_config(address, "http"));
+
+            let (status, body) = pull(cached_latest(config.clone()).await, "latest").await;
+            if policy == UpstreamErrorPolicy::Cache {
+                assert_eq!(StatusCode::OK, status);
+                assert_eq!(MANIFEST, body);
+
+                // Not cached
+                assert_eq!(StatusCode::NOT_FOUND, pull(cached_latest(config).await, "stable").await.0);
+            } else {
+                assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status);
+            }
+        }
+
+        // The failed upstream requests are timed as well
+        assert!(crate::metrics::UPSTREAM_RESPONSE_TIME.with_label_values(&["localhost", "manifest"]).get_sample_count() > observed);
+    }
+
+    #[actix_web::test]
+    async fn pulls_test() {
+        let address = closing_upstream().await;
+        let folder = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.upstreams.push(upstream_config(address, "http"));
+        let state = cached_latest(config).await;
+        let pulls = crate::metrics::PULLS_TOTAL.with_label_values(&["library/nginx"]).get();
+
+        // Served from the cache, upstream is failing
+        assert_eq!(StatusCode::OK, pull(state.clone(), "latest").await.0);
+        assert_eq!(StatusCode::OK, pull(state.clone(), "latest").await.0);
+
+        // Neither a missing tag nor a HEAD request is a pull
+        assert_eq!(StatusCode::NOT_FOUND, pull(state.clone(), "stable").await.0);
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(state.clone()))
+            .service(web::scope("/v2").configure(routes::registry_api_config))).await;
+        let req = test::TestRequest::default().method(Method::HEAD).uri("/v2/library/nginx/manifests/latest")
+            .insert_header((header::HOST, "localhost"))
+            .to_request();
+        assert_eq!(StatusCode::OK, test::call_service(&app, req).await.status());
+
+        state.pull_stats.flush().await;
+        assert_eq!(vec![("library/nginx".to_string(), 2)], state.manifests.most_pulled(10).await.unwrap());
+        assert_eq!(pulls + 2, crate::metrics::PULLS_TOTAL.with_label_values(&["library/nginx"]).get());
+    }
+
+    #[actix_web::test]
+    async fn upstream_unreachable_test() {
+        // Nothing listens on the port anymore: connection refused
+        let refused = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+
+        // Plain HTTP upstream configured as HTTPS: the TLS handshake fails
+        let server = HttpServer::new(|| App::new().default_service(web::to(HttpResponse::Ok)))
+            .bind(("127.0.0.1", 0)).unwrap();
+        let plain = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+
+        for upstream in [upstream_config(refused, "http"), upstream_config(plain, "https")] {
+            let folder = tempfile::tempdir().unwrap();
+            let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+            config.upstreams.push(upstream);
+
+            let (status, body) = pull(cached_latest(config.clone()).await, "latest").await;
+            assert_eq!(StatusCode::OK, status);
+            assert_eq!(MANIFEST, body);
+
+            let (status, body) = pull(cached_latest(config.clone()).await, "stable").await;
+            assert_eq!(StatusCode::NOT_FOUND, status);
+            assert!(std::str::from_utf8(&body).unwrap().contains("MANIFEST_UNKNOWN"));
+
+            config.storage.upstream_error = UpstreamErrorPolicy::Error;
+            assert_eq!(StatusCode::SERVICE_UNAVAILABLE, pull(cached_latest(config).await, "latest").await.0);
+        }
+    }
+
+    #[actix_web::test]
+    async fn manifest_ttl_test() {
+        let address = closing_upstream().await;
+        let folder = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.upstreams.push(upstream_config(address, "http"));
+
+        // Fresh: served from the cache while upstream is unreachable
+        config.storage.manifest_ttl_secs = Some(3600);
+        assert_eq!((StatusCode::OK, bytes::Bytes::from(MANIFEST)), pull(cached_latest(config.clone()).await, "latest").await);
+
+        // Stale: must be revalidated upstream
+        config.storage.manifest_ttl_secs = Some(0);
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, pull(cached_latest(config.clone()).await, "latest").await.0);
+
+        // Stale with the circuit open: served from the cache until the cooldown elapsed, upstream is only probed then
+        config.circuit_breaker.failures = Some(1);
+        let state = cached_latest(config.clone()).await;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, pull(state.clone(), "latest").await.0);
+        assert_eq!(CircuitState::Open, state.circuit_breakers.state("localhost"));
+        assert_eq!((StatusCode::OK, bytes::Bytes::from(MANIFEST)), pull(state.clone(), "latest").await);
+        assert_eq!((StatusCode::OK, bytes::Bytes::from(MANIFEST)), pull(state, "latest").await);
+
+        // The failed probe of the half-open circuit
+        config.circuit_breaker.cooldown = 0;
+        let state = cached_latest(config).await;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, pull(state.clone(), "latest").await.0);
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, pull(state, "latest").await.0);
+    }
+
+    #[actix_web::test]
+    async fn head_manifest_test() {
+        let methods = web::Data::new(parking_lot::Mutex::new(Vec::<String>::new()));
+        let upstream_methods = methods.clone();
+        let upstream = HttpServer::new(move || App::new()
+            .app_data(upstream_methods.clone())
+            .default_service(web::to(|req: HttpRequest, methods: web::Data<parking_lot::Mutex<Vec<String>>>| async move {
+                methods.lock().push(req.method().to_string());
+                HttpResponse::Ok()
+                    .insert_header((header::CONTENT_TYPE, MIME))
+                    .insert_header(("docker-content-digest", DIGEST))
+                    .body(MANIFEST)
+            })))
+            .workers(1)
+            .bind(("127.0.0.1", 0)).unwrap();
+        let upstream_address = upstream.addrs()[0];
+        actix_web::rt::spawn(upstream.run());
+
+        let folder = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.upstreams.push(upstream_config(upstream_address, "http"));
+
+        // A real server, the Content-Length of a HEAD response is only set while encoding it
+        let state = web::Data::new(cached_latest(config).await);
+        let cache = HttpServer::new(move || App::new()
+            .app_data(state.clone())
+            .service(web::scope("/v2").configure(routes::registry_api_config)))
+            .workers(1)
+            .bind(("127.0.0.1", 0)).unwrap();
+        let cache_address = cache.addrs()[0];
+        actix_web::rt::spawn(cache.run());
+
+        let head = |tag: &str| reqwest::Client::new()
+            .head(format!("http://{}/v2/library/nginx/manifests/{}", cache_address, tag))
+            .header(header::HOST.as_str(), "localhost")
+            .send();
+
+        // Answered from the database, without asking upstream
+        let response = head("latest").await.unwrap();
+        let digest = format!("sha256:{}", hex::encode(Sha256::digest(MANIFEST.as_bytes())));
+        assert_eq!(reqwest::StatusCode::OK, response.status());
+        assert_eq!(MANIFEST.len().to_string(), response.headers()[header::CONTENT_LENGTH.as_str()]);
+        assert_eq!(MIME, response.headers()[header::CONTENT_TYPE.as_str()]);
+        assert_eq!(digest, response.headers()["docker-content-digest"]);
+        assert!(methods.lock().is_empty());
+
+        // Not cached: upstream gets a HEAD as well
+        let response = head("stable").await.unwrap();
+        assert_eq!(reqwest::StatusCode::OK, response.status());
+        assert_eq!(MANIFEST.len().to_string(), response.headers()[header::CONTENT_LENGTH.as_str()]);
+        assert_eq!(DIGEST, response.headers()["docker-content-digest"]);
+        assert_eq!(vec!["HEAD".to_string()], *methods.lock());
+    }
+
+    #[actix_web::test]
+    async fn oversized_manifest_test() {
+        let upstream = HttpServer::new(|| App::new()
+            .default_service(web::to(|| async {
+                HttpResponse::Ok()
+                    .insert_header((header::CONTENT_TYPE, MIME))
+                    .insert_header(("docker-content-digest", DIGEST))
+                    .body(MANIFEST)
+            })))
+            .workers(1)
+            .bind(("127.0.0.1", 0)).unwrap();
+        let upstream_address = upstream.addrs()[0];
+        actix_web::rt::spawn(upstream.run());
+
+        for concurrent_manifests in [ConcurrentManifestsPolicy::Independent, ConcurrentManifestsPolicy::Coalesce] {
+            let folder = tempfile::tempdir().unwrap();
+            let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+            config.upstreams.push(upstream_config(upstream_address, "http"));
+            config.storage.concurrent_manifests = concurrent_manifests;
+            config.storage.max_manifest_bytes = Some(MANIFEST.len() as u64 - 1);
+
+            // Relayed to the client, but not stored
+            let (state, mut commands) = AppState::for_test(config).await;
+            let (status, body) = pull(state, "latest").await;
+            assert_eq!(StatusCode::OK, status);
+            assert_eq!(MANIFEST, body);
+            assert!(commands.try_recv().is_err());
+        }
+    }
+
+    #[actix_web::test]
+    async fn upstream_path_prefix_test() {
+        // A Nexus like registry, serving the registry API under a sub-path only
+        let upstream = HttpServer::new(|| App::new()
+            .route("/repository/docker-hub/v2/library/nginx/manifests/latest", web::get().to(|| async {
+                HttpResponse::Ok()
+                    .insert_header((header::CONTENT_TYPE, MIME))
+                    .insert_header(("docker-content-digest", DIGEST))
+                    .body(MANIFEST)
+            })))
+            .workers(1)
+            .bind(("127.0.0.1", 0)).unwrap();
+        let upstream_address = upstream.addrs()[0];
+        actix_web::rt::spawn(upstream.run());
+
+        let folder = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.upstreams.push(upstream_config(upstream_address, "http"));
+        assert_eq!(StatusCode::NOT_FOUND, pull(AppState::for_test(config.clone()).await.0, "latest").await.0);
+
+        config.upstreams[0].path_prefix = Some("repository/docker-hub/".to_string());
+        let (status, body) = pull(AppState::for_test(config).await.0, "latest").await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(MANIFEST, body);
+    }
+
+    #[actix_web::test]
+    async fn cache_manifests_test() {
+        let upstream = HttpServer::new(|| App::new()
+            .default_service(web::to(|| async {
+                HttpResponse::Ok()
+                    .insert_header((header::CONTENT_TYPE, MIME))
+                    .insert_header(("docker-content-digest", DIGEST))
+                    .body(MANIFEST)
+            })))
+            .workers(1)
+            .bind(("127.0.0.1", 0)).unwrap();
+        let upstream_address = upstream.addrs()[0];
+        actix_web::rt::spawn(upstream.run());
+
+        for concurrent_manifests in [ConcurrentManifestsPolicy::Independent, ConcurrentManifestsPolicy::Coalesce] {
+            let folder = tempfile::tempdir().unwrap();
+            let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+            config.upstreams.push(upstream_config(upstream_address, "http"));
+            config.storage.concurrent_manifests = concurrent_manifests;
+            config.storage.cache_manifests = false;
+
+            // Streamed to the client, never sent for persistence
+            let (state, mut commands) = AppState::for_test(config).await;
+            let (status, body) = pull(state, "latest").await;
+            assert_eq!(StatusCode::OK, status);
+            assert_eq!(MANIFEST, body);
+            assert!(commands.try_recv().is_err());
+        }
+
+        // A HEAD request goes upstream even for a tag indexed before
+        let methods = web::Data::new(parking_lot::Mutex::new(Vec::<String>::new()));
+        let upstream_methods = methods.clone();
+        let upstream = HttpServer::new(move || App::new()
+            .app_data(upstream_methods.clone())
+            .default_service(web::to(|req: HttpRequest, methods: web::Data<parking_lot::Mutex<Vec<String>>>| async move {
+                methods.lock().push(req.method().to_string());
+                HttpResponse::Ok().insert_header(("docker-content-digest", DIGEST)).finish()
+            })))
+            .workers(1)
+            .bind(("127.0.0.1", 0)).unwrap();
+        let upstream_address = upstream.addrs()[0];
+        actix_web::rt::spawn(upstream.run());
+
+        let folder = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.upstreams.push(upstream_config(upstream_address, "http"));
+        config.storage.cache_manifests = false;
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(cached_latest(config).await))
+            .service(web::scope("/v2").configure(routes::registry_api_config))).await;
+        let req = test::TestRequest::default().method(Method::HEAD).uri("/v2/library/nginx/manifests/latest")
+            .insert_header((header::HOST, "localhost"))
+            .to_request();
+        assert_eq!(StatusCode::OK, test::call_service(&app, req).await.status());
+        assert_eq!(vec!["HEAD".to_string()], *methods.lock());
+    }
+
+    #[actix_web::test]
+    async fn compressed_manifest_test() {
+        let upstream = HttpServer::new(|| App::new()
+            .default_service(web::to(|| async {
+                HttpResponse::Ok()
+                    .insert_header((header::CONTENT_TYPE, MIME))
+                    .insert_header(("docker-content-digest", DIGEST))
+                    .body(MANIFEST)
+            })))
+            .workers(1)
+            .bind(("127.0.0.1", 0)).unwrap();
+        let upstream_address = upstream.addrs()[0];
+        actix_web::rt::spawn(upstream.run());
+
+        let folder = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.upstreams.push(upstream_config(upstream_address, "http"));
+
+        // Over HTTP/2 an explicit Content-Length is sent as is, even once the body is compressed
+        let state = web::Data::new(AppState::for_test(config).await.0);
+        let cache = HttpServer::new(move || App::new()
+            .app_data(state.clone())
+            .wrap(middleware::Compress::default())
+            .service(web::scope("/v2").configure(routes::registry_api_config)))
+            .workers(1)
+            .bind_auto_h2c(("127.0.0.1", 0)).unwrap();
+        let cache_address = cache.addrs()[0];
+        actix_web::rt::spawn(cache.run());
+
+        let pull = |client: reqwest::ClientBuilder, accept_encoding: &'static str| async move {
+            client.http2_prior_knowledge().build().unwrap()
+                .get(format!("http://{}/v2/library/nginx/manifests/latest", cache_address))
+                .header(header::HOST.as_str(), "localhost")
+                .header(header::ACCEPT_ENCODING.as_str(), accept_encoding)
+                .send().await.unwrap()
+        };
+
+        // Compressed by the cache, without the size of the manifest upstream sent
+        let response = pull(reqwest::Client::builder().no_gzip(), "gzip").await;
+        assert_eq!("gzip", response.headers()[header::CONTENT_ENCODING.as_str()]);
+        assert!(response.headers().get(header::CONTENT_LENGTH.as_str()).is_none());
+        assert!(response.bytes().await.is_ok());
+
+        let response = pull(reqwest::Client::builder(), "gzip").await;
+        assert_eq!(MANIFEST, response.text().await.unwrap());
+
+        // Relayed as is otherwise
+        let response = pull(reqwest::Client::builder().no_gzip(), "identity").await;
+        assert!(response.headers().get(header::CONTENT_ENCODING.as_str()).is_none());
+        assert_eq!(MANIFEST.len().to_string(), response.headers()[header::CONTENT_LENGTH.as_str()]);
+        assert_eq!(MANIFEST, response.text().await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn unindexed_manifest_test() {
+        let address = closing_upstream().await;
+        let folder = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.upstreams.push(upstream_config(address, "http"));
+        let (state, _commands) = AppState::for_test(config).await;
+
+        // Stored, but the cache stopped before indexing it
+        let digest = Digest::parse(&format!("sha256:{}", hex::encode(Sha256::digest(MANIFEST.as_bytes())))).unwrap();
+        let path = state.storage.digest_path(&digest);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, MANIFEST).unwrap();
+
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(state))
+            .service(web::scope("/v2").configure(routes::registry_api_config))).await;
+
+        let pull = |reference: String| test::TestRequest::get()
+            .uri(&format!("/v2/library/nginx/manifests/{}", reference))
+            .insert_header((header::HOST, "localhost"))
+            .to_request();
+
+        // Found by digest while upstream is unreachable
+        let resp = test::call_service(&app, pull(digest.to_string())).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!(MIME, resp.headers().get(header::CONTENT_TYPE).unwrap());
+        assert_eq!(digest.to_string(), resp.headers().get("docker-content-digest").unwrap().to_str().unwrap());
+        assert_eq!(MANIFEST.as_bytes(), test::read_body(resp).await);
+
+        // A tag can't be resolved without its row
+        let resp = test::call_service(&app, pull("latest".to_string())).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+    }
+
+    #[actix_web::test]
+    async fn manifest_variants_test() {
+        let address = closing_upstream().await;
+        let folder = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.upstreams.push(upstream_config(address, "http"));
+
+        // The same tag cached as an image manifest and as an image index
+        let state = cached_latest(config).await;
+        let index = r#"{"schemaVersion":2,"mediaType":"application/vnd.oci.image.index.v1+json","manifests":[]}"#;
+        let index_mime = "application/vnd.oci.image.index.v1+json";
+        let index_digest = Digest::parse(&format!("sha256:{}", hex::encode(Sha256::digest(index.as_bytes())))).unwrap();
+        std::fs::write(state.storage.digest_path(&index_digest), index).unwrap();
+        let latest = Repository::new_with_reference("library/nginx", "latest").unwrap();
+        state.manifests.persist("localhost", &latest, index_digest.clone(), index.len() as i32, &index_mime.parse().unwrap(), None).await.unwrap();
+
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(state))
+            .service(web::scope("/v2").configure(routes::registry_api_config))).await;
+
+        let pull = |accept: Vec<&'static str>| {
+            let mut req = test::TestRequest::get().uri("/v2/library/nginx/manifests/latest")
+                .insert_header((header::HOST, "localhost"));
+            for accept in accept {
+                req = req.append_header((header::ACCEPT, accept));
+            }
+            test::call_service(&app, req.to_request())
+        };
+
+        // Each client gets the variant it accepts
+        let resp = pull(vec![MIME]).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!(MIME, resp.headers().get(header::CONTENT_TYPE).unwrap());
+        assert_eq!(MANIFEST, test::read_body(resp).await);
+
+        let resp = pull(vec![index_mime]).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!(index_mime, resp.headers().get(header::CONTENT_TYPE).unwrap());
+        assert_eq!(index_digest.to_string(), resp.headers().get("docker-content-digest").unwrap().to_str().unwrap());
+        assert_eq!(index, test::read_body(resp).await);
+
+        // In order of preference, within a header and across the headers
+        let resp = pull(vec!["application/vnd.oci.image.manifest.v1+json;q=0.5, application/vnd.oci.image.index.v1+json"]).await;
+        assert_eq!(index, test::read_body(resp).await);
+        let resp = pull(vec!["application/vnd.docker.distribution.manifest.v2+json", MIME, index_mime]).await;
+        assert_eq!(MANIFEST, test::read_body(resp).await);
+
+        // Only a whole type, or subtype, is a wildcard
+        let resp = pull(vec!["application/vnd.docker.distribution.manifest.v2+json", "application/vnd.oci.*"]).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+        let resp = pull(vec!["application/vnd.docker.distribution.manifest.v2+json", "application/*;q=0.9"]).await;
+        assert_eq!(MANIFEST, test::read_body(resp).await);
+        let resp = pull(vec!["*/*"]).await;
+        assert_eq!(MANIFEST, test::read_body(resp).await);
+
+        // No variant for the accepted media types
+        let resp = pull(vec!["application/vnd.docker.distribution.manifest.v2+json", "application/vnd.oci.image.index.v1+json;q=0"]).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+        assert!(std::str::from_utf8(&test::read_body(resp).await).unwrap().contains("MANIFEST_UNKNOWN"));
+    }
+
+    #[actix_web::test]
+    async fn circuit_breaker_test() {
+        // Upstream closing every connection, counting them
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let connections = std::sync::Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                let mut request = [0; 1024];
+                let _ = tokio::io::AsyncReadExt::read(&mut socket, &mut request).await;
+            }
+        });
+
+        let folder = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.upstreams.push(upstream_config(address, "http"));
+        config.circuit_breaker.failures = Some(2);
+        let state = cached_latest(config).await;
+
+        // Served from the cache after each failure, until the circuit opens
+        for _ in 0..2 {
+            assert_eq!((StatusCode::OK, bytes::Bytes::from(MANIFEST)), pull(state.clone(), "latest").await);
+        }
+        assert_eq!(2, connections.load(Ordering::SeqCst));
+
+        // Open: served from the cache without calling upstream
+        assert_eq!((StatusCode::OK, bytes::Bytes::from(MANIFEST)), pull(state.clone(), "latest").await);
+        let (status, body) = pull(state.clone(), "stable").await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+        assert!(std::str::from_utf8(&body).unwrap().contains("MANIFEST_UNKNOWN"));
+        assert_eq!(2, connections.load(Ordering::SeqCst));
+
+        // The blobs which are not cached can't be pulled
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(state))
+            .service(web::scope("/v2").configure(routes::registry_api_config))).await;
+        let req = test::TestRequest::get().uri(&format!("/v2/library/nginx/blobs/{}", DIGEST))
+            .insert_header((header::HOST, "localhost"))
+            .to_request();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, test::call_service(&app, req).await.status());
+        assert_eq!(2, connections.load(Ordering::SeqCst));
+    }
+
+    #[actix_web::test]
+    async fn request_timeout_test() {
+        // Upstream accepting the connections and never answering
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+
+        let folder = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.upstreams.push(upstream_config(address, "http"));
+        config.api.request_timeout_secs = Some(1);
+
+        // Served from the cache once the deadline elapsed, well before the upstream timeout
+        let started = std::time::Instant::now();
+        assert_eq!((StatusCode::OK, bytes::Bytes::from(MANIFEST)), pull(cached_latest(config.clone()).await, "latest").await);
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // Nothing to fall back to
+        let timed_out = crate::metrics::REQUESTS_TIMED_OUT.get();
+        assert_eq!(StatusCode::GATEWAY_TIMEOUT, pull(cached_latest(config).await, "stable").await.0);
+        assert!(crate::metrics::REQUESTS_TIMED_OUT.get() > timed_out);
+    }
+
+    /// Upstream sending an image index slowly, padded with whitespace, and the manifest of its platform
+    async fn slow_index(req: HttpRequest, requests: web::Data<parking_lot::Mutex<Vec<String>>>) -> HttpResponse {
+        requests.lock().push(req.path().to_string());
+        if req.path().ends_with(DIGEST) {
+            return HttpResponse::Ok().insert_header((header::CONTENT_TYPE, MIME)).body(MANIFEST);
+        }
+
+        let index = format!(r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.index.v1+json","manifests":[{{"mediaType":"{}","digest":"{}","size":{},"platform":{{"architecture":"amd64","os":"linux"}}}}]}}"#,
+                            MIME, DIGEST, MANIFEST.len());
+        let padding = futures_util::stream::iter(0..40).then(|_| async {
+            tokio::time::sleep(Duration::from_millis(25)).await;
+            Ok::<_, actix_web::Error>(bytes::Bytes::from(" ".repeat(1024)))
+        });
+        HttpResponse::Ok()
+            .insert_header((header::CONTENT_TYPE, "application/vnd.oci.image.index.v1+json"))
+            .streaming(futures_util::stream::once(async move { Ok(bytes::Bytes::from(index)) }).chain(padding))
+    }
+
+    #[actix_web::test]
+    async fn client_disconnect_test() {
+        let requests = web::Data::new(parking_lot::Mutex::new(Vec::<String>::new()));
+        let upstream_requests = requests.clone();
+        let upstream = HttpServer::new(move || App::new().app_data(upstream_requests.clone()).default_service(web::to(slow_index)))
+            .workers(1)
+            .bind(("127.0.0.1", 0)).unwrap();
+        let address = upstream.addrs()[0];
+        actix_web::rt::spawn(upstream.run());
+
+        for finish_cache_on_disconnect in [true, false] {
+            requests.lock().clear();
+            let folder = tempfile::tempdir().unwrap();
+            let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+            config.upstreams.push(upstream_config(address, "http"));
+            config.priming.platform = Some("linux/amd64".to_string());
+            config.streaming.finish_cache_on_disconnect = finish_cache_on_disconnect;
+            let (state, mut commands) = AppState::for_test(config).await;
+
+            let state = web::Data::new(state);
+            let cache = HttpServer::new(move || App::new()
+                .app_data(state.clone())
+                .service(web::scope("/v2").configure(routes::registry_api_config)))
+                .workers(1)
+                .bind(("127.0.0.1", 0)).unwrap();
+            let cache_address = cache.addrs()[0];
+            actix_web::rt::spawn(cache.run());
+
+            // The client goes away after the first chunk of the image index
+            let mut response = reqwest::Client::new()
+                .get(format!("http://{}/v2/library/nginx/manifests/latest", cache_address))
+                .header(header::HOST.as_str(), "localhost")
+                .send().await.unwrap();
+            assert!(response.chunk().await.unwrap().is_some());
+            drop(response);
+
+            let Some(crate::models::commands::RegistryCommand::PersistManifest(_, _, _, _, mut receiver)) = commands.recv().await else {
+                panic!("the image index is persisted");
+            };
+            while receiver.recv().await.is_some() {}
+            assert_eq!(!finish_cache_on_disconnect, receiver.is_aborted());
+
+            // Only the whole image index is primed
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            let primed = requests.lock().iter().any(|path| path.ends_with(DIGEST));
+            assert_eq!(finish_cache_on_disconnect, primed);
+        }
+    }
+}
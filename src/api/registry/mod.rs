@@ -2,19 +2,76 @@
 pub mod blobs;
 pub mod forward;
 pub mod manifests;
+pub mod referrers;
+pub mod tags;
 
-use actix_web::{HttpRequest, HttpResponse, web};
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use actix_web::{middleware, HttpRequest, HttpResponse, HttpResponseBuilder, web};
+use actix_web::body::{BodySize, BoxBody, MessageBody, SizedStream};
 use actix_web::http::{header, Method};
 use actix_web::http::header::{HeaderName, HeaderValue};
+use bytes::Bytes;
+use futures_util::{pin_mut, Stream, StreamExt, TryStreamExt};
 use reqwest::RequestBuilder;
+use tokio::io::{AsyncWriteExt, DuplexStream};
+use tokio::sync::oneshot;
 use url::Url;
+use crate::api::admin::{self, ADMIN_TOKEN_HEADER, UPSTREAM_TIMEOUT_HEADER};
+use crate::api::live_config::LiveConfig;
 use crate::api::registry::blobs::RepositoryRequest;
 use crate::api::state::AppState;
+use crate::config::app::MirrorConfig;
+use crate::config::response_headers::ResponseHeadersConfig;
+use crate::driver::StoredContent;
 use crate::error::error_kind::ErrorKind;
 use crate::error::registry::RegistryError;
 use crate::metrics;
-use crate::models::types::MimeType;
+use crate::models::chunks::ChunkSender;
+use crate::models::types::{MimeType, UpstreamHost};
+use crate::registry::digest::Digest;
 use crate::registry::repository::Repository;
+use crate::repository::active_reads::ReadGuard;
+use crate::telemetry;
+
+/// Registry API version, set on every `/v2` response so that the clients can detect the API
+const API_VERSION: (&str, &str) = ("Docker-Distribution-API-Version", "registry/2.0");
+
+/// Sets the API version on the `/v2` responses which do not have one, the cached and the error ones included.
+/// The one relayed from upstream is kept
+pub fn api_version_headers() -> middleware::DefaultHeaders {
+    middleware::DefaultHeaders::new().add(API_VERSION)
+}
+
+/// Sets `Content-Encoding: identity` on the blob responses which do not have an encoding, so that the Compress middleware
+/// sends them as they are stored: their digest is over these bytes, and the layers are compressed already.
+/// The encoding relayed from upstream is kept
+pub fn blob_encoding_headers() -> middleware::DefaultHeaders {
+    middleware::DefaultHeaders::new().add((header::CONTENT_ENCODING, "identity"))
+}
+
+/// Response body which keeps the blob marked as being read until it is fully streamed,
+/// so that an eviction cannot remove it in the meantime
+struct GuardedBody {
+    body: BoxBody,
+    _guard: ReadGuard,
+}
+
+impl MessageBody for GuardedBody {
+    type Error = <BoxBody as MessageBody>::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        Pin::new(&mut self.body).poll_next(cx)
+    }
+}
 
 /// Serve the content from the cache via the repository info
 async fn serve_from_cache(req: HttpRequest, repository: Repository, mime: Option<MimeType>, state: &web::Data<AppState>) -> Result<HttpResponse, RegistryError> {
@@ -23,19 +80,35 @@ async fn serve_from_cache(req: HttpRequest, repository: Repository, mime: Option
     let image_name = repository.name.clone();
     let repository_digest = repository.digest.clone();
 
-    // Load the file
-    let file = actix_files::NamedFile::open_async(state.storage.blob_path(repository)).await
-        .map_err(|e| RegistryError::new(ErrorKind::NotFound).with_error(e.to_string()))?;
+    // The storage of the upstream the request is for
+    let storage = state.storage.for_upstream(&upstream_host(&req));
 
-    // Add the content type if we have it
-    let file = if let Some(mime) = mime {
-        file.set_content_type(mime.parse().unwrap())
-    } else {
-        file
-    };
+    // The client already has this exact content
+    if let Some(ref digest) = repository_digest {
+        if etag_matches(&req, digest) {
+            return Ok(not_modified(&req, digest));
+        }
+    }
+
+    // Track the read before opening the file, so that the blob cannot be evicted while streaming it
+    let read_guard = repository_digest.as_ref().map(|digest| storage.acquire_read(digest));
+
+    let mut response = match storage.driver().open(repository).await? {
+        StoredContent::File(file) => {
+            let file = *file;
 
-    // Convert to response
-    let mut response = file.into_response(&req);
+            // Add the content type if we have it
+            let file = if let Some(mime) = mime {
+                file.set_content_type(mime.to_mime())
+            } else {
+                file
+            };
+
+            // Convert to response
+            file.into_response(&req)
+        }
+        StoredContent::Bytes(data) => HttpResponse::Ok().content_type(mime.unwrap_or_default().to_mime()).body(data),
+    };
 
     // Add the digest and etag if present
     if let Some(ref digest) = repository_digest {
@@ -50,6 +123,12 @@ async fn serve_from_cache(req: HttpRequest, repository: Repository, mime: Option
         response.headers_mut().insert(HeaderName::from_static("etag"), digest_string);
     }
 
+    // Keep the read guard alive until the whole body is sent
+    let response = match read_guard {
+        Some(guard) => response.map_body(|_, body| GuardedBody { body, _guard: guard }).map_into_boxed_body(),
+        None => response,
+    };
+
     // Collect the metrics for the cached data
     metrics::CACHED_RESPONSES.inc();
     metrics::RESPONSE_CODE_COLLECTOR.with_label_values(&[response.status().as_str(), req.method().as_str(), &image_name]).inc();
@@ -61,12 +140,166 @@ async fn serve_from_cache(req: HttpRequest, repository: Repository, mime: Option
     Ok(response)
 }
 
+/// Whether the If-None-Match header of the client request contains the digest, the etag of the cached content
+fn etag_matches(req: &HttpRequest, digest: &Digest) -> bool {
+    let digest = digest.to_string();
+    req.headers().get_all(header::IF_NONE_MATCH)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|etag| etag.trim().trim_start_matches("W/").trim_matches('"'))
+        .any(|etag| etag == "*" || etag == digest)
+}
+
+/// The media types of the Accept headers of the client request, most preferred first.
+/// The ones with a zero quality are not acceptable
+fn accepted_media_types(req: &HttpRequest) -> Vec<MimeType> {
+    let mut accepted: Vec<(MimeType, f32)> = req.headers().get_all(header::ACCEPT)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|media_type| {
+            let mut parameters = media_type.split(';').map(str::trim);
+            let media_type = parameters.next().filter(|media_type| !media_type.is_empty())?;
+            let quality = parameters.find_map(|parameter| parameter.strip_prefix("q="))
+                .and_then(|quality| quality.parse().ok())
+                .unwrap_or(1.0);
+            Some((media_type.parse().ok()?, quality))
+        })
+        .filter(|(_, quality)| *quality > 0.0)
+        .collect();
+
+    // Stable, the media types with the same quality keep the order of the client
+    accepted.sort_by(|a, b| b.1.total_cmp(&a.1));
+    accepted.into_iter().map(|(media_type, _)| media_type).collect()
+}
+
+/// Empty 304 response for a client which already has the content with the given digest
+fn not_modified(req: &HttpRequest, digest: &Digest) -> HttpResponse {
+    metrics::CACHED_RESPONSES.inc();
+    metrics::RESPONSE_CODE_COLLECTOR.with_label_values(&["304", req.method().as_str(), ""]).inc();
+
+    HttpResponse::NotModified()
+        .insert_header((HeaderName::from_static("docker-content-digest"), digest.to_string()))
+        .insert_header((header::ETAG, digest.to_string()))
+        .finish()
+}
+
+/// Next chunk of the upstream response, an upstream sending nothing for longer than the timeout is an error
+async fn next_chunk<S>(stream: &mut S, timeout: Duration) -> io::Result<Option<Bytes>>
+    where S: Stream<Item = reqwest::Result<Bytes>> + Unpin
+{
+    match tokio::time::timeout(timeout, stream.next()).await {
+        Ok(Some(Ok(chunk))) => Ok(Some(chunk)),
+        Ok(Some(Err(e))) => Err(io::Error::other(e)),
+        Ok(None) => Ok(None),
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, format!("no data from upstream for {:?}", timeout))),
+    }
+}
+
+/// How streaming an upstream response to the client and to the persistence ended
+#[derive(Debug, PartialEq)]
+enum FanOutEnd {
+    /// The whole upstream response was received
+    Complete,
+
+    /// Upstream failed or stalled, the content is incomplete
+    UpstreamFailed,
+
+    /// Neither the client nor the persistence consumed it anymore, the upstream request was closed
+    NoConsumer,
+}
+
+/// Stream the upstream response both to the client, via the duplex pipe of `client_stream`, and to the persistence.
+/// The persistence stops receiving once it gave up, e.g. for an oversized content, and so does the client once it disconnected,
+/// its persistence is then aborted too unless `finish_cache_on_disconnect`. Nobody consuming the upstream response anymore
+/// closes the upstream request. An upstream failing or stalling for longer than `chunk_timeout` aborts the persistence
+/// and fails the client response, rather than ending them as if the content was complete
+async fn fan_out<S>(content: &str, stream: S, persist_tx: Option<ChunkSender>, response_tx: DuplexStream, failed_tx: oneshot::Sender<io::Error>,
+                    chunk_timeout: Duration, finish_cache_on_disconnect: bool) -> FanOutEnd
+    where S: Stream<Item = reqwest::Result<Bytes>>
+{
+    pin_mut!(stream);
+    let mut persist_tx = persist_tx;
+    let mut response_tx = Some(response_tx);
+
+    loop {
+        let chunk = match next_chunk(&mut stream, chunk_timeout).await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => return FanOutEnd::Complete,
+            Err(e) => {
+                tracing::error!("Failed to stream {} from upstream: {}", content, e.to_string());
+                metrics::UPSTREAM_STREAM_FAILURES.inc();
+                if let Some(tx) = persist_tx.take() {
+                    tx.abort();
+                }
+                let _ = failed_tx.send(e);
+                return FanOutEnd::UpstreamFailed;
+            }
+        };
+
+        if let Some(ref tx) = persist_tx {
+            if let Err(e) = tx.send(chunk.clone()).await {
+                tracing::error!("Failed to send {} chunk for persistence: {}", content, e.to_string());
+                persist_tx = None;
+            }
+        }
+        if let Some(ref mut tx) = response_tx {
+            if let Err(e) = tx.write_all(&chunk).await {
+                tracing::info!("Client disconnected while pulling {}: {}", content, e.to_string());
+                response_tx = None;
+
+                // Stop downloading what nobody is waiting for, unless it is still being cached
+                if !finish_cache_on_disconnect {
+                    if let Some(tx) = persist_tx.take() {
+                        tx.abort();
+                    }
+                }
+            }
+        }
+
+        if persist_tx.is_none() && response_tx.is_none() {
+            tracing::info!("Stopped streaming {} from upstream, no consumer left", content);
+            return FanOutEnd::NoConsumer;
+        }
+    }
+}
+
+/// Client response body fed by the task streaming the upstream response via the duplex pipe.
+/// The body fails, instead of ending as if it was complete, when that task reports an upstream error.
+fn client_stream(response_rx: DuplexStream, failed: oneshot::Receiver<io::Error>) -> impl Stream<Item = io::Result<Bytes>> {
+    tokio_util::codec::FramedRead::new(response_rx, tokio_util::codec::BytesCodec::new())
+        .map_ok(|b| b.freeze())
+        .chain(futures_util::stream::once(failed).filter_map(|failed| async move { failed.ok().map(Err) }))
+}
+
+/// Reject a body declaring, via its Content-Length, a size over the configured maximum
+fn check_max_size(content_length: Option<u64>, max_size: Option<u64>) -> Result<(), RegistryError> {
+    match (content_length, max_size) {
+        (Some(content_length), Some(max_size)) if content_length > max_size => {
+            metrics::CACHE_OVERSIZED.inc();
+            Err(RegistryError::new(ErrorKind::MaxPayloadError)
+                .with_error(format!("{} bytes exceed the maximum size of {} bytes", content_length, max_size)))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Whether an upstream body declaring, via its Content-Length, a size over the configured maximum is too large to be cached.
+/// It is relayed to the client anyway
+fn exceeds_max_size(content_length: Option<u64>, max_size: Option<u64>) -> bool {
+    check_max_size(content_length, max_size).is_err()
+}
+
+/// The host the upstreams are matched against: the Host header of the client request
+fn upstream_host(req: &HttpRequest) -> UpstreamHost {
+    req.headers().get(header::HOST).and_then(|host| host.to_str().ok()).unwrap_or("").to_string()
+}
+
 /// Builds the upstream request URL starting from the client one
 fn build_upstream_req(req: &HttpRequest,  method: Method, state: &web::Data<AppState>) -> Result<RequestBuilder, RegistryError> {
 
-    let host_header = req.headers().get(header::HOST).cloned().unwrap_or_else(|| HeaderValue::from_static(""));
-    let host = host_header.to_str().unwrap_or("");
-    let upstream = state.upstreams.get(host);
+    let host = upstream_host(req);
+    let live = state.live();
+    let upstream = live.upstreams.get(&host);
 
     if upstream.is_none() {
         tracing::error!("Upstream not found for host {}", host);
@@ -77,7 +310,7 @@ fn build_upstream_req(req: &HttpRequest,  method: Method, state: &web::Data<AppS
     metrics::INCOMING_REQUESTS.inc();
 
     let upstream = upstream.unwrap();
-    let forward_url = format!("{}://{}", upstream.schema, upstream.registry);
+    let forward_url = upstream.base_url();
 
     // Rewrite the URL
     let mut new_url = Url::parse(&forward_url).unwrap();
@@ -85,21 +318,44 @@ fn build_upstream_req(req: &HttpRequest,  method: Method, state: &web::Data<AppS
     // Convert the original request URI to string
     let path = req.uri().path();
 
-    // Set the URL path
-    new_url.set_path(path);
+    // Set the URL path, behind the path prefix of the upstream
+    new_url.set_path(&upstream.upstream_path(path));
 
     // Set the URL query string parameters
     new_url.set_query(req.uri().query());
 
     // Create the upstream request
-    let mut upstream_request = state.client
+    let mut upstream_request = live.clients.for_upstream(&host)
         .request(method, new_url);
 
-    // Append the client request headers to the upstream request
-    for (header_name, header_value) in req.headers().iter().filter(|(h, _)| *h != "host") {
+    // The configured User-Agent replaces, or is appended to, the one of the client
+    let client_user_agent = req.headers().get(header::USER_AGENT).and_then(|value| value.to_str().ok());
+    let user_agent = upstream.user_agent.as_ref().unwrap_or(&live.user_agent).for_client(client_user_agent);
+
+    // Append the client request headers to the upstream request, the admin ones are meant for the cache only
+    for (header_name, header_value) in req.headers().iter().filter(|(h, _)| *h != "host" && *h != ADMIN_TOKEN_HEADER && *h != UPSTREAM_TIMEOUT_HEADER) {
+        if *header_name == header::USER_AGENT && user_agent.is_some() {
+            continue;
+        }
+
+        // The clients authenticate to the cache, the credentials of the cache replace theirs: `UpstreamClients` reads
+        // their secrets and sends them as a default header of the client of the upstream
+        if *header_name == header::AUTHORIZATION && upstream.credentials.is_some() {
+            continue;
+        }
         upstream_request = upstream_request.header(header_name, header_value);
     }
 
+    if let Some(user_agent) = user_agent {
+        upstream_request = upstream_request.header(header::USER_AGENT.as_str(), user_agent);
+    }
+
+    // Diagnose a slow upstream without changing the timeout of every request
+    if let Some(timeout) = admin::upstream_timeout(req, &state.app_config.admin) {
+        tracing::info!("Upstream timeout of {:?} for {} {}", timeout, req.method(), req.uri());
+        upstream_request = upstream_request.timeout(timeout);
+    }
+
     // TODO: This forwarded implementation is incomplete as it only handles the unofficial
     // X-Forwarded-For header but not the official Forwarded one.
     let upstream_request = match req.peer_addr() {
@@ -113,12 +369,411 @@ fn build_upstream_req(req: &HttpRequest,  method: Method, state: &web::Data<AppS
 
 }
 
-async fn validate_repository(repository_request: web::Path<RepositoryRequest>) -> Result<Repository, RegistryError> {
+/// Whether the upstream of the request, or one of its mirrors, can be called. Each of them is skipped while its egress budget
+/// is spent or its circuit is open, see `execute_upstream`
+fn upstream_allowed(req: &HttpRequest, state: &AppState) -> bool {
+    let host = upstream_host(req);
+    let live = state.live();
+    let mirrors = live.upstreams.get(&host).map(|config| config.mirrors.as_slice()).unwrap_or_default();
+    let allowed = is_callable(&host, state) || mirrors.iter().any(|mirror| is_callable(&mirror.base_url(), state));
+    if !allowed {
+        tracing::debug!("Upstream {} and its mirrors spent their egress budget or have an open circuit, skipping them for {} {}", host, req.method(), req.uri());
+    }
+    allowed
+}
+
+/// Whether the upstream, or the mirror by its base URL, has some egress budget left and its circuit is not open.
+/// Unlike `CircuitBreakers::allow`, it does not start a probe
+fn is_callable(target: &str, state: &AppState) -> bool {
+    state.egress_budgets.allow(target) && !state.circuit_breakers.is_open(target)
+}
+
+/// Whether the upstream, or the mirror, the request would be sent to did not have the blob or manifest a moment ago
+fn is_missing(req: &HttpRequest, repository: &Repository, state: &AppState) -> bool {
+    let host = upstream_host(req);
+    let credentials = client_credentials(req);
+    if is_callable(&host, state) {
+        return state.negative_cache.is_missing(&host, repository, credentials);
+    }
+
+    let live = state.live();
+    let mirrors = live.upstreams.get(&host).map(|config| config.mirrors.as_slice()).unwrap_or_default();
+    match mirrors.iter().map(MirrorConfig::base_url).find(|mirror| is_callable(mirror, state)) {
+        Some(mirror) => state.negative_cache.is_missing(&mirror, repository, credentials),
+        None => state.negative_cache.is_missing(&host, repository, credentials),
+    }
+}
+
+/// Remember for a while that the upstream, or the mirror which answered instead, does not have the blob or manifest,
+/// if it answered with a 404
+fn remember_missing(req: &HttpRequest, repository: &Repository, response: &reqwest::Response, state: &AppState) {
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        match response.extensions().get::<AnsweringMirror>() {
+            Some(AnsweringMirror(mirror)) => state.negative_cache.missing(mirror, repository, client_credentials(req)),
+            None => state.negative_cache.missing(&upstream_host(req), repository, client_credentials(req)),
+        }
+    }
+}
+
+/// The Authorization of the client, the 404s of upstream are only remembered for the clients sending the same one
+fn client_credentials(req: &HttpRequest) -> Option<&[u8]> {
+    req.headers().get(header::AUTHORIZATION).map(|value| value.as_bytes())
+}
+
+/// The base URL of the mirror which answered instead of the upstream, in the extensions of its response
+#[derive(Clone)]
+pub struct AnsweringMirror(pub String);
+
+/// Why an upstream request did not get any response
+#[derive(Debug)]
+enum UpstreamError {
+    /// The egress budget of the upstream is spent or its circuit is open, and none of its mirrors could be tried instead
+    CircuitOpen,
+
+    /// The request failed, e.g. it timed out or could not connect
+    Request(reqwest::Error),
+}
+
+impl UpstreamError {
+
+    fn is_timeout(&self) -> bool {
+        matches!(self, UpstreamError::Request(e) if e.is_timeout())
+    }
+
+    /// The error for the client: a 503 while the circuit is open, an error of the kind otherwise
+    fn into_registry_error(self, kind: ErrorKind) -> RegistryError {
+        match self {
+            UpstreamError::CircuitOpen => RegistryError::new(ErrorKind::Unavailable).with_error(self.to_string()),
+            UpstreamError::Request(e) => RegistryError::new(kind).with_error(e.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamError::CircuitOpen => write!(f, "the upstream and its mirrors spent their egress budget or have an open circuit"),
+            UpstreamError::Request(e) => e.fmt(f),
+        }
+    }
+}
+
+impl From<reqwest::Error> for UpstreamError {
+    fn from(e: reqwest::Error) -> Self {
+        UpstreamError::Request(e)
+    }
+}
+
+/// What an upstream request is for, the `kind` label of its response time
+#[derive(Clone, Copy, Debug)]
+enum UpstreamRequestKind {
+    Blob,
+    Manifest,
+    Referrers,
+    Tags,
+    Forward,
+}
+
+impl UpstreamRequestKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            UpstreamRequestKind::Blob => "blob",
+            UpstreamRequestKind::Manifest => "manifest",
+            UpstreamRequestKind::Referrers => "referrers",
+            UpstreamRequestKind::Tags => "tags",
+            UpstreamRequestKind::Forward => "forward",
+        }
+    }
+}
+
+/// Result of an upstream request, the `result` label of the upstream requests counter
+fn upstream_result(result: &reqwest::Result<reqwest::Response>) -> &'static str {
+    match result {
+        Ok(response) if response.status().is_client_error() => "client_error",
+        Ok(response) if response.status().is_server_error() => "server_error",
+        Ok(_) => "success",
+        Err(e) if e.is_timeout() => "timeout",
+        // The request could not be sent, or its response headers not received
+        Err(_) => "connect_error",
+    }
+}
+
+/// Send the request to the upstream, the outcome feeds the circuit breaker of the upstream and is counted per upstream.
+/// The time until the response headers, or the error, is observed in the upstream response time histogram.
+/// When the upstream fails, or while its egress budget is spent or its circuit is open, the request is sent to its mirrors, if any
+#[tracing::instrument(name = "upstream_request", skip_all, fields(otel.kind = "client", upstream = upstream, kind = kind.as_str(),
+    http.method = %upstream_request.method(), http.url = %upstream_request.url(), http.status_code = tracing::field::Empty))]
+async fn execute_upstream(upstream: &str, kind: UpstreamRequestKind, mut upstream_request: reqwest::Request, state: &AppState) -> Result<reqwest::Response, UpstreamError> {
+    // Upstream continues the trace
+    telemetry::inject(upstream_request.headers_mut());
+
+    // Kept aside for the mirrors, unless its body is a stream which can only be sent once
+    let live = state.live();
+    let mirrors = live.upstreams.get(upstream).map(|config| config.mirrors.as_slice()).unwrap_or_default();
+    let mirror_request = if mirrors.is_empty() { None } else { upstream_request.try_clone() };
+
+    // The upstream spent its budget or keeps failing, its mirrors answer instead
+    if !state.egress_budgets.allow(upstream) || !state.circuit_breakers.allow(upstream) {
+        tracing::debug!("Upstream {} spent its egress budget or has an open circuit, skipping it for {} {}", upstream, upstream_request.method(), upstream_request.url().path());
+        let Some(mirror_request) = mirror_request else { return Err(UpstreamError::CircuitOpen) };
+        return match execute_mirrors(upstream, mirrors, mirror_request, state, &live).await {
+            Some(result) => Ok(result?),
+            None => Err(UpstreamError::CircuitOpen),
+        };
+    }
+
+    let started = Instant::now();
+    let result = live.clients.for_upstream(upstream).execute(upstream_request).await;
+    metrics::exemplars::observe(&metrics::UPSTREAM_RESPONSE_TIME, &[upstream, kind.as_str()], started.elapsed().as_secs_f64());
+    metrics::UPSTREAM_REQUESTS_TOTAL.with_label_values(&[upstream, upstream_result(&result)]).inc();
+    if let Ok(response) = &result {
+        tracing::Span::current().record("http.status_code", response.status().as_u16());
+    }
+    match &result {
+        Ok(response) if !response.status().is_server_error() => state.circuit_breakers.success(upstream),
+        _ => {
+            state.circuit_breakers.failure(upstream);
+            if let Some(mirror_request) = mirror_request {
+                return match execute_mirrors(upstream, mirrors, mirror_request, state, &live).await {
+                    Some(Ok(response)) if !response.status().is_server_error() => Ok(response),
+                    _ => Ok(result?),
+                };
+            }
+        }
+    }
+    Ok(result?)
+}
+
+/// Send the request of the failed upstream to its mirrors in order, anonymously, skipping the ones which spent their
+/// egress budget or whose circuit is open.
+/// Returns the first response which is not a server error, otherwise the outcome of the last mirror tried, if any
+async fn execute_mirrors(upstream: &str, mirrors: &[MirrorConfig], upstream_request: reqwest::Request, state: &AppState, live: &LiveConfig) -> Option<reqwest::Result<reqwest::Response>> {
+    // The mirrors serve the registry API without the path prefix of the upstream
+    let path = upstream_request.url().path();
+    let registry_path = live.upstreams.get(upstream).map_or(path, |config| config.registry_path(path));
+
+    let mut last = None;
+    for mirror in mirrors {
+        let base_url = mirror.base_url();
+        let Ok(mut mirror_url) = Url::parse(&base_url) else { continue };
+        mirror_url.set_path(registry_path);
+        mirror_url.set_query(upstream_request.url().query());
+
+        // The mirror spent its budget or keeps failing as well
+        if !state.egress_budgets.allow(&base_url) || !state.circuit_breakers.allow(&base_url) {
+            continue;
+        }
+
+        let mut mirror_request = upstream_request.try_clone()?;
+        *mirror_request.url_mut() = mirror_url;
+        mirror_request.headers_mut().remove(header::AUTHORIZATION);
+
+        match live.clients.for_mirrors(upstream).execute(mirror_request).await {
+            Ok(mut response) if !response.status().is_server_error() => {
+                tracing::info!("Upstream {} failed, {} {} answered by its mirror {}", upstream, upstream_request.method(), upstream_request.url().path(), mirror.registry);
+                state.circuit_breakers.success(&base_url);
+                response.extensions_mut().insert(AnsweringMirror(base_url));
+                return Some(Ok(response));
+            }
+            Ok(response) => {
+                tracing::warn!("Mirror {} of upstream {} answered with {}", mirror.registry, upstream, response.status());
+                state.circuit_breakers.failure(&base_url);
+                last = Some(Ok(response));
+            }
+            Err(e) => {
+                tracing::warn!("Mirror {} of upstream {} failed: {}", mirror.registry, upstream, e);
+                state.circuit_breakers.failure(&base_url);
+                last = Some(Err(e));
+            }
+        }
+    }
+    last
+}
+
+/// Run the handler within the overall deadline of the request, api->request_timeout_secs, if any.
+/// The deadline covers everything until the response starts: the upstream request as well as the disk and database IO.
+/// Once it elapsed the handler is dropped, together with the upstream request it is waiting for, and the outer error is a 504
+async fn within_deadline<F, T>(req: &HttpRequest, state: &AppState, handler: F) -> Result<Result<T, RegistryError>, RegistryError>
+    where F: Future<Output = Result<T, RegistryError>>
+{
+    let Some(timeout) = state.live().request_timeout else {
+        return Ok(handler.await);
+    };
+
+    tokio::time::timeout(timeout, handler).await.map_err(|_| {
+        metrics::REQUESTS_TIMED_OUT.inc();
+        tracing::warn!("{} {} did not get a response within {:?}", req.method(), req.uri(), timeout);
+        RegistryError::new(ErrorKind::GatewayTimeout).with_error(format!("no response within {:?}", timeout))
+    })
+}
+
+/// The cached content is stored as upstream sends it, so upstream must not apply any transport compression
+/// on top of the representation the digest refers to
+fn identity_encoding(upstream_request: &mut reqwest::Request) {
+    upstream_request.headers_mut().insert(reqwest::header::ACCEPT_ENCODING, reqwest::header::HeaderValue::from_static("identity"));
+}
+
+/// Copy the headers of the upstream response to the client response, except the framing ones and the ones
+/// the configuration does not relay, by default the hop-by-hop ones. The `Content-Length` is the one of the
+/// relayed body, see `relayed_body`
+fn relay_headers(client_resp: &mut HttpResponseBuilder, headers: &reqwest::header::HeaderMap, config: &ResponseHeadersConfig) {
+    for (header_name, header_value) in headers.iter().filter(|(h, _)| **h != header::CONNECTION && **h != header::TRANSFER_ENCODING && **h != header::CONTENT_LENGTH) {
+        if config.relays(header_name.as_str()) {
+            client_resp.insert_header((header_name.clone(), header_value.clone()));
+        }
+    }
+}
+
+/// The Content-Length declared by upstream
+fn content_length(headers: &reqwest::header::HeaderMap) -> Option<u64> {
+    headers.get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+/// The media type of the content, as sent by upstream, `application/octet-stream` when it is missing or malformed
+pub(crate) fn content_type(headers: &reqwest::header::HeaderMap) -> MimeType {
+    headers.get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .unwrap_or_default()
+}
+
+/// The response relaying the body of upstream, sized as upstream declared it. Actix then frames it:
+/// the Content-Length is sent as is, unless the Compress middleware re-encodes an identity body for the client,
+/// in which case it is streamed without one rather than with the size of the body before the compression
+fn relayed_body<S, E>(mut client_resp: HttpResponseBuilder, content_length: Option<u64>, stream: S) -> HttpResponse
+    where S: Stream<Item = Result<Bytes, E>> + 'static,
+          E: Into<Box<dyn std::error::Error>> + 'static
+{
+    match content_length {
+        Some(content_length) => client_resp.body(SizedStream::new(content_length, stream)),
+        None => client_resp.streaming(stream),
+    }
+}
+
+async fn validate_repository(repository_request: web::Path<RepositoryRequest>, state: &web::Data<AppState>) -> Result<Repository, RegistryError> {
     // parse the name from the request
     let repository = repository_request.into_inner();
 
     // validate the repository
-    let repository = repository.is_valid().await?;
+    let repository = repository.is_valid(&state.app_config.repository_names).await?;
+
+    telemetry::record_repository(&repository);
+
+    allowed_repository(&repository.name, state)?;
 
     Ok(repository)
-}
\ No newline at end of file
+}
+
+/// Only the allowed container images go through the cache, whether they are pulled or anything else is forwarded for them
+fn allowed_repository(name: &str, state: &AppState) -> Result<(), RegistryError> {
+    if !state.live().repository_policy.allows(name) {
+        tracing::warn!("Denied access to {} by the allowed/denied repositories", name);
+        return Err(RegistryError::new(ErrorKind::RegistryNameUnknown).with_error(format!("{} is not allowed", name)));
+    }
+    Ok(())
+}
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use actix_web::{test, web, App, HttpResponse};
+    use actix_web::http::StatusCode;
+    use bytes::Bytes;
+    use futures_util::StreamExt;
+    use tokio::io::AsyncReadExt;
+    use tokio::sync::oneshot;
+    use crate::api::registry::{api_version_headers, fan_out, FanOutEnd};
+    use crate::api::routes;
+    use crate::api::state::AppState;
+    use crate::config::app::AppConfig;
+    use crate::config::streaming::PersistChannel;
+    use crate::models::chunks::chunk_channel;
+
+    fn upstream(chunks: &'static [&'static str]) -> impl futures_util::Stream<Item = reqwest::Result<Bytes>> {
+        futures_util::stream::iter(chunks).map(|chunk| Ok(Bytes::from_static(chunk.as_bytes())))
+    }
+
+    #[tokio::test]
+    async fn fan_out_test() {
+        let timeout = Duration::from_millis(100);
+
+        // Both the client and the persistence get the whole content
+        let (persist_tx, mut persist_rx) = chunk_channel(&PersistChannel::Unbounded);
+        let (response_tx, mut response_rx) = tokio::io::duplex(64);
+        let (failed_tx, failed_rx) = oneshot::channel();
+        assert_eq!(FanOutEnd::Complete, fan_out("blob", upstream(&["first", "second"]), Some(persist_tx), response_tx, failed_tx, timeout, true).await);
+        let mut response = String::new();
+        response_rx.read_to_string(&mut response).await.unwrap();
+        assert_eq!("firstsecond", response);
+        assert_eq!(Some(Bytes::from_static(b"first")), persist_rx.recv().await);
+        assert_eq!(Some(Bytes::from_static(b"second")), persist_rx.recv().await);
+        assert!(!persist_rx.is_aborted());
+        assert!(failed_rx.await.is_err());
+
+        // The client went away, the content is not cached either
+        let (persist_tx, persist_rx) = chunk_channel(&PersistChannel::Unbounded);
+        let (response_tx, response_rx) = tokio::io::duplex(64);
+        drop(response_rx);
+        let (failed_tx, _failed_rx) = oneshot::channel();
+        assert_eq!(FanOutEnd::NoConsumer, fan_out("blob", upstream(&["first", "second"]), Some(persist_tx), response_tx, failed_tx, timeout, false).await);
+        assert!(persist_rx.is_aborted());
+
+        // Upstream stalls
+        let (persist_tx, persist_rx) = chunk_channel(&PersistChannel::Unbounded);
+        let (response_tx, _response_rx) = tokio::io::duplex(64);
+        let (failed_tx, failed_rx) = oneshot::channel();
+        let stalled = upstream(&["first"]).chain(futures_util::stream::pending());
+        assert_eq!(FanOutEnd::UpstreamFailed, fan_out("manifest", stalled, Some(persist_tx), response_tx, failed_tx, timeout, true).await);
+        assert!(persist_rx.is_aborted());
+        assert_eq!(std::io::ErrorKind::TimedOut, failed_rx.await.unwrap().kind());
+    }
+
+    #[actix_web::test]
+    async fn api_version_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let (state, _commands) = AppState::for_test(AppConfig::with_storage_folder(folder.path().to_str().unwrap())).await;
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(state))
+            .service(web::scope("/v2").wrap(api_version_headers())
+                .route("/upstream", web::get().to(|| async { HttpResponse::Ok().insert_header(("Docker-Distribution-API-Version", "registry/2.1")).finish() }))
+                .configure(routes::registry_api_config))).await;
+
+        // Error responses too, here for an unknown upstream
+        let req = test::TestRequest::get().uri("/v2/library/nginx/manifests/latest").insert_header(("Host", "unknown.local")).to_request();
+        let response = test::call_service(&app, req).await;
+        assert!(response.status().is_client_error());
+        assert_eq!("registry/2.0", response.headers().get("docker-distribution-api-version").unwrap());
+
+        // The one of upstream is kept
+        let response = test::call_service(&app, test::TestRequest::get().uri("/v2/upstream").to_request()).await;
+        assert_eq!("registry/2.1", response.headers().get("docker-distribution-api-version").unwrap());
+    }
+
+    #[actix_web::test]
+    async fn repository_names_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.repository_names.max_components = 2;
+        config.repository_names.max_component_length = 8;
+        let (state, _commands) = AppState::for_test(config).await;
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(state))
+            .service(web::scope("/v2").configure(routes::registry_api_config))).await;
+
+        for uri in ["/v2/mycorp/team/app/manifests/latest", "/v2/library/nginxnginx/manifests/latest", "/v2/mycorp/team/app/tags/list"] {
+            let response = test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(StatusCode::NOT_FOUND, response.status(), "{}", uri);
+            assert!(std::str::from_utf8(&test::read_body(response).await).unwrap().contains("NAME_INVALID"), "{}", uri);
+        }
+
+        // Nor are the requests forwarded upstream for them
+        let response = test::call_service(&app, test::TestRequest::delete().uri("/v2/mycorp/team/app/manifests/latest").to_request()).await;
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+        assert!(std::str::from_utf8(&test::read_body(response).await).unwrap().contains("NAME_INVALID"));
+
+        // Within the limits, there is no upstream for this host
+        let response = test::call_service(&app, test::TestRequest::get().uri("/v2/library/nginx/manifests/latest").to_request()).await;
+        assert!(!std::str::from_utf8(&test::read_body(response).await).unwrap().contains("NAME_INVALID"));
+    }
+}
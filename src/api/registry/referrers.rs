@@ -0,0 +1,128 @@
+// SPDX-License-Identifier: Apache-2.0
+use actix_web::{
+    http::Method, web, HttpRequest, HttpResponse
+};
+use actix_web::http::header;
+use futures_util::StreamExt as _;
+use serde::{Deserialize, Serialize};
+use crate::api::in_flight::UpstreamRequestGuard;
+use crate::api::registry::blobs::RepositoryRequest;
+use crate::api::registry::{build_upstream_req, content_length, execute_upstream, relay_headers, relayed_body, upstream_allowed, upstream_host, validate_repository, within_deadline, UpstreamRequestKind};
+use crate::api::state::AppState;
+use crate::error::error_kind::ErrorKind;
+use crate::error::registry::RegistryError;
+use crate::metrics;
+use crate::registry::manifest::{Descriptor, OCI_IMAGE_INDEX};
+use crate::registry::repository::Repository;
+
+
+/// Query parameters of the referrers API
+#[derive(Deserialize, Debug)]
+pub struct ReferrersQuery {
+    #[serde(default, rename = "artifactType")]
+    pub artifact_type: Option<String>,
+}
+
+/// Image index listing the referrers of a manifest
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ReferrersIndex {
+    schema_version: u32,
+    media_type: &'static str,
+    manifests: Vec<Descriptor>,
+}
+
+/// Handle the referrers requests: https://github.com/opencontainers/distribution-spec/blob/main/spec.md#listing-referrers
+/// The upstream is asked first, since it knows about referrers which were never pulled through the cache,
+/// if it is unreachable or does not support the API then the referrers indexed by the cache are returned
+pub async fn get_referrers(referrers_request: web::Path<RepositoryRequest>,
+                           query: web::Query<ReferrersQuery>,
+                           req: HttpRequest,
+                           method: Method,
+                           state: web::Data<AppState>) -> Result<HttpResponse, RegistryError> {
+
+    // Increase the requests counter
+    metrics::INCOMING_REQUESTS.inc();
+
+    // Get the repository from the request
+    let repository = validate_repository(referrers_request, &state).await?;
+
+    // The referrers are only listed for a digest
+    let subject = repository.digest.clone().ok_or_else(|| RegistryError::new(ErrorKind::RegistryDigestInvalid)
+        .with_error(format!("Referrers can only be listed for a digest: {}", repository.reference)))?;
+
+    // Unless the upstream keeps failing, or does not answer within the deadline of the request
+    if upstream_allowed(&req, &state) {
+        match within_deadline(&req, &state, upstream_referrers(&req, method, &repository, &state)).await {
+            Ok(Ok(Some(response))) => return Ok(response),
+            Ok(Ok(None)) => {}
+            Ok(Err(e)) => return Err(e),
+            Err(e) => log::warn!("Upstream referrers timed out, serving them from cache: {}", e),
+        }
+    }
+
+    // Serve the referrers indexed by the cache
+    let artifact_type = query.into_inner().artifact_type;
+    let referrers = state.manifests.referrers(&repository, &subject, artifact_type.as_deref()).await?;
+
+    let index = ReferrersIndex {
+        schema_version: 2,
+        media_type: OCI_IMAGE_INDEX,
+        manifests: referrers.into_iter().map(|referrer| referrer.descriptor()).collect(),
+    };
+
+    let mut client_resp = HttpResponse::Ok();
+    client_resp.insert_header((header::CONTENT_TYPE, OCI_IMAGE_INDEX));
+
+    // Tell the client the filter was applied, as per spec
+    if artifact_type.is_some() {
+        client_resp.insert_header(("OCI-Filters-Applied", "artifactType"));
+    }
+
+    metrics::CACHED_RESPONSES.inc();
+    metrics::RESPONSE_CODE_COLLECTOR.with_label_values(&["200", req.method().as_str(), &repository.name]).inc();
+
+    // Logging
+    log::info!("*** Cached: {} {}", req.method(), req.uri());
+
+    Ok(client_resp.json(index))
+}
+
+/// The referrers listed by upstream, none when upstream failed or does not support the API
+async fn upstream_referrers(req: &HttpRequest, method: Method, repository: &Repository, state: &web::Data<AppState>) -> Result<Option<HttpResponse>, RegistryError> {
+    // Build the upstream request
+    let upstream_request = build_upstream_req(req, method, state)?;
+    let upstream_request = upstream_request.build().map_err(|e| RegistryError::new(ErrorKind::NotFound).with_error(e.to_string()))?;
+
+    // Log the upstream request
+    log::info!("Upstream: {} {}", upstream_request.method(), upstream_request.url());
+
+    // Execute the request against the upstream
+    let upstream_guard = UpstreamRequestGuard::start();
+    match execute_upstream(&upstream_host(req), UpstreamRequestKind::Referrers, upstream_request, state).await {
+        Ok(upstream_response) if upstream_response.status().is_success() => {
+
+            // Build the response for the client
+            let mut client_resp = HttpResponse::build(upstream_response.status());
+            relay_headers(&mut client_resp, upstream_response.headers(), &state.app_config.response_headers);
+            let content_length = content_length(upstream_response.headers());
+
+            metrics::UPSTREAM_RESPONSES.inc();
+            metrics::RESPONSE_CODE_COLLECTOR.with_label_values(&[upstream_response.status().as_str(), req.method().as_ref(), &repository.name]).inc();
+
+            // Still in flight until the response is fully streamed
+            Ok(Some(relayed_body(client_resp, content_length, state.egress_budgets.counted(&upstream_host(req), upstream_response).map(move |chunk| {
+                let _upstream_guard = &upstream_guard;
+                chunk
+            }))))
+        }
+        Ok(upstream_response) => {
+            log::info!("Upstream referrers returned {}, serving them from cache", upstream_response.status());
+            Ok(None)
+        }
+        Err(e) => {
+            log::warn!("Upstream referrers failed, serving them from cache: {}", e.to_string());
+            Ok(None)
+        }
+    }
+}
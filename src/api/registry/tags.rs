@@ -0,0 +1,188 @@
+// SPDX-License-Identifier: Apache-2.0
+use actix_web::{
+    http::Method, web, HttpRequest, HttpResponse
+};
+use actix_web::http::header;
+use futures_util::StreamExt as _;
+use serde::{Deserialize, Serialize};
+use crate::api::in_flight::UpstreamRequestGuard;
+use crate::api::registry::blobs::RepositoryRequest;
+use crate::api::registry::{build_upstream_req, content_length, execute_upstream, relay_headers, relayed_body, upstream_allowed, upstream_host, validate_repository, within_deadline, UpstreamRequestKind};
+use crate::api::state::AppState;
+use crate::error::error_kind::ErrorKind;
+use crate::error::registry::RegistryError;
+use crate::metrics;
+use crate::registry::repository::Repository;
+
+
+/// Pagination of the tags list API
+#[derive(Deserialize, Debug)]
+pub struct TagsQuery {
+    /// At most this many tags
+    #[serde(default)]
+    pub n: Option<u32>,
+
+    /// The tags after this one
+    #[serde(default)]
+    pub last: Option<String>,
+}
+
+/// Tags of a container image
+#[derive(Serialize)]
+struct TagsList {
+    name: String,
+    tags: Vec<String>,
+}
+
+/// Handle the tags list requests: https://github.com/opencontainers/distribution-spec/blob/main/spec.md#listing-tags
+/// The upstream is asked first, since it knows about tags which were never pulled through the cache,
+/// if it is unreachable or failing then the tags indexed by the cache are returned, in lexicographic order
+pub async fn get_tags(tags_request: web::Path<RepositoryRequest>,
+                      query: web::Query<TagsQuery>,
+                      req: HttpRequest,
+                      method: Method,
+                      state: web::Data<AppState>) -> Result<HttpResponse, RegistryError> {
+
+    // Increase the requests counter
+    metrics::INCOMING_REQUESTS.inc();
+
+    // Get the repository from the request
+    let repository = validate_repository(tags_request, &state).await?;
+
+    // Unless the upstream keeps failing, or does not answer within the deadline of the request
+    if upstream_allowed(&req, &state) {
+        match within_deadline(&req, &state, upstream_tags(&req, method, &repository, &state)).await {
+            Ok(Ok(Some(response))) => return Ok(response),
+            Ok(Ok(None)) => {}
+            Ok(Err(e)) => return Err(e),
+            Err(e) => log::warn!("Upstream tags timed out, serving them from cache: {}", e),
+        }
+    }
+
+    // Serve the tags indexed by the cache
+    let TagsQuery { n, last } = query.into_inner();
+    let (tags, more) = state.manifests.tags_page(&upstream_host(&req), &repository, n, last.as_deref()).await?;
+
+    // Nothing cached for the container image at all
+    if tags.is_empty() && last.is_none() && n != Some(0) {
+        return Err(RegistryError::new(ErrorKind::RegistryNameUnknown).with_error(format!("No tags of {} are cached", repository.name)));
+    }
+
+    let mut client_resp = HttpResponse::Ok();
+
+    // Where the next page starts, as per spec
+    if let (true, Some(n), Some(last)) = (more, n, tags.last()) {
+        client_resp.insert_header((header::LINK, format!("<{}?n={}&last={}>; rel=\"next\"", req.path(), n, last)));
+    }
+
+    metrics::CACHED_RESPONSES.inc();
+    metrics::RESPONSE_CODE_COLLECTOR.with_label_values(&["200", req.method().as_str(), &repository.name]).inc();
+
+    // Logging
+    log::info!("*** Cached: {} {}", req.method(), req.uri());
+
+    Ok(client_resp.json(TagsList { name: repository.name, tags }))
+}
+
+/// The tags listed by upstream, none when upstream failed. Its client errors, e.g. asking for authentication, are relayed
+async fn upstream_tags(req: &HttpRequest, method: Method, repository: &Repository, state: &web::Data<AppState>) -> Result<Option<HttpResponse>, RegistryError> {
+    // Build the upstream request
+    let upstream_request = build_upstream_req(req, method, state)?;
+    let upstream_request = upstream_request.build().map_err(|e| RegistryError::new(ErrorKind::NotFound).with_error(e.to_string()))?;
+
+    // Log the upstream request
+    log::info!("Upstream: {} {}", upstream_request.method(), upstream_request.url());
+
+    // Execute the request against the upstream
+    let upstream_guard = UpstreamRequestGuard::start();
+    match execute_upstream(&upstream_host(req), UpstreamRequestKind::Tags, upstream_request, state).await {
+        Ok(upstream_response) if !upstream_response.status().is_server_error() => {
+
+            // Build the response for the client
+            let mut client_resp = HttpResponse::build(upstream_response.status());
+            relay_headers(&mut client_resp, upstream_response.headers(), &state.app_config.response_headers);
+            let content_length = content_length(upstream_response.headers());
+
+            metrics::UPSTREAM_RESPONSES.inc();
+            metrics::RESPONSE_CODE_COLLECTOR.with_label_values(&[upstream_response.status().as_str(), req.method().as_ref(), &repository.name]).inc();
+
+            // Still in flight until the response is fully streamed
+            Ok(Some(relayed_body(client_resp, content_length, state.egress_budgets.counted(&upstream_host(req), upstream_response).map(move |chunk| {
+                let _upstream_guard = &upstream_guard;
+                chunk
+            }))))
+        }
+        Ok(upstream_response) => {
+            log::info!("Upstream tags returned {}, serving them from cache", upstream_response.status());
+            Ok(None)
+        }
+        Err(e) => {
+            log::warn!("Upstream tags failed, serving them from cache: {}", e.to_string());
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::{test, web, App};
+    use actix_web::http::{header, StatusCode};
+    use crate::api::routes;
+    use crate::api::state::AppState;
+    use crate::config::app::{AppConfig, UpstreamConfig};
+    use crate::registry::digest::Digest;
+    use crate::registry::repository::Repository;
+
+    const DIGEST: &str = "sha256:c1d07892979445e720a5cf1f5abe6a910f45c6d638bf9997d6a807924eee5190";
+    const MIME: &str = "application/vnd.oci.image.manifest.v1+json";
+
+    #[actix_web::test]
+    async fn cached_tags_test() {
+        // Nothing listens on the port, the upstream is unreachable
+        let address = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+
+        let folder = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.upstreams.push(UpstreamConfig::for_test("localhost", &format!("http://{}", address)));
+        let (state, _commands) = AppState::for_test(config).await;
+        for tag in ["v1", "v2", "v3", DIGEST] {
+            let repository = Repository::new_with_reference("library/nginx", tag).unwrap();
+            state.manifests.persist("localhost", &repository, Digest::parse(DIGEST).unwrap(), 400, &MIME.parse().unwrap(), None).await.unwrap();
+        }
+
+        let app = test::init_service(App::new()
+            .app_data(web::Data::new(state))
+            .service(web::scope("/v2").configure(routes::registry_api_config))).await;
+        let tags = |uri: &str| test::TestRequest::get().uri(uri).insert_header((header::HOST, "localhost")).to_request();
+
+        // Every tag, without the digests
+        let resp = test::call_service(&app, tags("/v2/library/nginx/tags/list")).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert!(resp.headers().get(header::LINK).is_none());
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(serde_json::json!({"name": "library/nginx", "tags": ["v1", "v2", "v3"]}), body);
+
+        // Paginated, with the next page linked
+        let resp = test::call_service(&app, tags("/v2/library/nginx/tags/list?n=2")).await;
+        assert_eq!("</v2/library/nginx/tags/list?n=2&last=v2>; rel=\"next\"", resp.headers().get(header::LINK).unwrap());
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(serde_json::json!(["v1", "v2"]), body["tags"]);
+
+        let resp = test::call_service(&app, tags("/v2/library/nginx/tags/list?n=2&last=v2")).await;
+        assert!(resp.headers().get(header::LINK).is_none());
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(serde_json::json!(["v3"]), body["tags"]);
+
+        // No tag asked for, or past the end
+        for uri in ["/v2/library/nginx/tags/list?n=0", "/v2/library/nginx/tags/list?last=v9"] {
+            let resp = test::call_service(&app, tags(uri)).await;
+            assert_eq!(StatusCode::OK, resp.status());
+            let body: serde_json::Value = test::read_body_json(resp).await;
+            assert_eq!(serde_json::json!([]), body["tags"], "{}", uri);
+        }
+
+        // Never pulled through the cache
+        let resp = test::call_service(&app, tags("/v2/library/redis/tags/list")).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+    }
+}
@@ -0,0 +1,79 @@
+// SPDX-License-Identifier: Apache-2.0
+use regex::Regex;
+
+/// Which container image names can be pulled through the cache
+pub struct RepositoryPolicy {
+    /// Everything is allowed when empty
+    allowed: Vec<Regex>,
+    denied: Vec<Regex>,
+}
+
+impl RepositoryPolicy {
+    pub fn new(allowed: &[String], denied: &[String]) -> Self {
+        RepositoryPolicy {
+            allowed: allowed.iter().map(|pattern| glob_regex(pattern)).collect(),
+            denied: denied.iter().map(|pattern| glob_regex(pattern)).collect(),
+        }
+    }
+
+    /// Whether the name matches an allowed pattern, if any, and none of the denied ones
+    pub fn allows(&self, name: &str) -> bool {
+        (self.allowed.is_empty() || self.allowed.iter().any(|allowed| allowed.is_match(name)))
+            && !self.denied.iter().any(|denied| denied.is_match(name))
+    }
+}
+
+/// Translate a glob into an anchored regex: `*` and `?` match within a single path component,
+/// `**` matches any number of them
+fn glob_regex(pattern: &str) -> Regex {
+    let mut regex = String::from("^");
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    regex.push('$');
+
+    Regex::new(&regex).expect("A glob translates into a valid regex")
+}
+
+#[cfg(test)]
+mod test {
+    use crate::api::repository_policy::RepositoryPolicy;
+
+    fn patterns(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|pattern| pattern.to_string()).collect()
+    }
+
+    #[test]
+    fn repository_policy_test() {
+        // Everything is allowed by default
+        assert!(RepositoryPolicy::new(&[], &[]).allows("anything/goes"));
+
+        let policy = RepositoryPolicy::new(&patterns(&["library/*", "mycorp/**"]), &patterns(&["mycorp/internal/**", "library/ng?nx"]));
+
+        assert!(policy.allows("library/debian"));
+        assert!(policy.allows("mycorp/base"));
+        assert!(policy.allows("mycorp/team/app"));
+
+        // `*` does not cross path components
+        assert!(!policy.allows("library/debian/slim"));
+        assert!(!policy.allows("other/debian"));
+
+        // Denied wins over allowed
+        assert!(!policy.allows("library/nginx"));
+        assert!(!policy.allows("mycorp/internal/secret"));
+
+        // The other regex characters are literals
+        let policy = RepositoryPolicy::new(&patterns(&["my.corp/*"]), &[]);
+        assert!(policy.allows("my.corp/app"));
+        assert!(!policy.allows("myxcorp/app"));
+    }
+}
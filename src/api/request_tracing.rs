@@ -0,0 +1,136 @@
+// SPDX-License-Identifier: Apache-2.0
+use std::future::{ready, Ready};
+use actix_web::body::MessageBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header;
+use futures_util::future::LocalBoxFuture;
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use crate::telemetry;
+
+/// Traces every request in its own span, continuing the trace of the client `traceparent` if any.
+/// The span is named after the route pattern the request matches, not its path, which holds the repository names and the digests.
+/// The upstream requests, the persistence and the streaming of the response are its child spans
+#[derive(Clone, Default)]
+pub struct RequestTracing;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestTracing
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+        B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = RequestTracingMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestTracingMiddleware { service }))
+    }
+}
+
+pub struct RequestTracingMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestTracingMiddleware<S>
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+        B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let host = req.headers().get(header::HOST).and_then(|host| host.to_str().ok()).unwrap_or("");
+        let name = match req.match_pattern() {
+            Some(pattern) => format!("{} {}", req.method(), pattern),
+            None => req.method().to_string(),
+        };
+        let span = tracing::info_span!("request",
+            otel.name = %name,
+            otel.kind = "server",
+            http.method = %req.method(),
+            http.target = %req.uri(),
+            http.host = host,
+            http.status_code = tracing::field::Empty,
+            repository = tracing::field::Empty,
+            digest = tracing::field::Empty,
+        );
+        span.set_parent(telemetry::extract(req.headers()));
+
+        let response = span.in_scope(|| self.service.call(req));
+
+        Box::pin(async move {
+            let response = response.await?;
+            tracing::Span::current().record("http.status_code", response.status().as_u16());
+            Ok(response)
+        }.instrument(span))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use actix_web::{test, web, App, HttpResponse};
+    use parking_lot::Mutex;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::Subscriber;
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::Layer;
+    use crate::api::request_tracing::RequestTracing;
+    use crate::registry::repository::Repository;
+    use crate::telemetry;
+
+    /// Collects the fields of the spans
+    #[derive(Clone, Default)]
+    struct Fields(Arc<Mutex<Vec<(String, String)>>>);
+
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0.lock().push((field.name().to_string(), format!("{:?}", value)));
+        }
+    }
+
+    impl<S: Subscriber> Layer<S> for Fields {
+        fn on_new_span(&self, attributes: &Attributes<'_>, _: &Id, _: Context<'_, S>) {
+            attributes.record(&mut self.clone());
+        }
+
+        fn on_record(&self, _: &Id, values: &Record<'_>, _: Context<'_, S>) {
+            values.record(&mut self.clone());
+        }
+    }
+
+    #[actix_web::test]
+    async fn request_tracing_test() {
+        let fields = Fields::default();
+        let _subscriber = tracing::subscriber::set_default(tracing_subscriber::registry().with(fields.clone()));
+
+        let app = test::init_service(App::new()
+            .service(web::scope("/v2").wrap(RequestTracing)
+                .route("/{name:.+}/manifests/{reference}", web::get().to(|| async {
+                    telemetry::record_repository(&Repository::new_with_reference("library/nginx", "latest").unwrap());
+                    HttpResponse::NotFound().finish()
+                })))).await;
+
+        let req = test::TestRequest::get().uri("/v2/library/nginx/manifests/latest").to_request();
+        test::call_service(&app, req).await;
+
+        // Named after the route, whatever the repository
+        let req = test::TestRequest::get().uri("/v2/library/debian/manifests/stable").to_request();
+        test::call_service(&app, req).await;
+
+        let fields = fields.0.lock();
+        let names = fields.iter().filter(|(name, _)| name == "otel.name").map(|(_, value)| value.as_str()).collect::<Vec<_>>();
+        assert_eq!(vec!["GET /v2/{name:.+}/manifests/{reference}"; 2], names);
+        assert!(fields.contains(&("repository".to_string(), "\"library/nginx\"".to_string())));
+        assert!(fields.contains(&("http.status_code".to_string(), "404".to_string())));
+        assert!(!fields.iter().any(|(name, _)| name == "digest"));
+    }
+}
@@ -1,8 +1,12 @@
 // SPDX-License-Identifier: Apache-2.0
 use actix_web::web;
+use crate::api::admin::{dead_letters, purge_repository, repository_tags, stats, summary, upstreams, verify_cache};
+use crate::api::registry::blob_encoding_headers;
 use crate::api::registry::blobs::cache;
-use crate::api::registry::forward::forward;
+use crate::api::registry::forward::{blob_uploads, forward};
 use crate::api::registry::manifests::get_manifests;
+use crate::api::registry::referrers::get_referrers;
+use crate::api::registry::tags::get_tags;
 
 pub fn registry_api_config(cfg: &mut web::ServiceConfig) {
     // ---------------------------------------------------------------------------------------------
@@ -12,18 +16,116 @@ pub fn registry_api_config(cfg: &mut web::ServiceConfig) {
         web::resource("/{name:((?:[^/]*/)*)(.*)}/manifests/{reference}")
             // MAYBE AUTH: get a manifest
             .route(web::get().to(get_manifests))
+
+            // check the existence of a manifest
+            .route(web::head().to(get_manifests))
+
+            // pushes are rejected, unless forwarded
+            .default_service(web::to(forward))
+    );
+    // ---------------------------------------------------------------------------------------------
+    // Referrers
+    // Get
+    cfg.service(
+        web::resource("/{name:((?:[^/]*/)*)(.*)}/referrers/{reference}")
+            // list the manifests referring to a digest
+            .route(web::get().to(get_referrers))
+    );
+    // ---------------------------------------------------------------------------------------------
+    // Tags
+    // Get
+    cfg.service(
+        web::resource("/{name:((?:[^/]*/)*)(.*)}/tags/list")
+            // list the tags of a container image
+            .route(web::get().to(get_tags))
+
+            // anything else is up to upstream
+            .default_service(web::to(forward))
+    );
+    // ---------------------------------------------------------------------------------------------
+    // Blob uploads
+    // Any method
+    cfg.service(
+        web::resource("/{name:((?:[^/]*/)*)(.*)}/blobs/uploads/{session:.*}")
+            // pushes are rejected, unless forwarded
+            .route(web::route().to(blob_uploads))
     );
     // ---------------------------------------------------------------------------------------------
     // BLOBS
     // Get
     cfg.service(
         web::resource("/{name:((?:[^/]*/)*)(.*)}/blobs/{reference}")
+            // never compressed again by the cache
+            .wrap(blob_encoding_headers())
+
             // retrieve a blob -
             .route(web::get().to(cache))
 
             // check the existence of a blob -
             .route(web::head().to(cache))
 
+            // pushes are rejected, unless forwarded
+            .default_service(web::to(forward))
+
         // Forward everything else
     ).default_service(web::to(forward));
-}
\ No newline at end of file
+}
+
+pub fn admin_api_config(cfg: &mut web::ServiceConfig) {
+    // ---------------------------------------------------------------------------------------------
+    // Cache
+    // Delete
+    cfg.service(
+        web::resource("/cache/{name:.+}")
+            // purge every tag and blob of a container image
+            .route(web::delete().to(purge_repository))
+    );
+    // ---------------------------------------------------------------------------------------------
+    // Repositories
+    // Get
+    cfg.service(
+        web::resource("/repositories/{name:.+}")
+            // list the cached tags of a container image
+            .route(web::get().to(repository_tags))
+    );
+    // ---------------------------------------------------------------------------------------------
+    // Verify
+    // Post
+    cfg.service(
+        web::resource("/verify")
+            // recompute the digest of the stored blobs, optionally removing the corrupted ones
+            .route(web::post().to(verify_cache))
+    );
+    // ---------------------------------------------------------------------------------------------
+    // Dead letters
+    // Get
+    cfg.service(
+        web::resource("/dead-letters")
+            // list the failed persistences
+            .route(web::get().to(dead_letters))
+    );
+    // ---------------------------------------------------------------------------------------------
+    // Stats
+    // Get
+    cfg.service(
+        web::resource("/stats")
+            // list the most pulled container images
+            .route(web::get().to(stats))
+    );
+    // ---------------------------------------------------------------------------------------------
+    // Summary
+    // Get
+    cfg.service(
+        web::resource("/summary")
+            // count what the cache holds
+            .route(web::get().to(summary))
+    );
+    // ---------------------------------------------------------------------------------------------
+    // Upstreams
+    // Get
+    cfg.service(
+        web::resource("/upstreams")
+            // list where the requests of each host are sent
+            .route(web::get().to(upstreams))
+    );
+}
@@ -0,0 +1,72 @@
+// SPDX-License-Identifier: Apache-2.0
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::Arc;
+use parking_lot::Mutex;
+use tokio::sync::OnceCell;
+
+/// Coalesces the concurrent calls with the same key: only the first one is executed,
+/// the other ones wait for it and get a copy of its result
+pub struct SingleFlight<K, V> {
+    flights: Mutex<HashMap<K, Arc<OnceCell<V>>>>,
+}
+
+impl<K, V> Default for SingleFlight<K, V> {
+    fn default() -> Self {
+        SingleFlight {
+            flights: Default::default(),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> SingleFlight<K, V> {
+
+    /// Run `call`, unless a call with the same key is already in flight.
+    /// If the caller executing it goes away, e.g. the client disconnected, one of the waiting ones takes over
+    pub async fn run<F, Fut>(&self, key: K, call: F) -> V
+        where
+            F: FnOnce() -> Fut,
+            Fut: Future<Output = V>,
+    {
+        let flight = self.flights.lock().entry(key.clone()).or_default().clone();
+        let value = flight.get_or_init(call).await.clone();
+
+        // The calls from now on start a new flight
+        let mut flights = self.flights.lock();
+        if flights.get(&key).is_some_and(|current| Arc::ptr_eq(current, &flight)) {
+            flights.remove(&key);
+        }
+
+        value
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use crate::api::single_flight::SingleFlight;
+
+    #[tokio::test]
+    async fn single_flight_test() {
+        let flights = SingleFlight::default();
+        let calls = AtomicUsize::new(0);
+
+        let call = || async {
+            calls.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            calls.load(Ordering::Relaxed)
+        };
+
+        // Concurrent calls with the same key share the result
+        let (a, b, c) = tokio::join!(flights.run("latest", call), flights.run("latest", call), flights.run("other", call));
+        assert_eq!(a, b);
+        assert_eq!(2, calls.load(Ordering::Relaxed));
+        assert!(c > 0);
+
+        // Once done, the next call starts a new flight
+        flights.run("latest", call).await;
+        assert_eq!(3, calls.load(Ordering::Relaxed));
+    }
+}
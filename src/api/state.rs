@@ -1,30 +1,126 @@
 // SPDX-License-Identifier: Apache-2.0
-use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::PathBuf;
 use std::sync::Arc;
-use crate::config::app::{AppConfig, UpstreamConfig};
+use std::sync::atomic::AtomicU64;
+use arc_swap::ArcSwap;
+use parking_lot::Mutex;
+use crate::api::circuit_breaker::CircuitBreakers;
+use crate::api::egress_budget::EgressBudgets;
+use crate::api::in_flight::InFlightLimiter;
+use crate::api::live_config::LiveConfig;
+use crate::api::negative_cache::NegativeCache;
+use crate::api::pull_stats::PullStats;
+use crate::api::readiness::Readiness;
+use crate::api::registry::manifests::{FetchedManifest, ManifestFlightKey};
+use crate::api::single_flight::SingleFlight;
+use crate::api::upstream_clients::UpstreamClients;
+use crate::config::app::AppConfig;
+use crate::error::registry::RegistryError;
 use crate::handlers::command::blob::service::ManifestService;
+use crate::priming::Primer;
 use crate::pubsub::command_bus::CommandBus;
+use crate::registry::manifest::Platform;
 use crate::repository::filesystem::FilesystemStorage;
 
 #[derive(Clone)]
 pub struct AppState {
-    pub client: reqwest::Client,
     pub command_bus: Arc<CommandBus>,
+
+    /// The config the cache started with, see `live` for the parts reloaded since
     pub app_config: AppConfig,
-    pub storage: FilesystemStorage,
-    pub upstreams: HashMap<String, UpstreamConfig>,
-    pub manifests: Arc<ManifestService>
+    pub storage: Arc<FilesystemStorage>,
+    pub manifests: Arc<ManifestService>,
+
+    /// Upstreams, timeouts and allowed/denied repositories, swapped when config.yaml is reloaded
+    pub live: Arc<ArcSwap<LiveConfig>>,
+
+    /// Only set when a priming platform is configured
+    pub primer: Option<Primer>,
+
+    /// Blobs being cached in the background after a Range request missed the cache
+    pub background_fetches: Arc<Mutex<HashSet<PathBuf>>>,
+
+    /// Shared by all the upstreams
+    pub in_flight: Arc<InFlightLimiter>,
+
+    /// Manifest pulls being coalesced
+    pub manifest_flights: Arc<SingleFlight<ManifestFlightKey, Result<FetchedManifest, RegistryError>>>,
+
+    /// Reported by /readyz
+    pub readiness: Arc<Readiness>,
+
+    /// Upstreams skipped while they keep failing
+    pub circuit_breakers: Arc<CircuitBreakers>,
+
+    /// Blobs served from the cache, 1 in `storage.verify_on_read` is verified
+    pub blob_reads: Arc<AtomicU64>,
+
+    /// Blobs and manifests upstream recently did not have
+    pub negative_cache: Arc<NegativeCache>,
+
+    /// Bytes fetched from each upstream, which is not called anymore once its budget is spent
+    pub egress_budgets: Arc<EgressBudgets>,
+
+    /// Pulls of the container images, written to the database in batches
+    pub pull_stats: Arc<PullStats>,
 }
 
 impl AppState {
-    pub fn new(client: reqwest::Client, command_bus: Arc<CommandBus>, app_config: AppConfig, storage: FilesystemStorage, manifests: Arc<ManifestService>) -> Self {
+    pub fn new(clients: UpstreamClients, command_bus: Arc<CommandBus>, app_config: AppConfig, storage: Arc<FilesystemStorage>, manifests: Arc<ManifestService>) -> Self {
+        let egress_budgets = Arc::new(EgressBudgets::new(&app_config.egress_budget));
+        let primer = app_config.priming.platform.as_deref().and_then(Platform::parse)
+            .map(|platform| Primer::new(clients.clone(), command_bus.clone(), storage.clone(), platform,
+                                        app_config.streaming.persist_channel.clone(), egress_budgets.clone()));
+
+        let in_flight = Arc::new(InFlightLimiter::new(&app_config.streaming));
+        let live = Arc::new(ArcSwap::from_pointee(LiveConfig::new(&app_config)));
+        let circuit_breakers = Arc::new(CircuitBreakers::new(&app_config.circuit_breaker));
+        let negative_cache = Arc::new(NegativeCache::new(&app_config.negative_cache));
+        let pull_stats = Arc::new(PullStats::new(manifests.clone(), &app_config.pull_stats));
+
         AppState {
-            client,
+            primer,
+            in_flight,
             command_bus,
-            upstreams: app_config.upstreams(),
+            live,
             app_config,
             storage,
-            manifests
+            manifests,
+            background_fetches: Default::default(),
+            manifest_flights: Default::default(),
+            readiness: Default::default(),
+            circuit_breakers,
+            blob_reads: Default::default(),
+            negative_cache,
+            egress_budgets,
+            pull_stats,
         }
     }
-}
\ No newline at end of file
+
+    /// The live parts of the config, as of now
+    pub fn live(&self) -> Arc<LiveConfig> {
+        self.live.load_full()
+    }
+}
+#[cfg(test)]
+impl AppState {
+    /// State for the handler tests, backed by an in-memory database.
+    /// The commands published on the bus are queued in the returned receiver.
+    pub async fn for_test(app_config: AppConfig) -> (AppState, tokio::sync::mpsc::Receiver<crate::models::commands::RegistryCommand>) {
+        let pool = crate::db::pool::DBPool::default().await;
+        crate::db::db_manifests::DBManifests::create_table(&pool).await;
+        crate::db::db_referrers::DBReferrers::create_table(&pool).await;
+        crate::db::db_dead_letters::DBDeadLetters::create_table(&pool).await;
+        crate::db::db_contents::DBContents::create_table(&pool).await;
+        crate::db::db_blob_refs::DBBlobRefs::create_table(&pool).await;
+        crate::db::db_pulls::DBPulls::create_table(&pool).await;
+        crate::db::db_blob_types::DBBlobTypes::create_table(&pool).await;
+
+        let (command_sender, command_receiver) = tokio::sync::mpsc::channel(16);
+        let storage = Arc::new(FilesystemStorage::new(app_config.clone()));
+        let state = AppState::new(UpstreamClients::new(&app_config.upstreams), CommandBus::new(command_sender, 16, Default::default()), app_config, storage,
+                                  ManifestService::from_pool(pool));
+        (state, command_receiver)
+    }
+}
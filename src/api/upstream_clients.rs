@@ -0,0 +1,168 @@
+// SPDX-License-Identifier: Apache-2.0
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use reqwest::ClientBuilder;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use crate::config::app::{HttpVersion, UpstreamConfig};
+use crate::models::types::UpstreamHost;
+
+/// Http clients for the upstream requests: a shared one, and one for each upstream speaking a specific HTTP version,
+/// resolving some hostnames to a specific IP address or authenticating with its own credentials.
+/// The mirrors of an upstream with credentials get a client of their own, which does not send them
+#[derive(Clone)]
+pub struct UpstreamClients {
+    shared: reqwest::Client,
+    upstreams: HashMap<UpstreamHost, reqwest::Client>,
+    mirrors: HashMap<UpstreamHost, reqwest::Client>,
+}
+
+impl UpstreamClients {
+
+    /// New instance of the UpstreamClients for the configured upstreams
+    pub fn new(upstreams: &[UpstreamConfig]) -> Self {
+        UpstreamClients {
+            shared: upstream_client(&HttpVersion::Auto, &HashMap::new(), None),
+            upstreams: upstreams.iter()
+                .filter(|upstream| upstream.http_version != HttpVersion::Auto || !upstream.resolve.is_empty() || upstream.credentials.is_some())
+                .map(|upstream| (upstream.host.clone(), upstream_client(&upstream.http_version, &upstream.resolve, authorization(upstream))))
+                .collect(),
+            mirrors: upstreams.iter()
+                .filter(|upstream| !upstream.mirrors.is_empty() && upstream.credentials.is_some())
+                .map(|upstream| (upstream.host.clone(), upstream_client(&upstream.http_version, &upstream.resolve, None)))
+                .collect(),
+        }
+    }
+
+    /// The client for the requests to the upstream of the host
+    pub fn for_upstream(&self, host: &str) -> &reqwest::Client {
+        self.upstreams.get(host).unwrap_or(&self.shared)
+    }
+
+    /// The client for the requests to the mirrors of the upstream of the host
+    pub fn for_mirrors(&self, host: &str) -> &reqwest::Client {
+        self.mirrors.get(host).unwrap_or_else(|| self.for_upstream(host))
+    }
+}
+
+/// The same client for all the upstreams
+impl From<reqwest::Client> for UpstreamClients {
+    fn from(client: reqwest::Client) -> Self {
+        UpstreamClients {
+            shared: client,
+            upstreams: HashMap::new(),
+            mirrors: HashMap::new(),
+        }
+    }
+}
+
+/// The Authorization header of the requests to the upstream, for the upstreams with their own credentials.
+/// Their secrets are read here, every time the clients are built. The config validation rejects the secrets
+/// which can't be read, one which can't be anymore, e.g. its file was removed in the meantime, is logged:
+/// the requests to the upstream are then sent without credentials
+fn authorization(upstream: &UpstreamConfig) -> Option<HeaderValue> {
+    let authorization = match upstream.credentials.as_ref()?.authorization() {
+        Ok(authorization) => authorization,
+        Err(e) => {
+            tracing::error!("Failed to read the credentials of upstream {}, its requests are sent without them: they {}", upstream.host, e);
+            return None;
+        }
+    };
+
+    let mut value = HeaderValue::from_str(&authorization)
+        .map_err(|e| tracing::error!("Credentials of upstream {} are not a valid header, its requests are sent without them: {}", upstream.host, e))
+        .ok()?;
+
+    // Neither logged nor sent along a redirect to another host, e.g. the storage of the blobs
+    value.set_sensitive(true);
+    Some(value)
+}
+
+/// Http client for the upstream requests
+fn upstream_client(http_version: &HttpVersion, resolve: &HashMap<String, IpAddr>, authorization: Option<HeaderValue>) -> reqwest::Client {
+    // TODO: 1. expose the timeout settings to the config
+    // TODO: 2. expose the possibility to skip TLS verification
+    // TODO: 3. allow to pass a proxy configuration
+    let mut builder = ClientBuilder::new()
+        .timeout(Duration::from_secs(15))
+        .connect_timeout(Duration::from_secs(5))
+        .tcp_nodelay(true)
+        // The bytes are relayed and stored exactly as upstream sends them,
+        // a decoded body would not match the digest and the Content-Encoding relayed to the client
+        .no_gzip()
+        .no_brotli()
+        .no_deflate();
+
+    // The port is the one of the URL, the one of the address is ignored
+    for (hostname, ip) in resolve {
+        builder = builder.resolve(hostname, SocketAddr::new(*ip, 0));
+    }
+
+    if let Some(authorization) = authorization {
+        builder = builder.default_headers(HeaderMap::from_iter([(AUTHORIZATION, authorization)]));
+    }
+
+    let builder = match http_version {
+        HttpVersion::Auto => builder,
+        HttpVersion::Http1 => builder.http1_only(),
+        HttpVersion::Http2 => builder.http2_prior_knowledge(),
+    };
+
+    builder.build().expect("Failed to create upstream http client")
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::{web, App, HttpRequest, HttpServer};
+    use crate::api::upstream_clients::UpstreamClients;
+    use crate::config::app::{HttpVersion, UpstreamConfig};
+
+    fn upstream_config(host: &str, http_version: HttpVersion) -> UpstreamConfig {
+        UpstreamConfig { http_version, ..UpstreamConfig::for_test(host, "http://127.0.0.1:443") }
+    }
+
+    #[actix_web::test]
+    async fn http_version_test() {
+        // Upstream speaking both HTTP/1.1 and cleartext HTTP/2, answering with the version of the request
+        let server = HttpServer::new(|| App::new().default_service(web::to(|req: HttpRequest| async move { format!("{:?}", req.version()) })))
+            .bind_auto_h2c(("127.0.0.1", 0)).unwrap();
+        let url = format!("http://{}/v2/", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+
+        let clients = UpstreamClients::new(&[
+            upstream_config("h2c.local", HttpVersion::Http2),
+            upstream_config("http1.local", HttpVersion::Http1),
+            upstream_config("auto.local", HttpVersion::Auto),
+        ]);
+
+        let version = |host: &'static str| {
+            let request = clients.for_upstream(host).get(&url);
+            async move { request.send().await.unwrap().text().await.unwrap() }
+        };
+
+        assert_eq!("HTTP/2.0", version("h2c.local").await);
+        assert_eq!("HTTP/1.1", version("http1.local").await);
+
+        // Nothing to negotiate HTTP/2 with over cleartext
+        assert_eq!("HTTP/1.1", version("auto.local").await);
+        assert_eq!("HTTP/1.1", version("unknown.local").await);
+    }
+
+    #[actix_web::test]
+    async fn resolve_test() {
+        let server = HttpServer::new(|| App::new().default_service(web::to(|| async { "mirror" })))
+            .bind(("127.0.0.1", 0)).unwrap();
+        let port = server.addrs()[0].port();
+        actix_web::rt::spawn(server.run());
+
+        let mut pinned = upstream_config("pinned.local", HttpVersion::Auto);
+        pinned.resolve.insert("registry.invalid".to_string(), "127.0.0.1".parse().unwrap());
+        let clients = UpstreamClients::new(&[pinned]);
+
+        // The hostname does not exist in the DNS
+        let url = format!("http://registry.invalid:{}/v2/", port);
+        let response = clients.for_upstream("pinned.local").get(&url).send().await.unwrap();
+        assert_eq!("mirror", response.text().await.unwrap());
+        assert!(clients.for_upstream("other.local").get(&url).send().await.is_err());
+    }
+}
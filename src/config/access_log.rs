@@ -0,0 +1,31 @@
+// SPDX-License-Identifier: Apache-2.0
+use serde::{Deserialize, Serialize};
+
+/// Sampling of the access logs of an upstream
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AccessLogConfig {
+    /// Log 1 in `sample_rate` requests, 1 logs every request
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: u64,
+
+    /// Log every error response regardless of the sampling
+    #[serde(default = "default_always_log_errors")]
+    pub always_log_errors: bool,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        AccessLogConfig {
+            sample_rate: default_sample_rate(),
+            always_log_errors: default_always_log_errors(),
+        }
+    }
+}
+
+fn default_sample_rate() -> u64 {
+    1
+}
+
+fn default_always_log_errors() -> bool {
+    true
+}
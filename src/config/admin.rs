@@ -0,0 +1,18 @@
+// SPDX-License-Identifier: Apache-2.0
+use serde::{Deserialize, Serialize};
+
+/// Admin API, served under `/admin`
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AdminConfig {
+    /// Bearer token the admin requests have to present, the admin API is disabled when not set
+    pub token: Option<String>,
+
+    /// Whether the admin API can remove content from the cache
+    #[serde(default)]
+    pub allow_delete: bool,
+
+    /// Whether a registry request presenting the token in the X-Admin-Token header can set
+    /// the timeout of its upstream request via the X-Upstream-Timeout-Ms header, to diagnose a slow upstream
+    #[serde(default)]
+    pub allow_upstream_timeout: bool,
+}
@@ -1,10 +1,31 @@
 // SPDX-License-Identifier: Apache-2.0
 use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Component, Path};
+use actix_web::http::header::HeaderName;
 use config::{Config, File};
 use serde::{Deserialize, Serialize};
+use crate::config::access_log::AccessLogConfig;
+use crate::config::admin::AdminConfig;
+use crate::config::auth::AuthConfig;
+use crate::config::circuit_breaker::CircuitBreakerConfig;
+use crate::config::credentials::CredentialsConfig;
+use crate::config::dead_letters::DeadLettersConfig;
 use crate::config::db::DBConfig;
-use crate::error::error_kind::ErrorKind;
+use crate::config::driver::StorageDriver;
+use crate::config::egress_budget::EgressBudgetConfig;
+use crate::config::eviction::EvictionConfig;
+use crate::config::negative_cache::NegativeCacheConfig;
+use crate::config::pull_stats::PullStatsConfig;
+use crate::config::priming::PrimingConfig;
+use crate::config::readiness::ReadinessConfig;
+use crate::config::repository_names::RepositoryNamesConfig;
+use crate::config::response_headers::ResponseHeadersConfig;
+use crate::config::streaming::{PersistChannel, StreamingConfig};
+use crate::config::telemetry::TelemetryConfig;
+use crate::config::tls::{private_keys, TlsCertificateConfig};
 use crate::error::registry::RegistryError;
+use crate::registry::manifest::Platform;
 
 const CONFIG_FILE_NAME:&str = "config.yaml";
 
@@ -17,11 +38,74 @@ pub struct AppConfig {
 
     #[serde(default)]
     pub db: DBConfig,
+
+    #[serde(default)]
+    pub eviction: EvictionConfig,
+
+    #[serde(default)]
+    pub streaming: StreamingConfig,
+
+    #[serde(default)]
+    pub priming: PrimingConfig,
+
+    #[serde(default)]
+    pub admin: AdminConfig,
+
+    #[serde(default)]
+    pub auth: AuthConfig,
+
+    #[serde(default)]
+    pub dead_letters: DeadLettersConfig,
+
+    #[serde(default)]
+    pub readiness: ReadinessConfig,
+
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+
+    #[serde(default)]
+    pub negative_cache: NegativeCacheConfig,
+
+    #[serde(default)]
+    pub pull_stats: PullStatsConfig,
+
+    #[serde(default)]
+    pub egress_budget: EgressBudgetConfig,
+
+    #[serde(default)]
+    pub response_headers: ResponseHeadersConfig,
+
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
+
+    /// Glob patterns of the container image names which can be pulled through the cache,
+    /// e.g. `library/*` or `mycorp/**`. Every name is allowed when empty
+    #[serde(default)]
+    pub allowed_repositories: Vec<String>,
+
+    /// Glob patterns of the container image names which can't be pulled through the cache,
+    /// even when they match an allowed pattern
+    #[serde(default)]
+    pub denied_repositories: Vec<String>,
+
+    #[serde(default)]
+    pub repository_names: RepositoryNamesConfig,
+
+    /// Whether the pushes, the writes to the manifests and the blobs, are forwarded to the upstream.
+    /// Otherwise they are rejected with a 405, the cache is meant for pulls only
+    #[serde(default)]
+    pub push_passthrough: bool,
+
+    /// User-Agent of the upstream requests, unless the upstream sets its own
+    #[serde(default)]
+    pub user_agent: UserAgentConfig,
 }
 
-impl From<Config> for AppConfig {
-    fn from(c: Config) -> Self {
-        c.try_deserialize().unwrap()
+impl TryFrom<Config> for AppConfig {
+    type Error = RegistryError;
+
+    fn try_from(c: Config) -> Result<Self, Self::Error> {
+        Ok(c.try_deserialize()?)
     }
 }
 
@@ -29,11 +113,12 @@ impl AppConfig {
 
     /// Load a specific Application Config
     pub fn load_file(source: &str) -> Result<AppConfig, RegistryError> {
-        let config = Config::builder()
+        Config::builder()
             .add_source(File::with_name(source))
-            .build().unwrap();
-        config.try_into().map_err(|_e| RegistryError::new(ErrorKind::ConfigError)
-            .with_error(format!("Failed to read config file {}", source)))
+            .build()
+            .map_err(RegistryError::from)
+            .and_then(AppConfig::try_from)
+            .map_err(|e| e.with_context(format!("Failed to read config file {}", source)))
     }
 
     /// Load the default config file: config.yaml
@@ -41,16 +126,226 @@ impl AppConfig {
         AppConfig::load_file(CONFIG_FILE_NAME)
     }
 
-    /// Whether the AppConfig is valid
-    pub fn is_valid(&self) -> bool {
+    /// Validate the AppConfig, returning every problem found
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
 
         // We need the hostname both for the realm and the oidc redirections
         if self.api.hostname.is_empty() {
-            tracing::error!("config.yaml has an empty api->hostname");
-            return false;
+            errors.push("config.yaml has an empty api->hostname".to_string());
+        }
+
+        // Sent within the double quotes of the WWW-Authenticate challenge
+        if self.auth.realm.as_ref().is_some_and(|realm| realm.is_empty() || realm.contains(['"', '\\']) || realm.chars().any(char::is_control)) {
+            errors.push("config.yaml auth->realm must not be empty nor contain double quotes, backslashes or control characters".to_string());
+        }
+
+        if self.storage.disk_usage_interval == 0 {
+            errors.push("config.yaml storage->disk_usage_interval must be greater than 0".to_string());
+        }
+
+        if self.storage.rename_attempts == 0 {
+            errors.push("config.yaml storage->rename_attempts must be greater than 0".to_string());
+        }
+
+        if self.storage.max_blob_bytes == Some(0) || self.storage.max_manifest_bytes == Some(0) {
+            errors.push("config.yaml storage->max_blob_bytes and storage->max_manifest_bytes must be greater than 0".to_string());
+        }
+
+        if self.storage.verify_on_read == Some(0) {
+            errors.push("config.yaml storage->verify_on_read must be greater than 0".to_string());
+        }
+
+        if self.storage.max_tags_per_repository == Some(0) {
+            errors.push("config.yaml storage->max_tags_per_repository must be greater than 0".to_string());
+        }
+
+        if self.storage.driver == StorageDriver::Distributed {
+            errors.push("config.yaml storage->driver distributed is not supported yet".to_string());
+        }
+
+        if self.storage.driver == StorageDriver::Memory && self.storage.memory_max_bytes == 0 {
+            errors.push("config.yaml storage->memory_max_bytes must be greater than 0 with the memory driver".to_string());
+        }
+
+        if self.storage.spool_dir.as_ref().is_some_and(|spool_dir| spool_dir.is_empty()) {
+            errors.push("config.yaml storage->spool_dir must not be empty".to_string());
+        }
+
+        if self.streaming.buffer_size == 0 {
+            errors.push("config.yaml streaming->buffer_size must be greater than 0".to_string());
+        }
+
+        if self.streaming.persist_channel == PersistChannel::Bounded(0) {
+            errors.push("config.yaml streaming->persist_channel bounded capacity must be greater than 0".to_string());
+        }
+
+        if self.streaming.chunk_timeout == 0 {
+            errors.push("config.yaml streaming->chunk_timeout must be greater than 0".to_string());
+        }
+
+        if self.streaming.command_queue_size == 0 {
+            errors.push("config.yaml streaming->command_queue_size must be greater than 0".to_string());
+        }
+
+        if self.streaming.max_in_flight == Some(0) {
+            errors.push("config.yaml streaming->max_in_flight must be greater than 0".to_string());
+        }
+
+        if let Err(e) = check_storage_folder(Path::new(&self.storage.folder)) {
+            errors.push(format!("config.yaml storage->folder {}", e));
+        }
+
+        if self.api.keep_alive_secs > 3600 {
+            errors.push("config.yaml api->keep_alive_secs must be at most 3600".to_string());
+        }
+
+        if self.api.http_workers.is_some_and(|workers| !(1..=1024).contains(&workers)) {
+            errors.push("config.yaml api->http_workers must be between 1 and 1024".to_string());
+        }
+
+        if self.api.request_timeout_secs == Some(0) {
+            errors.push("config.yaml api->request_timeout_secs must be greater than 0".to_string());
+        }
+
+        match (&self.api.tls_cert, &self.api.tls_key) {
+            (Some(cert), Some(key)) => {
+                if let Err(e) = check_pem_file(cert, rustls_pemfile::certs) {
+                    errors.push(format!("config.yaml api->tls_cert {}", e));
+                }
+                if let Err(e) = check_pem_file(key, private_keys) {
+                    errors.push(format!("config.yaml api->tls_key {}", e));
+                }
+            }
+            (None, None) => {}
+            _ => errors.push("config.yaml api->tls_cert and api->tls_key must be set together".to_string()),
         }
 
-        true
+        for certificate in &self.api.tls_certificates {
+            if certificate.hostname.is_empty() {
+                errors.push("config.yaml api->tls_certificates->hostname must not be empty".to_string());
+            }
+            if let Err(e) = check_pem_file(&certificate.cert, rustls_pemfile::certs) {
+                errors.push(format!("config.yaml api->tls_certificates->cert of {} {}", certificate.hostname, e));
+            }
+            if let Err(e) = check_pem_file(&certificate.key, private_keys) {
+                errors.push(format!("config.yaml api->tls_certificates->key of {} {}", certificate.hostname, e));
+            }
+        }
+
+        let tls = self.api.tls_cert.is_some() || self.api.tls_key.is_some() || !self.api.tls_certificates.is_empty();
+        if self.api.unix_socket.is_some() && tls {
+            errors.push("config.yaml api->unix_socket does not support TLS, api->tls_cert, api->tls_key and api->tls_certificates must not be set".to_string());
+        }
+
+        if self.api.unix_socket.as_ref().is_some_and(|path| path.is_empty()) {
+            errors.push("config.yaml api->unix_socket must not be empty".to_string());
+        }
+
+        let mut header_names = self.response_headers.strip.iter().chain(&self.response_headers.allow);
+        if header_names.any(|name| HeaderName::from_bytes(name.as_bytes()).is_err()) {
+            errors.push("config.yaml response_headers->strip and response_headers->allow must be header names".to_string());
+        }
+
+        for upstream in &self.upstreams {
+            if upstream.host.is_empty() || upstream.registry.is_empty() {
+                errors.push("config.yaml upstreams->host and upstreams->registry must not be empty".to_string());
+            }
+
+            if upstream.schema != "http" && upstream.schema != "https" {
+                errors.push(format!("config.yaml upstreams->schema of {} must be http or https", upstream.host));
+            }
+
+            if upstream.access_log.sample_rate == 0 {
+                errors.push(format!("config.yaml upstreams->access_log->sample_rate of {} must be greater than 0", upstream.host));
+            }
+
+            if let Some(folder) = &upstream.storage_folder {
+                if !is_valid_storage_folder(folder) {
+                    errors.push(format!("config.yaml upstreams->storage_folder of {} must be a relative subfolder not named after a digest algorithm", upstream.host));
+                }
+            }
+
+            if let Some(Err(e)) = upstream.credentials.as_ref().map(CredentialsConfig::authorization) {
+                errors.push(format!("config.yaml upstreams->credentials of {} {}", upstream.host, e));
+            }
+
+            if upstream.mirrors.iter().any(|mirror| mirror.registry.is_empty() || (mirror.schema != "http" && mirror.schema != "https")) {
+                errors.push(format!("config.yaml upstreams->mirrors of {} must have a registry and an http or https schema", upstream.host));
+            }
+
+            if upstream.path_prefix.as_ref().is_some_and(|prefix| prefix.contains(['?', '#']) || prefix.split('/').any(|segment| segment == "..")) {
+                errors.push(format!("config.yaml upstreams->path_prefix of {} must be a path, without query, fragment or ..", upstream.host));
+            }
+        }
+
+        if let Some(platform) = &self.priming.platform {
+            if Platform::parse(platform).is_none() {
+                errors.push("config.yaml priming->platform must be in the os/architecture[/variant] format".to_string());
+            }
+        }
+
+        if self.admin.token.as_ref().is_some_and(|token| token.is_empty()) {
+            errors.push("config.yaml admin->token must not be empty".to_string());
+        }
+
+        if self.allowed_repositories.iter().chain(&self.denied_repositories).any(|pattern| pattern.is_empty()) {
+            errors.push("config.yaml allowed_repositories and denied_repositories must not contain empty patterns".to_string());
+        }
+
+        if self.dead_letters.max_attempts <= 0 || self.dead_letters.retry_delay == 0 || self.dead_letters.interval == 0 || self.dead_letters.max_records <= 0 {
+            errors.push("config.yaml dead_letters->max_attempts, dead_letters->retry_delay, dead_letters->interval and dead_letters->max_records must be greater than 0".to_string());
+        }
+
+        if self.readiness.interval == 0 {
+            errors.push("config.yaml readiness->interval must be greater than 0".to_string());
+        }
+
+        if self.circuit_breaker.failures == Some(0) || self.circuit_breaker.window == 0 {
+            errors.push("config.yaml circuit_breaker->failures and circuit_breaker->window must be greater than 0".to_string());
+        }
+
+        if self.negative_cache.ttl == Some(0) || self.negative_cache.max_entries == 0 {
+            errors.push("config.yaml negative_cache->ttl and negative_cache->max_entries must be greater than 0".to_string());
+        }
+
+        if self.pull_stats.flush_interval == 0 {
+            errors.push("config.yaml pull_stats->flush_interval must be greater than 0".to_string());
+        }
+
+        if self.repository_names.max_components == 0 || self.repository_names.max_component_length == 0 {
+            errors.push("config.yaml repository_names->max_components and repository_names->max_component_length must be greater than 0".to_string());
+        }
+
+        if self.egress_budget.upstream_egress_budget_bytes == Some(0) || self.egress_budget.period_secs == 0 {
+            errors.push("config.yaml egress_budget->upstream_egress_budget_bytes and egress_budget->period_secs must be greater than 0".to_string());
+        }
+
+        if let Some(endpoint) = &self.telemetry.otlp_endpoint {
+            if !url::Url::parse(endpoint).is_ok_and(|url| url.scheme() == "http" || url.scheme() == "https") {
+                errors.push(format!("config.yaml telemetry->otlp_endpoint must be an http or https URL: {}", endpoint));
+            }
+        }
+
+        if !(0.0..=1.0).contains(&self.telemetry.sample_ratio) {
+            errors.push("config.yaml telemetry->sample_ratio must be between 0 and 1".to_string());
+        }
+
+        if let Some(min_free_percent) = self.eviction.min_free_percent {
+            if !(0.0..100.0).contains(&min_free_percent) {
+                errors.push("config.yaml eviction->min_free_percent must be between 0 and 100".to_string());
+            }
+
+            if self.eviction.interval == 0 {
+                errors.push("config.yaml eviction->interval must be greater than 0".to_string());
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
     }
 
     pub fn upstreams(&self) -> HashMap<String, UpstreamConfig> {
@@ -60,11 +355,255 @@ impl AppConfig {
         }
         config
     }
+
+    /// Realm of the WWW-Authenticate challenge of the 401 responses, none unless configured:
+    /// the listen address, e.g. 0.0.0.0, would not tell the clients where to authenticate
+    pub fn realm(&self) -> Option<&str> {
+        self.auth.realm.as_deref()
+    }
+}
+
+#[cfg(test)]
+impl AppConfig {
+    /// Minimal configuration storing the cache content in the given folder
+    pub fn with_storage_folder(folder: &str) -> AppConfig {
+        serde_json::from_value(serde_json::json!({
+            "api": { "hostname": "localhost" },
+            "upstreams": [],
+            "storage": { "folder": folder }
+        })).expect("Failed to build the test config")
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct StorageConfig {
     pub folder: String,
+
+    /// How often, in seconds, the disk usage metrics are recalculated by walking the storage folder
+    #[serde(default = "default_disk_usage_interval")]
+    pub disk_usage_interval: u64,
+
+    /// What an eviction does with a blob which is currently being served to a client
+    #[serde(default)]
+    pub eviction_read_policy: EvictionReadPolicy,
+
+    /// What happens to the old manifest when upstream moves a tag to a new digest
+    #[serde(default)]
+    pub tag_moved: TagMovedPolicy,
+
+    /// How the concurrent pulls of the same manifest are handled
+    #[serde(default)]
+    pub concurrent_manifests: ConcurrentManifestsPolicy,
+
+    /// What a manifest pull gets when the upstream request fails, e.g. upstream closed the connection
+    #[serde(default)]
+    pub upstream_error: UpstreamErrorPolicy,
+
+    /// How many times moving a stored blob to its final path is attempted before giving up
+    #[serde(default = "default_rename_attempts")]
+    pub rename_attempts: u32,
+
+    /// How a Range request for a blob which is not cached yet is handled
+    #[serde(default)]
+    pub range_miss: RangeMissPolicy,
+
+    /// Largest blob, in bytes, which is stored in the cache, by default there is no limit
+    #[serde(default)]
+    pub max_blob_bytes: Option<u64>,
+
+    /// Largest manifest, in bytes, which is stored in the cache, by default there is no limit
+    #[serde(default)]
+    pub max_manifest_bytes: Option<u64>,
+
+    /// Store only once the identical content pulled with different digest algorithms, e.g. sha256 and sha512:
+    /// the other paths are hard links to the first copy
+    #[serde(default)]
+    pub deduplicate: bool,
+
+    /// Seconds a cached tag is served from the cache, after an upstream failure or with its circuit open,
+    /// since it was last refreshed from upstream. Afterward the tag must be revalidated upstream,
+    /// and its pulls fail while upstream is unreachable, but for the ones turned away by its open circuit which are
+    /// served from the cache. By default the cached tags never go stale
+    #[serde(default)]
+    pub manifest_ttl_secs: Option<u64>,
+
+    /// Free disk space, in bytes, of the storage folder and of the spool folder under which the blobs and manifests are not stored anymore,
+    /// they are still streamed to the clients. Any amount is enough when not set
+    #[serde(default)]
+    pub min_free_bytes: Option<u64>,
+
+    /// Tags indexed per container image name, e.g. to bound the ephemeral CI tags. Beyond it, the tags least recently
+    /// refreshed from upstream are removed from the index along with the blobs only they reference.
+    /// The manifests pulled by digest do not count. By default there is no limit
+    #[serde(default)]
+    pub max_tags_per_repository: Option<u32>,
+
+    /// Verify the digest of 1 in `verify_on_read` cached blobs before serving them, 1 verifies every read. The HEAD requests are not reads.
+    /// A corrupted blob is removed and fetched from upstream again, one failing to be read is read once more, then served unverified.
+    /// Hashing is expensive, nothing is verified when not set
+    #[serde(default)]
+    pub verify_on_read: Option<u64>,
+
+    /// Whether the manifests are stored and indexed. Otherwise they are always pulled from upstream,
+    /// so that a moved tag is never served stale, while the blobs are still cached
+    #[serde(default = "default_cache_manifests")]
+    pub cache_manifests: bool,
+
+    /// Whether the stored blobs are synced to the disk before being served
+    #[serde(default)]
+    pub durability: Durability,
+
+    /// Where the blobs and the manifests are stored
+    #[serde(default)]
+    pub driver: StorageDriver,
+
+    /// Most bytes the memory driver holds, the blobs stored the longest ago are evicted to make room for a new one
+    #[serde(default = "default_memory_max_bytes")]
+    pub memory_max_bytes: u64,
+
+    /// Folder the blobs are written into until they are verified, then moved to the storage folder.
+    /// Next to their final path when not set
+    #[serde(default)]
+    pub spool_dir: Option<String>,
+}
+
+/// How the stored blobs are synced to the disk. The digest of a blob is verified while it is stored, not afterward:
+/// unless it was synced, a blob can be left truncated at its final path on power loss, see `verify_on_read`
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Durability {
+    /// Sync each blob before moving it to its final path
+    #[default]
+    Sync,
+
+    /// Sync the blobs stored within a second of each other together, in the background
+    Async,
+
+    /// Leave it to the operating system, the blobs stored recently can be lost, or left truncated, on power loss
+    None,
+}
+
+/// How the eviction treats blobs with active readers
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum EvictionReadPolicy {
+    /// Leave the blob in place, it will be considered again by the next eviction
+    #[default]
+    Skip,
+
+    /// Remove the blob as soon as the last reader is done
+    Defer,
+}
+
+/// How the old manifest of a moved tag is treated
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TagMovedPolicy {
+    /// Leave the old manifest in the cache, the eviction will take care of it
+    #[default]
+    Keep,
+
+    /// Remove the old manifest right away, unless another tag or digest still points to it
+    Remove,
+}
+
+/// How the concurrent pulls of the same manifest tag, or digest, are handled
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ConcurrentManifestsPolicy {
+    /// Each pull is forwarded upstream and streamed back, the last one to be stored wins
+    /// when upstream moves the tag in the meantime
+    #[default]
+    Independent,
+
+    /// The identical pulls, same credentials included, share a single upstream request.
+    /// The manifest is buffered before being sent to the clients
+    Coalesce,
+}
+
+/// How a failed upstream manifest request is handled, an upstream timing out is always served from the cache
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamErrorPolicy {
+    /// Serve the manifest from the cache, a 404 when it is not cached
+    #[default]
+    Cache,
+
+    /// Answer with a 503
+    Error,
+}
+
+/// How a Range request for a blob which is not cached yet is handled.
+/// In both cases the range is requested upstream and the client gets back only the bytes it asked for.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RangeMissPolicy {
+    /// Cache the blob with a separate full request to upstream, in the background
+    #[default]
+    Background,
+
+    /// Do not cache the blob, only a request without a range stores it
+    Proxy,
+}
+
+fn default_disk_usage_interval() -> u64 {
+    60
+}
+
+fn default_rename_attempts() -> u32 {
+    3
+}
+
+fn default_memory_max_bytes() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn default_cache_manifests() -> bool {
+    true
+}
+
+fn default_keep_alive_secs() -> u64 {
+    75
+}
+
+/// The storage folder has to be a writable directory, or be possible to create
+fn check_storage_folder(folder: &Path) -> Result<(), String> {
+    // The closest folder which exists
+    let existing = folder.ancestors()
+        .find(|folder| folder.exists())
+        .filter(|existing| existing.is_dir())
+        .ok_or_else(|| format!("{:?} is not a directory", folder))?;
+
+    // Nothing tells whether a folder is writable better than writing into it
+    let probe = existing.join(format!(".pier-cache-check-{}", std::process::id()));
+    std::fs::write(&probe, b"").map_err(|e| format!("{:?} is not writable: {}", existing, e))?;
+    if let Err(e) = std::fs::remove_file(&probe) {
+        tracing::warn!("Failed to remove {:?}: {}", probe, e.to_string());
+    }
+
+    Ok(())
+}
+
+/// The PEM file exists and contains at least one item of the kind read by the parser
+fn check_pem_file(path: &str, parse: fn(&mut dyn std::io::BufRead) -> std::io::Result<Vec<Vec<u8>>>) -> Result<(), String> {
+    let file = std::fs::File::open(path).map_err(|e| format!("{} can't be opened: {}", path, e))?;
+    match parse(&mut std::io::BufReader::new(file)) {
+        Ok(items) if !items.is_empty() => Ok(()),
+        Ok(_) => Err(format!("{} contains no PEM item of the expected kind", path)),
+        Err(e) => Err(format!("{} can't be parsed: {}", path, e)),
+    }
+}
+
+/// Whether the upstream storage folder stays within the storage folder without overlapping
+/// with the digest algorithm folders, in which the blobs of the main storage folder are stored
+fn is_valid_storage_folder(folder: &str) -> bool {
+    let mut components = Path::new(folder).components();
+    match components.next() {
+        Some(Component::Normal(first)) if first != "sha256" && first != "sha512" => {
+            components.all(|component| matches!(component, Component::Normal(_)))
+        }
+        _ => false,
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -72,7 +611,164 @@ pub struct UpstreamConfig {
     pub host: String,
     pub registry: String,
     pub port: u16,
-    pub schema: String
+    pub schema: String,
+
+    /// Sampling of the access logs for the requests to this upstream
+    #[serde(default)]
+    pub access_log: AccessLogConfig,
+
+    /// Subfolder of storage->folder where the blobs of this upstream are stored,
+    /// by default they are stored in storage->folder together with the ones of the other upstreams
+    #[serde(default)]
+    pub storage_folder: Option<String>,
+
+    /// HTTP version of the requests to this upstream
+    #[serde(default)]
+    pub http_version: HttpVersion,
+
+    /// IP address of some hostnames, e.g. the registry or its authentication server, used for the requests
+    /// to this upstream instead of resolving them via DNS
+    #[serde(default)]
+    pub resolve: HashMap<String, IpAddr>,
+
+    /// User-Agent of the requests to this upstream, instead of the one of the cache
+    #[serde(default)]
+    pub user_agent: Option<UserAgentConfig>,
+
+    /// Credentials of a private upstream registry, replacing the Authorization of the clients
+    #[serde(default)]
+    pub credentials: Option<CredentialsConfig>,
+
+    /// Registries tried in order when this upstream can't be reached, times out or answers with a server error.
+    /// The first response of one of them wins, and is cached
+    #[serde(default)]
+    pub mirrors: Vec<MirrorConfig>,
+
+    /// Path the upstream serves the registry API under, e.g. `/repository/docker-hub` for a Nexus or an Artifactory
+    /// mirror serving `/repository/docker-hub/v2/`. Not sent to the mirrors
+    #[serde(default)]
+    pub path_prefix: Option<String>,
+}
+
+/// Another registry serving the same content as an upstream, pulled from anonymously
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct MirrorConfig {
+    pub registry: String,
+    pub port: u16,
+    pub schema: String,
+}
+
+impl MirrorConfig {
+
+    /// The base URL of the mirror registry, the port is omitted when it is the default one of the schema
+    pub fn base_url(&self) -> String {
+        base_url(&self.schema, &self.registry, self.port)
+    }
+}
+
+/// User-Agent sent upstream, the one of the client is forwarded verbatim when no value is set
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Default)]
+pub struct UserAgentConfig {
+    /// The User-Agent, e.g. `registry-cache/1.2.3`
+    #[serde(default)]
+    pub value: Option<String>,
+
+    /// Whether the value is appended to the User-Agent of the client instead of replacing it
+    #[serde(default)]
+    pub append: bool,
+}
+
+impl UserAgentConfig {
+
+    /// The User-Agent of the upstream request given the one of the client, none to forward the one of the client
+    pub fn for_client(&self, client: Option<&str>) -> Option<String> {
+        let value = self.value.as_ref()?;
+        match client {
+            Some(client) if self.append && !client.is_empty() => Some(format!("{} {}", client, value)),
+            _ => Some(value.clone()),
+        }
+    }
+}
+
+/// HTTP version spoken with an upstream
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HttpVersion {
+    /// HTTP/2 when an https upstream offers it via ALPN, HTTP/1.1 otherwise
+    #[default]
+    Auto,
+
+    /// HTTP/1.1 only, for the registries mishandling HTTP/2
+    Http1,
+
+    /// HTTP/2 right away without negotiating it, cleartext HTTP/2 (h2c) for an http upstream
+    Http2,
+}
+
+impl UpstreamConfig {
+
+    /// The base URL of the upstream registry, the port is omitted when it is the default one of the schema
+    pub fn base_url(&self) -> String {
+        base_url(&self.schema, &self.registry, self.port)
+    }
+
+    /// The upstream path of a registry API path, e.g. `/v2/library/nginx/manifests/latest`, behind the path prefix if any
+    pub fn upstream_path(&self, path: &str) -> String {
+        match self.path_prefix() {
+            Some(prefix) => format!("/{}/{}", prefix, path.trim_start_matches('/')),
+            None => path.to_string(),
+        }
+    }
+
+    /// The registry API path of an upstream path, without the path prefix if any
+    pub fn registry_path<'a>(&self, path: &'a str) -> &'a str {
+        self.path_prefix()
+            .and_then(|prefix| path.strip_prefix('/')?.strip_prefix(prefix))
+            .filter(|path| path.starts_with('/'))
+            .unwrap_or(path)
+    }
+
+    /// The path prefix without its leading and trailing slashes, none when it is empty
+    fn path_prefix(&self) -> Option<&str> {
+        self.path_prefix.as_deref().map(|prefix| prefix.trim_matches('/')).filter(|prefix| !prefix.is_empty())
+    }
+}
+
+#[cfg(test)]
+impl UpstreamConfig {
+    /// Upstream of the host, pulling from the registry at the URL, e.g. `http://127.0.0.1:5000`, with the default settings
+    pub fn for_test(host: &str, url: &str) -> UpstreamConfig {
+        let url = reqwest::Url::parse(url).expect("Invalid upstream URL");
+        UpstreamConfig {
+            host: host.to_string(),
+            registry: url.host_str().expect("No registry in the upstream URL").to_string(),
+            port: url.port_or_known_default().expect("No port in the upstream URL"),
+            schema: url.scheme().to_string(),
+            access_log: Default::default(),
+            storage_folder: None,
+            http_version: Default::default(),
+            resolve: Default::default(),
+            user_agent: None,
+            credentials: None,
+            mirrors: Vec::new(),
+            path_prefix: None,
+        }
+    }
+}
+
+/// The base URL of a registry, the port is omitted when it is the default one of the schema
+fn base_url(schema: &str, registry: &str, port: u16) -> String {
+    let default_port = match schema {
+        "http" => Some(80),
+        "https" => Some(443),
+        _ => None,
+    };
+
+    if default_port == Some(port) {
+        format!("{}://{}", schema, registry)
+    } else {
+        format!("{}://{}:{}", schema, registry, port)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -97,5 +793,180 @@ pub struct ApiConfig {
     pub tls_key: Option<String>,
 
     /// The location of the TLS cert file
-    pub tls_cert: Option<String>
+    pub tls_cert: Option<String>,
+
+    /// Certificates selected via SNI for the hostname the clients ask for. The one of tls_cert and tls_key,
+    /// if any, is served to the other clients
+    #[serde(default)]
+    pub tls_certificates: Vec<TlsCertificateConfig>,
+
+    /// Seconds an idle client connection is kept open, 0 closes it after each response
+    #[serde(default = "default_keep_alive_secs")]
+    pub keep_alive_secs: u64,
+
+    /// Amount of HTTP worker threads, by default one per CPU
+    #[serde(default)]
+    pub http_workers: Option<usize>,
+
+    /// Seconds a registry request has to start its response, upstream requests and disk reads included,
+    /// before it gets a 504. The cached manifest is served instead, if any. No deadline when not set
+    #[serde(default)]
+    pub request_timeout_secs: Option<u64>,
+
+    /// Path of the Unix domain socket to listen to instead of the TCP port, e.g. for a container runtime
+    /// sharing the pod of the cache. The socket file is removed on shutdown
+    #[serde(default)]
+    pub unix_socket: Option<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::app::{AppConfig, UpstreamConfig};
+    use crate::config::driver::StorageDriver;
+    use crate::config::credentials::CredentialsConfig;
+    use crate::config::eviction::EvictionConfig;
+    use crate::error::error_kind::ErrorKind;
+
+    fn upstream(schema: &str, port: u16) -> UpstreamConfig {
+        UpstreamConfig::for_test("localhost", &format!("{}://registry.local:{}", schema, port))
+    }
+
+    #[test]
+    fn upstream_base_url_test() {
+        assert_eq!("https://registry.local", upstream("https", 443).base_url());
+        assert_eq!("http://registry.local", upstream("http", 80).base_url());
+        assert_eq!("http://registry.local:5000", upstream("http", 5000).base_url());
+        assert_eq!("https://registry.local:80", upstream("https", 80).base_url());
+
+        let url = url::Url::parse(&upstream("http", 5000).base_url()).unwrap();
+        assert_eq!(Some(5000), url.port());
+    }
+
+    #[test]
+    fn upstream_path_prefix_test() {
+        let path = "/v2/library/nginx/manifests/latest";
+        assert_eq!(path, upstream("https", 443).upstream_path(path));
+        assert_eq!(path, upstream("https", 443).registry_path(path));
+
+        // Whatever the slashes around the prefix
+        for prefix in ["/repository/docker-hub", "repository/docker-hub/", "/repository/docker-hub/"] {
+            let upstream = UpstreamConfig { path_prefix: Some(prefix.to_string()), ..upstream("https", 443) };
+            assert_eq!("/repository/docker-hub/v2/library/nginx/manifests/latest", upstream.upstream_path(path));
+            assert_eq!(path, upstream.registry_path(&upstream.upstream_path(path)));
+        }
+
+        // An empty prefix is no prefix, and a path of another prefix is left alone
+        let upstream = UpstreamConfig { path_prefix: Some("/".to_string()), ..upstream("https", 443) };
+        assert_eq!(path, upstream.upstream_path(path));
+        let upstream = UpstreamConfig { path_prefix: Some("/repository/docker".to_string()), ..upstream };
+        assert_eq!("/repository/docker-hub/v2/", upstream.registry_path("/repository/docker-hub/v2/"));
+    }
+
+    #[test]
+    fn validate_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().join("cache").to_str().unwrap());
+        config.upstreams.push(upstream("https", 443));
+        assert_eq!(Ok(()), config.validate());
+
+        // Every problem is reported at once
+        config.upstreams.push(upstream("ftp", 21));
+        config.api.tls_cert = Some(folder.path().join("missing.pem").to_str().unwrap().to_string());
+        config.streaming.buffer_size = 0;
+        config.api.http_workers = Some(0);
+        config.api.request_timeout_secs = Some(0);
+        config.response_headers.allow.push("x registry".to_string());
+        config.upstreams[0].credentials = Some(CredentialsConfig { username: Some("mirror".to_string()), ..Default::default() });
+        let errors = config.validate().unwrap_err();
+        assert_eq!(7, errors.len());
+        assert!(errors.iter().any(|error| error.contains("upstreams->credentials of localhost")));
+        assert!(errors.iter().any(|error| error.contains("response_headers->strip")));
+        assert!(errors.iter().any(|error| error.contains("api->http_workers")));
+        assert!(errors.iter().any(|error| error.contains("api->request_timeout_secs")));
+        assert!(errors.iter().any(|error| error.contains("upstreams->schema of localhost")));
+        assert!(errors.iter().any(|error| error.contains("tls_cert and api->tls_key")));
+
+        // The TLS files must be valid PEM files
+        std::fs::write(folder.path().join("cert.pem"), "not a certificate").unwrap();
+        config.api.tls_cert = Some(folder.path().join("cert.pem").to_str().unwrap().to_string());
+        config.api.tls_key = Some(folder.path().join("cert.pem").to_str().unwrap().to_string());
+        let errors = config.validate().unwrap_err();
+        assert!(errors.iter().any(|error| error.contains("api->tls_cert") && error.contains("no PEM item")));
+        assert!(errors.iter().any(|error| error.contains("api->tls_key") && error.contains("no PEM item")));
+
+        // No TLS over the Unix domain socket
+        config.api.unix_socket = Some(folder.path().join("cache.sock").to_str().unwrap().to_string());
+        assert!(config.validate().unwrap_err().iter().any(|error| error.contains("api->unix_socket does not support TLS")));
+
+        // The path prefix is a path
+        config.upstreams[0].path_prefix = Some("/repository/../docker-hub".to_string());
+        assert!(config.validate().unwrap_err().iter().any(|error| error.contains("upstreams->path_prefix of localhost")));
+
+        // The collector is reached over HTTP
+        config.telemetry.otlp_endpoint = Some("otel-collector:4317".to_string());
+        config.telemetry.sample_ratio = 1.5;
+        let errors = config.validate().unwrap_err();
+        assert!(errors.iter().any(|error| error.contains("telemetry->otlp_endpoint")));
+        assert!(errors.iter().any(|error| error.contains("telemetry->sample_ratio")));
+
+        // A budget of nothing would never call the upstreams
+        config.egress_budget.upstream_egress_budget_bytes = Some(0);
+        assert!(config.validate().unwrap_err().iter().any(|error| error.contains("egress_budget->upstream_egress_budget_bytes")));
+
+        // No name could ever be pulled
+        config.repository_names.max_components = 0;
+        assert!(config.validate().unwrap_err().iter().any(|error| error.contains("repository_names->max_components")));
+
+        // The realm could not be sent as it is
+        config.auth.realm = Some("cache \"local\"".to_string());
+        assert!(config.validate().unwrap_err().iter().any(|error| error.contains("auth->realm")));
+
+        // The blobs would be written into the working directory
+        config.storage.spool_dir = Some(String::new());
+        assert!(config.validate().unwrap_err().iter().any(|error| error.contains("storage->spool_dir")));
+
+        // The memory driver could not hold a single blob
+        config.storage.driver = StorageDriver::Memory;
+        config.storage.memory_max_bytes = 0;
+        assert!(config.validate().unwrap_err().iter().any(|error| error.contains("storage->memory_max_bytes")));
+        config.storage.driver = StorageDriver::Distributed;
+        assert!(config.validate().unwrap_err().iter().any(|error| error.contains("storage->driver")));
+
+        // The storage folder must be a folder
+        let file = folder.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        let config = AppConfig::with_storage_folder(file.to_str().unwrap());
+        assert!(config.validate().unwrap_err()[0].contains("storage->folder"));
+    }
+
+    #[test]
+    fn load_file_partial_eviction_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let path = folder.path().join("config.yaml");
+        std::fs::write(&path, "api:\n  hostname: localhost\nstorage:\n  folder: /tmp/cache\nupstreams: []\neviction:\n  min_free_percent: 10\n").unwrap();
+        let config = AppConfig::load_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(Some(10.0), config.eviction.min_free_percent);
+        assert_eq!(EvictionConfig::default().interval, config.eviction.interval);
+    }
+
+    #[test]
+    fn load_file_error_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let load = |yaml: &str| {
+            let path = folder.path().join("config.yaml");
+            std::fs::write(&path, yaml).unwrap();
+            AppConfig::load_file(path.to_str().unwrap()).unwrap_err()
+        };
+
+        let error = load("api:\n  hostname: localhost\nstorage:\n  folder: /tmp/cache\n");
+        assert_eq!(ErrorKind::ConfigError, error.kind);
+        assert!(error.error.contains("upstreams"), "{}", error.error);
+
+        let error = load("api:\n  hostname: localhost\nstorage:\n  folder: /tmp/cache\nupstreams:\n  - host: localhost\n    registry: registry.local\n    port: https\n    schema: https\n");
+        assert!(error.error.contains("port"), "{}", error.error);
+
+        let error = AppConfig::load_file(folder.path().join("missing").to_str().unwrap()).unwrap_err();
+        assert_eq!(ErrorKind::ConfigError, error.kind);
+        assert!(error.message.contains("missing"), "{}", error.message);
+    }
 }
@@ -0,0 +1,11 @@
+// SPDX-License-Identifier: Apache-2.0
+use serde::{Deserialize, Serialize};
+
+/// Authentication of the clients to the cache
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// Realm of the WWW-Authenticate challenge of the 401 responses, telling the clients where to authenticate.
+    /// The 401 responses have no WWW-Authenticate header when not set
+    pub realm: Option<String>,
+}
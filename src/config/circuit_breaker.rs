@@ -0,0 +1,27 @@
+// SPDX-License-Identifier: Apache-2.0
+use serde::{Deserialize, Serialize};
+
+/// Stop calling an upstream which keeps failing, and serve from the cache right away instead
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failed upstream requests, connection errors, timeouts and 5xx responses, opening the circuit.
+    /// By default the circuit never opens
+    pub failures: Option<u32>,
+
+    /// Seconds the consecutive failures must happen within
+    pub window: u64,
+
+    /// Seconds the circuit stays open before a single request probes the upstream again
+    pub cooldown: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            failures: None,
+            window: 60,
+            cooldown: 30,
+        }
+    }
+}
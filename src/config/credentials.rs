@@ -0,0 +1,82 @@
+// SPDX-License-Identifier: Apache-2.0
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::{Deserialize, Serialize};
+
+/// Credentials the cache authenticates to a private upstream registry with, instead of forwarding the ones of the clients.
+/// Either a username and a password, sent via basic authentication, or a bearer token
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct CredentialsConfig {
+    pub username: Option<String>,
+    pub password: Option<Secret>,
+    pub token: Option<Secret>,
+}
+
+/// A secret, so that it is not written in config.yaml it can be read from an environment variable or a file
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Secret {
+    Value(String),
+
+    /// Name of the environment variable
+    Env(String),
+
+    /// Path of the file, its trailing newline is ignored
+    File(String),
+}
+
+impl Secret {
+
+    /// Read the secret, an error tells why it can't be
+    pub fn read(&self) -> Result<String, String> {
+        match self {
+            Secret::Value(value) => Ok(value.clone()),
+            Secret::Env(name) => std::env::var(name).map_err(|e| format!("environment variable {} can't be read: {}", name, e)),
+            Secret::File(path) => std::fs::read_to_string(path)
+                .map(|secret| secret.trim_end_matches(['\r', '\n']).to_string())
+                .map_err(|e| format!("{} can't be read: {}", path, e)),
+        }
+    }
+}
+
+impl CredentialsConfig {
+
+    /// The value of the Authorization header of the upstream requests
+    pub fn authorization(&self) -> Result<String, String> {
+        match (&self.username, &self.password, &self.token) {
+            (Some(username), Some(password), None) => Ok(format!("Basic {}", STANDARD.encode(format!("{}:{}", username, password.read()?)))),
+            (None, None, Some(token)) => Ok(format!("Bearer {}", token.read()?)),
+            _ => Err("must be either a username and a password, or a token".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::credentials::{CredentialsConfig, Secret};
+
+    #[test]
+    fn authorization_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let file = folder.path().join("password");
+        std::fs::write(&file, "s3cret\n").unwrap();
+
+        let basic = CredentialsConfig {
+            username: Some("mirror".to_string()),
+            password: Some(Secret::File(file.to_str().unwrap().to_string())),
+            token: None,
+        };
+        assert_eq!(Ok("Basic bWlycm9yOnMzY3JldA==".to_string()), basic.authorization());
+
+        let bearer = CredentialsConfig { token: Some(Secret::Value("abc".to_string())), ..Default::default() };
+        assert_eq!(Ok("Bearer abc".to_string()), bearer.authorization());
+
+        // Missing secrets and incomplete credentials
+        let missing = CredentialsConfig { token: Some(Secret::Env("PIER_CACHE_MISSING_TOKEN".to_string())), ..Default::default() };
+        assert!(missing.authorization().unwrap_err().contains("PIER_CACHE_MISSING_TOKEN"));
+        let missing = CredentialsConfig { password: Some(Secret::File(folder.path().join("missing").to_str().unwrap().to_string())), ..basic.clone() };
+        assert!(missing.authorization().is_err());
+        assert!(CredentialsConfig { username: None, ..basic }.authorization().is_err());
+    }
+}
@@ -4,7 +4,15 @@ use serde::{Deserialize, Serialize};
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DBConfig {
     pub max_connections: u32,
-    pub uri: String
+    pub uri: String,
+
+    /// Seconds a connection waits for the database to be unlocked by another one before failing
+    #[serde(default = "default_busy_timeout")]
+    pub busy_timeout: u64,
+
+    /// Pages the write-ahead log grows to before it is checkpointed into the database, 0 disables it
+    #[serde(default = "default_wal_autocheckpoint")]
+    pub wal_autocheckpoint: u32,
 }
 
 impl Default for DBConfig {
@@ -12,7 +20,18 @@ impl Default for DBConfig {
         DBConfig {
             max_connections: 1,
             // uri: "sqlite:/tmp/cache/cache.db".to_string()
-            uri: "sqlite::memory:".to_string()
+            uri: "sqlite::memory:".to_string(),
+            busy_timeout: default_busy_timeout(),
+            wal_autocheckpoint: default_wal_autocheckpoint(),
         }
     }
-}
\ No newline at end of file
+}
+
+fn default_busy_timeout() -> u64 {
+    5
+}
+
+/// The SQLite default
+fn default_wal_autocheckpoint() -> u32 {
+    1000
+}
@@ -0,0 +1,35 @@
+// SPDX-License-Identifier: Apache-2.0
+use serde::{Deserialize, Serialize};
+
+/// Blobs and manifests whose persistence failed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DeadLettersConfig {
+    /// Whether the failed persistences are fetched again from upstream in the background.
+    /// The retries are anonymous, so they only succeed for upstreams not requiring authentication to pull
+    pub retry: bool,
+
+    /// How many times the persistence is attempted in total, the failures are kept for inspection afterward
+    pub max_attempts: i64,
+
+    /// Seconds to wait after the first failure before retrying, doubled at every attempt
+    pub retry_delay: u64,
+
+    /// How often, in seconds, the failures are checked for a retry
+    pub interval: u64,
+
+    /// Most failures kept, the oldest ones are dropped beyond it every `interval`
+    pub max_records: i64,
+}
+
+impl Default for DeadLettersConfig {
+    fn default() -> Self {
+        DeadLettersConfig {
+            retry: false,
+            max_attempts: 5,
+            retry_delay: 60,
+            interval: 30,
+            max_records: 10_000,
+        }
+    }
+}
@@ -4,11 +4,17 @@ use strum_macros::EnumString;
 
 /// Storage driver for the cache content
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, EnumString, Default)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
 pub enum StorageDriver {
-    /// Default filesystem
+    /// Default filesystem, the files in storage->folder are kept across restarts
     #[default]
     FileSystem,
 
     /// Not supported for now
-    Distributed
-}
\ No newline at end of file
+    Distributed,
+
+    /// The memory of the process, up to storage->memory_max_bytes, lost on restart.
+    /// For the tests and the ephemeral caches which should not touch the disk
+    Memory,
+}
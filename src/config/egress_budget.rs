@@ -0,0 +1,23 @@
+// SPDX-License-Identifier: Apache-2.0
+use serde::{Deserialize, Serialize};
+
+/// Bound the bytes fetched from each upstream, e.g. to avoid a surprise egress bill. Once the budget of an upstream
+/// is spent it is not called anymore, the cached content is still served, until the window slid far enough
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct EgressBudgetConfig {
+    /// Bytes which can be fetched from each upstream within the period. By default there is no budget
+    pub upstream_egress_budget_bytes: Option<u64>,
+
+    /// Seconds of the sliding window the fetched bytes are counted over
+    pub period_secs: u64,
+}
+
+impl Default for EgressBudgetConfig {
+    fn default() -> Self {
+        EgressBudgetConfig {
+            upstream_egress_budget_bytes: None,
+            period_secs: 86_400,
+        }
+    }
+}
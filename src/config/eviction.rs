@@ -0,0 +1,23 @@
+// SPDX-License-Identifier: Apache-2.0
+use serde::{Deserialize, Serialize};
+
+/// Eviction of the cached blobs
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct EvictionConfig {
+    /// Evict the least recently used blobs when the free space of the storage folder
+    /// drops below this percentage of the filesystem size. Eviction is disabled when not set
+    pub min_free_percent: Option<f64>,
+
+    /// How often, in seconds, the free space is checked
+    pub interval: u64,
+}
+
+impl Default for EvictionConfig {
+    fn default() -> Self {
+        EvictionConfig {
+            min_free_percent: None,
+            interval: 30,
+        }
+    }
+}
@@ -1,4 +1,21 @@
 // SPDX-License-Identifier: Apache-2.0
+pub mod access_log;
+pub mod admin;
 pub mod app;
+pub mod auth;
+pub mod circuit_breaker;
+pub mod credentials;
+pub mod dead_letters;
 pub mod driver;
-pub mod db;
\ No newline at end of file
+pub mod db;
+pub mod egress_budget;
+pub mod eviction;
+pub mod negative_cache;
+pub mod priming;
+pub mod pull_stats;
+pub mod readiness;
+pub mod repository_names;
+pub mod response_headers;
+pub mod streaming;
+pub mod telemetry;
+pub mod tls;
\ No newline at end of file
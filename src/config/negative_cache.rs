@@ -0,0 +1,23 @@
+// SPDX-License-Identifier: Apache-2.0
+use serde::{Deserialize, Serialize};
+
+/// Remember for a while the blobs and manifests upstream does not have, so that the pulls repeated
+/// e.g. by a CI loop get a 404 right away instead of asking upstream every time
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct NegativeCacheConfig {
+    /// Seconds a 404 of upstream is remembered. By default nothing is remembered
+    pub ttl: Option<u64>,
+
+    /// Maximum amount of 404s remembered, the newer ones are not once reached
+    pub max_entries: usize,
+}
+
+impl Default for NegativeCacheConfig {
+    fn default() -> Self {
+        NegativeCacheConfig {
+            ttl: None,
+            max_entries: 10_000,
+        }
+    }
+}
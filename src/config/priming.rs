@@ -0,0 +1,11 @@
+// SPDX-License-Identifier: Apache-2.0
+use serde::{Deserialize, Serialize};
+
+/// Cache priming of the pulled image indexes
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PrimingConfig {
+    /// Platform of this node in the `os/architecture[/variant]` format, e.g. `linux/arm64`.
+    /// When set, pulling an image index caches the manifest and the layers of this platform only,
+    /// the other platforms are still served but not primed
+    pub platform: Option<String>,
+}
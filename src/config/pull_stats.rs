@@ -0,0 +1,23 @@
+// SPDX-License-Identifier: Apache-2.0
+use serde::{Deserialize, Serialize};
+
+/// How the pulls of the container images are counted, for the admin stats and the `pulls_total` metric
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PullStatsConfig {
+    /// Seconds the pulls are counted in memory before being written to the database at once
+    pub flush_interval: u64,
+
+    /// Maximum amount of container images with their own `pulls_total` label,
+    /// the pulls of the container images pulled for the first time once reached are counted as `other`
+    pub max_metric_repositories: usize,
+}
+
+impl Default for PullStatsConfig {
+    fn default() -> Self {
+        PullStatsConfig {
+            flush_interval: 10,
+            max_metric_repositories: 1000,
+        }
+    }
+}
@@ -0,0 +1,38 @@
+// SPDX-License-Identifier: Apache-2.0
+use serde::{Deserialize, Serialize};
+
+/// When the cache reports itself ready to receive traffic via /readyz
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ReadinessConfig {
+    /// Whether the cache is ready right away or once it can reach an upstream
+    pub startup: StartupPolicy,
+
+    /// Seconds to wait for an upstream to answer, the cache is ready anyway afterward
+    pub timeout: u64,
+
+    /// How often, in seconds, the upstreams are probed while waiting
+    pub interval: u64,
+}
+
+impl Default for ReadinessConfig {
+    fn default() -> Self {
+        ReadinessConfig {
+            startup: Default::default(),
+            timeout: 300,
+            interval: 5,
+        }
+    }
+}
+
+/// What the cache waits for before being ready
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StartupPolicy {
+    /// Ready as soon as the server is listening
+    #[default]
+    Immediate,
+
+    /// Ready once at least one of the upstreams answers the `/v2/` probe, or once the timeout elapsed
+    Wait,
+}
@@ -0,0 +1,28 @@
+// SPDX-License-Identifier: Apache-2.0
+use serde::{Deserialize, Serialize};
+
+/// Path components of a container image name by default, e.g. `library/nginx` has 2 of them
+pub const DEFAULT_MAX_COMPONENTS: usize = 32;
+
+/// Characters of a path component of a container image name by default
+pub const DEFAULT_MAX_COMPONENT_LENGTH: usize = 128;
+
+/// Limits of the container image names, beyond them the requests are rejected before reaching the storage or upstream
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RepositoryNamesConfig {
+    /// Most path components of a name
+    pub max_components: usize,
+
+    /// Most characters of a path component
+    pub max_component_length: usize,
+}
+
+impl Default for RepositoryNamesConfig {
+    fn default() -> Self {
+        RepositoryNamesConfig {
+            max_components: DEFAULT_MAX_COMPONENTS,
+            max_component_length: DEFAULT_MAX_COMPONENT_LENGTH,
+        }
+    }
+}
@@ -0,0 +1,56 @@
+// SPDX-License-Identifier: Apache-2.0
+use serde::{Deserialize, Serialize};
+
+/// The headers of the upstream responses relayed to the clients.
+/// The framing ones, `Content-Length` and `Transfer-Encoding`, are always left to the cache
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ResponseHeadersConfig {
+    /// Names of the headers never relayed, by default the hop-by-hop ones
+    pub strip: Vec<String>,
+
+    /// Names of the only headers relayed, e.g. to hide the `Server` or the registry-internal `X-` headers.
+    /// Every header which is not stripped is relayed when empty
+    pub allow: Vec<String>,
+}
+
+impl Default for ResponseHeadersConfig {
+    fn default() -> Self {
+        ResponseHeadersConfig {
+            strip: ["connection", "keep-alive", "transfer-encoding", "upgrade", "proxy-authenticate"]
+                .iter().map(|name| name.to_string()).collect(),
+            allow: Vec::new(),
+        }
+    }
+}
+
+impl ResponseHeadersConfig {
+
+    /// Whether the header of an upstream response is relayed to the client, the names are case-insensitive
+    pub fn relays(&self, name: &str) -> bool {
+        let listed = |names: &[String]| names.iter().any(|listed| listed.eq_ignore_ascii_case(name));
+        !listed(&self.strip) && (self.allow.is_empty() || listed(&self.allow))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::response_headers::ResponseHeadersConfig;
+
+    #[test]
+    fn relays_test() {
+        let mut config = ResponseHeadersConfig::default();
+        assert!(config.relays("docker-content-digest"));
+        assert!(config.relays("set-cookie"));
+        assert!(!config.relays("keep-alive"));
+
+        config.strip.push("Set-Cookie".to_string());
+        assert!(!config.relays("set-cookie"));
+
+        // Stripped even when allowed
+        config.allow = vec!["docker-content-digest".to_string(), "content-type".to_string(), "upgrade".to_string()];
+        assert!(config.relays("Content-Type"));
+        assert!(!config.relays("server"));
+        assert!(!config.relays("upgrade"));
+    }
+}
@@ -0,0 +1,96 @@
+// SPDX-License-Identifier: Apache-2.0
+use serde::{Deserialize, Serialize};
+
+/// Streaming of the upstream responses to the client and to the persistence
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct StreamingConfig {
+    /// Size in bytes of the in-memory pipe between the upstream stream and the client response.
+    /// A small buffer wakes up the streaming task for every few KiB, which limits the throughput of
+    /// large layers over fast links, a large one uses more memory per in-flight request.
+    pub buffer_size: usize,
+
+    /// Channel handing over the upstream chunks to the persistence worker
+    pub persist_channel: PersistChannel,
+
+    /// Seconds the upstream can go without sending any data before its response is aborted,
+    /// both the persistence and the client response fail instead of hanging
+    pub chunk_timeout: u64,
+
+    /// Maximum amount of upstream responses streamed at the same time, across all the upstreams.
+    /// By default there is no limit.
+    pub max_in_flight: Option<usize>,
+
+    /// What happens to the requests over the max_in_flight limit
+    pub in_flight_policy: InFlightPolicy,
+
+    /// Whether the persistence workers are shared by all the upstreams or partitioned per upstream
+    pub persist_partition: PersistPartition,
+
+    /// Commands, i.e. blobs and manifests to persist, the command bus queue holds, and so do the queue of each worker pool
+    /// and the one of each of its workers. The queues only take memory for the commands they hold, but a queued
+    /// command keeps the chunks already received for it in memory as well, unless the persist channel is bounded.
+    /// The publishers wait once a queue is full
+    pub command_queue_size: usize,
+
+    /// Whether a blob or a manifest is still downloaded from upstream and cached after the client pulling it disconnected.
+    /// Otherwise the upstream request is aborted and nothing is cached.
+    pub finish_cache_on_disconnect: bool,
+
+    /// Seconds the persistence workers have at shutdown to finish the blobs and manifests they are persisting and
+    /// the queued ones. Whatever is left is abandoned, so that the shutdown fits in e.g. a termination grace period
+    pub drain_timeout: u64,
+}
+
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        StreamingConfig {
+            buffer_size: 64 * 1024,
+            persist_channel: Default::default(),
+            chunk_timeout: 10,
+            max_in_flight: None,
+            in_flight_policy: Default::default(),
+            persist_partition: Default::default(),
+            command_queue_size: 4096,
+            finish_cache_on_disconnect: true,
+            drain_timeout: 25,
+        }
+    }
+}
+
+/// Channel between the upstream stream and the persistence worker
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PersistChannel {
+    /// Never slows down the client, but buffers in memory whatever the disk can't keep up with
+    #[default]
+    Unbounded,
+
+    /// Buffers at most the given amount of chunks, a slow disk slows down the client response too
+    Bounded(usize),
+}
+
+/// How the requests over the in-flight upstream streams limit are treated
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum InFlightPolicy {
+    /// Wait until one of the in-flight streams is done
+    #[default]
+    Wait,
+
+    /// Answer with a 503 right away
+    Reject,
+}
+
+/// How the blobs and manifests to persist are spread across the persistence workers
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PersistPartition {
+    /// One queue and set of workers per kind of content, shared by all the upstreams
+    #[default]
+    Shared,
+
+    /// A queue and set of workers per kind of content and upstream, so that the backlog of a slow
+    /// upstream neither delays the persistence of the other ones nor slows down their clients
+    Upstream,
+}
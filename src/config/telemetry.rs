@@ -0,0 +1,29 @@
+// SPDX-License-Identifier: Apache-2.0
+use serde::{Deserialize, Serialize};
+
+/// Export the traces of the requests, from the client request to the persistence of its content,
+/// to an OpenTelemetry collector
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    /// OTLP over HTTP endpoint of the collector, e.g. `http://otel-collector:4318`, the traces are sent to `/v1/traces`.
+    /// Nothing is exported when not set
+    pub otlp_endpoint: Option<String>,
+
+    /// The `service.name` of the traces
+    pub service_name: String,
+
+    /// Share, between 0 and 1, of the traces started by the cache which are exported.
+    /// The traces continued from a client `traceparent` follow the decision of the client
+    pub sample_ratio: f64,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        TelemetryConfig {
+            otlp_endpoint: None,
+            service_name: "pier-cache".to_string(),
+            sample_ratio: 1.0,
+        }
+    }
+}
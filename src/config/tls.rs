@@ -0,0 +1,32 @@
+// SPDX-License-Identifier: Apache-2.0
+use std::io::BufRead;
+use rustls_pemfile::Item;
+use serde::{Deserialize, Serialize};
+
+/// Certificate served to the clients asking for a hostname via SNI, e.g. one per upstream
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TlsCertificateConfig {
+    /// Hostname the certificate must be valid for
+    pub hostname: String,
+
+    /// The location of the TLS cert file
+    pub cert: String,
+
+    /// The location of the TLS key file
+    pub key: String,
+}
+
+/// The private keys of a PEM file: the PKCS 8 ones first, then the PKCS 1 RSA ones and the SEC1 EC ones
+pub fn private_keys(reader: &mut dyn BufRead) -> std::io::Result<Vec<Vec<u8>>> {
+    let mut keys: Vec<(u8, Vec<u8>)> = rustls_pemfile::read_all(reader)?.into_iter()
+        .filter_map(|item| match item {
+            Item::PKCS8Key(key) => Some((0, key)),
+            Item::RSAKey(key) => Some((1, key)),
+            Item::ECKey(key) => Some((2, key)),
+            _ => None,
+        })
+        .collect();
+    keys.sort_by_key(|(kind, _)| *kind);
+
+    Ok(keys.into_iter().map(|(_, key)| key).collect())
+}
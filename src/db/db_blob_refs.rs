@@ -0,0 +1,195 @@
+// SPDX-License-Identifier: Apache-2.0
+use std::collections::HashSet;
+use sqlx::{Row, Error, Executor, Sqlite, SqliteConnection, SqlitePool};
+use sqlx::query::Query;
+use sqlx::sqlite::{SqliteArguments, SqliteRow};
+use crate::registry::digest::Digest;
+
+/// Record that a tag of an upstream, via the manifest it points to, references a digest
+const BLOB_REF_INSERT_QUERY: &str = "INSERT OR IGNORE INTO blob_refs (digest, upstream, name, tag, manifest) VALUES ($1, $2, $3, $4, $5);";
+
+/// The digests referenced by a tag of an upstream via a manifest
+const BLOB_REFS_FOR_MANIFEST: &str = "SELECT digest FROM blob_refs WHERE upstream = $1 AND name = $2 AND tag = $3 AND manifest = $4;";
+
+/// Drop the references of a tag of an upstream via a manifest
+const BLOB_REFS_DELETE_FOR_MANIFEST: &str = "DELETE FROM blob_refs WHERE upstream = $1 AND name = $2 AND tag = $3 AND manifest = $4;";
+
+/// The digests referenced by a manifest, whatever the tag
+const BLOB_REFS_FOR_MANIFEST_DIGEST: &str = "SELECT digest FROM blob_refs WHERE manifest = $1;";
+
+/// Drop the references via a manifest, whatever the tag
+const BLOB_REFS_DELETE_FOR_MANIFEST_DIGEST: &str = "DELETE FROM blob_refs WHERE manifest = $1;";
+
+/// The digests referenced by a container image name, of an upstream or of all of them when $2 is null
+const BLOB_REFS_FOR_NAME: &str = "SELECT digest FROM blob_refs WHERE name = $1 AND ($2 IS NULL OR upstream = $2);";
+
+/// Drop the references of a container image name, of an upstream or of all of them when $2 is null
+const BLOB_REFS_DELETE_BY_NAME: &str = "DELETE FROM blob_refs WHERE name = $1 AND ($2 IS NULL OR upstream = $2);";
+
+/// How many references a digest has, whatever the upstream: the upstreams can share a storage folder
+const BLOB_REF_COUNT: &str = "SELECT COUNT(*) FROM blob_refs WHERE digest = $1;";
+
+/// Whether any reference was recorded
+const BLOB_REFS_ANY: &str = "SELECT EXISTS(SELECT 1 FROM blob_refs);";
+
+/// Create the blob references database table
+const BLOB_REFS_TABLE: &str = r#"
+-- CREATORS
+CREATE TABLE IF NOT EXISTS blob_refs (
+digest           TEXT NOT NULL,
+upstream         TEXT NOT NULL,
+name             TEXT NOT NULL,
+tag              TEXT NOT NULL,
+manifest         TEXT NOT NULL,
+PRIMARY KEY(digest, upstream, name, tag, manifest)
+);
+
+CREATE INDEX IF NOT EXISTS blob_refs_name_ids ON blob_refs(name, tag);
+CREATE INDEX IF NOT EXISTS blob_refs_manifest_ids ON blob_refs(manifest);
+"#;
+
+/// Database Blob References Helper: which tags, via the manifest they point to,
+/// reference each stored digest, the manifest itself, its config and its layers
+pub struct DBBlobRefs;
+
+impl DBBlobRefs {
+
+    /// Creates the database table
+    pub async fn create_table(pool: &SqlitePool) {
+        pool.execute(BLOB_REFS_TABLE).await.expect("Failed to create the 'blob_refs' table");
+    }
+
+    /// Record that the tag of the upstream references the digests via the manifest
+    pub async fn insert(pool: &SqlitePool, upstream: &str, name: &str, tag: &str, manifest: &Digest, digests: &[Digest]) -> Result<(), Error> {
+        let mut transaction = pool.begin().await?;
+
+        for digest in digests {
+            sqlx::query(BLOB_REF_INSERT_QUERY)
+                .bind(digest.to_string())
+                .bind(upstream)
+                .bind(name)
+                .bind(tag)
+                .bind(manifest.to_string())
+                .execute(&mut *transaction).await?;
+        }
+
+        transaction.commit().await
+    }
+
+    /// Drop the references of the tag of the upstream via the manifest, returning the digests which are not referenced anymore.
+    /// Runs on the connection of the caller, so that it can be part of a larger transaction
+    pub async fn delete_for_manifest(connection: &mut SqliteConnection, upstream: &str, name: &str, tag: &str, manifest: &Digest) -> Result<Vec<Digest>, Error> {
+        let digests = DBBlobRefs::digests(connection, sqlx::query(BLOB_REFS_FOR_MANIFEST).bind(upstream).bind(name).bind(tag).bind(manifest.to_string())).await?;
+        sqlx::query(BLOB_REFS_DELETE_FOR_MANIFEST).bind(upstream).bind(name).bind(tag).bind(manifest.to_string())
+            .execute(&mut *connection).await?;
+        DBBlobRefs::unreferenced(connection, digests).await
+    }
+
+    /// Drop the references via the manifest whatever the tag, returning the digests which are not referenced anymore
+    pub async fn delete_for_manifest_digest(pool: &SqlitePool, manifest: &Digest) -> Result<Vec<Digest>, Error> {
+        let mut transaction = pool.begin().await?;
+
+        let digests = DBBlobRefs::digests(&mut transaction, sqlx::query(BLOB_REFS_FOR_MANIFEST_DIGEST).bind(manifest.to_string())).await?;
+        sqlx::query(BLOB_REFS_DELETE_FOR_MANIFEST_DIGEST).bind(manifest.to_string())
+            .execute(&mut *transaction).await?;
+        let released = DBBlobRefs::unreferenced(&mut transaction, digests).await?;
+
+        transaction.commit().await?;
+        Ok(released)
+    }
+
+    /// Drop the references of the container image name of the upstream, or of every upstream,
+    /// returning the digests which are not referenced anymore
+    pub async fn delete_by_name(pool: &SqlitePool, upstream: Option<&str>, name: &str) -> Result<Vec<Digest>, Error> {
+        let mut transaction = pool.begin().await?;
+
+        let digests = DBBlobRefs::digests(&mut transaction, sqlx::query(BLOB_REFS_FOR_NAME).bind(name).bind(upstream)).await?;
+        sqlx::query(BLOB_REFS_DELETE_BY_NAME).bind(name).bind(upstream)
+            .execute(&mut *transaction).await?;
+        let released = DBBlobRefs::unreferenced(&mut transaction, digests).await?;
+
+        transaction.commit().await?;
+        Ok(released)
+    }
+
+    /// Whether no reference was recorded yet, e.g. the table was just created
+    pub async fn is_empty(pool: &SqlitePool) -> Result<bool, Error> {
+        let any: bool = sqlx::query(BLOB_REFS_ANY)
+            .map(|row: SqliteRow| row.get(0))
+            .fetch_one(pool).await?;
+        Ok(!any)
+    }
+
+    /// The distinct digests returned by the query
+    async fn digests<'q>(connection: &mut SqliteConnection, query: Query<'q, Sqlite, SqliteArguments<'q>>) -> Result<Vec<Digest>, Error> {
+        let digests: Vec<String> = query
+            .map(|row: SqliteRow| row.get(0))
+            .fetch_all(connection).await?;
+
+        let mut unique = HashSet::new();
+        Ok(digests.iter()
+            .filter(|digest| unique.insert(digest.to_string()))
+            .filter_map(|digest| Digest::parse(digest).ok())
+            .collect())
+    }
+
+    /// The digests without any reference left
+    async fn unreferenced(connection: &mut SqliteConnection, digests: Vec<Digest>) -> Result<Vec<Digest>, Error> {
+        let mut unreferenced = Vec::new();
+        for digest in digests {
+            let count: i64 = sqlx::query(BLOB_REF_COUNT)
+                .bind(digest.to_string())
+                .map(|row: SqliteRow| row.get(0))
+                .fetch_one(&mut *connection).await?;
+            if count == 0 {
+                unreferenced.push(digest);
+            }
+        }
+        Ok(unreferenced)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::db::db_blob_refs::DBBlobRefs;
+    use crate::db::pool::DBPool;
+    use crate::registry::digest::Digest;
+
+    fn digest(hash: char) -> Digest {
+        Digest::parse(&format!("sha256:{}", hash.to_string().repeat(64))).expect("Failed to parse digest")
+    }
+
+    #[tokio::test]
+    async fn db_blob_refs_test() {
+
+        // Get an in memory database
+        let pool = DBPool::default().await;
+        DBBlobRefs::create_table(&pool).await;
+        assert!(DBBlobRefs::is_empty(&pool).await.expect("Failed to check the references"));
+
+        // Two images sharing the base layer
+        let (nginx, debian, base, layer) = (digest('a'), digest('b'), digest('c'), digest('d'));
+        DBBlobRefs::insert(&pool, "localhost", "library/nginx", "latest", &nginx, &[nginx.clone(), base.clone(), layer.clone()]).await.expect("Failed to insert the references");
+        DBBlobRefs::insert(&pool, "localhost", "library/debian", "latest", &debian, &[debian.clone(), base.clone()]).await.expect("Failed to insert the references");
+
+        // Recorded once
+        DBBlobRefs::insert(&pool, "localhost", "library/nginx", "latest", &nginx, std::slice::from_ref(&base)).await.expect("Failed to insert the references");
+
+        // The same tag pulled from another upstream
+        DBBlobRefs::insert(&pool, "mirror.local", "library/nginx", "latest", &nginx, &[nginx.clone(), layer.clone()]).await.expect("Failed to insert the references");
+
+        // Releasing the tag of one upstream keeps what the other one references
+        let released = DBBlobRefs::delete_for_manifest(&mut pool.acquire().await.unwrap(), "localhost", "library/nginx", "latest", &nginx).await.expect("Failed to delete the references");
+        assert!(released.is_empty());
+
+        // Only what nginx alone references is released
+        let mut released = DBBlobRefs::delete_by_name(&pool, Some("mirror.local"), "library/nginx").await.expect("Failed to delete the references");
+        released.sort_by_key(|digest| digest.to_string());
+        assert_eq!(vec![nginx.clone(), layer.clone()], released);
+
+        let mut released = DBBlobRefs::delete_by_name(&pool, None, "library/debian").await.expect("Failed to delete the references");
+        released.sort_by_key(|digest| digest.to_string());
+        assert_eq!(vec![debian, base], released);
+        assert!(DBBlobRefs::is_empty(&pool).await.expect("Failed to check the references"));
+    }
+}
@@ -0,0 +1,92 @@
+// SPDX-License-Identifier: Apache-2.0
+use sqlx::{Row, Error, Executor, SqlitePool};
+use sqlx::sqlite::SqliteRow;
+
+/// Return the media type upstream sent the blob with
+const BLOB_TYPE: &str = "SELECT mime FROM blob_types WHERE digest = $1;";
+
+/// Record the media type of a blob, replacing the one it was sent with before
+const BLOB_TYPE_UPSERT_QUERY: &str = "INSERT INTO blob_types (digest, mime) VALUES ($1, $2) ON CONFLICT(digest) DO UPDATE SET mime=EXCLUDED.mime;";
+
+/// Forget the media type of a blob which is not stored anymore
+const BLOB_TYPE_DELETE: &str = "DELETE FROM blob_types WHERE digest = $1;";
+
+/// Create the blob_types database table
+const BLOB_TYPES_TABLE:&str = r#"
+-- CREATORS
+CREATE TABLE IF NOT EXISTS blob_types (
+digest           TEXT NOT NULL,
+mime             TEXT NOT NULL,
+PRIMARY KEY(digest)
+);
+
+"#;
+
+/// Database Blob Types Helper: the media type of the blobs, as sent by upstream, to serve them with it from the cache
+pub struct DBBlobTypes;
+
+impl DBBlobTypes {
+
+    /// Creates the database table
+    pub async fn create_table(pool: &SqlitePool) {
+        pool.execute(BLOB_TYPES_TABLE).await.expect("Failed to create the 'blob_types' table");
+    }
+
+    /// Return the media type of a blob, if it was recorded
+    pub async fn mime_for(pool: &SqlitePool, digest: &str) -> Result<Option<String>, Error> {
+        sqlx::query(BLOB_TYPE)
+            .bind(digest)
+            .map(|row: SqliteRow| row.get(0))
+            .fetch_optional(pool).await
+    }
+
+    /// Record the media type of a blob
+    pub async fn upsert(pool: &SqlitePool, digest: &str, mime: &str) -> Result<u64, Error> {
+
+        let query = sqlx::query(BLOB_TYPE_UPSERT_QUERY)
+            .bind(digest)
+            .bind(mime);
+
+        Ok(query.execute(pool).await?.rows_affected())
+    }
+
+    /// Delete the media type of a blob
+    pub async fn delete(pool: &SqlitePool, digest: &str) -> Result<u64, Error> {
+
+        let query = sqlx::query(BLOB_TYPE_DELETE)
+            .bind(digest);
+
+        Ok(query.execute(pool).await?.rows_affected())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::db::db_blob_types::DBBlobTypes;
+    use crate::db::pool::DBPool;
+
+    #[tokio::test]
+    async fn db_blob_types_test() {
+
+        // Get an in memory database
+        let pool = DBPool::default().await;
+        DBBlobTypes::create_table(&pool).await;
+
+        let digest = "sha256:05c6e08f1d9fdafa03147fcb8f82f124c76d2f70e3d989dc8aadb5e7d7450bec";
+        assert_eq!(None, DBBlobTypes::mime_for(&pool, digest).await.expect("Failed to get the blob media type"));
+
+        DBBlobTypes::upsert(&pool, digest, "application/vnd.docker.image.rootfs.diff.tar.gzip").await.expect("Failed to upsert the blob media type");
+        assert_eq!(Some("application/vnd.docker.image.rootfs.diff.tar.gzip".to_string()),
+                   DBBlobTypes::mime_for(&pool, digest).await.expect("Failed to get the blob media type"));
+
+        // Pulled again through another upstream
+        DBBlobTypes::upsert(&pool, digest, "application/vnd.oci.image.layer.v1.tar+gzip").await.expect("Failed to upsert the blob media type");
+        assert_eq!(Some("application/vnd.oci.image.layer.v1.tar+gzip".to_string()),
+                   DBBlobTypes::mime_for(&pool, digest).await.expect("Failed to get the blob media type"));
+
+        // Evicted
+        assert_eq!(1, DBBlobTypes::delete(&pool, digest).await.expect("Failed to delete the blob media type"));
+        assert_eq!(None, DBBlobTypes::mime_for(&pool, digest).await.expect("Failed to get the blob media type"));
+        assert_eq!(0, DBBlobTypes::delete(&pool, digest).await.expect("Failed to delete the blob media type"));
+    }
+}
@@ -0,0 +1,74 @@
+// SPDX-License-Identifier: Apache-2.0
+use sqlx::{Row, Error, Executor, SqlitePool};
+use sqlx::sqlite::SqliteRow;
+
+/// Return the path the content was first stored at
+const CONTENT_PATH:&str = "SELECT path FROM contents WHERE content_key = $1;";
+
+/// Record where a content is stored, replacing a path which is gone
+const CONTENT_UPSERT_QUERY: &str = "INSERT INTO contents (content_key, path) VALUES ($1, $2) ON CONFLICT(content_key) DO UPDATE SET path=EXCLUDED.path;";
+
+/// Create the contents database table
+const CONTENTS_TABLE:&str = r#"
+-- CREATORS
+CREATE TABLE IF NOT EXISTS contents (
+content_key      TEXT NOT NULL,
+path             TEXT NOT NULL,
+PRIMARY KEY(content_key)
+);
+
+"#;
+
+/// Database Contents Helper: the index of the stored blobs by their content,
+/// regardless of the digest algorithm they were pulled with
+pub struct DBContents;
+
+impl DBContents {
+
+    /// Creates the database table
+    pub async fn create_table(pool: &SqlitePool) {
+        pool.execute(CONTENTS_TABLE).await.expect("Failed to create the 'contents' table");
+    }
+
+    /// Return the path of a content, if it was stored
+    pub async fn path_for(pool: &SqlitePool, content_key: &str) -> Result<Option<String>, Error> {
+        sqlx::query(CONTENT_PATH)
+            .bind(content_key)
+            .map(|row: SqliteRow| row.get(0))
+            .fetch_optional(pool).await
+    }
+
+    /// Record the path a content is stored at
+    pub async fn upsert(pool: &SqlitePool, content_key: &str, path: &str) -> Result<u64, Error> {
+
+        let query = sqlx::query(CONTENT_UPSERT_QUERY)
+            .bind(content_key)
+            .bind(path);
+
+        Ok(query.execute(pool).await?.rows_affected())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::db::db_contents::DBContents;
+    use crate::db::pool::DBPool;
+
+    #[tokio::test]
+    async fn db_contents_test() {
+
+        // Get an in memory database
+        let pool = DBPool::default().await;
+        DBContents::create_table(&pool).await;
+
+        let key = "sha256:05c6e08f1d9fdafa03147fcb8f82f124c76d2f70e3d989dc8aadb5e7d7450bec";
+        assert_eq!(None, DBContents::path_for(&pool, key).await.expect("Failed to get the content path"));
+
+        DBContents::upsert(&pool, key, "/tmp/cache/sha512/first").await.expect("Failed to upsert the content");
+        assert_eq!(Some("/tmp/cache/sha512/first".to_string()), DBContents::path_for(&pool, key).await.expect("Failed to get the content path"));
+
+        // The first copy is gone, e.g. evicted
+        DBContents::upsert(&pool, key, "/tmp/cache/sha256/second").await.expect("Failed to upsert the content");
+        assert_eq!(Some("/tmp/cache/sha256/second".to_string()), DBContents::path_for(&pool, key).await.expect("Failed to get the content path"));
+    }
+}
@@ -0,0 +1,173 @@
+// SPDX-License-Identifier: Apache-2.0
+use sqlx::{Row, Error, Executor, SqlitePool};
+use sqlx::sqlite::SqliteRow;
+use crate::db::stored_mime;
+use crate::models::dead_letter::DeadLetterRecord;
+use crate::models::types::MimeType;
+use crate::registry::digest::Digest;
+
+/// Return all the dead letters, the most recent failures first
+const DEAD_LETTERS:&str = "SELECT upstream, name, reference, digest, mime, reason, attempts, failed_at FROM dead_letters ORDER BY failed_at DESC;";
+
+/// Record a failure, counting the attempts made so far
+const DEAD_LETTER_UPSERT_QUERY: &str = "INSERT INTO dead_letters (upstream, name, reference, digest, mime, reason, attempts, failed_at) VALUES ($1, $2, $3, $4, $5, $6, 1, $7) ON CONFLICT(upstream, name, reference) DO UPDATE SET digest=EXCLUDED.digest, mime=EXCLUDED.mime, reason=EXCLUDED.reason, attempts=dead_letters.attempts + 1, failed_at=EXCLUDED.failed_at;";
+
+/// Delete a dead letter
+const DEAD_LETTER_DELETE_QUERY: &str = "DELETE FROM dead_letters WHERE upstream = $1 AND name = $2 AND reference = $3;";
+
+/// Delete the oldest dead letters beyond the most recent ones
+const DEAD_LETTERS_PRUNE_QUERY: &str = "DELETE FROM dead_letters WHERE rowid NOT IN (SELECT rowid FROM dead_letters ORDER BY failed_at DESC LIMIT $1);";
+
+/// Create the dead letters database table
+const DEAD_LETTERS_TABLE:&str = r#"
+-- CREATORS
+CREATE TABLE IF NOT EXISTS dead_letters (
+upstream         TEXT NOT NULL,
+name             TEXT NOT NULL,
+reference        TEXT NOT NULL,
+digest           TEXT NOT NULL,
+mime             TEXT,
+reason           TEXT NOT NULL,
+attempts         INTEGER NOT NULL,
+failed_at        INTEGER NOT NULL,
+PRIMARY KEY(upstream, name, reference)
+);
+
+"#;
+
+/// Database Dead Letters Helper
+pub struct DBDeadLetters;
+
+impl DBDeadLetters {
+
+    /// Parse the database row, skipping records which can't be parsed
+    fn parse(row: SqliteRow) -> Option<DeadLetterRecord> {
+        let digest = Digest::parse(row.get(3)).ok()?;
+
+        Some(DeadLetterRecord {
+            upstream: row.get(0),
+            name: row.get(1),
+            reference: row.get(2),
+            digest,
+            mime: row.get::<Option<String>, _>(4).map(|mime| stored_mime(&mime)),
+            reason: row.get(5),
+            attempts: row.get(6),
+            failed_at: row.get(7),
+        })
+    }
+
+    /// Creates the database table
+    pub async fn create_table(pool: &SqlitePool) {
+        pool.execute(DEAD_LETTERS_TABLE).await.expect("Failed to create the 'dead_letters' table");
+    }
+
+    /// Return all the dead letters
+    pub async fn dead_letters(pool: &SqlitePool) -> Result<Vec<DeadLetterRecord>, Error> {
+        let records = sqlx::query(DEAD_LETTERS)
+            .map(DBDeadLetters::parse)
+            .fetch_all(pool).await?;
+
+        Ok(records.into_iter().flatten().collect())
+    }
+
+    /// Record a failure, a new one for the same content increases its attempts
+    pub async fn upsert(pool: &SqlitePool, record: &DeadLetterRecord) -> Result<u64, Error> {
+
+        let query = sqlx::query(DEAD_LETTER_UPSERT_QUERY)
+            .bind(&record.upstream)
+            .bind(&record.name)
+            .bind(&record.reference)
+            .bind(record.digest.to_string())
+            .bind(record.mime.as_ref().map(MimeType::as_str))
+            .bind(&record.reason)
+            .bind(record.failed_at);
+
+        Ok(query.execute(pool).await?.rows_affected())
+    }
+
+    /// Delete the dead letter of a content, once it was persisted
+    pub async fn delete(pool: &SqlitePool, upstream: &str, name: &str, reference: &str) -> Result<u64, Error> {
+
+        let query = sqlx::query(DEAD_LETTER_DELETE_QUERY)
+            .bind(upstream)
+            .bind(name)
+            .bind(reference)
+            .execute(pool);
+
+        Ok(query.await?.rows_affected())
+    }
+
+    /// Keep the most recent dead letters only, returns how many were deleted
+    pub async fn prune(pool: &SqlitePool, max_records: i64) -> Result<u64, Error> {
+
+        let query = sqlx::query(DEAD_LETTERS_PRUNE_QUERY)
+            .bind(max_records)
+            .execute(pool);
+
+        Ok(query.await?.rows_affected())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::db::db_dead_letters::DBDeadLetters;
+    use crate::db::pool::DBPool;
+    use crate::models::dead_letter::DeadLetterRecord;
+    use crate::registry::digest::Digest;
+
+    #[tokio::test]
+    async fn db_dead_letters_test() {
+
+        // Get an in memory database
+        let pool = DBPool::default().await;
+        DBDeadLetters::create_table(&pool).await;
+
+        let digest = "sha256:05c6e08f1d9fdafa03147fcb8f82f124c76d2f70e3d989dc8aadb5e7d7450bec";
+        let blob = DeadLetterRecord {
+            upstream: "localhost:8080".to_string(),
+            name: "library/nginx".to_string(),
+            reference: digest.to_string(),
+            digest: Digest::parse(digest).unwrap(),
+            mime: None,
+            reason: "Digest mismatch".to_string(),
+            attempts: 0,
+            failed_at: 100,
+        };
+        let manifest = DeadLetterRecord {
+            reference: "latest".to_string(),
+            mime: Some("application/vnd.oci.image.manifest.v1+json".parse().unwrap()),
+            failed_at: 200,
+            ..blob.clone()
+        };
+
+        assert_eq!(1, DBDeadLetters::upsert(&pool, &blob).await.expect("Failed to upsert the blob"));
+        assert_eq!(1, DBDeadLetters::upsert(&pool, &manifest).await.expect("Failed to upsert the manifest"));
+
+        // Failing again counts the attempts
+        let retried = DeadLetterRecord { reason: "Blob was not fully received from upstream".to_string(), failed_at: 300, ..blob.clone() };
+        DBDeadLetters::upsert(&pool, &retried).await.expect("Failed to upsert the retried blob");
+
+        let dead_letters = DBDeadLetters::dead_letters(&pool).await.expect("Failed to get the dead letters");
+        assert_eq!(2, dead_letters.len());
+        assert_eq!(digest, dead_letters[0].reference);
+        assert_eq!(2, dead_letters[0].attempts);
+        assert_eq!(retried.reason, dead_letters[0].reason);
+        assert!(!dead_letters[0].is_manifest());
+        assert_eq!("latest", dead_letters[1].reference);
+        assert_eq!(1, dead_letters[1].attempts);
+        assert!(dead_letters[1].is_manifest());
+
+        // Persisted at last
+        assert_eq!(1, DBDeadLetters::delete(&pool, &blob.upstream, &blob.name, &blob.reference).await.expect("Failed to delete the dead letter"));
+        assert_eq!(1, DBDeadLetters::dead_letters(&pool).await.expect("Failed to get the dead letters").len());
+
+        // The oldest failures are dropped beyond the limit
+        let older = DeadLetterRecord { reference: "stable".to_string(), failed_at: 50, ..manifest.clone() };
+        DBDeadLetters::upsert(&pool, &older).await.expect("Failed to upsert the older manifest");
+        assert_eq!(1, DBDeadLetters::prune(&pool, 1).await.expect("Failed to prune the dead letters"));
+        let dead_letters = DBDeadLetters::dead_letters(&pool).await.expect("Failed to get the dead letters");
+        assert_eq!(1, dead_letters.len());
+        assert_eq!("latest", dead_letters[0].reference);
+        assert_eq!(0, DBDeadLetters::prune(&pool, 1).await.expect("Failed to prune the dead letters"));
+    }
+}
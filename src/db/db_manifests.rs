@@ -1,16 +1,61 @@
-use sqlx::{Row, Error, Executor, SqlitePool};
+use sqlx::{Row, Error, Executor, Sqlite, SqliteConnection, SqlitePool};
 use sqlx::sqlite::SqliteRow;
+use crate::db::stored_mime;
 use crate::models::manifest_record::ManifestRecord;
 use crate::registry::digest::Digest;
 
-/// Return the sha256 of the manifest for the specific container image name and tag
-const MANIFEST_FOR_TAG:&str = "SELECT name, tag, reference, size, mime FROM manifests where name = $1 AND tag = $2;";
+/// Return the variants, one per media type, of the manifest for the specific upstream, container image name and tag
+const MANIFESTS_FOR_TAG:&str = "SELECT name, tag, reference, size, mime, refreshed_at, upstream FROM manifests where upstream = $1 AND name = $2 AND tag = $3 ORDER BY rowid;";
+
+/// Return the variant of the manifest for the specific upstream, container image name, tag and media type
+const MANIFEST_FOR_VARIANT:&str = "SELECT name, tag, reference, size, mime, refreshed_at, upstream FROM manifests where upstream = $1 AND name = $2 AND tag = $3 AND mime = $4;";
 
 /// Upsert a record in the manifests table
-const MANIFEST_UPSERT_QUERY: &str = "INSERT INTO manifests (name, tag, reference, size, mime) VALUES ($1, $2, $3, $4, $5) ON CONFLICT(name, tag) DO UPDATE SET reference=EXCLUDED.reference;";
+const MANIFEST_UPSERT_QUERY: &str = "INSERT INTO manifests (upstream, name, tag, reference, size, mime, refreshed_at) VALUES ($1, $2, $3, $4, $5, $6, CAST(strftime('%s', 'now') AS INTEGER)) ON CONFLICT(name, tag, upstream, mime) DO UPDATE SET reference=EXCLUDED.reference, size=EXCLUDED.size, refreshed_at=EXCLUDED.refreshed_at;";
+
+/// Mark the variant of the manifest as refreshed from upstream, as long as it still points to the same digest
+const MANIFEST_REFRESH_QUERY: &str = "UPDATE manifests SET refreshed_at = CAST(strftime('%s', 'now') AS INTEGER) WHERE upstream = $1 AND name = $2 AND tag = $3 AND mime = $4 AND reference = $5;";
+
+/// Return the manifests of a container image name, from every upstream
+const MANIFESTS_FOR_NAME:&str = "SELECT name, tag, reference, size, mime, refreshed_at, upstream FROM manifests WHERE name = $1 ORDER BY tag, upstream, mime;";
+
+/// Return at most $4 tags, unless negative, of a container image name of an upstream after the tag $3, in lexicographic order.
+/// The digests, which contain a colon unlike the tags, are left out
+const MANIFEST_TAGS_PAGE: &str = "SELECT DISTINCT tag FROM manifests WHERE upstream = $1 AND name = $2 AND instr(tag, ':') = 0 AND tag > $3 ORDER BY tag LIMIT $4;";
+
+/// Return every indexed manifest
+const MANIFESTS_ALL:&str = "SELECT name, tag, reference, size, mime, refreshed_at, upstream FROM manifests ORDER BY name, tag, upstream, mime;";
+
+/// Return the manifests of a container image name, of an upstream or of all of them when $2 is null
+const MANIFESTS_FOR_UPSTREAM_NAME:&str = "SELECT name, tag, reference, size, mime, refreshed_at, upstream FROM manifests WHERE name = $1 AND ($2 IS NULL OR upstream = $2);";
+
+/// Delete all the manifests of a container image name, of an upstream or of all of them when $2 is null
+const MANIFEST_DELETE_BY_NAME: &str = "DELETE FROM manifests WHERE name = $1 AND ($2 IS NULL OR upstream = $2);";
+
+/// Delete every tag, and digest, pointing to a manifest digest
+const MANIFEST_DELETE_BY_REFERENCE: &str = "DELETE FROM manifests WHERE reference = $1;";
+
+/// Every manifest digest pointed to by a tag, or digest
+const MANIFEST_REFERENCES: &str = "SELECT DISTINCT reference FROM manifests;";
 
-/// Delete a manifest
-const MANIFEST_DELETE_QUERY: &str = "DELETE FROM manifests WHERE name = $1 AND tag = $2;";
+/// Delete every variant of a manifest
+const MANIFEST_DELETE_QUERY: &str = "DELETE FROM manifests WHERE upstream = $1 AND name = $2 AND tag = $3;";
+
+/// The tags of a container image name of an upstream beyond the most recently refreshed ones, the tag $4 being kept first.
+/// The digests, which contain a colon unlike the tags, are left alone
+const MANIFEST_TAGS_BEYOND: &str = r#"
+SELECT tag FROM manifests WHERE upstream = $1 AND name = $2 AND instr(tag, ':') = 0
+GROUP BY tag ORDER BY tag = $4 DESC, MAX(refreshed_at) DESC, MAX(rowid) DESC LIMIT -1 OFFSET $3
+"#;
+
+/// Total size of the manifests grouped by container image name
+const MANIFEST_SIZE_BY_NAME: &str = "SELECT name, SUM(size) FROM manifests GROUP BY name;";
+
+/// Total size of the manifests for a specific container image name
+const MANIFEST_SIZE_FOR_NAME: &str = "SELECT COALESCE(SUM(size), 0) FROM manifests WHERE name = $1;";
+
+/// Count the container image names and the indexed manifests, and sum their sizes
+const MANIFEST_SUMMARY: &str = "SELECT COUNT(DISTINCT name), COUNT(*), COALESCE(SUM(size), 0) FROM manifests;";
 
 /// DANGER: Delete all records
 const MANIFEST_DELETE_ALL:&str = "DELETE from manifests;";
@@ -24,7 +69,9 @@ tag              TEXT NOT NULL,
 reference        TEXT NOT NULL,
 size             INTEGER NOT NULL,
 mime             TEXT NOT NULL,
-PRIMARY KEY(name, tag)
+refreshed_at     INTEGER NOT NULL DEFAULT 0,
+upstream         TEXT NOT NULL DEFAULT '',
+PRIMARY KEY(name, tag, upstream, mime)
 );
 
 CREATE INDEX IF NOT EXISTS manifests_name_ids ON manifests(name);
@@ -33,6 +80,34 @@ CREATE INDEX IF NOT EXISTS manifests_reference_ids ON manifests(reference);
 
 "#;
 
+/// How many columns the primary key of the manifests table has
+const MANIFESTS_KEY_COLUMNS: &str = "SELECT COUNT(*) FROM pragma_table_info('manifests') WHERE pk > 0;";
+
+/// Whether the manifests table records when the tags were refreshed from upstream
+const MANIFESTS_REFRESHED_AT_COLUMN: &str = "SELECT COUNT(*) FROM pragma_table_info('manifests') WHERE name = 'refreshed_at';";
+
+/// The tags of a manifests table created before the refresh time was recorded are stale right away
+const MANIFESTS_ADD_REFRESHED_AT: &str = "ALTER TABLE manifests ADD COLUMN refreshed_at INTEGER NOT NULL DEFAULT 0;";
+
+/// Move aside a manifests table keyed by name and tag only, which holds a single variant per tag,
+/// or by name, tag and media type, which conflates the tags of the upstreams
+const MANIFESTS_BY_TAG_RENAME: &str = r#"
+ALTER TABLE manifests RENAME TO manifests_by_tag;
+DROP INDEX IF EXISTS manifests_name_ids;
+DROP INDEX IF EXISTS manifests_tag_ids;
+DROP INDEX IF EXISTS manifests_reference_ids;
+"#;
+
+/// Copy the records of the table moved aside into the manifests table keyed by upstream and media type as well.
+/// Which upstream they were pulled from is unknown
+const MANIFESTS_BY_TAG_COPY: &str = r#"
+INSERT INTO manifests (name, tag, reference, size, mime, refreshed_at) SELECT name, tag, reference, size, mime, refreshed_at FROM manifests_by_tag;
+DROP TABLE manifests_by_tag;
+"#;
+
+/// Assign the records migrated without their upstream to the given one
+const MANIFESTS_ADOPT: &str = "UPDATE OR IGNORE manifests SET upstream = $1 WHERE upstream = '';";
+
 /// Database Manifests Helper
 pub struct DBManifests;
 
@@ -43,32 +118,68 @@ impl DBManifests {
         let parsed_digest = Digest::parse(row.get(2)).ok();
         ManifestRecord::new(row.get(0), row.get(1),
                             parsed_digest, row.get(3),
-                            row.get(4))
+                            stored_mime(row.get(4)), row.get(5), row.get(6))
     }
 
-    /// Creates the database table
+    /// Creates the database table, migrating the one created before the variants of a tag, the refresh time
+    /// of the tags, or the upstream of the tags were tracked
     pub async fn create_table(pool: &SqlitePool) {
         pool.execute(MANIFESTS_TABLE).await.expect("Failed to create the 'manifests' table");
+
+        let refreshed_at: i64 = sqlx::query_scalar(MANIFESTS_REFRESHED_AT_COLUMN).fetch_one(pool).await
+            .expect("Failed to read the 'manifests' table schema");
+        if refreshed_at == 0 {
+            pool.execute(MANIFESTS_ADD_REFRESHED_AT).await.expect("Failed to add the 'refreshed_at' column to the 'manifests' table");
+        }
+
+        let key_columns: i64 = sqlx::query_scalar(MANIFESTS_KEY_COLUMNS).fetch_one(pool).await
+            .expect("Failed to read the 'manifests' table schema");
+        if key_columns < 4 {
+            DBManifests::migrate_variants(pool).await.expect("Failed to migrate the 'manifests' table");
+        }
+    }
+
+    /// Assign the manifests migrated without their upstream to the upstream, returning how many were.
+    /// Only right when there is a single upstream they can have been pulled from
+    pub async fn adopt(pool: &SqlitePool, upstream: &str) -> Result<u64, Error> {
+        let query = sqlx::query(MANIFESTS_ADOPT)
+            .bind(upstream)
+            .execute(pool);
+
+        Ok(query.await?.rows_affected())
+    }
+
+    /// Key the manifests by upstream and media type as well, keeping the existing records
+    async fn migrate_variants(pool: &SqlitePool) -> Result<(), Error> {
+        let mut transaction = pool.begin().await?;
+
+        (&mut *transaction).execute(MANIFESTS_BY_TAG_RENAME).await?;
+        (&mut *transaction).execute(MANIFESTS_TABLE).await?;
+        (&mut *transaction).execute(MANIFESTS_BY_TAG_COPY).await?;
+
+        transaction.commit().await
     }
 
-    /// Return an optional manifest record
-    pub async fn manifest_for_tag(pool: &SqlitePool, name: &str, tag: &str) -> Result<Option<ManifestRecord>, Error> {
+    /// Return the variants of a manifest pulled from the upstream, one per media type
+    pub async fn manifests_for_tag(pool: &SqlitePool, upstream: &str, name: &str, tag: &str) -> Result<Vec<ManifestRecord>, Error> {
 
-        sqlx::query(MANIFEST_FOR_TAG)
+        sqlx::query(MANIFESTS_FOR_TAG)
+            .bind(upstream)
             .bind(name)
             .bind(tag)
             .map(|row: SqliteRow| {
                 DBManifests::parse(row)
             })
-            .fetch_optional(pool).await
+            .fetch_all(pool).await
 
     }
 
     /// Deletes an entry in the manifest table
-    pub async fn delete(pool: &SqlitePool, name: &str, tag: &str) -> Result<u64, Error> {
+    pub async fn delete(pool: &SqlitePool, upstream: &str, name: &str, tag: &str) -> Result<u64, Error> {
 
         // Build the query
         let query = sqlx::query(MANIFEST_DELETE_QUERY)
+            .bind(upstream)
             .bind(name)
             .bind(tag)
             .execute(pool);
@@ -77,21 +188,194 @@ impl DBManifests {
         Ok(query.await?.rows_affected())
     }
 
+    /// Every tag, and digest, of a container image name, one record per media type
+    pub async fn list_by_name(pool: &SqlitePool, name: &str) -> Result<Vec<ManifestRecord>, Error> {
+
+        sqlx::query(MANIFESTS_FOR_NAME)
+            .bind(name)
+            .map(|row: SqliteRow| {
+                DBManifests::parse(row)
+            })
+            .fetch_all(pool).await
+
+    }
+
+    /// The tags of a container image name of an upstream, at most `n` of them unless `None`, after the tag `last` if any.
+    /// They are in lexicographic order, i.e. `v10` comes before `v9`, as the distribution spec pagination expects
+    pub async fn tags_page(pool: &SqlitePool, upstream: &str, name: &str, n: Option<u32>, last: Option<&str>) -> Result<Vec<String>, Error> {
+
+        sqlx::query(MANIFEST_TAGS_PAGE)
+            .bind(upstream)
+            .bind(name)
+            .bind(last.unwrap_or_default())
+            .bind(n.map(i64::from).unwrap_or(-1))
+            .map(|row: SqliteRow| row.get(0))
+            .fetch_all(pool).await
+
+    }
+
+    /// Every tag, and digest, of every container image name, one record per media type
+    pub async fn list_all(pool: &SqlitePool) -> Result<Vec<ManifestRecord>, Error> {
+
+        sqlx::query(MANIFESTS_ALL)
+            .map(|row: SqliteRow| {
+                DBManifests::parse(row)
+            })
+            .fetch_all(pool).await
+
+    }
+
+    /// Delete all the manifests of a container image name of the upstream, or of every upstream, returning the deleted records
+    pub async fn delete_by_name(pool: &SqlitePool, upstream: Option<&str>, name: &str) -> Result<Vec<ManifestRecord>, Error> {
+
+        let mut transaction = pool.begin().await?;
+
+        let manifests = sqlx::query(MANIFESTS_FOR_UPSTREAM_NAME)
+            .bind(name)
+            .bind(upstream)
+            .map(|row: SqliteRow| {
+                DBManifests::parse(row)
+            })
+            .fetch_all(&mut *transaction).await?;
+
+        sqlx::query(MANIFEST_DELETE_BY_NAME)
+            .bind(name)
+            .bind(upstream)
+            .execute(&mut *transaction).await?;
+
+        transaction.commit().await?;
+
+        Ok(manifests)
+    }
+
+    /// Delete every tag, and digest, pointing to the manifest digest, returning how many were deleted
+    pub async fn delete_by_reference(pool: &SqlitePool, reference: &Digest) -> Result<u64, Error> {
+        let query = sqlx::query(MANIFEST_DELETE_BY_REFERENCE)
+            .bind(reference.to_string())
+            .execute(pool);
+
+        Ok(query.await?.rows_affected())
+    }
+
     /// Upsert a manifest
-    pub async fn upsert(pool: &SqlitePool, name: &str, tag: &str, reference: Digest, size: i32, mime: &str) -> Result<u64, Error> {
+    pub async fn upsert<'e, E: Executor<'e, Database = Sqlite>>(executor: E, upstream: &str, name: &str, tag: &str, reference: Digest, size: i32, mime: &str) -> Result<u64, Error> {
 
         let digest = reference.to_string();
 
         let query = sqlx::query(MANIFEST_UPSERT_QUERY)
+            .bind(upstream)
             .bind(name)
             .bind(tag)
             .bind(digest)
             .bind(size)
             .bind(mime);
 
+        Ok(query.execute(executor).await?.rows_affected())
+    }
+
+    /// Mark the variant of the manifest as refreshed from upstream now, upstream confirmed it still points to the digest
+    pub async fn refresh(pool: &SqlitePool, upstream: &str, name: &str, tag: &str, mime: &str, reference: &Digest) -> Result<u64, Error> {
+
+        let query = sqlx::query(MANIFEST_REFRESH_QUERY)
+            .bind(upstream)
+            .bind(name)
+            .bind(tag)
+            .bind(mime)
+            .bind(reference.to_string());
+
         Ok(query.execute(pool).await?.rows_affected())
     }
 
+    /// Upsert a manifest and return the digest the tag was pointing to until now for the same upstream and media type, if any.
+    /// Runs on the connection of the caller, which is expected to be a transaction
+    pub async fn replace(connection: &mut SqliteConnection, upstream: &str, name: &str, tag: &str, reference: Digest, size: i32, mime: &str) -> Result<Option<Digest>, Error> {
+
+        let previous = sqlx::query(MANIFEST_FOR_VARIANT)
+            .bind(upstream)
+            .bind(name)
+            .bind(tag)
+            .bind(mime)
+            .map(|row: SqliteRow| {
+                DBManifests::parse(row)
+            })
+            .fetch_optional(&mut *connection).await?;
+
+        DBManifests::upsert(&mut *connection, upstream, name, tag, reference, size, mime).await?;
+
+        Ok(previous.and_then(|manifest| manifest.reference))
+    }
+
+    /// Delete the tags of a container image name of the upstream beyond the `max` most recently refreshed ones,
+    /// keeping `tag`, returning the deleted records. Runs on the connection of the caller, which is expected to be a transaction
+    pub async fn prune_tags(connection: &mut SqliteConnection, upstream: &str, name: &str, tag: &str, max: u32) -> Result<Vec<ManifestRecord>, Error> {
+
+        let tags: Vec<String> = sqlx::query(MANIFEST_TAGS_BEYOND)
+            .bind(upstream)
+            .bind(name)
+            .bind(max)
+            .bind(tag)
+            .map(|row: SqliteRow| row.get(0))
+            .fetch_all(&mut *connection).await?;
+
+        let mut pruned = Vec::new();
+        for tag in tags {
+            pruned.extend(sqlx::query(MANIFESTS_FOR_TAG)
+                .bind(upstream)
+                .bind(name)
+                .bind(&tag)
+                .map(|row: SqliteRow| {
+                    DBManifests::parse(row)
+                })
+                .fetch_all(&mut *connection).await?);
+
+            sqlx::query(MANIFEST_DELETE_QUERY)
+                .bind(upstream)
+                .bind(name)
+                .bind(&tag)
+                .execute(&mut *connection).await?;
+        }
+
+        Ok(pruned)
+    }
+
+    /// Return every manifest digest still pointed to by a tag, or digest
+    pub async fn references(pool: &SqlitePool) -> Result<Vec<Digest>, Error> {
+
+        let references: Vec<String> = sqlx::query(MANIFEST_REFERENCES)
+            .map(|row: SqliteRow| row.get(0))
+            .fetch_all(pool).await?;
+
+        Ok(references.iter().filter_map(|reference| Digest::parse(reference).ok()).collect())
+    }
+
+    /// Return the total size of the manifests for every container image name
+    pub async fn size_by_name(pool: &SqlitePool) -> Result<Vec<(String, i64)>, Error> {
+
+        sqlx::query(MANIFEST_SIZE_BY_NAME)
+            .map(|row: SqliteRow| (row.get(0), row.get(1)))
+            .fetch_all(pool).await
+
+    }
+
+    /// Return the total size of the manifests for a container image name
+    pub async fn size_for_name(pool: &SqlitePool, name: &str) -> Result<i64, Error> {
+
+        sqlx::query(MANIFEST_SIZE_FOR_NAME)
+            .bind(name)
+            .map(|row: SqliteRow| row.get(0))
+            .fetch_one(pool).await
+
+    }
+
+    /// Return how many container image names and manifests are indexed, and the total size of the manifests
+    pub async fn summary(pool: &SqlitePool) -> Result<(i64, i64, i64), Error> {
+
+        sqlx::query(MANIFEST_SUMMARY)
+            .map(|row: SqliteRow| (row.get(0), row.get(1), row.get(2)))
+            .fetch_one(pool).await
+
+    }
+
     /// Delete all matches (used for testing purposes only)
     #[allow(dead_code)]
     pub async fn delete_all(pool: &SqlitePool) -> Result<u64, Error> {
@@ -106,6 +390,8 @@ impl DBManifests {
 
 #[cfg(test)]
 mod test {
+    use sqlx::Executor;
+    use crate::dead_letters::unix_now;
     use crate::db::db_manifests::DBManifests;
     use crate::db::pool::DBPool;
     use crate::registry::digest::Digest;
@@ -129,11 +415,11 @@ mod test {
         DBManifests::delete_all(&pool).await.expect("Failed to truncate manifests table");
 
         // add a a new record
-        let total = DBManifests::upsert(&pool, &name, &tag, digest.clone(), size, mime).await.expect("Failed to upsert manifest record");
+        let total = DBManifests::upsert(&pool, "localhost", &name, &tag, digest.clone(), size, mime).await.expect("Failed to upsert manifest record");
         assert_eq!(1, total);
 
         // get the manifest for the name and tag
-        let manifest = DBManifests::manifest_for_tag(&pool, &name, &tag).await.expect("Failed to get manifest for image");
+        let manifest = DBManifests::manifests_for_tag(&pool, "localhost", &name, &tag).await.expect("Failed to get manifest for image").pop();
 
         // Assert we got a manifest
         assert!(manifest.is_some());
@@ -144,14 +430,53 @@ mod test {
         assert_eq!(tag, manifest.tag);
         assert_eq!(digest, manifest.reference.unwrap());
         assert_eq!(size, manifest.size);
-        assert_eq!(mime, manifest.mime);
+        assert_eq!(mime, manifest.mime.as_str());
+
+        // the size is aggregated per image name
+        let sizes = DBManifests::size_by_name(&pool).await.expect("Failed to get the manifest sizes");
+        assert_eq!(vec![(name.clone(), size as i64)], sizes);
+        let total_size = DBManifests::size_for_name(&pool, &name).await.expect("Failed to get the manifest size");
+        assert_eq!(size as i64, total_size);
+        let summary = DBManifests::summary(&pool).await.expect("Failed to summarize the manifests");
+        assert_eq!((1, 1, size as i64), summary);
 
         // Try the upsert functionality now
-        let total = DBManifests::upsert( &pool, &name, &tag, updated_digest.clone(), size, mime).await.expect("Failed to update manifest");
+        let total = DBManifests::upsert( &pool, "localhost", &name, &tag, updated_digest.clone(), size, mime).await.expect("Failed to update manifest");
         assert_eq!(1, total);
 
+        // Moving the tag back returns the digest it was pointing to
+        let previous = DBManifests::replace(&mut pool.acquire().await.unwrap(), "localhost", &name, &tag, digest.clone(), size + 1, mime).await.expect("Failed to replace manifest");
+        assert_eq!(Some(updated_digest.clone()), previous);
+        let references = DBManifests::references(&pool).await.expect("Failed to list the references");
+        assert!(references.contains(&digest));
+        assert!(!references.contains(&updated_digest));
+        let manifest = DBManifests::manifests_for_tag(&pool, "localhost", &name, &tag).await.expect("Failed to get manifest for image").pop().unwrap();
+        assert_eq!(size + 1, manifest.size);
+
+        let previous = DBManifests::replace(&mut pool.acquire().await.unwrap(), "localhost", &name, &tag, updated_digest.clone(), size, mime).await.expect("Failed to replace manifest");
+        assert_eq!(Some(digest.clone()), previous);
+
+        // Refreshed only while the tag still points to the revalidated digest
+        assert_eq!(0, DBManifests::refresh(&pool, "localhost", &name, &tag, mime, &digest).await.expect("Failed to refresh manifest"));
+        assert_eq!(1, DBManifests::refresh(&pool, "localhost", &name, &tag, mime, &updated_digest).await.expect("Failed to refresh manifest"));
+
+        // Another media type is another variant of the tag, not a move
+        let index_digest = Digest::parse("sha256:05c6e08f1d9fdafa03147fcb8f82f124c76d2f70e3d989dc8aadb5e7d7450bec").expect("Failed to parse index digest");
+        let index_mime = "application/vnd.oci.image.index.v1+json";
+        let previous = DBManifests::replace(&mut pool.acquire().await.unwrap(), "localhost", &name, &tag, index_digest.clone(), size, index_mime).await.expect("Failed to replace manifest");
+        assert_eq!(None, previous);
+        let variants = DBManifests::manifests_for_tag(&pool, "localhost", &name, &tag).await.expect("Failed to get manifest for image");
+        assert_eq!(vec![mime, index_mime], variants.iter().map(|variant| variant.mime.as_str()).collect::<Vec<_>>());
+        assert_eq!(Some(index_digest), variants[1].reference);
+
+        // Stored before the media type was validated, e.g. upstream did not send it
+        DBManifests::upsert(&pool, "localhost", &name, "untyped", digest.clone(), size, "").await.expect("Failed to upsert manifest record");
+        let untyped = DBManifests::manifests_for_tag(&pool, "localhost", &name, "untyped").await.expect("Failed to get manifest for image");
+        assert_eq!("application/octet-stream", untyped[0].mime.as_str());
+        DBManifests::delete(&pool, "localhost", &name, "untyped").await.expect("Failed to delete manifest record");
+
         // check if manifest for an image exists
-        let manifest = DBManifests::manifest_for_tag(&pool, &name, &tag).await.expect("Failed to get manifest for image");
+        let manifest = DBManifests::manifests_for_tag(&pool, "localhost", &name, &tag).await.expect("Failed to get manifest for image").into_iter().next();
         assert!(manifest.is_some());
 
         let manifest = manifest.unwrap();
@@ -159,8 +484,153 @@ mod test {
         assert_eq!(tag, manifest.tag);
         assert_eq!(updated_digest, manifest.reference.unwrap());
 
-        // Delete the record
-        let total = DBManifests::delete(&pool, &name, &tag).await.expect("Failed to delete manifest record");
-        assert_eq!(1, total);
+        // Delete the records of every variant
+        let total = DBManifests::delete(&pool, "localhost", &name, &tag).await.expect("Failed to delete manifest record");
+        assert_eq!(2, total);
+
+        // Purge all the manifests of an image, leaving the other images alone
+        DBManifests::upsert(&pool, "localhost", &name, &tag, digest.clone(), size, mime).await.expect("Failed to upsert manifest record");
+        DBManifests::upsert(&pool, "localhost", &name, &digest.to_string(), digest.clone(), size, mime).await.expect("Failed to upsert manifest record");
+        DBManifests::upsert(&pool, "localhost", "library/nginx", "latest", updated_digest.clone(), size, mime).await.expect("Failed to upsert manifest record");
+
+        let listed = DBManifests::list_by_name(&pool, &name).await.expect("Failed to list the manifests of the image");
+        assert_eq!(vec![tag.clone(), digest.to_string()], listed.iter().map(|manifest| manifest.tag.clone()).collect::<Vec<_>>());
+
+        // Of another upstream only
+        DBManifests::upsert(&pool, "mirror.local", &name, &tag, updated_digest.clone(), size, mime).await.expect("Failed to upsert manifest record");
+        let deleted = DBManifests::delete_by_name(&pool, Some("mirror.local"), &name).await.expect("Failed to delete the manifests of the image");
+        assert_eq!(1, deleted.len());
+        assert_eq!("mirror.local", deleted[0].upstream);
+
+        let deleted = DBManifests::delete_by_name(&pool, None, &name).await.expect("Failed to delete the manifests of the image");
+        assert_eq!(2, deleted.len());
+        assert!(deleted.iter().all(|manifest| manifest.name == name && manifest.reference == Some(digest.clone())));
+        assert!(DBManifests::manifests_for_tag(&pool, "localhost", &name, &tag).await.expect("Failed to get manifest for image").is_empty());
+        assert_eq!(vec![updated_digest], DBManifests::references(&pool).await.expect("Failed to get the references"));
+    }
+
+    #[tokio::test]
+    async fn prune_tags_test() {
+        let pool = DBPool::default().await;
+        DBManifests::create_table(&pool).await;
+
+        let digest = Digest::parse("sha256:c1d07892979445e720a5cf1f5abe6a910f45c6d638bf9997d6a807924eee5190").expect("Failed to parse digest");
+        let mime = "application/vnd.oci.image.manifest.v1+json";
+        for tag in ["build-1", "build-2", "build-3", "latest"] {
+            DBManifests::upsert(&pool, "localhost", "ci/app", tag, digest.clone(), 400, mime).await.expect("Failed to upsert manifest record");
+        }
+        DBManifests::upsert(&pool, "localhost", "ci/app", &digest.to_string(), digest.clone(), 400, mime).await.expect("Failed to upsert manifest record");
+        DBManifests::upsert(&pool, "localhost", "library/nginx", "build-0", digest.clone(), 400, mime).await.expect("Failed to upsert manifest record");
+
+        // build-3 was refreshed from upstream before the other tags
+        pool.execute("UPDATE manifests SET refreshed_at = 0 WHERE tag = 'build-3';").await.expect("Failed to age the tag");
+
+        // The least recently refreshed tags go first, the kept tag whatever its refresh time
+        let pruned = DBManifests::prune_tags(&mut pool.acquire().await.unwrap(), "localhost", "ci/app", "build-3", 2).await.expect("Failed to prune the tags");
+        assert_eq!(vec!["build-2", "build-1"], pruned.iter().map(|manifest| manifest.tag.as_str()).collect::<Vec<_>>());
+        let tags = DBManifests::list_by_name(&pool, "ci/app").await.expect("Failed to list the manifests of the image");
+        assert_eq!(vec!["build-3", "latest", digest.to_string().as_str()], tags.iter().map(|manifest| manifest.tag.as_str()).collect::<Vec<_>>());
+
+        // Within the limit, and the other images are left alone
+        assert!(DBManifests::prune_tags(&mut pool.acquire().await.unwrap(), "localhost", "ci/app", "latest", 2).await.expect("Failed to prune the tags").is_empty());
+        assert_eq!(1, DBManifests::list_by_name(&pool, "library/nginx").await.expect("Failed to list the manifests of the image").len());
+    }
+
+    #[tokio::test]
+    async fn tags_page_test() {
+        let pool = DBPool::default().await;
+        DBManifests::create_table(&pool).await;
+
+        let digest = Digest::parse("sha256:c1d07892979445e720a5cf1f5abe6a910f45c6d638bf9997d6a807924eee5190").expect("Failed to parse digest");
+        let mime = "application/vnd.oci.image.manifest.v1+json";
+        for tag in ["v9", "v10", "latest", "alpine"] {
+            DBManifests::upsert(&pool, "localhost", "library/nginx", tag, digest.clone(), 400, mime).await.expect("Failed to upsert manifest record");
+        }
+        DBManifests::upsert(&pool, "localhost", "library/nginx", "latest", digest.clone(), 400, "application/vnd.oci.image.index.v1+json").await.expect("Failed to upsert manifest record");
+        DBManifests::upsert(&pool, "localhost", "library/nginx", &digest.to_string(), digest.clone(), 400, mime).await.expect("Failed to upsert manifest record");
+        DBManifests::upsert(&pool, "docker.io", "library/nginx", "mainline", digest.clone(), 400, mime).await.expect("Failed to upsert manifest record");
+
+        // Once per tag, in lexicographic order, without the digests nor the tags of the other upstreams
+        let page = |n, last| DBManifests::tags_page(&pool, "localhost", "library/nginx", n, last);
+        assert_eq!(vec!["alpine", "latest", "v10", "v9"], page(None, None).await.expect("Failed to list the tags"));
+        assert_eq!(vec!["alpine", "latest"], page(Some(2), None).await.expect("Failed to list the tags"));
+        assert_eq!(vec!["v10"], page(Some(1), Some("latest")).await.expect("Failed to list the tags"));
+
+        // Nothing past the end, nor when no tag is asked for
+        assert!(page(Some(10), Some("v9")).await.expect("Failed to list the tags").is_empty());
+        assert!(page(Some(10), Some("zzz")).await.expect("Failed to list the tags").is_empty());
+        assert!(page(Some(0), None).await.expect("Failed to list the tags").is_empty());
+    }
+
+    #[tokio::test]
+    async fn migrate_variants_test() {
+        let pool = DBPool::default().await;
+
+        // Created before the variants of a tag were tracked
+        pool.execute(r#"
+        CREATE TABLE manifests (name TEXT NOT NULL, tag TEXT NOT NULL, reference TEXT NOT NULL, size INTEGER NOT NULL, mime TEXT NOT NULL, PRIMARY KEY(name, tag));
+        CREATE INDEX manifests_name_ids ON manifests(name);
+        INSERT INTO manifests VALUES ('library/nginx', 'latest', 'sha256:c1d07892979445e720a5cf1f5abe6a910f45c6d638bf9997d6a807924eee5190', 5117, 'application/vnd.oci.image.index.v1+json');
+        "#).await.expect("Failed to create the old manifests table");
+
+        DBManifests::create_table(&pool).await;
+
+        // The upstream of the existing records is unknown
+        assert_eq!(vec![String::new()], DBManifests::list_all(&pool).await.expect("Failed to list the manifests").into_iter().map(|manifest| manifest.upstream).collect::<Vec<_>>());
+        assert_eq!(1, DBManifests::adopt(&pool, "localhost").await.expect("Failed to adopt the manifests"));
+
+        // The existing records are kept, and the tag can have another variant
+        let digest = Digest::parse("sha256:77c8fe4188129f39831d01bd626696d8bbff5831180eb8061041181e1b1d17a0").expect("Failed to parse digest");
+        DBManifests::upsert(&pool, "localhost", "library/nginx", "latest", digest, 400, "application/vnd.oci.image.manifest.v1+json").await.expect("Failed to upsert the variant");
+        assert_eq!(2, DBManifests::manifests_for_tag(&pool, "localhost", "library/nginx", "latest").await.expect("Failed to get the variants").len());
+
+        // The migrated tag is stale right away, the stored one was just refreshed
+        let variants = DBManifests::manifests_for_tag(&pool, "localhost", "library/nginx", "latest").await.expect("Failed to get the variants");
+        assert_eq!(0, variants[0].refreshed_at);
+        assert!((unix_now() - variants[1].refreshed_at).abs() < 5);
+
+        // Migrated once
+        DBManifests::create_table(&pool).await;
+        assert_eq!(2, DBManifests::manifests_for_tag(&pool, "localhost", "library/nginx", "latest").await.expect("Failed to get the variants").len());
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn upstreams_test() {
+        let pool = DBPool::default().await;
+
+        // Created before the upstream of the tags was tracked, with the refresh time of the tags
+        pool.execute(r#"
+        CREATE TABLE manifests (name TEXT NOT NULL, tag TEXT NOT NULL, reference TEXT NOT NULL, size INTEGER NOT NULL, mime TEXT NOT NULL, refreshed_at INTEGER NOT NULL DEFAULT 0, PRIMARY KEY(name, tag, mime));
+        INSERT INTO manifests VALUES ('library/nginx', 'latest', 'sha256:c1d07892979445e720a5cf1f5abe6a910f45c6d638bf9997d6a807924eee5190', 5117, 'application/vnd.oci.image.index.v1+json', 1700000000);
+        "#).await.expect("Failed to create the old manifests table");
+
+        DBManifests::create_table(&pool).await;
+        let migrated = DBManifests::list_all(&pool).await.expect("Failed to list the manifests");
+        assert_eq!(1700000000, migrated[0].refreshed_at);
+        assert!(DBManifests::manifests_for_tag(&pool, "docker.io", "library/nginx", "latest").await.expect("Failed to get the variants").is_empty());
+
+        // The same tag of two upstreams points to different manifests
+        let mime = "application/vnd.oci.image.manifest.v1+json";
+        let digest = Digest::parse("sha256:77c8fe4188129f39831d01bd626696d8bbff5831180eb8061041181e1b1d17a0").expect("Failed to parse digest");
+        let other = Digest::parse("sha256:05c6e08f1d9fdafa03147fcb8f82f124c76d2f70e3d989dc8aadb5e7d7450bec").expect("Failed to parse digest");
+        assert_eq!(None, DBManifests::replace(&mut pool.acquire().await.unwrap(), "docker.io", "library/nginx", "latest", digest.clone(), 400, mime).await.expect("Failed to replace manifest"));
+        assert_eq!(None, DBManifests::replace(&mut pool.acquire().await.unwrap(), "mirror.local", "library/nginx", "latest", other.clone(), 400, mime).await.expect("Failed to replace manifest"));
+
+        let reference = |upstream: &'static str| {
+            let pool = pool.clone();
+            async move { DBManifests::manifests_for_tag(&pool, upstream, "library/nginx", "latest").await.expect("Failed to get the variants").pop().and_then(|manifest| manifest.reference) }
+        };
+        assert_eq!(Some(digest.clone()), reference("docker.io").await);
+        assert_eq!(Some(other.clone()), reference("mirror.local").await);
+
+        // Pruning and deleting the tags of an upstream leaves the other upstreams alone
+        DBManifests::upsert(&pool, "docker.io", "library/nginx", "stable", digest.clone(), 400, mime).await.expect("Failed to upsert manifest record");
+        let pruned = DBManifests::prune_tags(&mut pool.acquire().await.unwrap(), "docker.io", "library/nginx", "stable", 1).await.expect("Failed to prune the tags");
+        assert_eq!(vec!["docker.io"], pruned.iter().map(|manifest| manifest.upstream.as_str()).collect::<Vec<_>>());
+        assert_eq!(Some(other.clone()), reference("mirror.local").await);
+
+        assert_eq!(1, DBManifests::delete(&pool, "mirror.local", "library/nginx", "latest").await.expect("Failed to delete manifest record"));
+        assert_eq!(vec!["", "docker.io"], DBManifests::list_by_name(&pool, "library/nginx").await.expect("Failed to list the manifests")
+            .iter().map(|manifest| manifest.upstream.as_str()).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,85 @@
+// SPDX-License-Identifier: Apache-2.0
+use std::collections::HashMap;
+use sqlx::{Row, Error, Executor, SqlitePool};
+use sqlx::sqlite::SqliteRow;
+
+/// Count more pulls of the container image
+const PULL_UPSERT_QUERY: &str = "INSERT INTO pulls (name, count) VALUES ($1, $2) ON CONFLICT(name) DO UPDATE SET count=pulls.count + EXCLUDED.count;";
+
+/// Return the most pulled container images, the most pulled first
+const PULLS_TOP: &str = "SELECT name, count FROM pulls ORDER BY count DESC, name LIMIT $1;";
+
+/// Create the pulls database table
+const PULLS_TABLE:&str = r#"
+-- CREATORS
+CREATE TABLE IF NOT EXISTS pulls (
+name             TEXT NOT NULL,
+count            INTEGER NOT NULL,
+PRIMARY KEY(name)
+);
+
+"#;
+
+/// Database Pulls Helper: how many times each container image was pulled through the cache
+pub struct DBPulls;
+
+impl DBPulls {
+
+    /// Creates the database table
+    pub async fn create_table(pool: &SqlitePool) {
+        pool.execute(PULLS_TABLE).await.expect("Failed to create the 'pulls' table");
+    }
+
+    /// Count the pulls of the container images at once
+    pub async fn record(pool: &SqlitePool, pulls: &HashMap<String, u64>) -> Result<u64, Error> {
+        let mut transaction = pool.begin().await?;
+
+        let mut recorded = 0;
+        for (name, count) in pulls {
+            recorded += sqlx::query(PULL_UPSERT_QUERY)
+                .bind(name)
+                .bind(*count as i64)
+                .execute(&mut *transaction).await?
+                .rows_affected();
+        }
+
+        transaction.commit().await?;
+        Ok(recorded)
+    }
+
+    /// Return the container images pulled the most with their pulls, at most `limit` of them
+    pub async fn top(pool: &SqlitePool, limit: u32) -> Result<Vec<(String, i64)>, Error> {
+        sqlx::query(PULLS_TOP)
+            .bind(limit)
+            .map(|row: SqliteRow| (row.get(0), row.get(1)))
+            .fetch_all(pool).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use crate::db::db_pulls::DBPulls;
+    use crate::db::pool::DBPool;
+
+    #[tokio::test]
+    async fn db_pulls_test() {
+
+        // Get an in memory database
+        let pool = DBPool::default().await;
+        DBPulls::create_table(&pool).await;
+
+        let pulls = HashMap::from([("library/nginx".to_string(), 2), ("library/alpine".to_string(), 2), ("library/redis".to_string(), 1)]);
+        assert_eq!(3, DBPulls::record(&pool, &pulls).await.expect("Failed to record the pulls"));
+        DBPulls::record(&pool, &HashMap::from([("library/nginx".to_string(), 1)])).await.expect("Failed to record the pulls");
+
+        let top = DBPulls::top(&pool, 2).await.expect("Failed to get the most pulled images");
+        assert_eq!(vec![("library/nginx".to_string(), 3), ("library/alpine".to_string(), 2)], top);
+
+        // Same pulls, by name
+        DBPulls::record(&pool, &HashMap::from([("library/busybox".to_string(), 1)])).await.expect("Failed to record the pulls");
+        let top = DBPulls::top(&pool, 10).await.expect("Failed to get the most pulled images");
+        assert_eq!(vec!["library/nginx", "library/alpine", "library/busybox", "library/redis"],
+                   top.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>());
+    }
+}
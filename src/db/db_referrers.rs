@@ -0,0 +1,166 @@
+// SPDX-License-Identifier: Apache-2.0
+use sqlx::{Row, Error, Executor, SqlitePool};
+use sqlx::sqlite::SqliteRow;
+use crate::models::referrer_record::ReferrerRecord;
+use crate::registry::digest::Digest;
+
+/// Return the manifests referring to a subject
+const REFERRERS_FOR_SUBJECT:&str = "SELECT name, digest, subject, media_type, artifact_type, size, annotations FROM referrers WHERE name = $1 AND subject = $2 ORDER BY digest;";
+
+/// Return the manifests of a specific artifact type referring to a subject
+const REFERRERS_FOR_SUBJECT_AND_TYPE:&str = "SELECT name, digest, subject, media_type, artifact_type, size, annotations FROM referrers WHERE name = $1 AND subject = $2 AND artifact_type = $3 ORDER BY digest;";
+
+/// Upsert a record in the referrers table
+const REFERRER_UPSERT_QUERY: &str = "INSERT INTO referrers (name, digest, subject, media_type, artifact_type, size, annotations) VALUES ($1, $2, $3, $4, $5, $6, $7) ON CONFLICT(name, digest) DO UPDATE SET subject=EXCLUDED.subject, media_type=EXCLUDED.media_type, artifact_type=EXCLUDED.artifact_type, size=EXCLUDED.size, annotations=EXCLUDED.annotations;";
+
+/// Delete all the referrers of a container image name
+const REFERRERS_DELETE_BY_NAME: &str = "DELETE FROM referrers WHERE name = $1;";
+
+/// Create the referrers database table
+const REFERRERS_TABLE:&str = r#"
+-- CREATORS
+CREATE TABLE IF NOT EXISTS referrers (
+name             TEXT NOT NULL,
+digest           TEXT NOT NULL,
+subject          TEXT NOT NULL,
+media_type       TEXT NOT NULL,
+artifact_type    TEXT,
+size             INTEGER NOT NULL,
+annotations      TEXT,
+PRIMARY KEY(name, digest)
+);
+
+CREATE INDEX IF NOT EXISTS referrers_subject_ids ON referrers(name, subject);
+
+"#;
+
+/// Database Referrers Helper
+pub struct DBReferrers;
+
+impl DBReferrers {
+
+    /// Parse the database row, skipping records which can't be parsed
+    fn parse(row: SqliteRow) -> Option<ReferrerRecord> {
+        let digest = Digest::parse(row.get(1)).ok()?;
+        let subject = Digest::parse(row.get(2)).ok()?;
+        let annotations: Option<String> = row.get(6);
+
+        Some(ReferrerRecord {
+            name: row.get(0),
+            digest,
+            subject,
+            media_type: row.get(3),
+            artifact_type: row.get(4),
+            size: row.get(5),
+            annotations: annotations.and_then(|annotations| serde_json::from_str(&annotations).ok()),
+        })
+    }
+
+    /// Creates the database table
+    pub async fn create_table(pool: &SqlitePool) {
+        pool.execute(REFERRERS_TABLE).await.expect("Failed to create the 'referrers' table");
+    }
+
+    /// Return the referrers of a subject, optionally filtered by artifact type
+    pub async fn referrers(pool: &SqlitePool, name: &str, subject: &Digest, artifact_type: Option<&str>) -> Result<Vec<ReferrerRecord>, Error> {
+
+        let query = match artifact_type {
+            Some(artifact_type) => sqlx::query(REFERRERS_FOR_SUBJECT_AND_TYPE)
+                .bind(name)
+                .bind(subject.to_string())
+                .bind(artifact_type.to_string()),
+            None => sqlx::query(REFERRERS_FOR_SUBJECT)
+                .bind(name)
+                .bind(subject.to_string()),
+        };
+
+        let records = query.map(DBReferrers::parse).fetch_all(pool).await?;
+        Ok(records.into_iter().flatten().collect())
+    }
+
+    /// Delete all the referrers of a container image name
+    pub async fn delete_by_name(pool: &SqlitePool, name: &str) -> Result<u64, Error> {
+
+        let query = sqlx::query(REFERRERS_DELETE_BY_NAME)
+            .bind(name)
+            .execute(pool);
+
+        Ok(query.await?.rows_affected())
+    }
+
+    /// Upsert a referrer
+    pub async fn upsert(pool: &SqlitePool, record: &ReferrerRecord) -> Result<u64, Error> {
+
+        let annotations = match &record.annotations {
+            Some(annotations) => Some(serde_json::to_string(annotations).map_err(|e| Error::Protocol(e.to_string()))?),
+            None => None,
+        };
+
+        let query = sqlx::query(REFERRER_UPSERT_QUERY)
+            .bind(&record.name)
+            .bind(record.digest.to_string())
+            .bind(record.subject.to_string())
+            .bind(&record.media_type)
+            .bind(&record.artifact_type)
+            .bind(record.size)
+            .bind(annotations);
+
+        Ok(query.execute(pool).await?.rows_affected())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::db::db_referrers::DBReferrers;
+    use crate::db::pool::DBPool;
+    use crate::models::referrer_record::ReferrerRecord;
+    use crate::registry::digest::Digest;
+
+    #[tokio::test]
+    async fn db_referrers_test() {
+
+        // Get an in memory database
+        let pool = DBPool::default().await;
+        DBReferrers::create_table(&pool).await;
+
+        let name = "library/nginx";
+        let subject = Digest::parse("sha256:05c6e08f1d9fdafa03147fcb8f82f124c76d2f70e3d989dc8aadb5e7d7450bec").unwrap();
+        let signature = ReferrerRecord {
+            name: name.to_string(),
+            digest: Digest::parse("sha256:c1d07892979445e720a5cf1f5abe6a910f45c6d638bf9997d6a807924eee5190").unwrap(),
+            subject: subject.clone(),
+            media_type: "application/vnd.oci.image.manifest.v1+json".to_string(),
+            artifact_type: Some("application/vnd.dev.cosign.artifact.sig.v1+json".to_string()),
+            size: 512,
+            annotations: None,
+        };
+        let sbom = ReferrerRecord {
+            digest: Digest::parse("sha256:77c8fe4188129f39831d01bd626696d8bbff5831180eb8061041181e1b1d17a0").unwrap(),
+            artifact_type: Some("application/spdx+json".to_string()),
+            annotations: Some([("org.opencontainers.image.created".to_string(), "2023-10-01".to_string())].into()),
+            ..signature.clone()
+        };
+
+        assert_eq!(1, DBReferrers::upsert(&pool, &signature).await.expect("Failed to upsert the signature"));
+        assert_eq!(1, DBReferrers::upsert(&pool, &sbom).await.expect("Failed to upsert the sbom"));
+
+        // All the referrers
+        let referrers = DBReferrers::referrers(&pool, name, &subject, None).await.expect("Failed to get the referrers");
+        assert_eq!(2, referrers.len());
+
+        // Filtered by artifact type
+        let referrers = DBReferrers::referrers(&pool, name, &subject, Some("application/spdx+json")).await.expect("Failed to get the referrers");
+        assert_eq!(1, referrers.len());
+        assert_eq!(sbom.digest, referrers[0].digest);
+        assert_eq!(sbom.annotations, referrers[0].annotations);
+
+        // Scoped to the repository
+        let referrers = DBReferrers::referrers(&pool, "library/debian", &subject, None).await.expect("Failed to get the referrers");
+        assert!(referrers.is_empty());
+
+        // Purge the repository
+        assert_eq!(2, DBReferrers::delete_by_name(&pool, name).await.expect("Failed to delete the referrers"));
+        let referrers = DBReferrers::referrers(&pool, name, &subject, None).await.expect("Failed to get the referrers");
+        assert!(referrers.is_empty());
+    }
+}
@@ -1,4 +1,21 @@
 // SPDX-License-Identifier: Apache-2.0
 pub mod pool;
 pub mod db_health;
-pub mod db_manifests;
\ No newline at end of file
+pub mod db_contents;
+pub mod db_dead_letters;
+pub mod db_manifests;
+pub mod db_referrers;
+pub mod db_blob_refs;
+pub mod db_pulls;
+pub mod db_blob_types;
+
+use crate::models::types::MimeType;
+
+/// The media type of a stored record. Some were stored before their media type was validated,
+/// e.g. an empty one when upstream did not send it, those are `application/octet-stream`
+fn stored_mime(mime: &str) -> MimeType {
+    mime.parse().unwrap_or_else(|e| {
+        tracing::warn!("Stored media type is malformed: {}", e);
+        MimeType::default()
+    })
+}
@@ -1,8 +1,16 @@
 // SPDX-License-Identifier: Apache-2.0
-use sqlx::{Executor, SqlitePool};
-use sqlx::sqlite::SqlitePoolOptions;
+use std::str::FromStr;
+use std::time::Duration;
+use sqlx::SqlitePool;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
 use crate::config::db::DBConfig;
+use crate::db::db_blob_refs::DBBlobRefs;
+use crate::db::db_blob_types::DBBlobTypes;
+use crate::db::db_contents::DBContents;
+use crate::db::db_dead_letters::DBDeadLetters;
 use crate::db::db_manifests::DBManifests;
+use crate::db::db_pulls::DBPulls;
+use crate::db::db_referrers::DBReferrers;
 
 /// Database Pool
 pub struct DBPool;
@@ -11,20 +19,30 @@ impl DBPool {
 
     /// Create a new DB Pool from the DBConfig parameter
     pub async fn from_config(config: &DBConfig) -> SqlitePool {
+        // The pragmas are set on every connection of the pool, most of them only apply to the connection they run on
+        let options = SqliteConnectOptions::from_str(&config.uri).expect("Invalid database uri")
+            .journal_mode(SqliteJournalMode::Wal)
+            .busy_timeout(Duration::from_secs(config.busy_timeout))
+            .pragma("cache_size", "10000")
+            .pragma("wal_autocheckpoint", config.wal_autocheckpoint.to_string());
+
         // Build the pool from the config file
         let pool = SqlitePoolOptions::new()
             .min_connections(1)
             .max_connections(config.max_connections)
-            .connect(&config.uri)
+            .connect_with(options)
             .await.expect("Failed to create Database pool");
 
-        pool.execute("PRAGMA journal_mode=WAL;");
-        pool.execute("PRAGMA cache_size=10000;");
-
-        // Create the table
+        // Create the tables
         DBManifests::create_table(&pool).await;
+        DBReferrers::create_table(&pool).await;
+        DBDeadLetters::create_table(&pool).await;
+        DBContents::create_table(&pool).await;
+        DBBlobRefs::create_table(&pool).await;
+        DBPulls::create_table(&pool).await;
+        DBBlobTypes::create_table(&pool).await;
 
-        return pool;
+        pool
     }
 
     pub async fn default() -> SqlitePool {
@@ -34,4 +52,48 @@ impl DBPool {
             .connect("sqlite::memory:")
             .await.expect("Failed to create Database pool")
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::db::DBConfig;
+    use crate::db::pool::DBPool;
+
+    #[tokio::test]
+    async fn from_config_pragmas_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let config = DBConfig {
+            max_connections: 3,
+            uri: format!("sqlite:{}?mode=rwc", folder.path().join("cache.db").display()),
+            busy_timeout: 7,
+            wal_autocheckpoint: 500,
+        };
+        let pool = DBPool::from_config(&config).await;
+
+        // Every connection has them, not only the first one
+        let mut connections = Vec::new();
+        for _ in 0..config.max_connections {
+            let mut connection = pool.acquire().await.unwrap();
+            let busy_timeout: i64 = sqlx::query_scalar("PRAGMA busy_timeout;").fetch_one(&mut *connection).await.unwrap();
+            let wal_autocheckpoint: i64 = sqlx::query_scalar("PRAGMA wal_autocheckpoint;").fetch_one(&mut *connection).await.unwrap();
+            let cache_size: i64 = sqlx::query_scalar("PRAGMA cache_size;").fetch_one(&mut *connection).await.unwrap();
+            assert_eq!(7000, busy_timeout);
+            assert_eq!(500, wal_autocheckpoint);
+            assert_eq!(10000, cache_size);
+            connections.push(connection);
+        }
+    }
+
+    #[tokio::test]
+    async fn from_config_journal_mode_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let config = DBConfig {
+            uri: format!("sqlite:{}?mode=rwc", folder.path().join("cache.db").display()),
+            ..DBConfig::default()
+        };
+        let pool = DBPool::from_config(&config).await;
+
+        let journal_mode: String = sqlx::query_scalar("PRAGMA journal_mode;").fetch_one(&pool).await.unwrap();
+        assert_eq!("wal", journal_mode);
+    }
+}
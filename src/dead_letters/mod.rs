@@ -0,0 +1,253 @@
+// SPDX-License-Identifier: Apache-2.0
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use futures_util::{pin_mut, StreamExt};
+use reqwest::header::ACCEPT;
+use crate::api::egress_budget::EgressBudgets;
+use crate::api::registry::content_type;
+use crate::api::upstream_clients::UpstreamClients;
+use crate::config::app::{AppConfig, UpstreamConfig};
+use crate::config::dead_letters::DeadLettersConfig;
+use crate::config::streaming::PersistChannel;
+use crate::error::error_kind::ErrorKind;
+use crate::error::registry::RegistryError;
+use crate::handlers::command::blob::service::ManifestService;
+use crate::models::chunks::chunk_channel;
+use crate::models::commands::RegistryCommand;
+use crate::models::dead_letter::DeadLetterRecord;
+use crate::pubsub::command_bus::CommandBus;
+use crate::registry::repository::Repository;
+
+/// Fetches again from upstream the blobs and the manifests whose persistence failed,
+/// waiting longer and longer between the attempts
+#[derive(Clone)]
+pub struct DeadLetterRetrier {
+    clients: UpstreamClients,
+    command_bus: Arc<CommandBus>,
+    manifests: Arc<ManifestService>,
+    upstreams: HashMap<String, UpstreamConfig>,
+    persist_channel: PersistChannel,
+    config: DeadLettersConfig,
+    egress_budgets: Arc<EgressBudgets>,
+}
+
+impl DeadLetterRetrier {
+
+    /// New instance of the DeadLetterRetrier
+    pub fn new(clients: UpstreamClients, command_bus: Arc<CommandBus>, manifests: Arc<ManifestService>, config: &AppConfig,
+               egress_budgets: Arc<EgressBudgets>) -> Self {
+        DeadLetterRetrier {
+            clients,
+            command_bus,
+            manifests,
+            upstreams: config.upstreams(),
+            persist_channel: config.streaming.persist_channel.clone(),
+            config: config.dead_letters.clone(),
+            egress_budgets,
+        }
+    }
+
+    /// Periodically drop the oldest dead letters beyond dead_letters->max_records, and retry the ones which are due
+    pub async fn start(self) {
+        let mut ticker = tokio::time::interval(Duration::from_secs(self.config.interval));
+        loop {
+            ticker.tick().await;
+            self.prune().await;
+            if self.config.retry {
+                self.run().await;
+            }
+        }
+    }
+
+    /// Drop the oldest dead letters beyond dead_letters->max_records
+    async fn prune(&self) {
+        match self.manifests.prune_dead_letters(self.config.max_records).await {
+            Ok(0) => {}
+            Ok(pruned) => tracing::info!("Dropped the {} oldest dead letters", pruned),
+            Err(e) => tracing::error!("Failed to prune the dead letters: {}", e.to_string()),
+        }
+    }
+
+    /// Retry once the dead letters which are due.
+    /// Returns the number of blobs and manifests handed over again to the persistence
+    pub async fn run(&self) -> usize {
+        let dead_letters = match self.manifests.dead_letters().await {
+            Ok(dead_letters) => dead_letters,
+            Err(e) => {
+                tracing::error!("Failed to load the dead letters: {}", e.to_string());
+                return 0;
+            }
+        };
+
+        let now = unix_now();
+        let mut retried = 0;
+        for dead_letter in dead_letters.into_iter().filter(|dead_letter| self.is_due(dead_letter, now)) {
+            match self.retry(&dead_letter).await {
+                Ok(_) => retried += 1,
+                Err(e) => {
+                    tracing::error!("Failed to retry {}:{}: {}", dead_letter.name, dead_letter.reference, e);
+
+                    // A failed fetch is a failed attempt as well
+                    let dead_letter = DeadLetterRecord { reason: e.to_string(), failed_at: now, ..dead_letter };
+                    if let Err(e) = self.manifests.persist_dead_letter(&dead_letter).await {
+                        tracing::error!("Failed to persist the dead letter of {}:{}: {}", dead_letter.name, dead_letter.reference, e.to_string());
+                    }
+                }
+            }
+        }
+
+        retried
+    }
+
+    /// Whether the attempts are not exhausted yet and the backoff since the last failure elapsed
+    fn is_due(&self, dead_letter: &DeadLetterRecord, now: i64) -> bool {
+        let backoff = (self.config.retry_delay as i64) << (dead_letter.attempts - 1).clamp(0, 20);
+        dead_letter.attempts < self.config.max_attempts && dead_letter.failed_at + backoff <= now
+    }
+
+    /// Fetch the content from its upstream and hand it over to the persistence,
+    /// which records the dead letter again in case it fails once more
+    async fn retry(&self, dead_letter: &DeadLetterRecord) -> Result<(), RegistryError> {
+        let upstream = self.upstreams.get(&dead_letter.upstream)
+            .ok_or_else(|| RegistryError::new(ErrorKind::NotFound).with_error(format!("upstream {} is not configured", dead_letter.upstream)))?;
+
+        if !self.egress_budgets.allow(&dead_letter.upstream) {
+            return Err(RegistryError::new(ErrorKind::Unavailable).with_error(format!("egress budget of upstream {} is spent", dead_letter.upstream)));
+        }
+
+        let kind = if dead_letter.is_manifest() { "manifests" } else { "blobs" };
+        let url = format!("{}{}", upstream.base_url(), upstream.upstream_path(&format!("/v2/{}/{}/{}", dead_letter.name, kind, dead_letter.digest)));
+
+        let mut request = self.clients.for_upstream(&dead_letter.upstream).get(url);
+        if let Some(mime) = &dead_letter.mime {
+            request = request.header(ACCEPT, mime.as_str());
+        }
+
+        let response = request.send().await
+            .map_err(|e| RegistryError::new(ErrorKind::InternalError).with_error(e.to_string()))?;
+
+        if !response.status().is_success() {
+            return Err(RegistryError::new(ErrorKind::NotFound)
+                .with_error(format!("upstream returned {} for {}", response.status(), response.url())));
+        }
+
+        let repository = Repository::new_with_reference(&dead_letter.name, &dead_letter.reference)?;
+        let (sender, receiver) = chunk_channel(&self.persist_channel);
+        let command = match &dead_letter.mime {
+            Some(mime) => RegistryCommand::PersistManifest(dead_letter.upstream.clone(), repository, Some(dead_letter.digest.clone()), mime.clone(), receiver),
+            None => RegistryCommand::PersistBlob(dead_letter.upstream.clone(), repository, content_type(response.headers()), receiver),
+        };
+        self.command_bus.publish(command).await;
+
+        tracing::info!("Retrying {}:{}, attempt {}", dead_letter.name, dead_letter.reference, dead_letter.attempts + 1);
+
+        let stream = self.egress_budgets.counted(&dead_letter.upstream, response);
+        pin_mut!(stream);
+        while let Some(chunk) = stream.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    // The persistence records the failure
+                    tracing::error!("Failed to stream {}:{} from upstream: {}", dead_letter.name, dead_letter.reference, e.to_string());
+                    sender.abort();
+                    break;
+                }
+            };
+
+            if let Err(e) = sender.send(chunk).await {
+                tracing::error!("Failed to send {}:{} for persistence: {}", dead_letter.name, dead_letter.reference, e.to_string());
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Seconds since the unix epoch, the time unit of the dead letters
+pub fn unix_now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs() as i64).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use sha2::{Digest as Sha2Digest, Sha256};
+    use crate::api::egress_budget::EgressBudgets;
+    use crate::config::app::{AppConfig, UpstreamConfig};
+    use crate::dead_letters::{unix_now, DeadLetterRetrier};
+    use crate::db::db_blob_refs::DBBlobRefs;
+    use crate::db::db_dead_letters::DBDeadLetters;
+    use crate::db::db_manifests::DBManifests;
+    use crate::db::db_referrers::DBReferrers;
+    use crate::db::pool::DBPool;
+    use crate::handlers::command::blob::persist::BlobPersistHandler;
+    use crate::handlers::command::blob::service::ManifestService;
+    use crate::models::dead_letter::DeadLetterRecord;
+    use crate::pubsub::command_bus::CommandBus;
+    use crate::pubsub::subscriber::CommandSubscriberTrait;
+    use crate::registry::digest::Digest;
+    use crate::repository::filesystem::FilesystemStorage;
+
+    const BLOB: &str = "layer content";
+
+    #[actix_web::test]
+    async fn retry_dead_letter_test() {
+        let server = HttpServer::new(|| App::new().default_service(web::to(|| async { HttpResponse::Ok().body(BLOB) })))
+            .bind(("127.0.0.1", 0)).unwrap();
+        let port = server.addrs()[0].port();
+        actix_web::rt::spawn(server.run());
+
+        let folder = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(folder.path().join("sha256")).unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.upstreams.push(UpstreamConfig::for_test("localhost:8080", &format!("http://127.0.0.1:{}", port)));
+
+        let pool = DBPool::default().await;
+        DBManifests::create_table(&pool).await;
+        DBReferrers::create_table(&pool).await;
+        DBBlobRefs::create_table(&pool).await;
+        DBDeadLetters::create_table(&pool).await;
+        let manifests = ManifestService::from_pool(pool);
+
+        let digest = Digest::parse(&format!("sha256:{}", hex::encode(Sha256::digest(BLOB.as_bytes())))).unwrap();
+        let failed = DeadLetterRecord {
+            upstream: "localhost:8080".to_string(),
+            name: "library/nginx".to_string(),
+            reference: digest.to_string(),
+            digest: digest.clone(),
+            mime: None,
+            reason: "Blob was not fully received from upstream".to_string(),
+            attempts: 1,
+            failed_at: unix_now(),
+        };
+        manifests.persist_dead_letter(&failed).await.unwrap();
+
+        let (command_sender, mut commands) = tokio::sync::mpsc::channel(16);
+        let retrier = DeadLetterRetrier::new(reqwest::Client::new().into(), CommandBus::new(command_sender, 16, Default::default()), manifests.clone(), &config,
+                                              Arc::new(EgressBudgets::new(&Default::default())));
+
+        // The backoff did not elapse yet
+        assert_eq!(0, retrier.run().await);
+
+        // Failed twice, an hour ago
+        manifests.persist_dead_letter(&DeadLetterRecord { failed_at: unix_now() - 3600, ..failed.clone() }).await.unwrap();
+        assert_eq!(1, retrier.run().await);
+
+        // The persistence of the retry clears the dead letter
+        let storage = Arc::new(FilesystemStorage::new(config.clone()));
+        let handler = BlobPersistHandler::new(storage.clone(), manifests.clone(), config.storage.clone());
+        handler.run(commands.recv().await.unwrap()).await.expect("the retried blob is persisted");
+
+        assert_eq!(BLOB, std::fs::read_to_string(storage.digest_path(&digest)).unwrap());
+        assert!(manifests.dead_letters().await.unwrap().is_empty());
+
+        // The attempts are exhausted
+        for _ in 0..config.dead_letters.max_attempts {
+            manifests.persist_dead_letter(&DeadLetterRecord { failed_at: 0, ..failed.clone() }).await.unwrap();
+        }
+        assert_eq!(0, retrier.run().await);
+    }
+}
@@ -1,17 +1,42 @@
 // SPDX-License-Identifier: Apache-2.0
 use std::pin::Pin;
 use crate::error::registry::RegistryError;
+use crate::registry::digest::Digest;
 use crate::registry::repository::Repository;
 use async_trait::async_trait;
+use bytes::Bytes;
 use tokio::io::{AsyncRead, AsyncWrite};
 
+/// A stored blob, ready to be served to a client
+pub enum StoredContent {
+    /// A file, served with the support of the Range requests
+    File(Box<actix_files::NamedFile>),
+
+    /// The whole content, kept alive until it is sent even if the blob is removed in the meantime
+    Bytes(Bytes),
+}
+
 /// Interface for reading and storing blobs
 #[async_trait]
 pub trait RepositoryTrait {
     /// Persists a blob to the underlying storage driver
-    async fn persist(&self, repo: Repository) -> Result<Pin<Box<dyn AsyncWrite>>, RegistryError>;
+    async fn persist(&self, repo: Repository) -> Result<Pin<Box<dyn AsyncWrite + Send>>, RegistryError>;
 
     /// Get a buf reader from the underlying storage driver
-    async fn read(&self, repo: Repository) -> Result<Pin<Box<dyn AsyncRead>>, RegistryError>;
+    async fn read(&self, repo: Repository) -> Result<Pin<Box<dyn AsyncRead + Send>>, RegistryError>;
+
+    /// Open a stored blob to serve it
+    async fn open(&self, repo: Repository) -> Result<StoredContent, RegistryError>;
+
+    /// Whether the blob is stored
+    fn is_stored(&self, digest: &Digest) -> bool;
+
+    /// The whole content of a stored blob
+    async fn content(&self, digest: &Digest) -> std::io::Result<Bytes>;
+
+    /// Remove a stored blob, unless a client is still reading it. Returns whether it was removed
+    async fn remove(&self, digest: &Digest) -> std::io::Result<bool>;
 
-}
\ No newline at end of file
+    /// The amount of bytes and the number of unique blobs stored, it can be blocking IO
+    fn usage(&self) -> std::io::Result<(u64, u64)>;
+}
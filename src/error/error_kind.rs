@@ -21,6 +21,11 @@ const JWT_SIGN_ERROR:&str = "JWT_SIGN_ERROR";
 const NOT_FOUND:&str = "NOT_FOUND";
 const MAX_PAYLOAD_REACHED:&str = "PAYLOAD_REACHED_MAX_SIZE_LIMIT";
 const CONFIG_ERROR: &str = "CONFIG_ERROR";
+const UNAVAILABLE:&str = "UNAVAILABLE";
+const UNSUPPORTED:&str = "UNSUPPORTED";
+const DENIED:&str = "DENIED";
+const GATEWAY_TIMEOUT:&str = "GATEWAY_TIMEOUT";
+const BAD_GATEWAY:&str = "BAD_GATEWAY";
 const INVALID_SESSION:&str = "INVALID_SESSION";
 
 const SESSION_ERROR:&str = "SESSION_ERROR";
@@ -124,6 +129,21 @@ pub enum ErrorKind {
 
     /// Error loading config
     ConfigError,
+
+    /// The cache cannot take any more requests for now
+    Unavailable,
+
+    /// The operation is not supported by the cache, e.g. a push
+    Unsupported,
+
+    /// The caller is known but the operation is not allowed, e.g. a deletion disabled by config.yaml
+    Forbidden,
+
+    /// The request did not get a response within its deadline
+    GatewayTimeout,
+
+    /// Upstream could not be reached, e.g. a connection, DNS or TLS error
+    BadGateway,
 }
 
 impl fmt::Display for ErrorKind {
@@ -158,6 +178,11 @@ impl fmt::Display for ErrorKind {
             ErrorKind::RecordNotFound => NOT_FOUND,
             ErrorKind::MaxPayloadError => MAX_PAYLOAD_REACHED,
             ErrorKind::ConfigError => CONFIG_ERROR,
+            ErrorKind::Unavailable => UNAVAILABLE,
+            ErrorKind::Unsupported => UNSUPPORTED,
+            ErrorKind::Forbidden => DENIED,
+            ErrorKind::GatewayTimeout => GATEWAY_TIMEOUT,
+            ErrorKind::BadGateway => BAD_GATEWAY,
         };
 
         write!(f, "{}", kind)
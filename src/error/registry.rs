@@ -35,7 +35,13 @@ pub struct RegistryError {
     pub error: String,
 
     /// Realm for authentication of the registry
-    realm: String
+    realm: String,
+
+    /// Seconds after which the client may try again, sent as Retry-After
+    retry_after: Option<u64>,
+
+    /// Methods the resource supports, sent as Allow with the 405 responses
+    allow: Option<String>,
 }
 
 impl fmt::Debug for RegistryError {
@@ -69,6 +75,15 @@ impl From<serde_json::Error> for RegistryError {
     }
 }
 
+/// Converts from config::ConfigError to module error, the message names the offending field
+impl From<config::ConfigError> for RegistryError {
+    fn from(e: config::ConfigError) -> RegistryError {
+        RegistryError::new(ErrorKind::ConfigError)
+            .with_context("invalid configuration")
+            .with_error(e.to_string())
+    }
+}
+
 impl RegistryError {
 
     pub fn log(&self) {
@@ -77,7 +92,7 @@ impl RegistryError {
 
     /// Creates a new [`Error`](struct.Error.html)
     pub fn new(kind: ErrorKind) -> RegistryError {
-        RegistryError { kind, message: Default::default(), error: Default::default(), realm: Default::default() }
+        RegistryError { kind, message: Default::default(), error: Default::default(), realm: Default::default(), retry_after: None, allow: None }
     }
 
     /// Adds additional context to the [`Error`](struct.Error.html). The additional context will be appended to
@@ -90,12 +105,34 @@ impl RegistryError {
         self
     }
 
+    /// The client has to authenticate, the WWW-Authenticate challenge of the response tells it where, when there is a realm.
+    /// The realm is sent within double quotes, config.yaml auth->realm is validated for it
+    pub fn unauthorized(realm: Option<&str>) -> RegistryError {
+        let mut error = RegistryError::new(ErrorKind::Unauthorized);
+        if let Some(realm) = realm {
+            error.realm = format!("Bearer realm=\"{}\"", realm);
+        }
+        error
+    }
+
     /// Add the original error as string to the RegistryError
     pub fn with_error<S>(mut self, error: S) -> RegistryError where S: AsRef<str> {
         self.error = error.as_ref().to_string();
         self
     }
 
+    /// Ask the client to try again after the given seconds, e.g. while the cache is starting up
+    pub fn with_retry_after(mut self, seconds: u64) -> RegistryError {
+        self.retry_after = Some(seconds);
+        self
+    }
+
+    /// Tell the client which methods the resource supports, empty when it supports none
+    pub fn with_allow<S>(mut self, methods: S) -> RegistryError where S: AsRef<str> {
+        self.allow = Some(methods.as_ref().to_string());
+        self
+    }
+
     /// Returns the status code
     fn status_code(&self) -> StatusCode {
         match self.kind {
@@ -128,9 +165,20 @@ impl RegistryError {
             ErrorKind::JWTokenValidationError => StatusCode::UNAUTHORIZED,
             ErrorKind::JWTokenSignError => StatusCode::UNAUTHORIZED,
 
+            // 403 authenticated but not allowed
+            ErrorKind::Forbidden => StatusCode::FORBIDDEN,
+
             // 413 max request size
             ErrorKind::MaxPayloadError => StatusCode::PAYLOAD_TOO_LARGE,
 
+            // 503 too busy
+            ErrorKind::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+
+            // 405 not a pull
+            ErrorKind::Unsupported => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorKind::GatewayTimeout => StatusCode::GATEWAY_TIMEOUT,
+            ErrorKind::BadGateway => StatusCode::BAD_GATEWAY,
+
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -170,9 +218,20 @@ impl error::ResponseError for RegistryError {
             ErrorKind::JWTokenValidationError => StatusCode::UNAUTHORIZED,
             ErrorKind::JWTokenSignError => StatusCode::UNAUTHORIZED,
 
+            // 403 authenticated but not allowed
+            ErrorKind::Forbidden => StatusCode::FORBIDDEN,
+
             // 413 max request size
             ErrorKind::MaxPayloadError => StatusCode::PAYLOAD_TOO_LARGE,
 
+            // 503 too busy
+            ErrorKind::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+
+            // 405 not a pull
+            ErrorKind::Unsupported => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorKind::GatewayTimeout => StatusCode::GATEWAY_TIMEOUT,
+            ErrorKind::BadGateway => StatusCode::BAD_GATEWAY,
+
             // Internal server error
             ErrorKind::JSONError => StatusCode::INTERNAL_SERVER_ERROR,
             ErrorKind::SQLError => StatusCode::INTERNAL_SERVER_ERROR,
@@ -217,6 +276,14 @@ impl error::ResponseError for RegistryError {
             }
         }
 
+        if let Some(retry_after) = self.retry_after {
+            builder.insert_header((header::RETRY_AFTER, retry_after));
+        }
+
+        if let Some(allow) = &self.allow {
+            builder.insert_header((header::ALLOW, allow.as_str()));
+        }
+
 
         builder.body(body.unwrap())
     }
@@ -0,0 +1,29 @@
+// SPDX-License-Identifier: Apache-2.0
+use std::path::Path;
+
+/// Space of the filesystem backing a folder
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct DiskSpace {
+    /// Bytes available to the process
+    pub available: u64,
+
+    /// Size of the filesystem
+    pub total: u64,
+}
+
+/// Source of the filesystem free space
+pub trait FreeSpace {
+    fn disk_space(&self, path: &Path) -> std::io::Result<DiskSpace>;
+}
+
+/// Queries the actual filesystem (statvfs on unix)
+pub struct FilesystemFreeSpace;
+
+impl FreeSpace for FilesystemFreeSpace {
+    fn disk_space(&self, path: &Path) -> std::io::Result<DiskSpace> {
+        Ok(DiskSpace {
+            available: fs2::available_space(path)?,
+            total: fs2::total_space(path)?,
+        })
+    }
+}
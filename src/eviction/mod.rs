@@ -0,0 +1,238 @@
+// SPDX-License-Identifier: Apache-2.0
+pub mod free_space;
+
+use std::sync::Arc;
+use std::time::Duration;
+use crate::config::eviction::EvictionConfig;
+use crate::eviction::free_space::FreeSpace;
+use crate::handlers::command::blob::service::ManifestService;
+use crate::metrics;
+use crate::registry::digest::Digest;
+use crate::repository::active_reads::Eviction;
+use crate::repository::blob_refs;
+use crate::repository::filesystem::FilesystemStorage;
+
+/// Evicts the least recently used blobs when the storage folder is running out of disk space.
+/// The free space is queried from the filesystem rather than calculated from the stored bytes,
+/// so that it takes into account everything else using the same disk.
+#[derive(Clone)]
+pub struct Evictor {
+    storage: Arc<FilesystemStorage>,
+    manifests: Arc<ManifestService>,
+    free_space: Arc<dyn FreeSpace + Send + Sync>,
+    config: EvictionConfig,
+}
+
+impl Evictor {
+
+    /// New instance of the Evictor
+    pub fn new(storage: Arc<FilesystemStorage>, manifests: Arc<ManifestService>, free_space: Arc<dyn FreeSpace + Send + Sync>, config: EvictionConfig) -> Self {
+        Evictor {
+            storage,
+            manifests,
+            free_space,
+            config,
+        }
+    }
+
+    /// Periodically check the free disk space and evict blobs if needed
+    pub async fn start(self) {
+        if self.config.min_free_percent.is_none() {
+            return;
+        }
+
+        let mut ticker = tokio::time::interval(Duration::from_secs(self.config.interval));
+        loop {
+            ticker.tick().await;
+            self.run().await;
+        }
+    }
+
+    /// Check the free disk space once and evict blobs until the threshold is respected.
+    /// Returns the number of blobs evicted
+    pub async fn run(&self) -> usize {
+        let evictor = self.clone();
+
+        // Walking the folder and deleting files is blocking IO
+        match tokio::task::spawn_blocking(move || evictor.evict()).await {
+            Ok(Ok((evicted, removed))) => {
+                for digest in &removed {
+                    blob_refs::forget_type(&self.storage, &self.manifests, digest).await;
+                }
+                evicted
+            }
+            Ok(Err(e)) => {
+                tracing::error!("Failed to evict blobs: {}", e.to_string());
+                0
+            }
+            Err(e) => {
+                tracing::error!("Failed to run the blobs eviction task: {}", e.to_string());
+                0
+            }
+        }
+    }
+
+    /// The number of blobs evicted, and the digests of the ones removed right away
+    fn evict(&self) -> std::io::Result<(usize, Vec<Digest>)> {
+        let Some(min_free_percent) = self.config.min_free_percent else { return Ok((0, Vec::new())) };
+
+        // The storage folder only: evicting blobs frees nothing in the spool folder, which holds the blobs being written,
+        // the persistence checks its free space before writing into it
+        let space = self.free_space.disk_space(&self.storage.folder())?;
+        metrics::CACHE_DISK_FREE_BYTES.set(space.available as i64);
+
+        let required = (space.total as f64 * min_free_percent / 100.0) as u64;
+        if space.available >= required {
+            return Ok((0, Vec::new()));
+        }
+
+        tracing::warn!("Free disk space {} is below {}% of {}, evicting blobs", space.available, min_free_percent, space.total);
+
+        // Least recently used first
+        let mut blobs = self.storage.blobs()?;
+        blobs.sort_by_key(|blob| blob.last_access);
+
+        let mut available = space.available;
+        let mut evicted = 0;
+        let mut removed = Vec::new();
+        for blob in blobs {
+            if available >= required {
+                break;
+            }
+
+            match self.storage.evict(blob.path.clone()) {
+                Ok(Eviction::Removed) => {
+                    metrics::CACHE_DISK_BYTES.sub(blob.size as i64);
+                    metrics::CACHE_BLOB_COUNT.dec();
+                    removed.push(blob.digest.clone());
+                }
+                Ok(Eviction::Deferred) => {}
+                Ok(Eviction::Skipped) => {
+                    tracing::debug!("Skipped eviction of blob being served: {}", blob.digest);
+                    continue;
+                }
+                Err(e) => {
+                    tracing::error!("Failed to evict blob {}: {}", blob.digest, e.to_string());
+                    continue;
+                }
+            }
+
+            available += blob.size;
+            evicted += 1;
+            metrics::CACHE_EVICTED_BLOBS.inc();
+        }
+
+        tracing::info!("Evicted {} blobs", evicted);
+
+        Ok((evicted, removed))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+    use crate::config::app::AppConfig;
+    use crate::config::eviction::EvictionConfig;
+    use crate::eviction::Evictor;
+    use crate::db::db_blob_types::DBBlobTypes;
+    use crate::db::pool::DBPool;
+    use crate::eviction::free_space::{DiskSpace, FreeSpace};
+    use crate::handlers::command::blob::service::ManifestService;
+    use crate::registry::digest::Digest;
+    use crate::repository::filesystem::FilesystemStorage;
+
+    /// Reports a fixed amount of free space
+    struct MockFreeSpace(DiskSpace);
+
+    impl FreeSpace for MockFreeSpace {
+        fn disk_space(&self, _path: &Path) -> std::io::Result<DiskSpace> {
+            Ok(self.0)
+        }
+    }
+
+    /// Store three 1000 bytes blobs, the first one being the least recently accessed
+    fn storage_with_blobs(folder: &tempfile::TempDir) -> (Arc<FilesystemStorage>, Vec<Digest>) {
+        let storage = Arc::new(FilesystemStorage::new(AppConfig::with_storage_folder(folder.path().to_str().unwrap())));
+
+        let digests: Vec<Digest> = ["a1", "b2", "c3"].iter()
+            .map(|hash| Digest::parse(&format!("sha256:{}", hash.repeat(32))).unwrap())
+            .collect();
+
+        for (age, digest) in digests.iter().rev().enumerate() {
+            let path = storage.digest_path(digest);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, vec![0u8; 1000]).unwrap();
+
+            let accessed = SystemTime::now() - Duration::from_secs(3600 * (age as u64 + 1));
+            let times = std::fs::FileTimes::new().set_accessed(accessed).set_modified(accessed);
+            std::fs::File::options().write(true).open(&path).unwrap().set_times(times).unwrap();
+        }
+
+        (storage, digests)
+    }
+
+    async fn manifests() -> Arc<ManifestService> {
+        let pool = DBPool::default().await;
+        DBBlobTypes::create_table(&pool).await;
+        ManifestService::from_pool(pool)
+    }
+
+    fn evictor(storage: Arc<FilesystemStorage>, manifests: Arc<ManifestService>, available: u64) -> Evictor {
+        let free_space = MockFreeSpace(DiskSpace { available, total: 10000 });
+        let config = EvictionConfig { min_free_percent: Some(20.0), ..Default::default() };
+        Evictor::new(storage, manifests, Arc::new(free_space), config)
+    }
+
+    #[tokio::test]
+    async fn eviction_low_free_space_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let (storage, digests) = storage_with_blobs(&folder);
+        let manifests = manifests().await;
+        let mime = "application/vnd.oci.image.layer.v1.tar+gzip".parse().unwrap();
+        for digest in &digests {
+            manifests.persist_blob_type(digest, &mime).await.unwrap();
+        }
+
+        // 5% free, 20% required: 1500 bytes have to be freed
+        let evicted = evictor(storage.clone(), manifests.clone(), 500).run().await;
+        assert_eq!(2, evicted);
+
+        // The least recently used blobs are gone, and so are their media types
+        assert!(!storage.digest_path(&digests[0]).exists());
+        assert!(!storage.digest_path(&digests[1]).exists());
+        assert!(storage.digest_path(&digests[2]).exists());
+        assert_eq!(None, manifests.blob_type(&digests[0]).await.unwrap());
+        assert_eq!(None, manifests.blob_type(&digests[1]).await.unwrap());
+        assert_eq!(Some(mime), manifests.blob_type(&digests[2]).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn eviction_enough_free_space_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let (storage, digests) = storage_with_blobs(&folder);
+
+        let evicted = evictor(storage.clone(), manifests().await, 5000).run().await;
+        assert_eq!(0, evicted);
+
+        for digest in digests {
+            assert!(storage.digest_path(&digest).exists());
+        }
+    }
+
+    #[tokio::test]
+    async fn eviction_skips_blob_being_served_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let (storage, digests) = storage_with_blobs(&folder);
+
+        // The least recently used blob is being served, so the next ones go instead
+        let _guard = storage.acquire_read(&digests[0]);
+        let evicted = evictor(storage.clone(), manifests().await, 500).run().await;
+        assert_eq!(2, evicted);
+
+        assert!(storage.digest_path(&digests[0]).exists());
+        assert!(!storage.digest_path(&digests[1]).exists());
+        assert!(!storage.digest_path(&digests[2]).exists());
+    }
+}
@@ -1,52 +1,158 @@
 // SPDX-License-Identifier: Apache-2.0
+use std::future::Future;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use async_trait::async_trait;
 use bytes::Bytes;
+use parking_lot::Mutex;
 use tokio::fs::OpenOptions;
 use tokio::io::{AsyncSeekExt, AsyncWriteExt};
-use tokio::sync::mpsc::UnboundedReceiver;
+use crate::config::app::{Durability, StorageConfig, TagMovedPolicy};
+use crate::config::driver::StorageDriver;
+use crate::config::streaming::PersistChannel;
+use crate::dead_letters::unix_now;
+use crate::driver::RepositoryTrait;
+use crate::error::registry::RegistryError;
+use crate::eviction::free_space::{FilesystemFreeSpace, FreeSpace};
 use crate::handlers::command::blob::service::ManifestService;
+use crate::metrics;
+use crate::models::chunks::{chunk_channel, ChunkReceiver};
 use crate::models::commands::RegistryCommand;
+use crate::models::dead_letter::DeadLetterRecord;
 use crate::models::events::RegistryEvent;
+use crate::models::referrer_record::ReferrerRecord;
+use crate::models::types::{ManifestSize, MimeType};
 use crate::pubsub::subscriber::CommandSubscriberTrait;
-use crate::registry::digest::Digest;
+use crate::registry::digest::{Digest, DigestAlgorithm, DigestHasher};
+use crate::registry::manifest::{Manifest, DOCKER_SIGNED_MANIFEST_V1};
 use crate::registry::repository::Repository;
+use crate::repository::blob_refs;
 use crate::repository::filesystem::FilesystemStorage;
 
+/// How many times the manifest indexing is attempted
+const INDEX_ATTEMPTS: u32 = 3;
+
+/// Delay between the manifest indexing attempts, multiplied by the attempt number
+const INDEX_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Delay before retrying a failed blob rename, doubled at every attempt
+const RENAME_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// Delay during which the blobs stored are gathered to be synced together, with the async durability
+const SYNC_BATCH_DELAY: Duration = Duration::from_secs(1);
+
+/// A blob successfully stored in the cache
+struct PersistedBlob {
+    /// Amount of bytes stored
+    size: u64,
+
+    /// Whether the blob was not stored before
+    created: bool,
+}
+
+/// Why a blob or a manifest was not persisted
+enum PersistError {
+    /// Over the configured maximum size, fetching it again would not help
+    Oversized(u64),
+
+    /// The free disk space, in bytes, is below the configured minimum
+    LowSpace(u64),
+
+    /// Anything else, e.g. an upstream stream which broke off or a digest mismatch
+    Failed(String),
+}
+
 /// Manages the blob persistence
 pub struct BlobPersistHandler {
     service: Arc<FilesystemStorage>,
-    manifests: Arc<ManifestService>
+    manifests: Arc<ManifestService>,
+    config: StorageConfig,
+    free_space: Arc<dyn FreeSpace + Send + Sync>,
+
+    /// The blobs stored and not synced yet, with the async durability
+    pending_syncs: Arc<Mutex<Vec<PathBuf>>>,
 }
 
 impl BlobPersistHandler {
 
     /// Create a new ARC wrapped instance of the RoleAddSubscriber
-    pub fn new(service: Arc<FilesystemStorage>, manifests: Arc<ManifestService>) -> Arc<Self> {
+    pub fn new(service: Arc<FilesystemStorage>, manifests: Arc<ManifestService>, config: StorageConfig) -> Arc<Self> {
+        BlobPersistHandler::with_free_space(service, manifests, config, Arc::new(FilesystemFreeSpace))
+    }
+
+    /// Same as `new`, with the source of the free disk space checked against storage->min_free_bytes
+    pub fn with_free_space(service: Arc<FilesystemStorage>, manifests: Arc<ManifestService>, config: StorageConfig,
+                           free_space: Arc<dyn FreeSpace + Send + Sync>) -> Arc<Self> {
         Arc::new(BlobPersistHandler {
             service,
-            manifests
+            manifests,
+            config,
+            free_space,
+            pending_syncs: Default::default(),
         })
     }
 
-    /// Persists the blob and verifies its sha256
-    async fn persist(&self, repository: Repository, mut receiver: UnboundedReceiver<Bytes>) -> Option<RegistryEvent> {
+    /// Whether there is enough free disk space to store one more blob, in the storage folder and in the spool folder
+    /// the blob is written into first, which can be another filesystem. It is checked before writing anything
+    /// so that a burst of large blobs can't fill the disk faster than the eviction frees it
+    fn check_free_space(&self) -> Result<(), PersistError> {
+        let Some(min_free_bytes) = self.config.min_free_bytes else { return Ok(()) };
+
+        let spool = self.config.spool_dir.as_ref().map(PathBuf::from);
+        for folder in std::iter::once(self.service.folder()).chain(spool) {
+            match self.free_space.disk_space(&folder) {
+                Ok(space) if space.available < min_free_bytes => return Err(PersistError::LowSpace(space.available)),
+                Ok(_) => {}
+                Err(e) => {
+                    // The write itself tells whether the disk is full
+                    tracing::warn!("Failed to check the free disk space of {}: {}", folder.display(), e.to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Persists the blob and verifies its sha256, returns why it failed otherwise.
+    /// The persistence is aborted as soon as the blob grows over `max_size`.
+    async fn persist(&self, storage: &FilesystemStorage, repository: Repository, max_size: Option<u64>, mut receiver: ChunkReceiver) -> Result<PersistedBlob, PersistError> {
+        // The other drivers are written into as the blob is received, it is stored once its digest matched
+        if self.config.driver != StorageDriver::FileSystem {
+            return persist_to_driver(storage.driver(), repository, max_size, receiver).await;
+        }
+
+        // Not even started, dropping the receiver leaves the client response alone
+        self.check_free_space()?;
+
         // The original digest
         let original_digest = repository.clone().digest.unwrap();
 
-        // Build the blob file path
-        let file_path_tmp = self.service.blob_path_tmp(repository.clone());
-        let file_path_final = self.service.blob_path(repository.clone());
+        // Build the blob file path, the tmp one is unique to this attempt
+        let file_path_tmp = storage.blob_path_tmp(repository.clone());
+        let file_path_final = storage.blob_path(repository.clone());
+
+        // The folder of an upstream storage does not exist until its first blob is stored
+        if let Some(folder) = file_path_tmp.parent() {
+            if let Err(e) = tokio::fs::create_dir_all(folder).await {
+                return Err(PersistError::Failed(format!("Failed to create blob folder {:?}: {}", folder, e)));
+            }
+        }
 
         // Create the file options
         let mut options = OpenOptions::new();
 
         // We need to have a reference otherwise the Options get freed
-        let options = options.read(true).write(true).create(true);
+        let options = options.read(true).write(true).create(true).truncate(true);
 
         // Now open the file
         let file = options.open(&file_path_tmp).await;
 
+        // Amount of bytes written
+        let mut size = 0;
+
+        // Whether the blob was not stored before
+        let created;
+
         // Check if we could open a file handle
         match file {
             // Success
@@ -56,20 +162,48 @@ impl BlobPersistHandler {
                 while let Some(chunk) = receiver.recv().await {
                     // Write the whole chunk
                     if let Err(e) = file.write(chunk.as_ref()).await {
-                        tracing::error!("Failed to persist blob: {}", e.to_string());
-                        return None;
+                        drop(file);
+                        remove_tmp(&file_path_tmp).await;
+                        return Err(PersistError::Failed(format!("Failed to write blob: {}", e)));
+                    }
+                    size += chunk.len() as u64;
+
+                    // Do not fill the disk with an unbounded upstream response
+                    if let Some(max_size) = max_size.filter(|max_size| size > *max_size) {
+                        metrics::CACHE_OVERSIZED.inc();
+
+                        drop(file);
+                        if let Err(e) = tokio::fs::remove_file(&file_path_tmp).await {
+                            tracing::error!("Failed to remove oversized blob: {}", e.to_string());
+                        }
+                        return Err(PersistError::Oversized(max_size));
                     }
                 }
 
-                // Sync all the data to disk, so that we can calculate the file hash
-                if let Err(e) = file.sync_data().await {
-                    tracing::error!("Failed to sync file to disk: {} {}", original_digest, e.to_string());
-                    return None;
+                // The upstream stream broke off, what we got is not the whole blob
+                if receiver.is_aborted() {
+                    drop(file);
+                    if let Err(e) = tokio::fs::remove_file(&file_path_tmp).await {
+                        tracing::error!("Failed to remove incomplete blob: {}", e.to_string());
+                    }
+                    return Err(PersistError::Failed("Blob was not fully received from upstream".to_string()));
+                }
+
+                // Sync all the data to disk, or at least hand it over to the operating system, so that we can calculate the file hash
+                let written = match self.config.durability {
+                    Durability::Sync => file.sync_data().await.map_err(|e| format!("Failed to sync file to disk: {}", e)),
+                    Durability::Async | Durability::None => file.flush().await.map_err(|e| format!("Failed to flush file: {}", e)),
+                };
+                if let Err(e) = written {
+                    drop(file);
+                    remove_tmp(&file_path_tmp).await;
+                    return Err(PersistError::Failed(e));
                 }
 
                 if let Err(e) = file.rewind().await {
-                    tracing::error!("Failed to rewind file {} {}", original_digest, e.to_string());
-                    return None;
+                    drop(file);
+                    remove_tmp(&file_path_tmp).await;
+                    return Err(PersistError::Failed(format!("Failed to rewind file: {}", e)));
                 }
 
                 // Calculate the sha256 to make sure the cached content is valid
@@ -81,40 +215,525 @@ impl BlobPersistHandler {
                         // This means that the digest are different, so there corrupted data
                         if blob_digest != original_digest {
 
-                            // log it
-                            tracing::error!("Digest mismatch {} - {}", blob_digest, original_digest);
-
                             // delete the file now - no reason to keep around broken data
                             if let Err(e) = tokio::fs::remove_file(file_path_tmp).await {
                                 tracing::error!("Failed to remove corrupted blob: {}", e.to_string());
                             }
-                            return None;
+                            return Err(PersistError::Failed(format!("Digest mismatch {} - {}", blob_digest, original_digest)));
                         }
                     }
                     Err(e) => {
-                        tracing::error!("Failed to calculate blob digest: {}", e.to_string());
-                        return None;
+                        remove_tmp(&file_path_tmp).await;
+                        return Err(PersistError::Failed(format!("Failed to calculate blob digest: {}", e)));
                     }
                 }
 
                 // if we got here, it means the blob was stored successfully and the digest was good
 
-                // Now move the file from a tmp one to the final one
-                if let Err(e) = tokio::fs::rename(file_path_tmp, file_path_final).await {
-                    tracing::error!("Failed to rename blob: {}", e.to_string());
-                    return None;
+                // Whether we are replacing a blob which was already stored
+                let replaced = tokio::fs::metadata(&file_path_final).await.ok();
+
+                // The sha256 of the content, regardless of the digest algorithm it was pulled with
+                let content_key = match self.config.deduplicate {
+                    true => content_key(&original_digest, &file_path_tmp).await,
+                    false => None,
+                };
+
+                // Link to the identical content if it is already stored, otherwise store this copy
+                let linked = match (&content_key, &replaced) {
+                    (Some(content_key), None) => self.link_content(content_key, &file_path_tmp, &file_path_final).await,
+                    _ => false,
+                };
+
+                if !linked {
+                    // Now move the file from a tmp one to the final one
+                    let sync = self.config.durability == Durability::Sync;
+                    if let Err(e) = rename_blob(file_path_tmp, file_path_final.clone(), self.config.rename_attempts, |tmp, path| move_blob(tmp, path, sync)).await {
+                        return Err(PersistError::Failed(format!("Failed to rename blob: {}", e)));
+                    }
+
+                    if let Some(content_key) = &content_key {
+                        if let Err(e) = self.manifests.persist_content(content_key, &file_path_final).await {
+                            tracing::error!("Failed to index the content of blob {}: {}", original_digest, e.to_string());
+                        }
+                    }
+
+                    if self.config.durability == Durability::Async {
+                        self.sync_later(file_path_final.clone());
+                    }
                 }
 
+                created = replaced.is_none();
+
+                // Keep the disk usage metrics up to date until the next full recalculation
+                match replaced {
+                    Some(metadata) => metrics::CACHE_DISK_BYTES.add(size as i64 - metadata.len() as i64),
+                    None if linked => {
+                        metrics::CACHE_DEDUPLICATED_BLOBS.inc();
+                        metrics::CACHE_BLOB_COUNT.inc();
+                    }
+                    None => {
+                        metrics::CACHE_DISK_BYTES.add(size as i64);
+                        metrics::CACHE_BLOB_COUNT.inc();
+                    }
+                }
 
                 tracing::info!("Blob stored in cache successfully: {}/{}", repository.name, original_digest);
             }
             Err(e) => {
-                tracing::error!("failed to persist blob: {:?} {}", file_path_final, e.to_string());
-                return None
+                return Err(PersistError::Failed(format!("Failed to open blob file {:?}: {}", file_path_tmp, e)));
+            }
+        }
+
+        Ok(PersistedBlob { size, created })
+    }
+
+    /// Sync the blob to the disk along with the other ones stored within SYNC_BATCH_DELAY
+    fn sync_later(&self, path: PathBuf) {
+        let mut pending_syncs = self.pending_syncs.lock();
+        pending_syncs.push(path);
+
+        // The first one of the batch syncs all of them
+        if pending_syncs.len() == 1 {
+            let pending_syncs = self.pending_syncs.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(SYNC_BATCH_DELAY).await;
+                let paths = std::mem::take(&mut *pending_syncs.lock());
+                for path in paths {
+                    // Evicted in the meantime, there is nothing left to sync
+                    let synced = match tokio::fs::File::open(&path).await {
+                        Ok(file) => file.sync_data().await,
+                        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = synced {
+                        tracing::warn!("Failed to sync blob {:?} to disk: {}", path, e.to_string());
+                    }
+                }
+            });
+        }
+    }
+
+    /// Hard link the final path to the identical content stored under another digest algorithm,
+    /// dropping the downloaded copy. Returns false when there is nothing to link to, e.g. it was evicted
+    async fn link_content(&self, content_key: &Digest, file_path_tmp: &Path, file_path_final: &Path) -> bool {
+        let existing = match self.manifests.content_path(content_key).await {
+            Ok(Some(existing)) => existing,
+            Ok(None) => return false,
+            Err(e) => {
+                tracing::error!("Failed to look up the content {}: {}", content_key, e.to_string());
+                return false;
+            }
+        };
+
+        // A hard link, unlike a symlink, keeps the content around when the other path is evicted
+        if let Err(e) = tokio::fs::hard_link(&existing, file_path_final).await {
+            tracing::warn!("Failed to link {:?} to the identical content {:?}: {}", file_path_final, existing, e.to_string());
+            return false;
+        }
+
+        if let Err(e) = tokio::fs::remove_file(file_path_tmp).await {
+            tracing::error!("Failed to remove deduplicated blob: {}", e.to_string());
+        }
+
+        tracing::info!("Blob {:?} linked to the identical content {:?}", file_path_final, existing);
+        true
+    }
+
+    /// Index the manifest tag and prune the tags beyond `max_tags`, retrying in case of transient database errors.
+    /// Returns the digest the tag was pointing to until now, and the digests the pruned tags do not reference anymore
+    async fn index_manifest(&self, upstream: &str, repository: &Repository, digest: &Digest, size: u64, mime: &MimeType, max_tags: Option<u32>) -> Result<(Option<Digest>, Vec<Digest>), RegistryError> {
+        let mut attempt = 1;
+        loop {
+            match self.manifests.persist(upstream, repository, digest.clone(), size as ManifestSize, mime, max_tags).await {
+                Ok(indexed) => return Ok(indexed),
+                Err(e) if attempt < INDEX_ATTEMPTS => {
+                    tracing::warn!("failed to persist manifest index, attempt {}/{}: {}", attempt, INDEX_ATTEMPTS, e.to_string());
+                    tokio::time::sleep(INDEX_RETRY_DELAY * attempt).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Receive the whole manifest of an upstream which did not send its digest and hash it.
+    /// Returns the digest and the received manifest, to be persisted as if upstream had sent the digest
+    async fn hash_manifest(&self, mime: &MimeType, mut receiver: ChunkReceiver) -> Result<(Digest, ChunkReceiver), PersistError> {
+        // The digest of a signed schema 1 manifest is the one of its payload, without the signatures
+        let algo = manifest_digest_algorithm(mime)
+            .ok_or_else(|| PersistError::Failed(format!("The digest of a {} manifest can't be computed", mime)))?;
+
+        let mut manifest = Vec::new();
+        while let Some(chunk) = receiver.recv().await {
+            manifest.extend_from_slice(&chunk);
+
+            if let Some(max_size) = self.config.max_manifest_bytes.filter(|max_size| manifest.len() as u64 > *max_size) {
+                metrics::CACHE_OVERSIZED.inc();
+                return Err(PersistError::Oversized(max_size));
+            }
+        }
+        if receiver.is_aborted() {
+            return Err(PersistError::Failed("Manifest was not fully received from upstream".to_string()));
+        }
+
+        let digest = Digest::hash_bytes(algo, &manifest);
+
+        let (sender, receiver) = chunk_channel(&PersistChannel::Unbounded);
+        if sender.send(Bytes::from(manifest)).await.is_err() {
+            return Err(PersistError::Failed("Failed to hand over the received manifest".to_string()));
+        }
+        Ok((digest, receiver))
+    }
+
+    /// Persists the manifest and indexes it for the tag, or digest, it was pulled with.
+    /// The index row is only written once the manifest is renamed into place, so that an indexed manifest is
+    /// always stored. When the cache stops in between, the manifest is stored but not indexed: a pull by digest
+    /// is served from the storage anyway, and the next pull of the tag indexes it
+    async fn persist_manifest(&self, upstream: &str, repository: &Repository, digest: &Digest, mime: &MimeType, receiver: ChunkReceiver) -> Result<(), PersistError> {
+
+        // Pulled by digest, upstream must have sent that very manifest rather than index another one under it
+        if let Some(requested) = repository.digest.as_ref().filter(|requested| *requested != digest) {
+            return Err(PersistError::Failed(format!("Digest mismatch {} - {}", digest, requested)));
+        }
+
+        // The storage of the upstream the manifest comes from
+        let storage = self.service.for_upstream(upstream);
+
+        // Build the manifest repository with the digest of the manifest, which the received bytes are verified against
+        let manifest_repository = Repository::new_with_reference(&repository.name, &digest.to_string())
+            .map_err(|e| PersistError::Failed(format!("Failed to build manifest repository: {}", e)))?;
+
+        // File system persistence
+        let PersistedBlob { size, created } = self.persist(&storage, manifest_repository, self.config.max_manifest_bytes, receiver).await?;
+
+        // The stored manifest, to look up what it references
+        let data = match storage.driver().content(digest).await {
+            Ok(data) => Some(data),
+            Err(e) => {
+                tracing::error!("failed to read manifest {}: {}", digest, e.to_string());
+                None
+            }
+        };
+
+        // Recorded before the tag is indexed, so that the blobs it shares with the previous manifest and the pruned tags stay referenced
+        if let Some(data) = &data {
+            if let Err(e) = self.manifests.reference_blobs(upstream, repository, digest, &blob_refs::referenced_digests(digest, data)).await {
+                tracing::error!("failed to record the blob references of {}:{}: {}", repository.name, repository.reference, e.to_string());
+            }
+        }
+
+        // Database index persistence, only for a stored manifest. A new tag may exceed the tags kept for the container image
+        let max_tags = self.config.max_tags_per_repository.filter(|_| repository.digest.is_none());
+        let (previous, pruned) = match self.index_manifest(upstream, repository, digest, size, mime, max_tags).await {
+            Ok(indexed) => indexed,
+            Err(e) => {
+                // Do not leave behind a manifest nothing points to,
+                // unless it was already stored and indexed for another tag
+                if created {
+                    if let Err(e) = self.manifests.release_blobs(upstream, repository, digest).await {
+                        tracing::error!("failed to release the blob references of unindexed manifest {}: {}", digest, e.to_string());
+                    }
+                    if let Err(e) = storage.driver().remove(digest).await {
+                        tracing::error!("failed to remove unindexed manifest {}: {}", digest, e.to_string());
+                    }
+                }
+                return Err(PersistError::Failed(format!("Failed to persist manifest index: {}", e)));
+            }
+        };
+
+        // The tag now points to a new manifest
+        if let Some(previous) = previous.filter(|previous| previous != digest) {
+            self.tag_moved(upstream, &storage, repository, &previous).await;
+        }
+
+        // The tags beyond the maximum were removed from the index along with the indexing
+        if let Some(max_tags) = max_tags {
+            self.prune_tags(&storage, repository, &pruned, max_tags).await;
+        }
+
+        // Refresh the disk usage of the container image
+        match self.manifests.size_for_name(&repository.name).await {
+            Ok(total) => metrics::CACHE_REPOSITORY_BYTES.with_label_values(&[&repository.name]).set(total),
+            Err(e) => tracing::error!("failed to calculate the manifests size: {}", e.to_string()),
+        }
+
+        // Referrers API index
+        if let Some(data) = &data {
+            self.index_referrer(repository, digest, mime, size, data).await;
+        }
+
+        Ok(())
+    }
+
+    /// Record the media type upstream sent the blob with, to serve it with the same one from the cache.
+    /// `application/octet-stream` is what the unrecorded ones are served with anyway
+    async fn record_blob_type(&self, repository: &Repository, mime: &MimeType) {
+        let Some(ref digest) = repository.digest else { return };
+        if *mime == MimeType::default() {
+            return;
+        }
+        if let Err(e) = self.manifests.persist_blob_type(digest, mime).await {
+            tracing::error!("Failed to record the media type of blob {}: {}", digest, e);
+        }
+    }
+
+    /// Keep track of the failed persistences in the dead letters, so that they can be inspected and retried,
+    /// and forget about them once persisted. `manifest` is the digest and the mime type of a manifest
+    async fn settle(&self, upstream: &str, repository: &Repository, manifest: Option<(Digest, MimeType)>, result: Result<(), PersistError>) -> Option<RegistryEvent> {
+        let reason = match result {
+            Ok(_) => {
+                if let Err(e) = self.manifests.delete_dead_letter(upstream, repository).await {
+                    tracing::error!("failed to delete the dead letter of {}:{}: {}", repository.name, repository.reference, e.to_string());
+                }
+                return Some(match manifest {
+                    Some((digest, _)) => RegistryEvent::ManifestPersisted(upstream.to_string(), repository.clone(), digest),
+                    None => RegistryEvent::BlobPersisted(upstream.to_string(), repository.clone()),
+                });
+            }
+            Err(PersistError::Oversized(max_size)) => {
+                tracing::error!("{}:{} exceeds the maximum size of {} bytes", repository.name, repository.reference, max_size);
+                return None;
+            }
+            Err(PersistError::LowSpace(available)) => {
+                tracing::warn!("{}:{} is not stored, only {} bytes of disk space are free", repository.name, repository.reference, available);
+                metrics::CACHE_SKIPPED_LOW_SPACE.inc();
+                return None;
+            }
+            Err(PersistError::Failed(reason)) => reason,
+        };
+
+        tracing::error!("Failed to persist {}:{}: {}", repository.name, repository.reference, reason);
+
+        let (digest, mime) = match manifest {
+            Some((digest, mime)) => (digest, Some(mime)),
+            None => (repository.digest.clone()?, None),
+        };
+
+        let dead_letter = DeadLetterRecord {
+            upstream: upstream.to_string(),
+            name: repository.name.clone(),
+            reference: repository.reference.clone(),
+            digest,
+            mime,
+            reason,
+            attempts: 1,
+            failed_at: unix_now(),
+        };
+
+        metrics::CACHE_DEAD_LETTERS.inc();
+        if let Err(e) = self.manifests.persist_dead_letter(&dead_letter).await {
+            tracing::error!("failed to persist the dead letter of {}:{}: {}", repository.name, repository.reference, e.to_string());
+        }
+
+        None
+    }
+
+    /// Account for a tag moved upstream to a new manifest, and remove the old manifest, its config and its layers
+    /// if the policy asks for it and no other tag or digest references them anymore
+    async fn tag_moved(&self, upstream: &str, storage: &FilesystemStorage, repository: &Repository, previous: &Digest) {
+        metrics::CACHE_TAG_MOVED.inc();
+        tracing::info!("Tag {}:{} moved from {}", repository.name, repository.reference, previous);
+
+        // The references are kept along with the old manifest, so that a purge still removes it
+        if self.config.tag_moved == TagMovedPolicy::Keep {
+            return;
+        }
+
+        let released = match self.manifests.release_blobs(upstream, repository, previous).await {
+            Ok(released) => released,
+            Err(e) => {
+                tracing::error!("failed to release the blob references of manifest {}: {}", previous, e.to_string());
+                return;
+            }
+        };
+
+        let mut removed = 0;
+        for digest in &released {
+            removed += blob_refs::unlink(std::slice::from_ref(storage), &self.manifests, digest).await;
+        }
+        if removed > 0 {
+            tracing::info!("Removed {} blobs of manifest {} of moved tag {}:{}", removed, previous, repository.name, repository.reference);
+        }
+    }
+
+    /// Remove the manifests, configs and layers of the tags of the container image pruned beyond the maximum
+    /// which no other tag or digest references anymore
+    async fn prune_tags(&self, storage: &FilesystemStorage, repository: &Repository, released: &[Digest], max_tags: u32) {
+        let mut removed = 0;
+        for digest in released {
+            removed += blob_refs::unlink(std::slice::from_ref(storage), &self.manifests, digest).await;
+        }
+        if removed > 0 {
+            tracing::info!("Removed {} blobs of the tags of {} beyond the maximum of {}", removed, repository.name, max_tags);
+        }
+    }
+
+    /// Index the manifest in the referrers table, in case it refers to another manifest via its subject
+    async fn index_referrer(&self, repository: &Repository, digest: &Digest, mime: &MimeType, size: u64, data: &[u8]) {
+        // Not every manifest is an OCI/Docker v2 JSON manifest, nothing to index in that case
+        let Ok(manifest) = Manifest::parse(data) else { return };
+        let Some(subject) = manifest.subject.clone() else { return };
+
+        let referrer = ReferrerRecord {
+            name: repository.name.clone(),
+            digest: digest.clone(),
+            subject: subject.digest,
+            media_type: manifest.media_type.clone().unwrap_or_else(|| mime.to_string()),
+            artifact_type: manifest.effective_artifact_type(),
+            size: size as i64,
+            annotations: manifest.annotations,
+        };
+
+        if let Err(e) = self.manifests.persist_referrer(&referrer).await {
+            tracing::error!("failed to persist referrer index: {}", e.to_string());
+        }
+    }
+}
+
+/// Persists the blob through the writer of the storage driver, hashing it as it is written: the driver only stores it
+/// once the writer is shut down, after its digest matched. Returns why it failed otherwise.
+/// The persistence is aborted as soon as the blob grows over `max_size`
+async fn persist_to_driver(driver: &(dyn RepositoryTrait + Send + Sync), repository: Repository, max_size: Option<u64>, mut receiver: ChunkReceiver) -> Result<PersistedBlob, PersistError> {
+    let original_digest = repository.clone().digest.unwrap();
+
+    // Dropping the writer before it is shut down stores nothing
+    let mut writer = driver.persist(repository.clone()).await
+        .map_err(|e| PersistError::Failed(format!("Failed to store blob: {}", e)))?;
+    let mut hasher = DigestHasher::new(original_digest.algo);
+    let mut size = 0;
+    while let Some(chunk) = receiver.recv().await {
+        size += chunk.len() as u64;
+        if let Some(max_size) = max_size.filter(|max_size| size > *max_size) {
+            metrics::CACHE_OVERSIZED.inc();
+            return Err(PersistError::Oversized(max_size));
+        }
+
+        hasher.update(&chunk);
+        if let Err(e) = writer.write_all(&chunk).await {
+            return Err(PersistError::Failed(format!("Failed to write blob: {}", e)));
+        }
+    }
+
+    // The upstream stream broke off, what we got is not the whole blob
+    if receiver.is_aborted() {
+        return Err(PersistError::Failed("Blob was not fully received from upstream".to_string()));
+    }
+
+    let blob_digest = hasher.finalize();
+    if blob_digest != original_digest {
+        return Err(PersistError::Failed(format!("Digest mismatch {} - {}", blob_digest, original_digest)));
+    }
+
+    let created = !driver.is_stored(&original_digest);
+    if let Err(e) = writer.shutdown().await {
+        return Err(PersistError::Failed(format!("Failed to store blob: {}", e)));
+    }
+
+    tracing::info!("Blob stored successfully: {}/{}", repository.name, original_digest);
+    Ok(PersistedBlob { size, created })
+}
+
+/// Remove the tmp file of a failed persistence, nothing else would ever reuse it
+async fn remove_tmp(tmp: &Path) {
+    if let Err(e) = tokio::fs::remove_file(tmp).await {
+        tracing::error!("Failed to remove tmp blob {:?}: {}", tmp, e.to_string());
+    }
+}
+
+/// Move the verified blob from its tmp file to its final path. The rename can fail transiently,
+/// e.g. while an antivirus holds a handle on the file, so it is retried with an exponential backoff.
+/// The tmp file is removed once all the attempts failed.
+async fn rename_blob<F, Fut>(tmp: PathBuf, path: PathBuf, attempts: u32, rename: F) -> std::io::Result<()>
+    where
+        F: Fn(PathBuf, PathBuf) -> Fut,
+        Fut: Future<Output = std::io::Result<()>>,
+{
+    let mut attempt = 1;
+    loop {
+        match rename(tmp.clone(), path.clone()).await {
+            Ok(_) => return Ok(()),
+            Err(e) if attempt < attempts => {
+                tracing::warn!("Failed to rename blob {:?}, attempt {}/{}: {}", tmp, attempt, attempts, e.to_string());
+                tokio::time::sleep(RENAME_RETRY_DELAY * 2u32.pow(attempt - 1)).await;
+                attempt += 1;
+            }
+            Err(e) => {
+                if let Err(e) = tokio::fs::remove_file(&tmp).await {
+                    tracing::error!("Failed to remove tmp blob {:?}: {}", tmp, e.to_string());
+                }
+                return Err(e);
             }
         }
+    }
+}
 
-        Some(RegistryEvent::BlobPersisted)
+/// Move the blob from its tmp file to its final path. The tmp file of a spool folder on another filesystem
+/// can't be renamed into place, it is copied instead, see `copy_blob`
+async fn move_blob(tmp: PathBuf, path: PathBuf, sync: bool) -> std::io::Result<()> {
+    match tokio::fs::rename(&tmp, &path).await {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => copy_blob(&tmp, &path, sync).await,
+        result => result,
+    }
+}
+
+/// Copy the blob next to its final path then rename it into place, so that it never shows up partially written,
+/// and remove the tmp file. The copy is synced to the disk first when `sync` is set
+async fn copy_blob(tmp: &Path, path: &Path, sync: bool) -> std::io::Result<()> {
+    let staged = path.with_file_name(tmp.file_name().unwrap_or_default());
+
+    let copied = async {
+        tokio::fs::copy(tmp, &staged).await?;
+        if sync {
+            tokio::fs::File::open(&staged).await?.sync_data().await?;
+        }
+        tokio::fs::rename(&staged, path).await
+    }.await;
+
+    if let Err(e) = copied {
+        if staged.exists() {
+            remove_tmp(&staged).await;
+        }
+        return Err(e);
+    }
+
+    remove_tmp(tmp).await;
+    Ok(())
+}
+
+/// The sha256 digest of a verified blob, which for a sha256 blob is its own digest
+async fn content_key(digest: &Digest, path: &Path) -> Option<Digest> {
+    if digest.algo == DigestAlgorithm::Sha256 {
+        return Some(digest.clone());
+    }
+
+    let file = match tokio::fs::File::open(path).await {
+        Ok(file) => file.into_std().await,
+        Err(e) => {
+            tracing::error!("Failed to open blob {:?} to hash its content: {}", path, e.to_string());
+            return None;
+        }
+    };
+
+    match Digest::hash_digest_file(DigestAlgorithm::Sha256, file).await {
+        Ok(content_key) => Some(content_key),
+        Err(e) => {
+            tracing::error!("Failed to hash the content of blob {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+/// The algorithm a manifest of the media type is hashed with when upstream did not send its digest, sha256 unless
+/// the media type has a `digest` parameter. None when hashing the manifest as received does not give its digest
+fn manifest_digest_algorithm(mime: &MimeType) -> Option<DigestAlgorithm> {
+    if mime.is(DOCKER_SIGNED_MANIFEST_V1) {
+        return None;
+    }
+
+    match mime.parameter("digest") {
+        Some(algo) => algo.parse().ok(),
+        None => Some(DigestAlgorithm::Sha256),
     }
 }
 
@@ -126,51 +745,671 @@ impl CommandSubscriberTrait for BlobPersistHandler {
             RegistryCommand::Shutdown => {
                 None
             }
-            RegistryCommand::PersistBlob(repository, receiver) => {
-                self.persist(repository, receiver).await
+            RegistryCommand::PersistBlob(upstream, repository, mime, receiver) => {
+                let result = self.persist(&self.service.for_upstream(&upstream), repository.clone(), self.config.max_blob_bytes, receiver).await;
+                if result.is_ok() {
+                    self.record_blob_type(&repository, &mime).await;
+                }
+                self.settle(&upstream, &repository, None, result.map(|_| ())).await
+            }
+            RegistryCommand::PersistManifest(upstream, repository, digest, mime, receiver) => {
+                // Not every upstream sends the digest, without it the manifest is hashed once received
+                let (digest, receiver) = match digest {
+                    Some(digest) => (digest, receiver),
+                    None => match self.hash_manifest(&mime, receiver).await {
+                        Ok(hashed) => hashed,
+                        Err(e) => return self.settle(&upstream, &repository, None, Err(e)).await,
+                    },
+                };
+                let result = self.persist_manifest(&upstream, &repository, &digest, &mime, receiver).await;
+                self.settle(&upstream, &repository, Some((digest, mime)), result).await
             }
-            RegistryCommand::PersistManifest(repository, digest, size, mime, receiver) => {
+        }
 
-                match digest {
-                    Some(digest) => {
-                        // Build the repository with the sha256 of the manifest
-                        let manifest_digest = digest.to_string();
 
-                        // Build the manifest repository with the sha256 of the manifest
-                        let manifest_repository = Repository::new_with_reference(&repository.name, &manifest_digest);
+    }
 
-                        // Make sure we build the manifest correctly
-                        match manifest_repository {
-                            Ok(manifest_repository) => {
+    fn supports_concurrency(&self) -> bool {
+        true
+    }
+}
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use bytes::Bytes;
+    use sha2::{Digest as Sha2Digest, Sha256};
+    use crate::config::app::{AppConfig, Durability, StorageConfig, TagMovedPolicy};
+    use crate::config::streaming::PersistChannel;
+    use crate::db::db_blob_refs::DBBlobRefs;
+    use crate::db::db_contents::DBContents;
+    use crate::db::db_dead_letters::DBDeadLetters;
+    use crate::db::db_manifests::DBManifests;
+    use crate::db::db_referrers::DBReferrers;
+    use crate::db::pool::DBPool;
+    use crate::eviction::free_space::{DiskSpace, FreeSpace};
+    use crate::handlers::command::blob::persist::{copy_blob, manifest_digest_algorithm, rename_blob, BlobPersistHandler};
+    use crate::handlers::command::blob::service::ManifestService;
+    use crate::metrics;
+    use crate::models::chunks::chunk_channel;
+    use crate::models::commands::RegistryCommand;
+    use crate::models::types::MimeType;
+    use crate::pubsub::subscriber::CommandSubscriberTrait;
+    use crate::registry::digest::{Digest, DigestAlgorithm};
+    use crate::registry::repository::Repository;
+    use crate::repository::filesystem::FilesystemStorage;
 
-                                // File system persistence
-                                if let Some(RegistryEvent::BlobPersisted) = self.persist(manifest_repository, receiver).await {
+    const MANIFEST: &str = r#"{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","layers":[]}"#;
+    const MOVED_MANIFEST: &str = r#"{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","layers":[],"annotations":{"moved":"true"}}"#;
+    const MIME: &str = "application/vnd.oci.image.manifest.v1+json";
 
-                                    // Database index persistence
-                                    if let Err(e) = self.manifests.persist(&repository, digest, size, &mime).await {
-                                        tracing::error!("failed to persist manifest index: {}", e.to_string());
-                                        return None;
-                                    }
+    /// Storage in a temporary folder, with the algorithm folder already created
+    fn storage(folder: &tempfile::TempDir) -> Arc<FilesystemStorage> {
+        std::fs::create_dir_all(folder.path().join("sha256")).unwrap();
+        Arc::new(FilesystemStorage::new(AppConfig::with_storage_folder(folder.path().to_str().unwrap())))
+    }
 
-                                    return Some(RegistryEvent::BlobPersisted);
-                                }
-                                None
-                            }
-                            Err(e) => {
-                                tracing::error!("failed to build manifest repository: {}", e.to_string());
-                                None
-                            }
-                        }
-                    }
-                    None => None
-                }
+    /// Storage config of the handler
+    fn config(folder: &tempfile::TempDir, tag_moved: TagMovedPolicy) -> StorageConfig {
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap()).storage;
+        config.tag_moved = tag_moved;
+        config
+    }
+
+    /// Run the persist manifest command for the MANIFEST content
+    async fn persist_manifest(handler: &BlobPersistHandler) -> (Option<crate::models::events::RegistryEvent>, Digest) {
+        persist_tagged_manifest(handler, "latest", MANIFEST).await
+    }
+
+    /// Run the persist manifest command for a tag
+    async fn persist_tagged_manifest(handler: &BlobPersistHandler, tag: &str, manifest: &'static str) -> (Option<crate::models::events::RegistryEvent>, Digest) {
+        let digest = Digest::parse(&format!("sha256:{}", hex::encode(Sha256::digest(manifest.as_bytes())))).unwrap();
+        let repository = Repository::new_with_reference("library/nginx", tag).unwrap();
+
+        let (sender, receiver) = chunk_channel(&PersistChannel::Unbounded);
+        sender.send(Bytes::from_static(manifest.as_bytes())).await.unwrap();
+        drop(sender);
+
+        let event = handler.run(RegistryCommand::PersistManifest(String::new(), repository, Some(digest.clone()), MIME.parse().unwrap(), receiver)).await;
+        (event, digest)
+    }
+
+    #[tokio::test]
+    async fn persist_manifest_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let storage = storage(&folder);
+
+        let pool = DBPool::default().await;
+        DBManifests::create_table(&pool).await;
+        DBReferrers::create_table(&pool).await;
+        DBBlobRefs::create_table(&pool).await;
+        let manifests = ManifestService::from_pool(pool);
+        let handler = BlobPersistHandler::new(storage.clone(), manifests.clone(), config(&folder, TagMovedPolicy::Keep));
+
+        let (event, digest) = persist_manifest(&handler).await;
+        assert!(event.is_some());
+        assert!(storage.digest_path(&digest).exists());
+
+        let repository = Repository::new_with_reference("library/nginx", "latest").unwrap();
+        let record = manifests.get("", &repository, &[]).await.unwrap().expect("Manifest was not indexed");
+        assert_eq!(Some(digest), record.reference);
+        assert_eq!(MANIFEST.len() as i32, record.size);
+    }
+
+    #[tokio::test]
+    async fn persist_manifest_without_digest_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let storage = storage(&folder);
+
+        let pool = DBPool::default().await;
+        DBManifests::create_table(&pool).await;
+        DBReferrers::create_table(&pool).await;
+        DBBlobRefs::create_table(&pool).await;
+        let manifests = ManifestService::from_pool(pool);
+        let handler = BlobPersistHandler::new(storage.clone(), manifests.clone(), config(&folder, TagMovedPolicy::Keep));
+
+        // Upstream did not send the docker-content-digest header
+        let run = |mime: &'static str| {
+            let handler = handler.clone();
+            async move {
+                let (sender, receiver) = chunk_channel(&PersistChannel::Unbounded);
+                sender.send(Bytes::from_static(&MANIFEST.as_bytes()[..20])).await.unwrap();
+                sender.send(Bytes::from_static(&MANIFEST.as_bytes()[20..])).await.unwrap();
+                drop(sender);
+
+                let repository = Repository::new_with_reference("library/nginx", "latest").unwrap();
+                handler.run(RegistryCommand::PersistManifest(String::new(), repository, None, mime.parse().unwrap(), receiver)).await
             }
+        };
+
+        // The digest of a signed schema 1 manifest can't be computed from what was received
+        assert!(run("application/vnd.docker.distribution.manifest.v1+prettyjws").await.is_none());
+
+        assert!(run(MIME).await.is_some());
+        let digest = Digest::parse(&format!("sha256:{}", hex::encode(Sha256::digest(MANIFEST.as_bytes())))).unwrap();
+        assert_eq!(MANIFEST.as_bytes(), std::fs::read(storage.digest_path(&digest)).unwrap());
+
+        let repository = Repository::new_with_reference("library/nginx", "latest").unwrap();
+        let record = manifests.get("", &repository, &[]).await.unwrap().expect("Manifest was not indexed");
+        assert_eq!(Some(digest), record.reference);
+    }
+
+    #[test]
+    fn manifest_digest_algorithm_test() {
+        let algorithm = |mime: &str| manifest_digest_algorithm(&mime.parse().unwrap());
+        assert_eq!(Some(DigestAlgorithm::Sha256), algorithm(MIME));
+        assert_eq!(Some(DigestAlgorithm::Sha256), manifest_digest_algorithm(&MimeType::default()));
+        assert_eq!(Some(DigestAlgorithm::Sha512), algorithm("application/vnd.oci.image.manifest.v1+json; digest=sha512"));
+        assert_eq!(None, algorithm("application/vnd.oci.image.manifest.v1+json; digest=md5"));
+        assert_eq!(None, algorithm("application/vnd.docker.distribution.manifest.v1+prettyjws"));
+    }
+
+    #[tokio::test]
+    async fn persist_manifest_digest_mismatch_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let storage = storage(&folder);
+
+        let pool = DBPool::default().await;
+        DBManifests::create_table(&pool).await;
+        DBDeadLetters::create_table(&pool).await;
+        let manifests = ManifestService::from_pool(pool);
+        let handler = BlobPersistHandler::new(storage.clone(), manifests.clone(), config(&folder, TagMovedPolicy::Keep));
+
+        let digest = |manifest: &str| Digest::parse(&format!("sha256:{}", hex::encode(Sha256::digest(manifest.as_bytes())))).unwrap();
+        let persist = |reference: String, advertised: Digest| {
+            let handler = handler.clone();
+            async move {
+                let (sender, receiver) = chunk_channel(&PersistChannel::Unbounded);
+                sender.send(Bytes::from_static(MANIFEST.as_bytes())).await.unwrap();
+                drop(sender);
+                let repository = Repository::new_with_reference("library/nginx", &reference).unwrap();
+                handler.run(RegistryCommand::PersistManifest(String::new(), repository, Some(advertised), MIME.parse().unwrap(), receiver)).await
+            }
+        };
+
+        // The bytes do not hash to the advertised digest
+        assert!(persist("latest".to_string(), digest(MOVED_MANIFEST)).await.is_none());
+
+        // Pulled by a digest, upstream advertised and sent another manifest
+        assert!(persist(digest(MOVED_MANIFEST).to_string(), digest(MANIFEST)).await.is_none());
+
+        // Neither stored nor indexed
+        assert_eq!(0, std::fs::read_dir(folder.path().join("sha256")).unwrap().count());
+        for reference in ["latest".to_string(), digest(MOVED_MANIFEST).to_string()] {
+            let repository = Repository::new_with_reference("library/nginx", &reference).unwrap();
+            assert!(manifests.get("", &repository, &[]).await.unwrap().is_none());
         }
+        assert!(manifests.dead_letters().await.unwrap().iter().all(|dead_letter| dead_letter.reason.starts_with("Digest mismatch")));
 
+        // Pulled by its digest
+        assert!(persist(digest(MANIFEST).to_string(), digest(MANIFEST)).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn persist_manifest_index_failure_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let storage = storage(&folder);
+
+        // The manifests table does not exist, so the indexing fails
+        let manifests = ManifestService::from_pool(DBPool::default().await);
+        let handler = BlobPersistHandler::new(storage.clone(), manifests, config(&folder, TagMovedPolicy::Keep));
 
+        let (event, digest) = persist_manifest(&handler).await;
+        assert!(event.is_none());
+
+        // No orphan manifest is left behind
+        assert!(!storage.digest_path(&digest).exists());
+        let leftovers = std::fs::read_dir(folder.path().join("sha256")).unwrap().count();
+        assert_eq!(0, leftovers);
     }
 
-    fn supports_concurrency(&self) -> bool {
-        true
+    #[tokio::test]
+    async fn persist_manifest_tag_moved_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let storage = storage(&folder);
+
+        let pool = DBPool::default().await;
+        DBManifests::create_table(&pool).await;
+        DBReferrers::create_table(&pool).await;
+        DBBlobRefs::create_table(&pool).await;
+        let manifests = ManifestService::from_pool(pool);
+        let handler = BlobPersistHandler::new(storage.clone(), manifests.clone(), config(&folder, TagMovedPolicy::Remove));
+        let moved_tags = metrics::CACHE_TAG_MOVED.get();
+
+        // Both tags point to the same manifest
+        let (_, old_digest) = persist_tagged_manifest(&handler, "latest", MANIFEST).await;
+        persist_tagged_manifest(&handler, "stable", MANIFEST).await;
+
+        // latest moves: the old manifest is still referenced by stable
+        let (event, new_digest) = persist_tagged_manifest(&handler, "latest", MOVED_MANIFEST).await;
+        assert!(event.is_some());
+        assert!(storage.digest_path(&old_digest).exists());
+        assert!(storage.digest_path(&new_digest).exists());
+
+        let latest = Repository::new_with_reference("library/nginx", "latest").unwrap();
+        let record = manifests.get("", &latest, &[]).await.unwrap().expect("Manifest was not indexed");
+        assert_eq!(Some(new_digest.clone()), record.reference);
+        assert_eq!(MOVED_MANIFEST.len() as i32, record.size);
+
+        // stable moves too: nothing points to the old manifest anymore
+        persist_tagged_manifest(&handler, "stable", MOVED_MANIFEST).await;
+        assert!(!storage.digest_path(&old_digest).exists());
+        assert!(storage.digest_path(&new_digest).exists());
+
+        assert!(metrics::CACHE_TAG_MOVED.get() >= moved_tags + 2);
+    }
+
+    #[tokio::test]
+    async fn persist_manifest_max_tags_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let storage = storage(&folder);
+
+        let pool = DBPool::default().await;
+        DBManifests::create_table(&pool).await;
+        DBReferrers::create_table(&pool).await;
+        DBBlobRefs::create_table(&pool).await;
+        let manifests = ManifestService::from_pool(pool);
+        let mut config = config(&folder, TagMovedPolicy::Keep);
+        config.max_tags_per_repository = Some(1);
+        let handler = BlobPersistHandler::new(storage.clone(), manifests.clone(), config);
+
+        // Both tags point to the same manifest: pruning build-1 keeps it for build-2
+        let (_, old_digest) = persist_tagged_manifest(&handler, "build-1", MANIFEST).await;
+        persist_tagged_manifest(&handler, "build-2", MANIFEST).await;
+        assert!(storage.digest_path(&old_digest).exists());
+
+        // Nothing points to the old manifest once build-2 is pruned
+        let (event, new_digest) = persist_tagged_manifest(&handler, "build-3", MOVED_MANIFEST).await;
+        assert!(event.is_some());
+        assert!(!storage.digest_path(&old_digest).exists());
+        assert!(storage.digest_path(&new_digest).exists());
+
+        // Pruned along with the indexing of build-4, build-3 shares its manifest with it
+        persist_tagged_manifest(&handler, "build-4", MOVED_MANIFEST).await;
+        assert!(storage.digest_path(&new_digest).exists());
+
+        let tags = manifests.list_by_name("library/nginx").await.unwrap();
+        assert_eq!(vec!["build-4"], tags.iter().map(|manifest| manifest.tag.as_str()).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn persist_manifest_oversized_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let storage = storage(&folder);
+
+        let pool = DBPool::default().await;
+        DBManifests::create_table(&pool).await;
+        DBReferrers::create_table(&pool).await;
+        DBBlobRefs::create_table(&pool).await;
+        let manifests = ManifestService::from_pool(pool);
+        let mut config = config(&folder, TagMovedPolicy::Keep);
+        config.max_manifest_bytes = Some(MANIFEST.len() as u64 - 1);
+        let handler = BlobPersistHandler::new(storage.clone(), manifests.clone(), config);
+        let oversized = metrics::CACHE_OVERSIZED.get();
+
+        let (event, digest) = persist_manifest(&handler).await;
+        assert!(event.is_none());
+        assert!(metrics::CACHE_OVERSIZED.get() > oversized);
+
+        // Neither the manifest nor its tmp file is left behind, and it is not indexed
+        assert!(!storage.digest_path(&digest).exists());
+        let leftovers = std::fs::read_dir(folder.path().join("sha256")).unwrap().count();
+        assert_eq!(0, leftovers);
+        let repository = Repository::new_with_reference("library/nginx", "latest").unwrap();
+        assert!(manifests.get("", &repository, &[]).await.unwrap().is_none());
+    }
+
+    /// Reports a fixed amount of free space
+    struct MockFreeSpace(u64);
+
+    impl FreeSpace for MockFreeSpace {
+        fn disk_space(&self, _path: &Path) -> std::io::Result<DiskSpace> {
+            Ok(DiskSpace { available: self.0, total: 1 << 30 })
+        }
+    }
+
+    /// Reports no free space in a folder, plenty elsewhere
+    struct FullFolder(PathBuf);
+
+    impl FreeSpace for FullFolder {
+        fn disk_space(&self, path: &Path) -> std::io::Result<DiskSpace> {
+            let available = if path == self.0 { 0 } else { 1 << 30 };
+            Ok(DiskSpace { available, total: 1 << 30 })
+        }
+    }
+
+    #[tokio::test]
+    async fn persist_low_space_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let storage = storage(&folder);
+        let pool = DBPool::default().await;
+        DBDeadLetters::create_table(&pool).await;
+        let manifests = ManifestService::from_pool(pool);
+        let mut config = config(&folder, TagMovedPolicy::Keep);
+        config.min_free_bytes = Some(1024);
+
+        let digest = Digest::parse(&format!("sha256:{}", hex::encode(Sha256::digest(b"whole blob")))).unwrap();
+        let repository = Repository::new_with_reference("library/nginx", &digest.to_string()).unwrap();
+        let persist = |available: u64| {
+            let handler = BlobPersistHandler::with_free_space(storage.clone(), manifests.clone(), config.clone(), Arc::new(MockFreeSpace(available)));
+            let repository = repository.clone();
+            async move {
+                let (sender, receiver) = chunk_channel(&PersistChannel::Unbounded);
+                sender.send(Bytes::from_static(b"whole blob")).await.unwrap();
+                drop(sender);
+                handler.run(RegistryCommand::PersistBlob(String::new(), repository, MimeType::default(), receiver)).await
+            }
+        };
+
+        // Skipped without writing anything, nor retrying it later
+        let skipped = metrics::CACHE_SKIPPED_LOW_SPACE.get();
+        assert!(persist(1023).await.is_none());
+        assert!(metrics::CACHE_SKIPPED_LOW_SPACE.get() > skipped);
+        assert_eq!(0, std::fs::read_dir(folder.path().join("sha256")).unwrap().count());
+        assert!(manifests.dead_letters().await.unwrap().is_empty());
+
+        assert!(persist(1024).await.is_some());
+        assert!(storage.digest_path(&digest).exists());
+
+        // The spool folder, on another filesystem, is full
+        let spool = tempfile::tempdir().unwrap();
+        config.spool_dir = Some(spool.path().to_str().unwrap().to_string());
+        let other = Digest::parse(&format!("sha256:{}", hex::encode(Sha256::digest(b"other blob")))).unwrap();
+        let repository = Repository::new_with_reference("library/nginx", &other.to_string()).unwrap();
+        let handler = BlobPersistHandler::with_free_space(storage.clone(), manifests.clone(), config, Arc::new(FullFolder(spool.path().to_path_buf())));
+        let (sender, receiver) = chunk_channel(&PersistChannel::Unbounded);
+        sender.send(Bytes::from_static(b"other blob")).await.unwrap();
+        drop(sender);
+        assert!(handler.run(RegistryCommand::PersistBlob(String::new(), repository, MimeType::default(), receiver)).await.is_none());
+        assert!(!storage.digest_path(&other).exists());
+    }
+
+    #[tokio::test]
+    async fn persist_blob_aborted_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let storage = storage(&folder);
+        let pool = DBPool::default().await;
+        DBDeadLetters::create_table(&pool).await;
+        let manifests = ManifestService::from_pool(pool);
+        let handler = BlobPersistHandler::new(storage.clone(), manifests.clone(), config(&folder, TagMovedPolicy::Keep));
+
+        let digest = Digest::parse(&format!("sha256:{}", hex::encode(Sha256::digest(b"whole blob")))).unwrap();
+        let repository = Repository::new_with_reference("library/nginx", &digest.to_string()).unwrap();
+
+        // Upstream stalled after the first chunk
+        let (sender, receiver) = chunk_channel(&PersistChannel::Unbounded);
+        sender.send(Bytes::from_static(b"whole")).await.unwrap();
+        sender.abort();
+
+        let event = handler.run(RegistryCommand::PersistBlob(String::new(), repository.clone(), MimeType::default(), receiver)).await;
+        assert!(event.is_none());
+        assert!(!storage.digest_path(&digest).exists());
+        let leftovers = std::fs::read_dir(folder.path().join("sha256")).unwrap().count();
+        assert_eq!(0, leftovers);
+
+        // Recorded as a dead letter
+        let dead_letters = manifests.dead_letters().await.unwrap();
+        assert_eq!(1, dead_letters.len());
+        assert_eq!(digest, dead_letters[0].digest);
+        assert_eq!("library/nginx", dead_letters[0].name);
+        assert_eq!("Blob was not fully received from upstream", dead_letters[0].reason);
+
+        // Persisted by a later attempt
+        let (sender, receiver) = chunk_channel(&PersistChannel::Unbounded);
+        sender.send(Bytes::from_static(b"whole blob")).await.unwrap();
+        drop(sender);
+        assert!(handler.run(RegistryCommand::PersistBlob(String::new(), repository, MimeType::default(), receiver)).await.is_some());
+        assert!(manifests.dead_letters().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn persist_blob_concurrently_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let storage = storage(&folder);
+        let pool = DBPool::default().await;
+        DBDeadLetters::create_table(&pool).await;
+        let manifests = ManifestService::from_pool(pool);
+        let handler = BlobPersistHandler::new(storage.clone(), manifests, config(&folder, TagMovedPolicy::Keep));
+
+        let digest = Digest::parse(&format!("sha256:{}", hex::encode(Sha256::digest(b"whole blob")))).unwrap();
+        let repository = Repository::new_with_reference("library/nginx", &digest.to_string()).unwrap();
+
+        // Two first pulls of the same blob, whose chunks interleave
+        let (first_sender, first_receiver) = chunk_channel(&PersistChannel::Unbounded);
+        let (second_sender, second_receiver) = chunk_channel(&PersistChannel::Unbounded);
+        let first = tokio::spawn({
+            let handler = handler.clone();
+            let repository = repository.clone();
+            async move { handler.run(RegistryCommand::PersistBlob(String::new(), repository, MimeType::default(), first_receiver)).await }
+        });
+        let second = tokio::spawn({
+            let handler = handler.clone();
+            let repository = repository.clone();
+            async move { handler.run(RegistryCommand::PersistBlob(String::new(), repository, MimeType::default(), second_receiver)).await }
+        });
+        first_sender.send(Bytes::from_static(b"whole")).await.unwrap();
+        second_sender.send(Bytes::from_static(b"whole")).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        first_sender.send(Bytes::from_static(b" blob")).await.unwrap();
+        second_sender.send(Bytes::from_static(b" blob")).await.unwrap();
+        drop(first_sender);
+        drop(second_sender);
+
+        // Neither corrupted the other one
+        assert!(first.await.unwrap().is_some());
+        assert!(second.await.unwrap().is_some());
+        assert_eq!(b"whole blob".to_vec(), std::fs::read(storage.digest_path(&digest)).unwrap());
+        let leftovers = std::fs::read_dir(folder.path().join("sha256")).unwrap().count();
+        assert_eq!(1, leftovers);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn persist_durability_test() {
+        for durability in [Durability::Sync, Durability::Async, Durability::None] {
+            let folder = tempfile::tempdir().unwrap();
+            let storage = storage(&folder);
+            let pool = DBPool::default().await;
+            DBDeadLetters::create_table(&pool).await;
+            let mut config = config(&folder, TagMovedPolicy::Keep);
+            config.durability = durability.clone();
+            let handler = BlobPersistHandler::new(storage.clone(), ManifestService::from_pool(pool), config);
+
+            let digest = Digest::parse(&format!("sha256:{}", hex::encode(Sha256::digest(b"whole blob")))).unwrap();
+            let repository = Repository::new_with_reference("library/nginx", &digest.to_string()).unwrap();
+            let (sender, receiver) = chunk_channel(&PersistChannel::Unbounded);
+            sender.send(Bytes::from_static(b"whole blob")).await.unwrap();
+            drop(sender);
+
+            // Stored and verified whatever the mode
+            assert!(handler.run(RegistryCommand::PersistBlob(String::new(), repository, MimeType::default(), receiver)).await.is_some(), "{:?}", durability);
+            assert_eq!(b"whole blob".to_vec(), std::fs::read(storage.digest_path(&digest)).unwrap());
+
+            // Only the async one syncs it later
+            let pending = handler.pending_syncs.lock().clone();
+            match durability {
+                Durability::Async => assert_eq!(vec![storage.digest_path(&digest)], pending),
+                _ => assert!(pending.is_empty()),
+            }
+        }
+
+        // The batch is synced after the delay
+        let folder = tempfile::tempdir().unwrap();
+        let pool = DBPool::default().await;
+        let handler = BlobPersistHandler::new(storage(&folder), ManifestService::from_pool(pool), config(&folder, TagMovedPolicy::Keep));
+        std::fs::write(folder.path().join("blob"), b"blob").unwrap();
+        handler.sync_later(folder.path().join("evicted"));
+        handler.sync_later(folder.path().join("blob"));
+        assert_eq!(2, handler.pending_syncs.lock().len());
+        tokio::time::sleep(super::SYNC_BATCH_DELAY + std::time::Duration::from_millis(200)).await;
+        assert!(handler.pending_syncs.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn persist_deduplicated_test() {
+        use std::os::unix::fs::MetadataExt;
+        use sha2::Sha512;
+
+        let folder = tempfile::tempdir().unwrap();
+        let storage = storage(&folder);
+        let pool = DBPool::default().await;
+        DBDeadLetters::create_table(&pool).await;
+        DBContents::create_table(&pool).await;
+        let manifests = ManifestService::from_pool(pool);
+        let mut config = config(&folder, TagMovedPolicy::Keep);
+        config.deduplicate = true;
+        let handler = BlobPersistHandler::new(storage.clone(), manifests.clone(), config);
+
+        let persist = |content: &'static [u8], digest: Digest| {
+            let handler = handler.clone();
+            async move {
+                let repository = Repository::new_with_reference("library/nginx", &digest.to_string()).unwrap();
+                let (sender, receiver) = chunk_channel(&PersistChannel::Unbounded);
+                sender.send(Bytes::from_static(content)).await.unwrap();
+                drop(sender);
+                assert!(handler.run(RegistryCommand::PersistBlob(String::new(), repository, MimeType::default(), receiver)).await.is_some());
+            }
+        };
+
+        // The same layer referenced by both algorithms
+        let sha512 = Digest::parse(&format!("sha512:{}", hex::encode(Sha512::digest(b"identical layer")))).unwrap();
+        let sha256 = Digest::parse(&format!("sha256:{}", hex::encode(Sha256::digest(b"identical layer")))).unwrap();
+        persist(b"identical layer", sha512.clone()).await;
+        let linked = metrics::CACHE_DEDUPLICATED_BLOBS.get();
+        persist(b"identical layer", sha256.clone()).await;
+        assert!(metrics::CACHE_DEDUPLICATED_BLOBS.get() > linked);
+
+        // A single physical copy
+        let first = std::fs::metadata(storage.digest_path(&sha512)).unwrap();
+        let second = std::fs::metadata(storage.digest_path(&sha256)).unwrap();
+        assert_eq!(first.ino(), second.ino());
+        assert_eq!(2, first.nlink());
+        assert_eq!("identical layer", std::fs::read_to_string(storage.digest_path(&sha256)).unwrap());
+
+        // The content outlives the removal of the first path
+        std::fs::remove_file(storage.digest_path(&sha512)).unwrap();
+        assert_eq!("identical layer", std::fs::read_to_string(storage.digest_path(&sha256)).unwrap());
+
+        // A different content is stored on its own
+        let other = Digest::parse(&format!("sha256:{}", hex::encode(Sha256::digest(b"other layer")))).unwrap();
+        persist(b"other layer", other.clone()).await;
+        assert_eq!(1, std::fs::metadata(storage.digest_path(&other)).unwrap().nlink());
+    }
+
+    #[tokio::test]
+    async fn persist_artifacts_test() {
+        const HELM_CHART: &str = r#"{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{"mediaType":"application/vnd.cncf.helm.config.v1+json","digest":"sha256:1111111111111111111111111111111111111111111111111111111111111111","size":117},"layers":[{"mediaType":"application/vnd.cncf.helm.chart.content.v1.tar+gzip","digest":"sha256:2222222222222222222222222222222222222222222222222222222222222222","size":3624}]}"#;
+        const SBOM: &str = r#"{"mediaType":"application/vnd.oci.artifact.manifest.v1+json","artifactType":"application/spdx+json","blobs":[{"mediaType":"application/spdx+json","digest":"sha256:3333333333333333333333333333333333333333333333333333333333333333","size":8192}],"subject":{"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"sha256:05c6e08f1d9fdafa03147fcb8f82f124c76d2f70e3d989dc8aadb5e7d7450bec","size":1234}}"#;
+
+        let folder = tempfile::tempdir().unwrap();
+        let storage = storage(&folder);
+        let pool = DBPool::default().await;
+        DBManifests::create_table(&pool).await;
+        DBReferrers::create_table(&pool).await;
+        DBBlobRefs::create_table(&pool).await;
+        let manifests = ManifestService::from_pool(pool);
+        let handler = BlobPersistHandler::new(storage.clone(), manifests.clone(), config(&folder, TagMovedPolicy::Keep));
+
+        // Stored and indexed whatever their config and artifact media types
+        let (event, chart) = persist_tagged_manifest(&handler, "chart", HELM_CHART).await;
+        assert!(event.is_some());
+        let (event, sbom) = persist_tagged_manifest(&handler, "sbom", SBOM).await;
+        assert!(event.is_some());
+        for (tag, digest) in [("chart", &chart), ("sbom", &sbom)] {
+            let repository = Repository::new_with_reference("library/nginx", tag).unwrap();
+            let record = manifests.get("", &repository, &[]).await.unwrap().expect("Artifact was not indexed");
+            assert_eq!(Some(digest.clone()), record.reference);
+        }
+
+        // The SBOM is listed as a referrer of its subject
+        let repository = Repository::new("library/nginx").unwrap();
+        let subject = Digest::parse("sha256:05c6e08f1d9fdafa03147fcb8f82f124c76d2f70e3d989dc8aadb5e7d7450bec").unwrap();
+        let referrers = manifests.referrers(&repository, &subject, Some("application/spdx+json")).await.unwrap();
+        assert_eq!(1, referrers.len());
+        assert_eq!(sbom, referrers[0].digest);
+
+        // The chart config and content, and the SBOM document, are referenced like image layers
+        let chart_repository = Repository::new_with_reference("library/nginx", "chart").unwrap();
+        assert_eq!(3, manifests.release_blobs("", &chart_repository, &chart).await.unwrap().len());
+        let sbom_repository = Repository::new_with_reference("library/nginx", "sbom").unwrap();
+        let released = manifests.release_blobs("", &sbom_repository, &sbom).await.unwrap();
+        assert!(released.contains(&Digest::parse("sha256:3333333333333333333333333333333333333333333333333333333333333333").unwrap()));
+    }
+
+    #[tokio::test]
+    async fn persist_spool_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let spool = tempfile::tempdir().unwrap();
+        let mut app_config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        app_config.storage.spool_dir = Some(spool.path().to_str().unwrap().to_string());
+        let storage = Arc::new(FilesystemStorage::new(app_config.clone()));
+        storage.create_folders().unwrap();
+        let pool = DBPool::default().await;
+        DBDeadLetters::create_table(&pool).await;
+        let handler = BlobPersistHandler::new(storage.clone(), ManifestService::from_pool(pool), app_config.storage);
+
+        let digest = Digest::parse(&format!("sha256:{}", hex::encode(Sha256::digest(b"whole blob")))).unwrap();
+        let repository = Repository::new_with_reference("library/nginx", &digest.to_string()).unwrap();
+        let (sender, receiver) = chunk_channel(&PersistChannel::Unbounded);
+        sender.send(Bytes::from_static(b"whole blob")).await.unwrap();
+        drop(sender);
+
+        // Written into the spool folder, then moved into the storage folder
+        assert!(handler.run(RegistryCommand::PersistBlob(String::new(), repository, MimeType::default(), receiver)).await.is_some());
+        assert_eq!(b"whole blob".to_vec(), std::fs::read(storage.digest_path(&digest)).unwrap());
+        assert_eq!(0, std::fs::read_dir(spool.path().join("sha256")).unwrap().count());
+    }
+
+    #[tokio::test]
+    async fn copy_blob_test() {
+        let spool = tempfile::tempdir().unwrap();
+        let folder = tempfile::tempdir().unwrap();
+        let tmp = spool.path().join("blob_1_2_tmp");
+        let path = folder.path().join("blob");
+        std::fs::write(&tmp, b"blob").unwrap();
+
+        // Across filesystems the blob is copied, nothing is left behind
+        copy_blob(&tmp, &path, true).await.expect("The blob was not copied");
+        assert!(!tmp.exists());
+        assert_eq!(b"blob".to_vec(), std::fs::read(&path).unwrap());
+        assert_eq!(vec![path.clone()], std::fs::read_dir(folder.path()).unwrap().map(|entry| entry.unwrap().path()).collect::<Vec<_>>());
+
+        // The tmp file is kept for the next attempt when the copy fails
+        std::fs::write(&tmp, b"blob").unwrap();
+        assert!(copy_blob(&tmp, &folder.path().join("missing").join("blob"), false).await.is_err());
+        assert!(tmp.exists());
+    }
+
+    #[tokio::test]
+    async fn rename_blob_retry_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let tmp = folder.path().join("blob_tmp");
+        let path = folder.path().join("blob");
+        std::fs::write(&tmp, b"blob").unwrap();
+
+        // The first two renames fail transiently
+        let failures = AtomicU32::new(2);
+        rename_blob(tmp.clone(), path.clone(), 3, |from, to| {
+            let fail = failures.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1)).is_ok();
+            async move {
+                if fail {
+                    return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "file in use"));
+                }
+                tokio::fs::rename(from, to).await
+            }
+        }).await.expect("The rename was not retried");
+
+        assert!(!tmp.exists());
+        assert_eq!(b"blob".to_vec(), std::fs::read(&path).unwrap());
+
+        // Once all the attempts failed the tmp file is cleaned up
+        std::fs::write(&tmp, b"blob").unwrap();
+        let result = rename_blob(tmp.clone(), folder.path().join("other"), 3, |_, _| async {
+            Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "file in use"))
+        }).await;
+
+        assert!(result.is_err());
+        assert!(!tmp.exists());
+        assert!(!folder.path().join("other").exists());
+    }
+}
@@ -1,12 +1,22 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use sqlx::SqlitePool;
 use crate::config::db::DBConfig;
+use crate::db::db_blob_refs::DBBlobRefs;
+use crate::db::db_blob_types::DBBlobTypes;
+use crate::db::db_contents::DBContents;
+use crate::db::db_dead_letters::DBDeadLetters;
 use crate::db::db_manifests::DBManifests;
+use crate::db::db_pulls::DBPulls;
+use crate::db::db_referrers::DBReferrers;
 use crate::db::pool::DBPool;
 use crate::error::error_kind::ErrorKind;
 use crate::error::registry::RegistryError;
+use crate::models::dead_letter::DeadLetterRecord;
 use crate::models::manifest_record::ManifestRecord;
-use crate::models::types::MimeType;
+use crate::models::referrer_record::ReferrerRecord;
+use crate::models::types::{ManifestSize, MimeType};
 use crate::registry::digest::Digest;
 use crate::registry::repository::Repository;
 
@@ -21,15 +31,241 @@ impl ManifestService {
         })
     }
 
-    /// Persists a link between an image tag and a digest
-    pub async fn persist(&self, repository: &Repository, reference: Digest, size: i32, mime: &MimeType) -> Result<u64, RegistryError> {
-        DBManifests::upsert(&self.pool, &repository.components.join("/"), &repository.reference, reference, size, mime).await
+    /// New instance backed by an existing pool
+    #[cfg(test)]
+    pub fn from_pool(pool: SqlitePool) -> Arc<ManifestService> {
+        Arc::new(ManifestService {
+            pool
+        })
+    }
+
+    /// Persists a link between an image tag of the upstream and a digest and, with `max_tags`, removes the tags of the
+    /// container image name of the upstream beyond the `max_tags` most recently refreshed ones along with their blob references,
+    /// in a single transaction. Returns the digest the tag was linked to until now, and the digests which are not referenced anymore
+    pub async fn persist(&self, upstream: &str, repository: &Repository, reference: Digest, size: ManifestSize, mime: &MimeType, max_tags: Option<u32>) -> Result<(Option<Digest>, Vec<Digest>), RegistryError> {
+        self.replace(upstream, repository, reference, size, mime, max_tags).await
             .map_err(|e| RegistryError::new(ErrorKind::RegistryManifestInvalid).with_error(e.to_string()))
     }
 
-    /// Get a reference from a tag name
-    pub async fn get(&self, repository: &Repository) -> Result<Option<ManifestRecord>, RegistryError> {
-        DBManifests::manifest_for_tag(&self.pool, &repository.components.join("/"), &repository.reference).await
+    /// The transaction of `persist`
+    async fn replace(&self, upstream: &str, repository: &Repository, reference: Digest, size: ManifestSize, mime: &MimeType, max_tags: Option<u32>) -> Result<(Option<Digest>, Vec<Digest>), sqlx::Error> {
+        let name = repository.components.join("/");
+        let mut transaction = self.pool.begin().await?;
+
+        let previous = DBManifests::replace(&mut transaction, upstream, &name, &repository.reference, reference, size, mime.as_str()).await?;
+
+        let mut released = Vec::new();
+        if let Some(max) = max_tags {
+            for manifest in DBManifests::prune_tags(&mut transaction, upstream, &name, &repository.reference, max).await? {
+                let Some(reference) = manifest.reference else { continue };
+                released.extend(DBBlobRefs::delete_for_manifest(&mut transaction, upstream, &manifest.name, &manifest.tag, &reference).await?);
+            }
+        }
+
+        transaction.commit().await?;
+        Ok((previous, released))
+    }
+
+    /// Mark the cached variant of the tag as refreshed from upstream, which confirmed it still points to the digest
+    pub async fn refresh(&self, upstream: &str, repository: &Repository, mime: &MimeType, reference: &Digest) -> Result<u64, RegistryError> {
+        DBManifests::refresh(&self.pool, upstream, &repository.components.join("/"), &repository.reference, mime.as_str(), reference).await
+            .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))
+    }
+
+    /// Assign the manifests indexed before their upstream was tracked to the upstream, returning how many were
+    pub async fn adopt(&self, upstream: &str) -> Result<u64, RegistryError> {
+        DBManifests::adopt(&self.pool, upstream).await
+            .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))
+    }
+
+    /// Every tag, and digest, of a container image name which is indexed
+    pub async fn list_by_name(&self, name: &str) -> Result<Vec<ManifestRecord>, RegistryError> {
+        DBManifests::list_by_name(&self.pool, name).await
+            .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))
+    }
+
+    /// Every tag, and digest, of every container image name which is indexed
+    pub async fn list_all(&self) -> Result<Vec<ManifestRecord>, RegistryError> {
+        DBManifests::list_all(&self.pool).await
+            .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))
+    }
+
+    /// Remove every tag and digest of a container image name of the upstream, or of every upstream, from the index,
+    /// returning the removed manifest records. The referrers, which are not tracked per upstream, go with the last upstream
+    pub async fn delete_by_name(&self, upstream: Option<&str>, name: &str) -> Result<Vec<ManifestRecord>, RegistryError> {
+        let manifests = DBManifests::delete_by_name(&self.pool, upstream, name).await
+            .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))?;
+
+        if self.list_by_name(name).await?.is_empty() {
+            DBReferrers::delete_by_name(&self.pool, name).await
+                .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))?;
+        }
+
+        Ok(manifests)
+    }
+
+    /// Remove from the index every tag, and digest, pointing to the manifest digest, and the blob references via it
+    pub async fn delete_by_reference(&self, reference: &Digest) -> Result<u64, RegistryError> {
+        let deleted = DBManifests::delete_by_reference(&self.pool, reference).await
+            .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))?;
+
+        DBBlobRefs::delete_for_manifest_digest(&self.pool, reference).await
+            .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))?;
+
+        Ok(deleted)
+    }
+
+    /// Record that the tag, or digest, of the upstream references the digests via the manifest it points to
+    pub async fn reference_blobs(&self, upstream: &str, repository: &Repository, manifest: &Digest, digests: &[Digest]) -> Result<(), RegistryError> {
+        DBBlobRefs::insert(&self.pool, upstream, &repository.name, &repository.reference, manifest, digests).await
+            .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))
+    }
+
+    /// Drop the references of the tag, or digest, of the upstream via the manifest it pointed to,
+    /// returning the digests which are not referenced anymore by any upstream
+    pub async fn release_blobs(&self, upstream: &str, repository: &Repository, manifest: &Digest) -> Result<Vec<Digest>, RegistryError> {
+        let released = async {
+            let mut transaction = self.pool.begin().await?;
+            let released = DBBlobRefs::delete_for_manifest(&mut transaction, upstream, &repository.name, &repository.reference, manifest).await?;
+            transaction.commit().await?;
+            Ok::<_, sqlx::Error>(released)
+        };
+        released.await
+            .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))
+    }
+
+    /// Drop the references of every tag, and digest, of a container image name of the upstream, or of every upstream,
+    /// returning the digests which are not referenced anymore by any upstream
+    pub async fn release_name(&self, upstream: Option<&str>, name: &str) -> Result<Vec<Digest>, RegistryError> {
+        DBBlobRefs::delete_by_name(&self.pool, upstream, name).await
+            .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))
+    }
+
+    /// Whether no blob reference was recorded yet, e.g. for a cache which predates them
+    pub async fn has_no_blob_refs(&self) -> Result<bool, RegistryError> {
+        DBBlobRefs::is_empty(&self.pool).await
+            .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))
+    }
+
+    /// Every manifest digest which is still indexed
+    pub async fn references(&self) -> Result<Vec<Digest>, RegistryError> {
+        DBManifests::references(&self.pool).await
+            .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))
+    }
+
+    /// Total manifest bytes for each container image name
+    pub async fn size_by_name(&self) -> Result<Vec<(String, i64)>, RegistryError> {
+        DBManifests::size_by_name(&self.pool).await
+            .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))
+    }
+
+    /// How many container image names and manifests are indexed, and the total size of the manifests
+    pub async fn summary(&self) -> Result<(i64, i64, i64), RegistryError> {
+        DBManifests::summary(&self.pool).await
+            .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))
+    }
+
+    /// Total manifest bytes for a container image name
+    pub async fn size_for_name(&self, name: &str) -> Result<i64, RegistryError> {
+        DBManifests::size_for_name(&self.pool, name).await
+            .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))
+    }
+
+    /// Index a manifest referring to another one via its subject
+    pub async fn persist_referrer(&self, referrer: &ReferrerRecord) -> Result<u64, RegistryError> {
+        DBReferrers::upsert(&self.pool, referrer).await
+            .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))
+    }
+
+    /// The manifests referring to a subject, optionally filtered by artifact type
+    pub async fn referrers(&self, repository: &Repository, subject: &Digest, artifact_type: Option<&str>) -> Result<Vec<ReferrerRecord>, RegistryError> {
+        DBReferrers::referrers(&self.pool, &repository.name, subject, artifact_type).await
+            .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))
+    }
+
+    /// Record a failed persistence
+    pub async fn persist_dead_letter(&self, dead_letter: &DeadLetterRecord) -> Result<u64, RegistryError> {
+        DBDeadLetters::upsert(&self.pool, dead_letter).await
+            .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))
+    }
+
+    /// Forget about a past failed persistence of the content
+    pub async fn delete_dead_letter(&self, upstream: &str, repository: &Repository) -> Result<u64, RegistryError> {
+        DBDeadLetters::delete(&self.pool, upstream, &repository.name, &repository.reference).await
+            .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))
+    }
+
+    /// Drop the oldest failed persistences beyond the most recent ones
+    pub async fn prune_dead_letters(&self, max_records: i64) -> Result<u64, RegistryError> {
+        DBDeadLetters::prune(&self.pool, max_records).await
+            .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))
+    }
+
+    /// Every failed persistence which did not succeed since
+    pub async fn dead_letters(&self) -> Result<Vec<DeadLetterRecord>, RegistryError> {
+        DBDeadLetters::dead_letters(&self.pool).await
+            .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))
+    }
+
+    /// Path a content, keyed by its sha256 digest, was stored at
+    pub async fn content_path(&self, content_key: &Digest) -> Result<Option<PathBuf>, RegistryError> {
+        DBContents::path_for(&self.pool, &content_key.to_string()).await
+            .map(|path| path.map(PathBuf::from))
+            .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))
+    }
+
+    /// Record the path a content, keyed by its sha256 digest, is stored at
+    pub async fn persist_content(&self, content_key: &Digest, path: &Path) -> Result<u64, RegistryError> {
+        DBContents::upsert(&self.pool, &content_key.to_string(), &path.to_string_lossy()).await
+            .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))
+    }
+
+    /// Media type upstream sent the blob with, if it was recorded
+    pub async fn blob_type(&self, digest: &Digest) -> Result<Option<MimeType>, RegistryError> {
+        DBBlobTypes::mime_for(&self.pool, &digest.to_string()).await
+            .map(|mime| mime.and_then(|mime| mime.parse().ok()))
+            .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))
+    }
+
+    /// Record the media type upstream sent the blob with
+    pub async fn persist_blob_type(&self, digest: &Digest, mime: &MimeType) -> Result<u64, RegistryError> {
+        DBBlobTypes::upsert(&self.pool, &digest.to_string(), mime.as_str()).await
+            .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))
+    }
+
+    /// Forget the media type of a blob which is not stored anymore
+    pub async fn delete_blob_type(&self, digest: &Digest) -> Result<u64, RegistryError> {
+        DBBlobTypes::delete(&self.pool, &digest.to_string()).await
+            .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))
+    }
+
+    /// Count the pulls of the container images, by name
+    pub async fn record_pulls(&self, pulls: &HashMap<String, u64>) -> Result<u64, RegistryError> {
+        DBPulls::record(&self.pool, pulls).await
+            .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))
+    }
+
+    /// The container images pulled the most with their pulls, at most `limit` of them
+    pub async fn most_pulled(&self, limit: u32) -> Result<Vec<(String, i64)>, RegistryError> {
+        DBPulls::top(&self.pool, limit).await
+            .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))
+    }
+
+    /// A page of the tags of a container image of the upstream, at most `n` of them after `last`,
+    /// and whether more tags follow it
+    pub async fn tags_page(&self, upstream: &str, repository: &Repository, n: Option<u32>, last: Option<&str>) -> Result<(Vec<String>, bool), RegistryError> {
+        let mut tags = DBManifests::tags_page(&self.pool, upstream, &repository.name, n.map(|n| n.saturating_add(1)), last).await
+            .map_err(|e| RegistryError::new(ErrorKind::SQLError).with_error(e.to_string()))?;
+
+        let more = n.is_some_and(|n| tags.len() > n as usize);
+        tags.truncate(n.map_or(usize::MAX, |n| n as usize));
+        Ok((tags, more))
+    }
+
+    /// Get a reference from a tag name of the upstream: the variant matching best the accepted media types, most preferred first
+    pub async fn get(&self, upstream: &str, repository: &Repository, accepted: &[MimeType]) -> Result<Option<ManifestRecord>, RegistryError> {
+        DBManifests::manifests_for_tag(&self.pool, upstream, &repository.components.join("/"), &repository.reference).await
+            .map(|variants| ManifestRecord::negotiate(variants, accepted))
             .map_err(|e| RegistryError::new(ErrorKind::RegistryManifestInvalid).with_error(e.to_string()))
     }
 }
\ No newline at end of file
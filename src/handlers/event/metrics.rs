@@ -0,0 +1,34 @@
+// SPDX-License-Identifier: Apache-2.0
+use std::sync::Arc;
+use async_trait::async_trait;
+use crate::metrics;
+use crate::models::events::RegistryEvent;
+use crate::pubsub::subscriber::EventSubscriberTrait;
+
+/// Counts the blobs stored in the cache
+pub struct PersistedMetricsHandler;
+
+impl PersistedMetricsHandler {
+    pub fn new() -> Arc<PersistedMetricsHandler> {
+        Arc::new(PersistedMetricsHandler)
+    }
+}
+
+#[async_trait]
+impl EventSubscriberTrait for PersistedMetricsHandler {
+    async fn run(&self, event: &RegistryEvent) {
+        match event {
+            RegistryEvent::BlobPersisted(upstream, repository) => {
+                tracing::debug!("Persisted blob {}@{} of upstream {}", repository.name, repository.reference, upstream);
+                metrics::BLOBS_PERSISTED_TOTAL.inc();
+            }
+            RegistryEvent::ManifestPersisted(upstream, repository, digest) => {
+                tracing::debug!("Persisted manifest {}:{} as {} of upstream {}", repository.name, repository.reference, digest, upstream);
+            }
+        }
+    }
+
+    fn supports_concurrency(&self) -> bool {
+        false
+    }
+}
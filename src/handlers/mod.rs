@@ -1,2 +1,3 @@
 // SPDX-License-Identifier: Apache-2.0
-pub mod command;
\ No newline at end of file
+pub mod command;
+pub mod event;
@@ -1,10 +1,13 @@
 // SPDX-License-Identifier: Apache-2.0
 use std::sync::Arc;
+use std::time::Duration;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use crate::config::app::AppConfig;
-use crate::db::pool::DBPool;
+use crate::eviction::Evictor;
+use crate::eviction::free_space::FilesystemFreeSpace;
 use crate::handlers::command::blob::persist::BlobPersistHandler;
 use crate::handlers::command::blob::service::ManifestService;
+use crate::handlers::event::metrics::PersistedMetricsHandler;
 use crate::models::commands::{PERSIST_BLOB, PERSIST_MANIFEST};
 use crate::pubsub::command_bus::CommandBus;
 use crate::repository::filesystem::FilesystemStorage;
@@ -20,10 +23,30 @@ mod models;
 mod handlers;
 mod metrics;
 mod db;
+mod eviction;
+mod dead_letters;
+mod priming;
+mod telemetry;
+
+/// Validate config.yaml and exit, instead of starting the server
+const CHECK_CONFIG_FLAG: &str = "--check-config";
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
 
+    if std::env::args().any(|arg| arg == CHECK_CONFIG_FLAG) {
+        std::process::exit(check_config());
+    }
+
+    // Get access to the config
+    let config = AppConfig::load().expect("Application Config error");
+
+    // Traces exported to the collector, when configured
+    let (telemetry, telemetry_error) = match telemetry::layer(&config.telemetry) {
+        Ok(telemetry) => (telemetry, None),
+        Err(e) => (None, Some(e)),
+    };
+
     // Logging
     tracing_subscriber::registry()
         .with(
@@ -34,18 +57,25 @@ async fn main() -> std::io::Result<()> {
             }),
         )
         .with(tracing_subscriber::fmt::layer())
+        .with(telemetry)
         .init();
 
-    // Get access to the config
-    let config = AppConfig::load().expect("Application Config error");
-    if !config.is_valid() {
-        return Ok(tracing::error!("invalid config.yaml"));
+    if let Some(e) = telemetry_error {
+        tracing::error!("{}", e);
+    }
+
+    if let Err(errors) = config.validate() {
+        for error in errors {
+            tracing::error!("{}", error);
+        }
+        tracing::error!("invalid config.yaml");
+        return Ok(());
     }
 
     // Init the command bus
-    let queue_size = 4096;
+    let queue_size = config.streaming.command_queue_size;
     let (command_sender, command_receiver) = tokio::sync::mpsc::channel(queue_size);
-    let command_bus = CommandBus::new(command_sender, queue_size);
+    let command_bus = CommandBus::new(command_sender, queue_size, config.streaming.persist_partition.clone());
     let local_command_bus = command_bus.clone();
     tokio::spawn(async move {
         local_command_bus.start(command_receiver).await;
@@ -54,19 +84,65 @@ async fn main() -> std::io::Result<()> {
     // Manifest service
     let manifest_service = ManifestService::new(&config.db).await;
     let filesystem_storage = Arc::new(FilesystemStorage::new(config.clone()));
-    let blob_handler = BlobPersistHandler::new(filesystem_storage, manifest_service.clone());
+
+    // The manifests indexed before their upstream was tracked can only come from a single upstream,
+    // with several of them they are not served anymore and get pulled again
+    if let [upstream] = config.upstreams.as_slice() {
+        match manifest_service.adopt(&upstream.host).await {
+            Ok(0) => {}
+            Ok(adopted) => tracing::info!("{} manifests indexed before their upstream was tracked assigned to {}", adopted, upstream.host),
+            Err(e) => tracing::error!("Failed to assign the manifests to upstream {}: {}", upstream.host, e),
+        }
+    }
+
+    // Otherwise no blob could ever be stored
+    if let Err(e) = filesystem_storage.create_folders() {
+        tracing::error!("Failed to create the storage folder {}: {}", config.storage.folder, e);
+        return Err(e);
+    }
+
+    // Disk usage metrics
+    tokio::spawn(metrics::disk_usage::start(filesystem_storage.clone(), manifest_service.clone(),
+                                            Duration::from_secs(config.storage.disk_usage_interval)));
+
+    // Free disk space based eviction
+    let evictor = Evictor::new(filesystem_storage.clone(), manifest_service.clone(), Arc::new(FilesystemFreeSpace), config.eviction.clone());
+    tokio::spawn(evictor.start());
+
+    let blob_handler = BlobPersistHandler::new(filesystem_storage.clone(), manifest_service.clone(), config.storage.clone());
 
     // Subscribe the persistence handler
     command_bus.subscribe(PERSIST_BLOB.to_string(), blob_handler.clone()).await;
     command_bus.subscribe(PERSIST_MANIFEST.to_string(), blob_handler).await;
 
+    // Subscribe the handlers of the persisted content
+    command_bus.subscribe_events(PersistedMetricsHandler::new()).await;
+
     // Start the API server
-    if let Err(e) = api::server::start(config.clone(), command_bus.clone(), manifest_service).await {
+    if let Err(e) = api::server::start(config.clone(), command_bus.clone(), filesystem_storage, manifest_service).await {
         tracing::info!("Error shutting down registry cache {}", e);
     }
 
+    telemetry::shutdown();
     tracing::info!("Shutdown completed");
 
     Ok(())
 
 }
+
+/// Load and validate config.yaml, printing every problem found.
+/// Returns the exit code of the process
+fn check_config() -> i32 {
+    match AppConfig::load().map_err(|e| vec![e.to_string()]).and_then(|config| config.validate()) {
+        Ok(_) => {
+            println!("config.yaml is valid");
+            0
+        }
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{}", error);
+            }
+            1
+        }
+    }
+}
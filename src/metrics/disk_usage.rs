@@ -0,0 +1,42 @@
+// SPDX-License-Identifier: Apache-2.0
+use std::sync::Arc;
+use std::time::Duration;
+use crate::handlers::command::blob::service::ManifestService;
+use crate::metrics;
+use crate::repository::filesystem::FilesystemStorage;
+
+/// Periodically recalculate the disk usage metrics.
+/// The persistence handler keeps them up to date in between, this corrects any drift.
+pub async fn start(storage: Arc<FilesystemStorage>, manifests: Arc<ManifestService>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+
+    loop {
+        ticker.tick().await;
+        update(storage.clone(), &manifests).await;
+    }
+}
+
+/// Walk the storage folder and query the manifests table to refresh the disk usage metrics
+pub async fn update(storage: Arc<FilesystemStorage>, manifests: &ManifestService) {
+
+    // Walking the folder is blocking IO
+    match tokio::task::spawn_blocking(move || storage.disk_usage()).await {
+        Ok(Ok((bytes, blobs))) => {
+            metrics::CACHE_DISK_BYTES.set(bytes as i64);
+            metrics::CACHE_BLOB_COUNT.set(blobs as i64);
+        }
+        Ok(Err(e)) => tracing::error!("Failed to calculate the cache disk usage: {}", e.to_string()),
+        Err(e) => tracing::error!("Failed to run the cache disk usage task: {}", e.to_string()),
+    }
+
+    // Per container image usage
+    match manifests.size_by_name().await {
+        Ok(sizes) => {
+            metrics::CACHE_REPOSITORY_BYTES.reset();
+            for (name, size) in sizes {
+                metrics::CACHE_REPOSITORY_BYTES.with_label_values(&[&name]).set(size);
+            }
+        }
+        Err(e) => tracing::error!("Failed to calculate the repositories disk usage: {}", e.to_string()),
+    }
+}
@@ -0,0 +1,195 @@
+// SPDX-License-Identifier: Apache-2.0
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+use lazy_static::lazy_static;
+use opentelemetry::trace::TraceContextExt;
+use parking_lot::Mutex;
+use prometheus::core::Metric;
+use prometheus::proto::{MetricFamily, MetricType};
+use prometheus::HistogramVec;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Content type of the OpenMetrics text format, the one which carries the exemplars
+pub const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// A histogram bucket: the name of the histogram, the labels of the observation and the upper bound of the bucket
+type BucketKey = (String, Vec<(String, String)>, u64);
+
+/// The trace of an observation which landed in a bucket
+struct Exemplar {
+    trace_id: String,
+    value: f64,
+
+    /// Seconds since the unix epoch
+    timestamp: f64,
+}
+
+lazy_static! {
+    /// The exemplar of the last traced observation of each bucket
+    static ref EXEMPLARS: Mutex<HashMap<BucketKey, Exemplar>> = Default::default();
+}
+
+/// Observe the value on the histogram of the label values. When the current span is part of a sampled trace,
+/// i.e. the traces are exported via `telemetry.otlp_endpoint`, the trace becomes the exemplar of the bucket
+pub fn observe(histogram: &HistogramVec, labels: &[&str], value: f64) {
+    let histogram = histogram.with_label_values(labels);
+    histogram.observe(value);
+
+    let context = tracing::Span::current().context();
+    let span_context = context.span().span_context().clone();
+    if !span_context.is_valid() || !span_context.is_sampled() {
+        return;
+    }
+
+    let metric = histogram.metric();
+    let upper_bound = metric.get_histogram().get_bucket().iter()
+        .map(|bucket| bucket.get_upper_bound())
+        .find(|upper_bound| value <= *upper_bound)
+        .unwrap_or(f64::INFINITY);
+    let Some(name) = prometheus::core::Collector::desc(&histogram).first().map(|desc| desc.fq_name.clone()) else { return };
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+    EXEMPLARS.lock().insert((name, label_pairs(&metric), upper_bound.to_bits()), Exemplar {
+        trace_id: span_context.trace_id().to_string(),
+        value,
+        timestamp,
+    });
+}
+
+/// Encode the metric families in the OpenMetrics text format, with the exemplars of the histogram buckets.
+/// The counters whose name does not end with `_total`, which OpenMetrics requires, keep their name and are typed `unknown`
+pub fn encode(families: &[MetricFamily]) -> String {
+    let exemplars = EXEMPLARS.lock();
+    let mut output = String::new();
+
+    for family in families {
+        let name = family.get_name();
+        let (family_name, kind) = match family.get_field_type() {
+            MetricType::COUNTER => match name.strip_suffix("_total") {
+                Some(family_name) => (family_name, "counter"),
+                None => (name, "unknown"),
+            },
+            MetricType::GAUGE => (name, "gauge"),
+            MetricType::HISTOGRAM => (name, "histogram"),
+            MetricType::SUMMARY => (name, "summary"),
+            MetricType::UNTYPED => (name, "unknown"),
+        };
+        let _ = writeln!(output, "# TYPE {} {}", family_name, kind);
+        let _ = writeln!(output, "# HELP {} {}", family_name, escape(family.get_help()));
+
+        for metric in family.get_metric() {
+            let labels = label_pairs(metric);
+            match family.get_field_type() {
+                MetricType::COUNTER => sample(&mut output, name, &labels, None, metric.get_counter().get_value()),
+                MetricType::GAUGE => sample(&mut output, name, &labels, None, metric.get_gauge().get_value()),
+                MetricType::UNTYPED => sample(&mut output, name, &labels, None, metric.get_untyped().get_value()),
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let bucket = format!("{}_bucket", name);
+                    let buckets = histogram.get_bucket().iter()
+                        .map(|bucket| (bucket.get_upper_bound(), bucket.get_cumulative_count()))
+                        .chain(std::iter::once((f64::INFINITY, histogram.get_sample_count())));
+                    for (upper_bound, count) in buckets {
+                        sample(&mut output, &bucket, &labels, Some(("le", number(upper_bound))), count as f64);
+                        if let Some(exemplar) = exemplars.get(&(name.to_string(), labels.clone(), upper_bound.to_bits())) {
+                            output.pop();
+                            let _ = writeln!(output, " # {{trace_id=\"{}\"}} {} {:.3}", exemplar.trace_id, number(exemplar.value), exemplar.timestamp);
+                        }
+                    }
+                    sample(&mut output, &format!("{}_count", name), &labels, None, histogram.get_sample_count() as f64);
+                    sample(&mut output, &format!("{}_sum", name), &labels, None, histogram.get_sample_sum());
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        sample(&mut output, name, &labels, Some(("quantile", number(quantile.get_quantile()))), quantile.get_value());
+                    }
+                    sample(&mut output, &format!("{}_count", name), &labels, None, summary.get_sample_count() as f64);
+                    sample(&mut output, &format!("{}_sum", name), &labels, None, summary.get_sample_sum());
+                }
+            }
+        }
+    }
+
+    output.push_str("# EOF\n");
+    output
+}
+
+/// The labels of the metric, in the order the encoders write them
+fn label_pairs(metric: &prometheus::proto::Metric) -> Vec<(String, String)> {
+    metric.get_label().iter().map(|label| (label.get_name().to_string(), label.get_value().to_string())).collect()
+}
+
+/// Write a sample line, with the extra label of the histogram buckets and of the summary quantiles if any
+fn sample(output: &mut String, name: &str, labels: &[(String, String)], extra: Option<(&str, String)>, value: f64) {
+    let labels = labels.iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .chain(extra.as_ref().map(|(name, value)| (*name, value.as_str())))
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect::<Vec<_>>();
+
+    match labels.is_empty() {
+        true => { let _ = writeln!(output, "{} {}", name, number(value)); }
+        false => { let _ = writeln!(output, "{}{{{}}} {}", name, labels.join(","), number(value)); }
+    }
+}
+
+/// A number as OpenMetrics writes it
+fn number(value: f64) -> String {
+    match value {
+        f64::INFINITY => "+Inf".to_string(),
+        f64::NEG_INFINITY => "-Inf".to_string(),
+        value if value.is_nan() => "NaN".to_string(),
+        value => value.to_string(),
+    }
+}
+
+/// Escape a label value or the text of a HELP line
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\n', "\\n").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod test {
+    use opentelemetry::trace::{TraceContextExt, TracerProvider};
+    use prometheus::core::Collector;
+    use prometheus::{HistogramOpts, HistogramVec};
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::layer::SubscriberExt;
+    use crate::metrics::exemplars::{encode, observe};
+
+    #[test]
+    fn exemplars_test() {
+        let histogram = HistogramVec::new(HistogramOpts::new("exemplars_test_seconds", "Seconds of the test").buckets(vec![0.1, 0.5]), &["kind"]).unwrap();
+
+        // Not traced
+        observe(&histogram, &["blob"], 0.05);
+
+        // Traced, as when the traces are exported
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let trace_id = tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("request");
+            let _entered = span.enter();
+            observe(&histogram, &["blob"], 0.3);
+            observe(&histogram, &["manifest"], 2.0);
+            span.context().span().span_context().trace_id().to_string()
+        });
+
+        let encoded = encode(&histogram.collect());
+        let lines = encoded.lines().collect::<Vec<_>>();
+        assert_eq!(["# TYPE exemplars_test_seconds histogram", "# HELP exemplars_test_seconds Seconds of the test"], lines[..2]);
+        for line in [r#"exemplars_test_seconds_bucket{kind="blob",le="0.1"} 1"#, r#"exemplars_test_seconds_bucket{kind="blob",le="+Inf"} 2"#,
+                     r#"exemplars_test_seconds_count{kind="blob"} 2"#, r#"exemplars_test_seconds_bucket{kind="manifest",le="0.5"} 0"#] {
+            assert!(lines.contains(&line), "{} in {}", line, encoded);
+        }
+
+        // The traced observations are the exemplars of their bucket, with their timestamp
+        for exemplar in [format!(r#"exemplars_test_seconds_bucket{{kind="blob",le="0.5"}} 2 # {{trace_id="{}"}} 0.3 "#, trace_id),
+                         format!(r#"exemplars_test_seconds_bucket{{kind="manifest",le="+Inf"}} 1 # {{trace_id="{}"}} 2 "#, trace_id)] {
+            assert!(lines.iter().any(|line| line.starts_with(&exemplar)), "{} in {}", exemplar, encoded);
+        }
+        assert_eq!(Some(&"# EOF"), lines.last());
+    }
+}
@@ -1,7 +1,10 @@
 // SPDX-License-Identifier: Apache-2.0
+pub mod disk_usage;
+pub mod exemplars;
+
 use lazy_static::lazy_static;
 use prometheus::{
-    HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
 };
 
 lazy_static! {
@@ -15,20 +18,109 @@ lazy_static! {
     pub static ref UPSTREAM_RESPONSES: IntCounter =
         IntCounter::new("upstream_responses", "Upstream Responses").expect("upstream_responses metric cannot be created");
 
-    pub static ref CONNECTED_CLIENTS: IntGauge =
-        IntGauge::new("connected_clients", "Connected Clients").expect("connected_clients metric cannot be created");
-
     pub static ref RESPONSE_CODE_COLLECTOR: IntCounterVec = IntCounterVec::new(
         Opts::new("response_code", "Response Code"),
         &["statuscode", "type", "image"]
     )
     .expect("response_code metric cannot be created");
 
-    pub static ref RESPONSE_TIME_COLLECTOR: HistogramVec = HistogramVec::new(
-        HistogramOpts::new("response_time", "Response Times"),
-        &["env"]
+    pub static ref UPSTREAM_RESPONSE_TIME: HistogramVec = HistogramVec::new(
+        HistogramOpts::new("upstream_response_time_seconds", "Seconds until the upstream answered, or failed to, per upstream and kind of request")
+            .buckets(vec![0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 15.0, 30.0]),
+        &["upstream", "kind"]
+    )
+    .expect("upstream_response_time_seconds metric cannot be created");
+
+    pub static ref UPSTREAM_REQUESTS_TOTAL: IntCounterVec = IntCounterVec::new(
+        Opts::new("upstream_requests_total", "Upstream requests per upstream and result: success, client_error, server_error, timeout or connect_error"),
+        &["upstream", "result"]
+    )
+    .expect("upstream_requests_total metric cannot be created");
+
+    pub static ref UPSTREAM_STREAMS_IN_FLIGHT: IntGauge =
+        IntGauge::new("upstream_streams_in_flight", "Upstream responses being streamed").expect("upstream_streams_in_flight metric cannot be created");
+
+    pub static ref UPSTREAM_IN_FLIGHT: IntGauge =
+        IntGauge::new("upstream_in_flight", "Upstream requests sent and not fully received yet").expect("upstream_in_flight metric cannot be created");
+
+    pub static ref REQUESTS_TIMED_OUT: IntCounter =
+        IntCounter::new("requests_timed_out", "Registry requests which did not start their response within api->request_timeout_secs").expect("requests_timed_out metric cannot be created");
+
+    pub static ref CACHE_DISK_BYTES: IntGauge =
+        IntGauge::new("cache_disk_bytes", "Bytes stored in the cache folder").expect("cache_disk_bytes metric cannot be created");
+
+    pub static ref CACHE_BLOB_COUNT: IntGauge =
+        IntGauge::new("cache_blob_count", "Blobs stored in the cache folder").expect("cache_blob_count metric cannot be created");
+
+    pub static ref CACHE_DISK_FREE_BYTES: IntGauge =
+        IntGauge::new("cache_disk_free_bytes", "Free bytes of the filesystem backing the cache folder").expect("cache_disk_free_bytes metric cannot be created");
+
+    pub static ref CACHE_EVICTED_BLOBS: IntCounter =
+        IntCounter::new("cache_evicted_blobs", "Blobs evicted from the cache").expect("cache_evicted_blobs metric cannot be created");
+
+    pub static ref CACHE_TAG_MOVED: IntCounter =
+        IntCounter::new("cache_tag_moved", "Tags moved upstream to a new manifest digest").expect("cache_tag_moved metric cannot be created");
+
+    pub static ref CACHE_OVERSIZED: IntCounter =
+        IntCounter::new("cache_oversized", "Blobs and manifests not stored for exceeding the configured maximum size").expect("cache_oversized metric cannot be created");
+
+    pub static ref CACHE_SKIPPED_LOW_SPACE: IntCounter =
+        IntCounter::new("cache_skipped_low_space", "Blobs and manifests not stored for the free disk space being below storage.min_free_bytes").expect("cache_skipped_low_space metric cannot be created");
+
+    pub static ref CACHE_CORRUPTED_BLOBS: IntCounter =
+        IntCounter::new("cache_corrupted_blobs", "Cached blobs whose digest did not match when verified on read, fetched from upstream again").expect("cache_corrupted_blobs metric cannot be created");
+
+    pub static ref PULLS_TOTAL: IntCounterVec = IntCounterVec::new(
+        Opts::new("pulls_total", "Manifests served per container image, from upstream or from the cache"),
+        &["repository"]
+    )
+    .expect("pulls_total metric cannot be created");
+
+    pub static ref NEGATIVE_CACHE_HITS: IntCounter =
+        IntCounter::new("negative_cache_hits", "Blob and manifest pulls answered with a 404 without asking upstream, which recently did not have them").expect("negative_cache_hits metric cannot be created");
+
+    pub static ref CACHE_DEDUPLICATED_BLOBS: IntCounter =
+        IntCounter::new("cache_deduplicated_blobs", "Blobs linked to the identical content stored under another digest algorithm").expect("cache_deduplicated_blobs metric cannot be created");
+
+    pub static ref BLOBS_PERSISTED_TOTAL: IntCounter =
+        IntCounter::new("blobs_persisted_total", "Blobs stored in the cache").expect("blobs_persisted_total metric cannot be created");
+
+    pub static ref UPSTREAM_NOT_MODIFIED: IntCounter =
+        IntCounter::new("upstream_not_modified", "Cached manifests revalidated upstream with a 304 Not Modified instead of being pulled again").expect("upstream_not_modified metric cannot be created");
+
+    pub static ref UPSTREAM_STREAM_FAILURES: IntCounter =
+        IntCounter::new("upstream_stream_failures", "Upstream blob and manifest responses which failed or stalled while being streamed").expect("upstream_stream_failures metric cannot be created");
+
+    pub static ref CACHE_DEAD_LETTERS: IntCounter =
+        IntCounter::new("cache_dead_letters", "Blobs and manifests whose persistence failed and was recorded as a dead letter").expect("cache_dead_letters metric cannot be created");
+
+    pub static ref UPSTREAM_CIRCUIT_STATE: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("upstream_circuit_state", "Circuit breaker of the upstream: 0 closed, 1 open, 2 half-open"),
+        &["upstream"]
+    )
+    .expect("upstream_circuit_state metric cannot be created");
+    pub static ref UPSTREAM_EGRESS_BUDGET_REMAINING: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("upstream_egress_budget_remaining_bytes", "Bytes which can still be fetched from the upstream within the egress budget window"),
+        &["upstream"]
     )
-    .expect("response_time metric cannot be created");
+    .expect("upstream_egress_budget_remaining_bytes metric cannot be created");
+    pub static ref PERSIST_BACKLOG: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("persist_backlog", "Blobs and manifests queued or being persisted per upstream"),
+        &["upstream"]
+    )
+    .expect("persist_backlog metric cannot be created");
+    pub static ref COMMAND_BUS_QUEUE_LENGTH: IntGauge =
+        IntGauge::new("command_bus_queue_length", "Commands queued in the command bus, not dispatched to a worker pool yet").expect("command_bus_queue_length metric cannot be created");
+    pub static ref WORKER_POOL_PENDING: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("worker_pool_pending", "Commands published to the worker pool and not picked up by a worker yet, per pool"),
+        &["pool"]
+    )
+    .expect("worker_pool_pending metric cannot be created");
+    pub static ref CACHE_REPOSITORY_BYTES: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("cache_repository_bytes", "Manifest bytes stored per container image"),
+        &["image"]
+    )
+    .expect("cache_repository_bytes metric cannot be created");
 }
 
 pub fn register_metrics() {
@@ -39,21 +131,88 @@ pub fn register_metrics() {
         .register(Box::new(INCOMING_REQUESTS.clone()))
         .expect("incoming_requests collector can cannot registered");
 
-    registry
-        .register(Box::new(CONNECTED_CLIENTS.clone()))
-        .expect("connected_clients collector can cannot registered");
-
     registry
         .register(Box::new(RESPONSE_CODE_COLLECTOR.clone()))
         .expect("response_code collector can cannot registered");
 
     registry
-        .register(Box::new(RESPONSE_TIME_COLLECTOR.clone()))
-        .expect("response_time collector can cannot registered");
+        .register(Box::new(UPSTREAM_RESPONSE_TIME.clone()))
+        .expect("upstream_response_time_seconds collector can cannot registered");
+
+    registry
+        .register(Box::new(UPSTREAM_REQUESTS_TOTAL.clone()))
+        .expect("upstream_requests_total collector can cannot registered");
 
     registry.register(Box::new(CACHED_RESPONSES.clone()))
         .expect("cached_responses collector can cannot registered");
 
+    registry.register(Box::new(UPSTREAM_STREAMS_IN_FLIGHT.clone()))
+        .expect("upstream_streams_in_flight collector can cannot registered");
+
+    registry.register(Box::new(UPSTREAM_IN_FLIGHT.clone()))
+        .expect("upstream_in_flight collector can cannot registered");
+
+    registry.register(Box::new(REQUESTS_TIMED_OUT.clone()))
+        .expect("requests_timed_out collector can cannot registered");
+
     registry.register(Box::new(UPSTREAM_RESPONSES.clone()))
         .expect("upstream_responses collector can cannot registered");
+
+    registry.register(Box::new(CACHE_DISK_BYTES.clone()))
+        .expect("cache_disk_bytes collector can cannot registered");
+
+    registry.register(Box::new(CACHE_BLOB_COUNT.clone()))
+        .expect("cache_blob_count collector can cannot registered");
+
+    registry.register(Box::new(CACHE_DISK_FREE_BYTES.clone()))
+        .expect("cache_disk_free_bytes collector can cannot registered");
+
+    registry.register(Box::new(CACHE_EVICTED_BLOBS.clone()))
+        .expect("cache_evicted_blobs collector can cannot registered");
+
+    registry.register(Box::new(CACHE_TAG_MOVED.clone()))
+        .expect("cache_tag_moved collector can cannot registered");
+
+    registry.register(Box::new(CACHE_OVERSIZED.clone()))
+        .expect("cache_oversized collector can cannot registered");
+
+    registry.register(Box::new(CACHE_SKIPPED_LOW_SPACE.clone()))
+        .expect("cache_skipped_low_space collector can cannot registered");
+
+    registry.register(Box::new(CACHE_CORRUPTED_BLOBS.clone()))
+        .expect("cache_corrupted_blobs collector can cannot registered");
+
+    registry.register(Box::new(PULLS_TOTAL.clone()))
+        .expect("pulls_total collector can cannot registered");
+
+    registry.register(Box::new(NEGATIVE_CACHE_HITS.clone()))
+        .expect("negative_cache_hits collector can cannot registered");
+
+    registry.register(Box::new(CACHE_DEDUPLICATED_BLOBS.clone()))
+        .expect("cache_deduplicated_blobs collector can cannot registered");
+
+    registry.register(Box::new(BLOBS_PERSISTED_TOTAL.clone()))
+        .expect("blobs_persisted_total collector can cannot registered");
+
+    registry.register(Box::new(UPSTREAM_NOT_MODIFIED.clone()))
+        .expect("upstream_not_modified collector can cannot registered");
+
+    registry.register(Box::new(UPSTREAM_STREAM_FAILURES.clone()))
+        .expect("upstream_stream_failures collector can cannot registered");
+
+    registry.register(Box::new(CACHE_DEAD_LETTERS.clone()))
+        .expect("cache_dead_letters collector can cannot registered");
+
+    registry.register(Box::new(UPSTREAM_CIRCUIT_STATE.clone()))
+        .expect("upstream_circuit_state collector can cannot registered");
+    registry.register(Box::new(UPSTREAM_EGRESS_BUDGET_REMAINING.clone()))
+        .expect("upstream_egress_budget_remaining_bytes collector can cannot registered");
+    registry.register(Box::new(PERSIST_BACKLOG.clone()))
+        .expect("persist_backlog collector can cannot registered");
+    registry.register(Box::new(COMMAND_BUS_QUEUE_LENGTH.clone()))
+        .expect("command_bus_queue_length collector can cannot registered");
+    registry.register(Box::new(WORKER_POOL_PENDING.clone()))
+        .expect("worker_pool_pending collector can cannot registered");
+    registry.register(Box::new(CACHE_REPOSITORY_BYTES.clone()))
+        .expect("cache_repository_bytes collector can cannot registered");
 }
\ No newline at end of file
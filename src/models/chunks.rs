@@ -0,0 +1,127 @@
+// SPDX-License-Identifier: Apache-2.0
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use bytes::Bytes;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::SendError;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use crate::config::streaming::PersistChannel;
+
+/// Sending side of the channel carrying the blob chunks to the persistence worker
+pub struct ChunkSender {
+    sender: Sender,
+    aborted: Arc<AtomicBool>,
+}
+
+enum Sender {
+    Unbounded(mpsc::UnboundedSender<Bytes>),
+    Bounded(mpsc::Sender<Bytes>),
+}
+
+/// Receiving side of the channel carrying the blob chunks to the persistence worker
+#[derive(Debug)]
+pub struct ChunkReceiver {
+    receiver: Receiver,
+    aborted: Arc<AtomicBool>,
+
+    /// Trace context of the request the chunks come from, which does not keep its span open
+    trace_context: opentelemetry::Context,
+}
+
+#[derive(Debug)]
+enum Receiver {
+    Unbounded(mpsc::UnboundedReceiver<Bytes>),
+    Bounded(mpsc::Receiver<Bytes>),
+}
+
+/// Create the chunks channel of the configured kind
+pub fn chunk_channel(kind: &PersistChannel) -> (ChunkSender, ChunkReceiver) {
+    let (sender, receiver) = match kind {
+        PersistChannel::Unbounded => {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            (Sender::Unbounded(sender), Receiver::Unbounded(receiver))
+        }
+        PersistChannel::Bounded(capacity) => {
+            let (sender, receiver) = mpsc::channel(*capacity);
+            (Sender::Bounded(sender), Receiver::Bounded(receiver))
+        }
+    };
+
+    let aborted = Arc::new(AtomicBool::new(false));
+    (ChunkSender { sender, aborted: aborted.clone() }, ChunkReceiver { receiver, aborted, trace_context: tracing::Span::current().context() })
+}
+
+impl ChunkSender {
+    /// Send a chunk, waiting for capacity in case of a bounded channel
+    pub async fn send(&self, chunk: Bytes) -> Result<(), SendError<Bytes>> {
+        match &self.sender {
+            Sender::Unbounded(sender) => sender.send(chunk),
+            Sender::Bounded(sender) => sender.send(chunk).await,
+        }
+    }
+
+    /// Close the channel without completing the blob, e.g. because the upstream stream broke off
+    pub fn abort(self) {
+        self.aborted.store(true, Ordering::SeqCst);
+    }
+}
+
+impl ChunkReceiver {
+    /// Receive the next chunk, None once the sender is dropped
+    pub async fn recv(&mut self) -> Option<Bytes> {
+        match &mut self.receiver {
+            Receiver::Unbounded(receiver) => receiver.recv().await,
+            Receiver::Bounded(receiver) => receiver.recv().await,
+        }
+    }
+
+    /// Whether the sender gave up before sending the whole blob, only meaningful once `recv` returned None
+    pub fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::SeqCst)
+    }
+
+    /// Trace context of the request the chunks come from, the persistence is traced as its child
+    pub fn trace_context(&self) -> &opentelemetry::Context {
+        &self.trace_context
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+    use crate::config::streaming::PersistChannel;
+    use crate::models::chunks::chunk_channel;
+
+    #[tokio::test]
+    async fn bounded_chunk_channel_test() {
+        let (sender, mut receiver) = chunk_channel(&PersistChannel::Bounded(1));
+
+        let producer = tokio::spawn(async move {
+            for chunk in ["a", "b", "c"] {
+                sender.send(Bytes::from_static(chunk.as_bytes())).await.unwrap();
+            }
+        });
+
+        let mut received = Vec::new();
+        while let Some(chunk) = receiver.recv().await {
+            received.push(chunk);
+        }
+        producer.await.unwrap();
+
+        assert_eq!(received, vec!["a", "b", "c"]);
+        assert!(!receiver.is_aborted());
+    }
+
+    #[tokio::test]
+    async fn aborted_chunk_channel_test() {
+        let (sender, mut receiver) = chunk_channel(&PersistChannel::Unbounded);
+
+        sender.send(Bytes::from_static(b"a")).await.unwrap();
+        sender.abort();
+
+        // The chunks sent before the abort are still delivered
+        assert_eq!(Some(Bytes::from_static(b"a")), receiver.recv().await);
+        assert_eq!(None, receiver.recv().await);
+        assert!(receiver.is_aborted());
+    }
+}
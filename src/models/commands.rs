@@ -1,9 +1,8 @@
 // SPDX-License-Identifier: Apache-2.0
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
-use bytes::Bytes;
-use tokio::sync::mpsc::UnboundedReceiver;
-use crate::models::types::{ManifestSize, MimeType};
+use crate::models::chunks::ChunkReceiver;
+use crate::models::types::{MimeType, UpstreamHost};
 use crate::pubsub::command::ChannelId;
 use crate::registry::digest::Digest;
 use crate::registry::repository::Repository;
@@ -15,24 +14,42 @@ pub const PERSIST_MANIFEST:&str = "persist_manifest";
 #[derive(Debug)]
 pub enum RegistryCommand {
     Shutdown,
-    PersistBlob(Repository, UnboundedReceiver<Bytes>),
-    PersistManifest(Repository, Option<Digest>, ManifestSize, MimeType, UnboundedReceiver<Bytes>),
+    PersistBlob(UpstreamHost, Repository, MimeType, ChunkReceiver),
+    PersistManifest(UpstreamHost, Repository, Option<Digest>, MimeType, ChunkReceiver),
 }
 
 impl RegistryCommand {
     pub fn id(&self) -> String {
         match self {
             RegistryCommand::Shutdown => String::from(SHUTDOWN),
-            RegistryCommand::PersistBlob(repo,_) => repo.reference.to_string(),
-            RegistryCommand::PersistManifest(repo, _, _, _, _) => repo.reference.to_string(),
+            RegistryCommand::PersistBlob(_, repo, _, _) => repo.reference.to_string(),
+            RegistryCommand::PersistManifest(_, repo, _, _, _) => repo.reference.to_string(),
         }
 
     }
 
+    /// The upstream the content comes from
+    pub fn upstream(&self) -> &str {
+        match self {
+            RegistryCommand::Shutdown => "",
+            RegistryCommand::PersistBlob(upstream, _, _, _) => upstream,
+            RegistryCommand::PersistManifest(upstream, _, _, _, _) => upstream,
+        }
+    }
+
+    /// Trace context of the request the content comes from, an empty one for a shutdown
+    pub fn trace_context(&self) -> opentelemetry::Context {
+        match self {
+            RegistryCommand::Shutdown => opentelemetry::Context::new(),
+            RegistryCommand::PersistBlob(_, _, _, receiver) => receiver.trace_context().clone(),
+            RegistryCommand::PersistManifest(_, _, _, _, receiver) => receiver.trace_context().clone(),
+        }
+    }
+
     pub fn topic(&self) -> String {
         match self {
             RegistryCommand::Shutdown => String::from(SHUTDOWN),
-            RegistryCommand::PersistBlob(_,_) => String::from(PERSIST_BLOB),
+            RegistryCommand::PersistBlob(_,_,_,_) => String::from(PERSIST_BLOB),
             RegistryCommand::PersistManifest(_,_,_,_,_) => String::from(PERSIST_MANIFEST),
         }
 
@@ -0,0 +1,29 @@
+use serde::Serialize;
+use crate::models::types::{MimeType, UpstreamHost};
+use crate::registry::digest::Digest;
+
+/// DeadLetterRecord keeps track of a blob or a manifest whose persistence failed
+#[derive(Serialize, Clone, Debug)]
+pub struct DeadLetterRecord {
+    pub upstream: UpstreamHost,
+    pub name: String,
+
+    /// The digest of a blob, the tag or digest a manifest was pulled with
+    pub reference: String,
+    pub digest: Digest,
+
+    /// Only set for the manifests
+    pub mime: Option<MimeType>,
+    pub reason: String,
+    pub attempts: i64,
+
+    /// Unix timestamp in seconds of the last failure
+    pub failed_at: i64,
+}
+
+impl DeadLetterRecord {
+    /// Whether the record is about a manifest rather than a blob
+    pub fn is_manifest(&self) -> bool {
+        self.mime.is_some()
+    }
+}
@@ -1,7 +1,14 @@
 // SPDX-License-Identifier: Apache-2.0
 use strum::Display;
+use crate::models::types::UpstreamHost;
+use crate::registry::digest::Digest;
+use crate::registry::repository::Repository;
 
 #[derive(Clone, Display, Debug)]
 pub enum RegistryEvent {
-    BlobPersisted
-}
\ No newline at end of file
+    /// The blob was stored in the cache
+    BlobPersisted(UpstreamHost, Repository),
+
+    /// The manifest with the digest was stored in the cache and its tag indexed
+    ManifestPersisted(UpstreamHost, Repository, Digest),
+}
@@ -1,4 +1,4 @@
-use crate::models::types::MimeType;
+use crate::models::types::{MimeType, UpstreamHost};
 use crate::registry::digest::Digest;
 
 /// ManifestRecord keeps an index between the container image manifest tag and its reference
@@ -8,16 +8,51 @@ pub struct ManifestRecord {
     pub reference: Option<Digest>,
     pub size: i32,
     pub mime: MimeType,
+
+    /// Seconds since the unix epoch the tag was last stored from upstream
+    pub refreshed_at: i64,
+
+    /// Host of the upstream the tag was pulled from, empty when indexed before the upstream was tracked
+    pub upstream: UpstreamHost,
 }
 
 impl ManifestRecord {
-    pub fn new(name: String, tag: String, reference: Option<Digest>, size: i32, mime: MimeType) -> ManifestRecord {
+    pub fn new(name: String, tag: String, reference: Option<Digest>, size: i32, mime: MimeType, refreshed_at: i64, upstream: UpstreamHost) -> ManifestRecord {
         ManifestRecord {
             name,
             tag,
             reference,
             size,
-            mime
+            mime,
+            refreshed_at,
+            upstream,
+        }
+    }
+
+    /// Whether the tag was not refreshed from upstream for longer than the time to live, if any
+    pub fn is_stale(&self, ttl: Option<u64>, now: i64) -> bool {
+        ttl.is_some_and(|ttl| self.refreshed_at.saturating_add(ttl as i64) <= now)
+    }
+
+    /// The variant matching best the accepted media types, most preferred first, `*/*` and `type/*` match
+    /// any media type and any media type of that type. The first variant stored when the client did not ask for any media type
+    pub fn negotiate(mut variants: Vec<ManifestRecord>, accepted: &[MimeType]) -> Option<ManifestRecord> {
+        if accepted.is_empty() {
+            return variants.into_iter().next();
+        }
+
+        let position = accepted.iter()
+            .find_map(|accepted| variants.iter().position(|variant| ManifestRecord::accepts(accepted, &variant.mime)))?;
+        Some(variants.swap_remove(position))
+    }
+
+    /// Whether the media type satisfies the accepted one
+    fn accepts(accepted: &MimeType, mime: &MimeType) -> bool {
+        let accepted = accepted.essence();
+        match accepted.strip_suffix('*') {
+            Some(prefix) if prefix.is_empty() || prefix == "*/" => true,
+            Some(prefix) => prefix.ends_with('/') && mime.essence().starts_with(prefix),
+            None => mime.is(accepted),
         }
     }
 
@@ -1,5 +1,8 @@
 // SPDX-License-Identifier: Apache-2.0
+pub mod chunks;
 pub mod commands;
+pub mod dead_letter;
 pub mod events;
 pub mod manifest_record;
+pub mod referrer_record;
 pub mod types;
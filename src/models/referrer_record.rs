@@ -0,0 +1,29 @@
+use std::collections::HashMap;
+use crate::registry::digest::Digest;
+use crate::registry::manifest::Descriptor;
+
+/// ReferrerRecord keeps an index between a manifest and the manifests referring to it via their subject
+#[derive(Clone, Debug)]
+pub struct ReferrerRecord {
+    pub name: String,
+    pub digest: Digest,
+    pub subject: Digest,
+    pub media_type: String,
+    pub artifact_type: Option<String>,
+    pub size: i64,
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+impl ReferrerRecord {
+    /// The descriptor of the referrer as listed in the referrers API image index
+    pub fn descriptor(self) -> Descriptor {
+        Descriptor {
+            media_type: self.media_type,
+            digest: self.digest,
+            size: self.size,
+            artifact_type: self.artifact_type,
+            annotations: self.annotations,
+            platform: None,
+        }
+    }
+}
@@ -1,2 +1,116 @@
-pub type MimeType = String;
-pub type ManifestSize = i32;
\ No newline at end of file
+use std::fmt;
+use std::str::FromStr;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use crate::registry::manifest::{DOCKER_MANIFEST_LIST, OCI_IMAGE_INDEX};
+
+pub type ManifestSize = i32;
+pub type UpstreamHost = String;
+
+/// Media type of a manifest, with its parameters if any, e.g. `application/vnd.oci.image.manifest.v1+json`.
+/// Only a well formed one can be built
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MimeType(String);
+
+impl MimeType {
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The media type without its parameters
+    pub fn essence(&self) -> &str {
+        self.0.split(';').next().unwrap_or_default().trim()
+    }
+
+    /// The value of a parameter of the media type, e.g. `digest` of a schema 1 manifest
+    pub fn parameter(&self, name: &str) -> Option<&str> {
+        self.0.split(';').skip(1)
+            .filter_map(|parameter| parameter.split_once('='))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim().trim_matches('"'))
+    }
+
+    /// Whether it is a Docker manifest list
+    pub fn is_manifest_list(&self) -> bool {
+        self.is(DOCKER_MANIFEST_LIST)
+    }
+
+    /// Whether it is an OCI image index
+    pub fn is_oci_index(&self) -> bool {
+        self.is(OCI_IMAGE_INDEX)
+    }
+
+    /// Whether it is the media type given, regardless of the case and the parameters
+    pub fn is(&self, media_type: &str) -> bool {
+        self.essence().eq_ignore_ascii_case(media_type)
+    }
+
+    /// The media type to send in a Content-Type header, `application/octet-stream` when it can't be
+    pub fn to_mime(&self) -> mime::Mime {
+        self.0.parse().unwrap_or(mime::APPLICATION_OCTET_STREAM)
+    }
+}
+
+/// `application/octet-stream`, for the content whose media type is not known
+impl Default for MimeType {
+    fn default() -> Self {
+        MimeType(mime::APPLICATION_OCTET_STREAM.to_string())
+    }
+}
+
+impl FromStr for MimeType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        s.parse::<mime::Mime>()
+            .map(|_| MimeType(s.to_string()))
+            .map_err(|e| format!("'{}' is not a valid media type: {}", s, e))
+    }
+}
+
+impl fmt::Display for MimeType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for MimeType {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for MimeType {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::models::types::MimeType;
+
+    #[test]
+    fn mime_type_test() {
+        let index: MimeType = "application/vnd.oci.image.index.v1+json".parse().unwrap();
+        assert!(index.is_oci_index());
+        assert!(!index.is_manifest_list());
+        assert!(!index.is("application/vnd.docker.distribution.manifest.v2+json"));
+
+        let list: MimeType = "Application/vnd.docker.distribution.manifest.list.v2+json; charset=utf-8".parse().unwrap();
+        assert!(list.is_manifest_list());
+        assert_eq!("Application/vnd.docker.distribution.manifest.list.v2+json", list.essence());
+        assert_eq!(Some("utf-8"), list.parameter("charset"));
+
+        let signed: MimeType = "application/vnd.docker.distribution.manifest.v1+prettyjws; digest=\"sha512\"".parse().unwrap();
+        assert_eq!(Some("sha512"), signed.parameter("digest"));
+        assert_eq!(None, signed.parameter("charset"));
+
+        // Malformed ones can't be built
+        for malformed in ["", "manifest", "/json", "application json", "application/json; =x"] {
+            assert!(malformed.parse::<MimeType>().is_err(), "{}", malformed);
+        }
+        assert_eq!("application/octet-stream", MimeType::default().to_mime().as_ref());
+    }
+}
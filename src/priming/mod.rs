@@ -0,0 +1,283 @@
+// SPDX-License-Identifier: Apache-2.0
+use std::sync::Arc;
+use futures_util::{pin_mut, StreamExt};
+use reqwest::header::{HeaderValue, ACCEPT, AUTHORIZATION};
+use url::Url;
+use crate::api::egress_budget::EgressBudgets;
+use crate::api::upstream_clients::UpstreamClients;
+use crate::config::streaming::PersistChannel;
+use crate::error::error_kind::ErrorKind;
+use crate::error::registry::RegistryError;
+use crate::models::chunks::chunk_channel;
+use crate::models::commands::RegistryCommand;
+use crate::models::types::MimeType;
+use crate::pubsub::command_bus::CommandBus;
+use crate::registry::manifest::{Descriptor, Manifest, Platform};
+use crate::registry::repository::Repository;
+use crate::repository::filesystem::FilesystemStorage;
+
+/// The upstream an image index was pulled from
+struct Upstream<'a> {
+    host: String,
+    storage: FilesystemStorage,
+    index_url: &'a Url,
+    authorization: Option<&'a HeaderValue>,
+}
+
+/// Caches the manifest and the blobs of a single platform whenever an image index is pulled,
+/// instead of waiting for the client to request them one by one
+#[derive(Clone)]
+pub struct Primer {
+    clients: UpstreamClients,
+    command_bus: Arc<CommandBus>,
+    storage: Arc<FilesystemStorage>,
+    platform: Platform,
+    persist_channel: PersistChannel,
+    egress_budgets: Arc<EgressBudgets>,
+}
+
+impl Primer {
+
+    pub fn new(clients: UpstreamClients, command_bus: Arc<CommandBus>, storage: Arc<FilesystemStorage>,
+               platform: Platform, persist_channel: PersistChannel, egress_budgets: Arc<EgressBudgets>) -> Self {
+        Primer {
+            clients,
+            command_bus,
+            storage,
+            platform,
+            persist_channel,
+            egress_budgets,
+        }
+    }
+
+    /// Whether the content type is an image index the primer can resolve
+    pub fn is_index(content_type: &MimeType) -> bool {
+        content_type.is_oci_index() || content_type.is_manifest_list()
+    }
+
+    /// Cache the manifest and the blobs of the configured platform of the image index pulled from `index_url`
+    pub async fn prime(&self, upstream: &str, index_url: &Url, name: &str, authorization: Option<HeaderValue>, index: &[u8]) {
+        let index = match Manifest::parse(index) {
+            Ok(index) => index,
+            Err(e) => {
+                tracing::error!("Failed to parse image index {}: {}", index_url, e.to_string());
+                return;
+            }
+        };
+
+        let Some(descriptor) = index.platform_manifest(&self.platform) else {
+            tracing::debug!("No {:?} manifest in image index {}", self.platform, index_url);
+            return;
+        };
+
+        let upstream = Upstream {
+            host: upstream.to_string(),
+            storage: self.storage.for_upstream(upstream),
+            index_url,
+            authorization: authorization.as_ref(),
+        };
+
+        let manifest = match self.manifest(&upstream, name, descriptor).await {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                tracing::error!("Failed to prime manifest {}/{}: {}", name, descriptor.digest, e);
+                return;
+            }
+        };
+
+        for blob in manifest.blobs() {
+            if let Err(e) = self.blob(&upstream, name, blob).await {
+                tracing::error!("Failed to prime blob {}/{}: {}", name, blob.digest, e);
+            }
+        }
+
+        tracing::info!("Primed {}/{} for {}/{}", name, descriptor.digest, self.platform.os, self.platform.architecture);
+    }
+
+    /// Load the platform manifest from the cache, or fetch and persist it
+    async fn manifest(&self, upstream: &Upstream<'_>, name: &str, descriptor: &Descriptor) -> Result<Manifest, RegistryError> {
+        let data = match upstream.storage.driver().content(&descriptor.digest).await {
+            Ok(data) => data,
+            Err(_) => {
+                let response = self.fetch(upstream, name, "manifests", descriptor).await?;
+                let data = response.bytes().await
+                    .map_err(|e| RegistryError::new(ErrorKind::RegistryManifestUnknown).with_error(e.to_string()))?;
+                self.egress_budgets.record(&upstream.host, data.len() as u64);
+
+                let repository = Repository::new_with_reference(name, &descriptor.digest.to_string())?;
+                let (sender, receiver) = chunk_channel(&self.persist_channel);
+                self.command_bus.publish(RegistryCommand::PersistManifest(upstream.host.clone(), repository, Some(descriptor.digest.clone()),
+                                                                          descriptor.media_type.parse().unwrap_or_default(), receiver)).await;
+                if let Err(e) = sender.send(data.clone()).await {
+                    tracing::error!("Failed to send manifest for persistence: {}", e.to_string());
+                }
+                data
+            }
+        };
+
+        Manifest::parse(&data).map_err(|e| RegistryError::new(ErrorKind::RegistryManifestInvalid).with_error(e.to_string()))
+    }
+
+    /// Fetch and persist the blob, unless it is already cached
+    async fn blob(&self, upstream: &Upstream<'_>, name: &str, descriptor: &Descriptor) -> Result<(), RegistryError> {
+        if upstream.storage.driver().is_stored(&descriptor.digest) {
+            return Ok(());
+        }
+
+        let response = self.fetch(upstream, name, "blobs", descriptor).await?;
+
+        let repository = Repository::new_with_reference(name, &descriptor.digest.to_string())?;
+        let (sender, receiver) = chunk_channel(&self.persist_channel);
+        self.command_bus.publish(RegistryCommand::PersistBlob(upstream.host.clone(), repository, descriptor.media_type.parse().unwrap_or_default(), receiver)).await;
+
+        let stream = self.egress_budgets.counted(&upstream.host, response);
+        pin_mut!(stream);
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|e| RegistryError::new(ErrorKind::RegistryBlobError).with_error(e.to_string()))?;
+            sender.send(chunk).await
+                .map_err(|e| RegistryError::new(ErrorKind::RegistryBlobError).with_error(e.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Request a manifest or a blob of the repository from the upstream the index was pulled from
+    async fn fetch(&self, upstream: &Upstream<'_>, name: &str, kind: &str, descriptor: &Descriptor) -> Result<reqwest::Response, RegistryError> {
+        if !self.egress_budgets.allow(&upstream.host) {
+            return Err(RegistryError::new(ErrorKind::Unavailable).with_error(format!("egress budget of upstream {} is spent", upstream.host)));
+        }
+
+        // Behind the path prefix of the upstream, if any, as the image index
+        let mut url = upstream.index_url.clone();
+        let index_path = upstream.index_url.path();
+        let prefix = &index_path[..index_path.find(&format!("/v2/{}/", name)).unwrap_or(0)];
+        url.set_path(&format!("{}/v2/{}/{}/{}", prefix, name, kind, descriptor.digest));
+        url.set_query(None);
+
+        let mut request = self.clients.for_upstream(&upstream.host).get(url).header(ACCEPT, descriptor.media_type.as_str());
+        if let Some(authorization) = upstream.authorization {
+            request = request.header(AUTHORIZATION, authorization.clone());
+        }
+
+        let response = request.send().await
+            .map_err(|e| RegistryError::new(ErrorKind::InternalError).with_error(e.to_string()))?;
+
+        if !response.status().is_success() {
+            return Err(RegistryError::new(ErrorKind::NotFound)
+                .with_error(format!("upstream returned {} for {}", response.status(), response.url())));
+        }
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use parking_lot::Mutex;
+    use sha2::{Digest as Sha2Digest, Sha256};
+    use url::Url;
+    use crate::api::egress_budget::EgressBudgets;
+    use crate::config::app::AppConfig;
+    use crate::config::egress_budget::EgressBudgetConfig;
+    use crate::config::streaming::PersistChannel;
+    use crate::models::chunks::ChunkReceiver;
+    use crate::models::commands::RegistryCommand;
+    use crate::priming::Primer;
+    use crate::pubsub::command_bus::CommandBus;
+    use crate::registry::manifest::Platform;
+    use crate::repository::filesystem::FilesystemStorage;
+
+    fn sha256(data: &str) -> String {
+        format!("sha256:{}", hex::encode(Sha256::digest(data.as_bytes())))
+    }
+
+    fn image_manifest(config: &str, layer: &str) -> String {
+        format!(r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json",
+            "config":{{"mediaType":"application/vnd.oci.image.config.v1+json","digest":"{}","size":{}}},
+            "layers":[{{"mediaType":"application/vnd.oci.image.layer.v1.tar+gzip","digest":"{}","size":{}}}]}}"#,
+                sha256(config), config.len(), sha256(layer), layer.len())
+    }
+
+    async fn upstream(req: HttpRequest, content: web::Data<HashMap<String, String>>, hits: web::Data<Mutex<Vec<String>>>) -> HttpResponse {
+        hits.lock().push(req.path().to_string());
+        match content.get(req.path()) {
+            Some(body) => HttpResponse::Ok().body(body.clone()),
+            None => HttpResponse::NotFound().finish(),
+        }
+    }
+
+    async fn drain(mut receiver: ChunkReceiver) -> String {
+        let mut data = Vec::new();
+        while let Some(chunk) = receiver.recv().await {
+            data.extend_from_slice(&chunk);
+        }
+        String::from_utf8(data).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn prime_platform_test() {
+        let amd64 = image_manifest("amd64 config", "amd64 layer");
+        let arm64 = image_manifest("arm64 config", "arm64 layer");
+        let index = format!(r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.index.v1+json","manifests":[
+            {{"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"{}","size":{},"platform":{{"architecture":"arm64","os":"linux"}}}},
+            {{"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"{}","size":{},"platform":{{"architecture":"amd64","os":"linux"}}}}]}}"#,
+                            sha256(&arm64), arm64.len(), sha256(&amd64), amd64.len());
+
+        let mut content = HashMap::new();
+        for (kind, data) in [("manifests", &amd64), ("manifests", &arm64), ("blobs", &"amd64 config".to_string()),
+                             ("blobs", &"amd64 layer".to_string()), ("blobs", &"arm64 config".to_string()), ("blobs", &"arm64 layer".to_string())] {
+            content.insert(format!("/v2/library/nginx/{}/{}", kind, sha256(data)), data.clone());
+        }
+
+        // Upstream registry
+        let content = web::Data::new(content);
+        let hits = web::Data::new(Mutex::new(Vec::<String>::new()));
+        let upstream_hits = hits.clone();
+        let server = HttpServer::new(move || App::new().app_data(content.clone()).app_data(upstream_hits.clone()).default_service(web::to(upstream)))
+            .workers(1)
+            .bind(("127.0.0.1", 0)).unwrap();
+        let address = server.addrs()[0];
+        let server = server.run();
+        let server_handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let folder = tempfile::tempdir().unwrap();
+        let storage = Arc::new(FilesystemStorage::new(AppConfig::with_storage_folder(folder.path().to_str().unwrap())));
+        let (command_sender, mut command_receiver) = tokio::sync::mpsc::channel(16);
+        let egress_budgets = Arc::new(EgressBudgets::new(&EgressBudgetConfig { upstream_egress_budget_bytes: Some(1_000_000), period_secs: 60 }));
+        let primer = Primer::new(reqwest::Client::new().into(), CommandBus::new(command_sender, 16, Default::default()), storage,
+                                 Platform::parse("linux/amd64").unwrap(), PersistChannel::Unbounded, egress_budgets.clone());
+
+        let index_url = Url::parse(&format!("http://{}/v2/library/nginx/manifests/latest", address)).unwrap();
+        primer.prime("localhost", &index_url, "library/nginx", None, index.as_bytes()).await;
+        server_handle.stop(true).await;
+
+        // Only the linux/amd64 manifest and its blobs are persisted
+        let mut persisted = Vec::new();
+        while let Ok(command) = command_receiver.try_recv() {
+            match command {
+                RegistryCommand::PersistManifest(_, repository, digest, _, receiver) => {
+                    assert_eq!(Some(sha256(&amd64)), digest.map(|d| d.to_string()));
+                    assert_eq!(sha256(&amd64), repository.reference);
+                    assert_eq!(amd64, drain(receiver).await);
+                    persisted.push(sha256(&amd64));
+                }
+                RegistryCommand::PersistBlob(_, repository, _, receiver) => {
+                    let data = drain(receiver).await;
+                    assert_eq!(sha256(&data), repository.reference);
+                    persisted.push(data);
+                }
+                RegistryCommand::Shutdown => unreachable!(),
+            }
+        }
+        assert_eq!(vec![sha256(&amd64), "amd64 config".to_string(), "amd64 layer".to_string()], persisted);
+        assert!(hits.lock().iter().all(|path| !path.contains(&sha256(&arm64)) && !path.contains(&sha256("arm64 layer"))));
+
+        // Counted against the egress budget of the upstream
+        let fetched = amd64.len() + "amd64 config".len() + "amd64 layer".len();
+        assert_eq!(Some(1_000_000 - fetched as u64), egress_budgets.remaining("localhost"));
+    }
+}
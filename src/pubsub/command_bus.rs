@@ -2,10 +2,16 @@
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
+use futures_util::future::join_all;
 use tokio::sync::RwLock;
+use tokio::time::{Duration, Instant};
 use tracing::log;
+use crate::config::streaming::PersistPartition;
+use crate::metrics;
 use crate::models::commands::RegistryCommand;
-use crate::pubsub::subscriber::{CommandSubscriber};
+use crate::pubsub::event_bus::EventBus;
+use crate::pubsub::near_capacity::NearCapacity;
+use crate::pubsub::subscriber::{CommandSubscriber, EventSubscriber};
 use crate::pubsub::worker::Worker;
 use crate::pubsub::worker_pool::WorkerPool;
 
@@ -17,45 +23,73 @@ pub struct CommandBus {
     queue: tokio::sync::mpsc::Sender<RegistryCommand>,
 
     /// Subscribers is a map of events, as keys and
-    /// as values, a list of functions to execute when that specific event is processed
+    /// as values, a list of functions to execute when that specific event is processed.
+    /// When partitioned per upstream the keys are the events together with the upstream
     subscribers: Arc<RwLock<HashMap<String, Arc<WorkerPool>>>>,
 
+    /// The handler of each event, to start the worker pool of an upstream once it sends its first command
+    handlers: RwLock<HashMap<String, CommandSubscriber>>,
+
+    /// Receives the events emitted by the handlers
+    events: Arc<EventBus>,
+
+    /// Whether the worker pools are shared by all the upstreams
+    partition: PersistPartition,
+
     /// Amount of CPUs the server has
     cpus: usize,
 
-    /// The size of the workers channel
-    buffer_size: usize,
+    /// The size of the queue of each worker pool and of each of its workers
+    queue_size: usize,
 
     /// Whether the bus is shutting down
-    shutting_down: AtomicBool
+    shutting_down: AtomicBool,
+
+    /// Whether the queue is near capacity
+    near_capacity: NearCapacity,
 }
 
 /// Bus
 impl CommandBus {
 
     /// New instance
-    pub fn new(queue: tokio::sync::mpsc::Sender<RegistryCommand>, buffer_size: usize) -> Arc<CommandBus> {
+    pub fn new(queue: tokio::sync::mpsc::Sender<RegistryCommand>, queue_size: usize, partition: PersistPartition) -> Arc<CommandBus> {
 
         Arc::new(CommandBus {
             queue,
             subscribers: Arc::new(Default::default()),
+            handlers: Default::default(),
+            events: Default::default(),
+            partition,
             cpus: num_cpus::get(),
-            buffer_size,
+            queue_size,
             shutting_down: Default::default(),
+            near_capacity: Default::default(),
         })
     }
 
-    pub async fn shutdown(&self) {
+    /// Stop accepting commands and wait for the workers to drain their queues, for at most `drain_timeout` overall.
+    /// Returns how many workers did not drain in time
+    pub async fn shutdown(&self, drain_timeout: Duration) -> usize {
         self.shutting_down.store(true, Ordering::Relaxed);
-        for (topic, pool) in self.subscribers.write().await.iter() {
+        let deadline = Instant::now() + drain_timeout;
+
+        // All at once, so that the worker pools drain in parallel
+        let subscribers = self.subscribers.write().await;
+        let undrained: usize = join_all(subscribers.iter().map(|(topic, pool)| {
             tracing::info!("Shutting down worker pool for topic: {}", topic);
-            pool.shutdown().await;
+            pool.shutdown(deadline)
+        })).await.into_iter().sum();
+        if undrained > 0 {
+            tracing::warn!("{} persistence workers did not drain within {:?}, their blobs and manifests are not persisted", undrained, drain_timeout);
         }
+        undrained
     }
 
     /// Start processing the events
     pub async fn start(&self, mut receiver: tokio::sync::mpsc::Receiver<RegistryCommand>) {
         while let Some(exec) = receiver.recv().await {
+            metrics::COMMAND_BUS_QUEUE_LENGTH.set(self.queue_length() as i64);
 
             let guard = self.subscribers.read().await;
 
@@ -65,11 +99,14 @@ impl CommandBus {
             // If we have some
             if let Some(worker_pool) = worker_pool {
                 worker_pool.publish(exec).await;
+            } else {
+                metrics::PERSIST_BACKLOG.with_label_values(&[exec.upstream()]).dec();
             }
         }
     }
 
     /// Publish asynchronously a new event in the bus
+    #[tracing::instrument(name = "persist_enqueued", skip_all, fields(topic = %exec.topic(), upstream = exec.upstream()))]
     pub async fn publish(&self, exec: RegistryCommand) {
 
         // If we are already shutting down, do not queue any messages
@@ -78,51 +115,264 @@ impl CommandBus {
             return;
         }
 
+        metrics::PERSIST_BACKLOG.with_label_values(&[exec.upstream()]).inc();
+
+        // Straight to the worker pool of the upstream, so that a full queue only slows down the clients of that upstream
+        if self.partition == PersistPartition::Upstream {
+            match self.upstream_pool(&exec).await {
+                Some(worker_pool) => worker_pool.publish(exec).await,
+                None => metrics::PERSIST_BACKLOG.with_label_values(&[exec.upstream()]).dec(),
+            }
+            return;
+        }
+
+        // Waits while the queue is full
+        self.near_capacity.check("command bus", &self.queue);
         if let Err(e) = self.queue.send(exec).await {
+            metrics::PERSIST_BACKLOG.with_label_values(&[e.0.upstream()]).dec();
             log::error!("failed to queue event with error: {:?}", e);
         }
+        metrics::COMMAND_BUS_QUEUE_LENGTH.set(self.queue_length() as i64);
+    }
+
+    /// Commands waiting in the queue
+    fn queue_length(&self) -> usize {
+        self.queue.max_capacity() - self.queue.capacity()
+    }
+
+    /// The worker pool of the upstream of the command, started the first time the upstream sends a command
+    async fn upstream_pool(&self, exec: &RegistryCommand) -> Option<Arc<WorkerPool>> {
+        let key = format!("{}/{}", exec.topic(), exec.upstream());
+
+        if let Some(worker_pool) = self.subscribers.read().await.get(&key) {
+            return Some(worker_pool.clone());
+        }
+
+        let handler = self.handlers.read().await.get(&exec.topic())?.clone();
+
+        let mut subscribers = self.subscribers.write().await;
+        if subscribers.get(&key).is_none() {
+            tracing::info!("Starting the worker pool for topic {} of upstream {}", exec.topic(), exec.upstream());
+            let worker_pool = self.start_pool(&key, handler).await;
+            subscribers.insert(key.clone(), worker_pool);
+        }
+        subscribers.get(&key).cloned()
     }
 
     /// Subscribe a subscriber to a topic
     pub async fn subscribe(&self, topic: String, handler: CommandSubscriber) {
 
+        // The worker pools of the upstreams are started on their first command
+        self.handlers.write().await.entry(topic.clone()).or_insert_with(|| handler.clone());
+        if self.partition == PersistPartition::Upstream {
+            return;
+        }
+
         // Mutable subscribers
         let mut subscribers = self.subscribers.write().await;
 
         // If we don't have a worker pool for this kind of topic
         // then add it
         if subscribers.get(&topic).is_none() {
-            // Create the channel
-            let (event_sender, event_receiver) = tokio::sync::mpsc::channel(4096);
+            let worker_pool = self.start_pool(&topic, handler).await;
+
+            // Add the pool
+            subscribers.insert(topic, worker_pool);
+
+        }
+    }
+
+    /// Subscribe a subscriber to the events emitted by the handlers, e.g. once a blob is persisted
+    pub async fn subscribe_events(&self, subscriber: EventSubscriber) {
+        self.events.subscribe(subscriber).await;
+    }
+
+    /// Start a worker pool running the handler, named after its key in the subscribers
+    async fn start_pool(&self, name: &str, handler: CommandSubscriber) -> Arc<WorkerPool> {
+        // Create the channel
+        let (event_sender, event_receiver) = tokio::sync::mpsc::channel(self.queue_size);
+
+        // Commands published to the pool, until a worker picks them up
+        let pending = metrics::WORKER_POOL_PENDING.with_label_values(&[name]);
+
+        // Create the pool
+        let worker_pool = WorkerPool::new(name, event_sender, pending.clone());
+
+        // Clone it
+        let worker_pool_clone = worker_pool.clone();
+
+        // Start listening for messages
+        tokio::spawn(async move {
+            worker_pool_clone.start(event_receiver).await
+        });
+
+        // Now create the N amount of channels
+        // Persist the data to the disk for each entity
+        for channel in 0..self.cpus {
+
+            // Start a parallel sink
+            let worker = Worker::new(self.queue_size, handler.clone(), self.events.clone(), pending.clone());
+
+            // Start the processing in background
+            let sender = worker.start().await;
+
+            // Subscribe the sink to the worker pool
+            worker_pool.subscribe(channel, sender).await;
+        }
+
+        worker_pool
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use async_trait::async_trait;
+    use tokio::sync::{mpsc, Notify};
+    use crate::config::streaming::{PersistChannel, PersistPartition};
+    use crate::metrics;
+    use crate::models::chunks::chunk_channel;
+    use crate::models::commands::{RegistryCommand, PERSIST_BLOB};
+    use crate::models::events::RegistryEvent;
+    use crate::pubsub::command_bus::CommandBus;
+    use crate::handlers::event::metrics::PersistedMetricsHandler;
+    use crate::pubsub::subscriber::{CommandSubscriberTrait, EventSubscriberTrait};
+    use crate::registry::repository::Repository;
 
-            // Create the pool
-            let worker_pool = WorkerPool::new(event_sender);
+    /// Persists one command at a time, the ones of the slow upstreams only once released
+    struct SequentialHandler {
+        release: Notify,
+        persisted: mpsc::UnboundedSender<String>,
+    }
 
-            // Clone it
-            let worker_pool_clone = worker_pool.clone();
+    #[async_trait]
+    impl CommandSubscriberTrait for SequentialHandler {
+        async fn run(&self, cmd: RegistryCommand) -> Option<RegistryEvent> {
+            if cmd.upstream().starts_with("slow.") {
+                self.release.notified().await;
+            }
+            self.persisted.send(cmd.upstream().to_string()).unwrap();
+            None
+        }
+
+        fn supports_concurrency(&self) -> bool {
+            false
+        }
+    }
 
-            // Start listening for messages
-            tokio::spawn(async move {
-                worker_pool_clone.start(event_receiver).await
-            });
+    /// Persists every command right away
+    struct PersistedHandler;
 
-            // Now create the N amount of channels
-            // Persist the data to the disk for each entity
-            for channel in 0..self.cpus {
+    #[async_trait]
+    impl CommandSubscriberTrait for PersistedHandler {
+        async fn run(&self, cmd: RegistryCommand) -> Option<RegistryEvent> {
+            let RegistryCommand::PersistBlob(upstream, repository, _, _) = cmd else { return None };
+            Some(RegistryEvent::BlobPersisted(upstream, repository))
+        }
 
-                // Start a parallel sink
-                let worker = Worker::new(self.buffer_size, handler.clone());
+        fn supports_concurrency(&self) -> bool {
+            true
+        }
+    }
 
-                // Start the processing in background
-                let sender = worker.start().await;
+    /// Records the upstream of the events
+    struct EventRecorder {
+        events: mpsc::UnboundedSender<String>,
+    }
 
-                // Subscribe the sink to the worker pool
-                worker_pool.subscribe(channel, sender).await;
+    #[async_trait]
+    impl EventSubscriberTrait for EventRecorder {
+        async fn run(&self, event: &RegistryEvent) {
+            if let RegistryEvent::BlobPersisted(upstream, _) = event {
+                self.events.send(upstream.clone()).unwrap();
             }
+        }
 
-            // Add the pool
-            subscribers.insert(topic, worker_pool);
+        fn supports_concurrency(&self) -> bool {
+            true
+        }
+    }
 
+    fn persist_blob(upstream: &str) -> RegistryCommand {
+        let (_, receiver) = chunk_channel(&PersistChannel::Unbounded);
+        RegistryCommand::PersistBlob(upstream.to_string(), Repository::new_with_reference("library/nginx", "latest").unwrap(), Default::default(), receiver)
+    }
+
+    #[tokio::test]
+    async fn upstream_partition_test() {
+        let (queue, _receiver) = mpsc::channel(16);
+        let bus = CommandBus::new(queue, 16, PersistPartition::Upstream);
+
+        let (persisted, mut persisted_rx) = mpsc::unbounded_channel();
+        let handler = Arc::new(SequentialHandler { release: Notify::new(), persisted });
+        bus.subscribe(PERSIST_BLOB.to_string(), handler.clone()).await;
+
+        for _ in 0..3 {
+            bus.publish(persist_blob("slow.registry")).await;
+        }
+        bus.publish(persist_blob("fast.registry")).await;
+
+        // The fast upstream is persisted while the slow one is stuck
+        let next = tokio::time::timeout(Duration::from_secs(1), persisted_rx.recv()).await;
+        assert_eq!(Some("fast.registry".to_string()), next.unwrap());
+        assert_eq!(3, metrics::PERSIST_BACKLOG.with_label_values(&["slow.registry"]).get());
+
+        // The first command of the slow upstream was picked up by its worker, at least the other ones are pending
+        let pending = metrics::WORKER_POOL_PENDING.with_label_values(&[&format!("{}/slow.registry", PERSIST_BLOB)]).get();
+        assert!((2..=3).contains(&pending));
+        assert_eq!(0, metrics::WORKER_POOL_PENDING.with_label_values(&[&format!("{}/fast.registry", PERSIST_BLOB)]).get());
+
+        // Until the slow upstream catches up
+        for _ in 0..3 {
+            handler.release.notify_one();
+            let next = tokio::time::timeout(Duration::from_secs(1), persisted_rx.recv()).await;
+            assert_eq!(Some("slow.registry".to_string()), next.unwrap());
         }
+        assert_eq!(0, metrics::WORKER_POOL_PENDING.with_label_values(&[&format!("{}/slow.registry", PERSIST_BLOB)]).get());
+    }
+
+    #[tokio::test]
+    async fn shutdown_drain_timeout_test() {
+        let (queue, _receiver) = mpsc::channel(16);
+        let bus = CommandBus::new(queue, 16, PersistPartition::Upstream);
+
+        let (persisted, mut persisted_rx) = mpsc::unbounded_channel();
+        let handler = Arc::new(SequentialHandler { release: Notify::new(), persisted });
+        bus.subscribe(PERSIST_BLOB.to_string(), handler.clone()).await;
+
+        bus.publish(persist_blob("slow.draining.registry")).await;
+        bus.publish(persist_blob("fast.draining.registry")).await;
+        let next = tokio::time::timeout(Duration::from_secs(1), persisted_rx.recv()).await;
+        assert_eq!(Some("fast.draining.registry".to_string()), next.unwrap());
+
+        // The worker stuck on the slow upstream does not hold up the shutdown
+        let undrained = tokio::time::timeout(Duration::from_secs(1), bus.shutdown(Duration::from_millis(100))).await;
+        assert_eq!(1, undrained.expect("The shutdown did not give up on the stuck worker"));
+
+        // Nothing is accepted anymore
+        bus.publish(persist_blob("fast.draining.registry")).await;
+        assert!(tokio::time::timeout(Duration::from_millis(100), persisted_rx.recv()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn events_test() {
+        let (queue, receiver) = mpsc::channel(16);
+        let bus = CommandBus::new(queue, 16, PersistPartition::Shared);
+        let local_bus = bus.clone();
+        tokio::spawn(async move { local_bus.start(receiver).await });
+
+        bus.subscribe(PERSIST_BLOB.to_string(), Arc::new(PersistedHandler)).await;
+        bus.subscribe_events(PersistedMetricsHandler::new()).await;
+        let (events, mut events_rx) = mpsc::unbounded_channel();
+        bus.subscribe_events(Arc::new(EventRecorder { events })).await;
+
+        let persisted = metrics::BLOBS_PERSISTED_TOTAL.get();
+        bus.publish(persist_blob("events.registry")).await;
+
+        // Every subscriber gets the events returned by the handler, in the order they subscribed
+        let next = tokio::time::timeout(Duration::from_secs(1), events_rx.recv()).await;
+        assert_eq!(Some("events.registry".to_string()), next.unwrap());
+        assert_eq!(persisted + 1, metrics::BLOBS_PERSISTED_TOTAL.get());
     }
-}
\ No newline at end of file
+}
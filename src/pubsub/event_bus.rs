@@ -0,0 +1,37 @@
+// SPDX-License-Identifier: Apache-2.0
+use tokio::sync::RwLock;
+use crate::models::events::RegistryEvent;
+use crate::pubsub::subscriber::EventSubscriber;
+
+/// Event Bus
+/// Hands over the events emitted by the command handlers, e.g. a blob was persisted, to every subscriber
+#[derive(Default)]
+pub struct EventBus {
+
+    /// The subscribers interested in the events
+    subscribers: RwLock<Vec<EventSubscriber>>,
+}
+
+impl EventBus {
+
+    /// Subscribe a subscriber to all the events
+    pub async fn subscribe(&self, subscriber: EventSubscriber) {
+        self.subscribers.write().await.push(subscriber);
+    }
+
+    /// Publish the event to every subscriber
+    pub async fn publish(&self, event: RegistryEvent) {
+        for subscriber in self.subscribers.read().await.iter() {
+            if subscriber.supports_concurrency() {
+                let subscriber = subscriber.clone();
+                let event = event.clone();
+                tokio::spawn(async move {
+                    subscriber.run(&event).await;
+                });
+            } else {
+                // WARNING: this blocks the worker which emitted the event, so the execution should be fast
+                subscriber.run(&event).await;
+            }
+        }
+    }
+}
@@ -1,6 +1,8 @@
 // SPDX-License-Identifier: Apache-2.0
 mod worker_pool;
 mod worker;
+mod near_capacity;
 pub mod subscriber;
 pub mod command;
 pub mod command_bus;
+pub mod event_bus;
@@ -0,0 +1,60 @@
+// SPDX-License-Identifier: Apache-2.0
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::mpsc::Sender;
+use crate::models::commands::RegistryCommand;
+
+/// Share of the capacity of a queue from which it is near capacity
+const NEAR_CAPACITY_PERCENT: usize = 90;
+
+/// Warns once each time a queue of commands fills up to near its capacity, past which the publishers
+/// wait for the persistence to catch up
+#[derive(Default)]
+pub struct NearCapacity(AtomicBool);
+
+impl NearCapacity {
+
+    /// Commands waiting in the queue
+    pub fn check(&self, name: &str, queue: &Sender<RegistryCommand>) -> usize {
+        let queued = queue.max_capacity() - queue.capacity();
+        if queued * 100 < queue.max_capacity() * NEAR_CAPACITY_PERCENT {
+            self.0.store(false, Ordering::Relaxed);
+        } else if !self.0.swap(true, Ordering::Relaxed) {
+            tracing::warn!("The {} queue is near capacity, {} of {} commands: the persistence is falling behind", name, queued, queue.max_capacity());
+        }
+        queued
+    }
+
+    /// Whether the queue was near capacity when last checked
+    #[cfg(test)]
+    pub fn is_near(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::mpsc;
+    use crate::models::commands::RegistryCommand;
+    use crate::pubsub::near_capacity::NearCapacity;
+
+    #[tokio::test]
+    async fn check_test() {
+        let (queue, mut receiver) = mpsc::channel(10);
+        let near_capacity = NearCapacity::default();
+
+        for _ in 0..8 {
+            queue.send(RegistryCommand::Shutdown).await.unwrap();
+        }
+        assert_eq!(8, near_capacity.check("test", &queue));
+        assert!(!near_capacity.is_near());
+
+        queue.send(RegistryCommand::Shutdown).await.unwrap();
+        assert_eq!(9, near_capacity.check("test", &queue));
+        assert!(near_capacity.is_near());
+
+        // Until it drains
+        receiver.recv().await.unwrap();
+        assert_eq!(8, near_capacity.check("test", &queue));
+        assert!(!near_capacity.is_near());
+    }
+}
@@ -17,15 +17,12 @@ pub trait CommandSubscriberTrait {
 /// Event Pub Sub Bus Trait
 #[async_trait]
 pub trait EventSubscriberTrait {
-    /// The function to execute when a message of interest is received
-    async fn run(&self, event: &RegistryEvent) -> Option<RegistryEvent>;
-
-    /// Receive the responses from the run operation
-    fn responder(&self) -> Option<tokio::sync::mpsc::Sender<RegistryEvent>>;
+    /// The function to execute when an event is published
+    async fn run(&self, event: &RegistryEvent);
 
     /// Whether the run operation can be executed concurrently
     fn supports_concurrency(&self) -> bool;
 }
 
 pub type CommandSubscriber = Arc<dyn CommandSubscriberTrait + 'static + Sync + Send>;
-// pub type EventSubscriber = Arc<dyn EventSubscriberTrait + 'static + Sync + Send>;
\ No newline at end of file
+pub type EventSubscriber = Arc<dyn EventSubscriberTrait + 'static + Sync + Send>;
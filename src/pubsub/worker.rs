@@ -1,7 +1,13 @@
 // SPDX-License-Identifier: Apache-2.0
+use std::sync::Arc;
+use prometheus::IntGauge;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::Sender;
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use crate::metrics;
 use crate::models::commands::RegistryCommand;
+use crate::pubsub::event_bus::EventBus;
 use crate::pubsub::subscriber::CommandSubscriber;
 
 /// Worker of the worker pool which process the commands and executes them
@@ -11,16 +17,24 @@ pub struct Worker {
 
     /// The subscriber for this worker
     handler: CommandSubscriber,
+
+    /// Receives the events emitted by the subscriber
+    events: Arc<EventBus>,
+
+    /// Commands of the worker pool not picked up by a worker yet
+    pending: IntGauge,
 }
 
 impl Worker {
 
     /// New worker instance for the specific Handler
-    pub fn new(buffer_size: usize, handler: CommandSubscriber) -> Self {
+    pub fn new(buffer_size: usize, handler: CommandSubscriber, events: Arc<EventBus>, pending: IntGauge) -> Self {
         // New instance
         Worker {
             buffer_size,
-            handler
+            handler,
+            events,
+            pending,
         }
     }
 
@@ -31,6 +45,8 @@ impl Worker {
 
         // Clone the worker reference (behind an Arc)
         let local_worker = self.handler.clone();
+        let events = self.events.clone();
+        let pending = self.pending.clone();
 
         // Start the processing of the commands in a different task
         tokio::spawn(async move {
@@ -43,6 +59,11 @@ impl Worker {
                     receiver.close();
                     return;
                 }
+                pending.dec();
+
+                // A child of the request the content comes from
+                let span = tracing::info_span!(parent: None, "persist", topic = %cmd.topic(), upstream = cmd.upstream(), reference = %cmd.id());
+                span.set_parent(cmd.trace_context());
 
                 // check if the worker supports concurrency
                 if local_worker.supports_concurrency() {
@@ -50,15 +71,26 @@ impl Worker {
 
                     // Clone the worker ARC
                     let async_worker = local_worker.clone();
+                    let events = events.clone();
 
                     // run the method in a different task
                     tokio::spawn(async move {
-                        async_worker.run(cmd).await;
+                        let upstream = cmd.upstream().to_string();
+                        let event = async_worker.run(cmd).instrument(span).await;
+                        metrics::PERSIST_BACKLOG.with_label_values(&[&upstream]).dec();
+                        if let Some(event) = event {
+                            events.publish(event).await;
+                        }
                     });
                 } else {
                     // run the method in the current task
                     // WARNING: this blocks reading other commands, so the execution should be fast
-                    local_worker.run(cmd).await;
+                    let upstream = cmd.upstream().to_string();
+                    let event = local_worker.run(cmd).instrument(span).await;
+                    metrics::PERSIST_BACKLOG.with_label_values(&[&upstream]).dec();
+                    if let Some(event) = event {
+                        events.publish(event).await;
+                    }
                 }
             }
         });
@@ -67,4 +99,79 @@ impl Worker {
         sender
     }
 
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use async_trait::async_trait;
+    use futures_util::future::BoxFuture;
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+    use parking_lot::Mutex;
+    use prometheus::IntGauge;
+    use tokio::sync::mpsc;
+    use tracing_subscriber::layer::SubscriberExt;
+    use crate::config::streaming::PersistChannel;
+    use crate::models::chunks::chunk_channel;
+    use crate::models::commands::RegistryCommand;
+    use crate::models::events::RegistryEvent;
+    use crate::pubsub::subscriber::CommandSubscriberTrait;
+    use crate::pubsub::worker::Worker;
+    use crate::registry::repository::Repository;
+
+    /// Keeps the exported spans
+    #[derive(Clone, Debug, Default)]
+    struct Exported(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for Exported {
+        fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+            self.0.lock().extend(batch);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    struct Persisted(mpsc::UnboundedSender<()>);
+
+    #[async_trait]
+    impl CommandSubscriberTrait for Persisted {
+        async fn run(&self, _cmd: RegistryCommand) -> Option<RegistryEvent> {
+            self.0.send(()).unwrap();
+            None
+        }
+
+        fn supports_concurrency(&self) -> bool {
+            false
+        }
+    }
+
+    #[tokio::test]
+    async fn persist_span_test() {
+        let exported = Exported::default();
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder().with_simple_exporter(exported.clone()).build();
+        let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("test"));
+        let _subscriber = tracing::subscriber::set_default(tracing_subscriber::registry().with(layer));
+
+        let (persisted_tx, mut persisted_rx) = mpsc::unbounded_channel();
+        let worker = Worker::new(1, Arc::new(Persisted(persisted_tx)), Default::default(), IntGauge::new("pending", "pending").unwrap());
+        let sender = worker.start().await;
+
+        // The content of a request, which is over by the time it is persisted
+        let receiver = tracing::info_span!("request").in_scope(|| chunk_channel(&PersistChannel::Unbounded).1);
+        let repository = Repository::new_with_reference("library/nginx", "latest").unwrap();
+        sender.send(RegistryCommand::PersistBlob("localhost".to_string(), repository, Default::default(), receiver)).await.unwrap();
+        persisted_rx.recv().await.unwrap();
+
+        let span = |name: &str| exported.0.lock().iter().find(|span| span.name == name).cloned();
+        for _ in 0..50 {
+            if span("request").is_some() && span("persist").is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let (request, persist) = (span("request").unwrap(), span("persist").unwrap());
+        assert_eq!(request.span_context.trace_id(), persist.span_context.trace_id());
+        assert_eq!(request.span_context.span_id(), persist.parent_span_id);
+    }
+}
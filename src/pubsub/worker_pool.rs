@@ -2,19 +2,31 @@
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::mpsc::{Receiver, Sender};
+use prometheus::IntGauge;
 use tokio::sync::RwLock;
+use tokio::time::{timeout_at, Instant};
 use tracing::log;
 use crate::models::commands::RegistryCommand;
 use crate::pubsub::command::ChannelId;
+use crate::pubsub::near_capacity::NearCapacity;
 
 
 /// CommandWorkerPool
 /// Dispatches the commands to sub workers
 pub struct WorkerPool {
 
+    /// Topic of the pool, and upstream when partitioned per upstream
+    name: String,
+
     /// Sender to queue events
     queue: Sender<RegistryCommand>,
 
+    /// Commands published and not picked up by a worker yet
+    pending: IntGauge,
+
+    /// Whether the queue is near capacity
+    near_capacity: NearCapacity,
+
     /// Subscribers is a map of events, as keys and
     /// as values, a list of functions to execute when that specific event is processed
     subscribers: Arc<RwLock<HashMap<u64, Sender<RegistryCommand>>>>,
@@ -27,9 +39,12 @@ pub struct WorkerPool {
 impl WorkerPool {
 
     /// New instance
-    pub fn new(queue: Sender<RegistryCommand>) -> Arc<WorkerPool> {
+    pub fn new(name: &str, queue: Sender<RegistryCommand>, pending: IntGauge) -> Arc<WorkerPool> {
         Arc::new(WorkerPool {
+            name: name.to_string(),
             queue,
+            pending,
+            near_capacity: Default::default(),
             subscribers: Arc::new(Default::default()),
             modulo: num_cpus::get() as u64
         })
@@ -71,6 +86,7 @@ impl WorkerPool {
                     }
                 });
             } else {
+                self.pending.dec();
                 log::error!("WARNING: subscriber not found!")
             }
         }
@@ -78,7 +94,10 @@ impl WorkerPool {
 
     /// Publish asynchronously a new event in the bus
     pub async fn publish(&self, cmd: RegistryCommand) {
+        self.pending.inc();
+        self.near_capacity.check(&format!("worker pool {}", self.name), &self.queue);
         if let Err(e) = self.queue.send(cmd).await {
+            self.pending.dec();
             log::error!("failed to queue event with error: {:?}", e.to_string());
         }
     }
@@ -89,15 +108,32 @@ impl WorkerPool {
         writer.insert(worker_id as u64, subscriber);
     }
 
-    pub async fn shutdown(&self, ) {
+    /// Ask every worker to stop once it processed the commands queued before, and wait for them until the deadline.
+    /// Returns how many workers did not drain in time
+    pub async fn shutdown(&self, deadline: Instant) -> usize {
         let subs =  self.subscribers.write().await;
+
+        // All at once, so that the workers drain in parallel
+        let mut stopping = Vec::new();
         for (index, sub) in subs.iter() {
             tracing::info!("Shutting down worker pool: {}", index);
-            if (sub.send(RegistryCommand::Shutdown).await).is_err() {
-                continue;
-            } else {
-                sub.closed().await;
+            match timeout_at(deadline, sub.send(RegistryCommand::Shutdown)).await {
+                Ok(Ok(_)) => stopping.push((index, sub)),
+                Ok(Err(_)) => continue,
+                Err(_) => {
+                    tracing::warn!("Worker {} of pool {} did not drain in time, its queue is still full", index, self.name);
+                    stopping.push((index, sub));
+                }
+            }
+        }
+
+        let mut undrained = 0;
+        for (index, sub) in stopping {
+            if timeout_at(deadline, sub.closed()).await.is_err() {
+                tracing::warn!("Worker {} of pool {} did not drain in time", index, self.name);
+                undrained += 1;
             }
         }
+        undrained
     }
 }
\ No newline at end of file
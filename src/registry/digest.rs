@@ -110,7 +110,8 @@ impl Digest {
             DigestAlgorithm::Sha256 => {
                 let handle = tokio::task::spawn_blocking(move || async move {
                     let mut hasher = Sha256::new();
-                    let _n = std::io::copy(&mut file, &mut hasher);
+                    std::io::copy(&mut file, &mut hasher).map_err(|e| RegistryError::new(ErrorKind::RegistryBlobUploadInvalid)
+                        .with_context("failed to read the file to hash").with_error(e.to_string()))?;
                     let hash = hasher.finalize();
                     Ok(Digest {
                         algo,
@@ -130,7 +131,8 @@ impl Digest {
             DigestAlgorithm::Sha512 => {
                 let handle = tokio::task::spawn_blocking(move || async move {
                     let mut hasher = Sha512::new();
-                    let _n = std::io::copy(&mut file, &mut hasher);
+                    std::io::copy(&mut file, &mut hasher).map_err(|e| RegistryError::new(ErrorKind::RegistryBlobUploadInvalid)
+                        .with_context("failed to read the file to hash").with_error(e.to_string()))?;
                     let hash = hasher.finalize();
                     Ok(Digest {
                         algo,
@@ -150,6 +152,15 @@ impl Digest {
         }
     }
 
+    /// Hash data which is already in memory, e.g. a manifest
+    pub fn hash_bytes(algo: DigestAlgorithm, data: &[u8]) -> Digest {
+        let hash = match algo {
+            DigestAlgorithm::Sha256 => hex::encode(Sha256::digest(data)),
+            DigestAlgorithm::Sha512 => hex::encode(Sha512::digest(data)),
+        };
+        Digest { algo, hash }
+    }
+
     // /// Returns a hash in the form of: hash
     // pub async fn hash_reference(algo: DigestAlgorithm, data: &[u8]) -> Result<String, RegistryError> {
     //     let digest = Self::hash_digest(algo, data).await?;
@@ -191,13 +202,45 @@ impl Digest {
             message: e,
         })?;
 
+        // The hash is always handled lowercase, as on the disk, whatever the case upstream or the client sent
         Ok(Digest {
             algo: algo_enum,
-            hash: digest,
+            hash: digest.to_lowercase(),
         })
     }
 }
 
+/// Hashes data received in chunks, e.g. a blob streamed from upstream
+pub enum DigestHasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl DigestHasher {
+
+    pub fn new(algo: DigestAlgorithm) -> Self {
+        match algo {
+            DigestAlgorithm::Sha256 => DigestHasher::Sha256(Sha256::new()),
+            DigestAlgorithm::Sha512 => DigestHasher::Sha512(Sha512::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            DigestHasher::Sha256(hasher) => hasher.update(data),
+            DigestHasher::Sha512(hasher) => hasher.update(data),
+        }
+    }
+
+    /// The digest of all the data hashed
+    pub fn finalize(self) -> Digest {
+        match self {
+            DigestHasher::Sha256(hasher) => Digest { algo: DigestAlgorithm::Sha256, hash: hex::encode(hasher.finalize()) },
+            DigestHasher::Sha512(hasher) => Digest { algo: DigestAlgorithm::Sha512, hash: hex::encode(hasher.finalize()) },
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
 
@@ -232,4 +275,13 @@ mod test {
         assert_eq!(parsed_digest, digest);
 
     }
+
+    #[tokio::test]
+    async fn digest_lowercase_test() {
+        let digest = Digest::parse("SHA256:05C6E08F1D9FDAFA03147FCB8F82F124C76D2F70E3D989DC8AADB5E7D7450BEC").expect("failed to parse digest");
+
+        assert_eq!(DigestAlgorithm::Sha256, digest.algo);
+        assert_eq!("05c6e08f1d9fdafa03147fcb8f82f124c76d2f70e3d989dc8aadb5e7d7450bec", digest.hash);
+        assert_eq!("sha256:05c6e08f1d9fdafa03147fcb8f82f124c76d2f70e3d989dc8aadb5e7d7450bec", digest.to_string());
+    }
 }
\ No newline at end of file
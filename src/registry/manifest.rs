@@ -0,0 +1,251 @@
+// SPDX-License-Identifier: Apache-2.0
+// Subset of the OCI image manifest spec used by the cache:
+// https://github.com/opencontainers/image-spec/blob/main/manifest.md
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use crate::registry::digest::Digest;
+
+/// OCI image index media type
+pub const OCI_IMAGE_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+
+/// Docker manifest list media type, the Docker equivalent of the OCI image index
+pub const DOCKER_MANIFEST_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+
+/// Signed Docker schema 1 manifest media type, its digest is the one of the payload without the signatures
+pub const DOCKER_SIGNED_MANIFEST_V1: &str = "application/vnd.docker.distribution.manifest.v1+prettyjws";
+
+/// Platform an image index entry was built for
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Platform {
+    pub os: String,
+    pub architecture: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+}
+
+impl Platform {
+
+    /// Parse a platform in the `os/architecture[/variant]` format, e.g. `linux/arm64/v8`
+    pub fn parse(platform: &str) -> Option<Platform> {
+        let parts = platform.split('/').collect::<Vec<&str>>();
+        match parts.as_slice() {
+            [os, architecture] if !os.is_empty() && !architecture.is_empty() => Some(Platform {
+                os: os.to_string(),
+                architecture: architecture.to_string(),
+                variant: None,
+            }),
+            [os, architecture, variant] if !os.is_empty() && !architecture.is_empty() && !variant.is_empty() => Some(Platform {
+                os: os.to_string(),
+                architecture: architecture.to_string(),
+                variant: Some(variant.to_string()),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Whether the given platform satisfies this one, a missing variant matches any variant
+    pub fn matches(&self, other: &Platform) -> bool {
+        self.os == other.os && self.architecture == other.architecture
+            && (self.variant.is_none() || self.variant == other.variant)
+    }
+}
+
+/// Content descriptor pointing to another blob or manifest
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Descriptor {
+    pub media_type: String,
+    pub digest: Digest,
+    pub size: i64,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifact_type: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<HashMap<String, String>>,
+
+    /// Only set for the image index entries
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Platform>,
+}
+
+/// Image manifest or image index
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    #[serde(default)]
+    pub media_type: Option<String>,
+
+    #[serde(default)]
+    pub artifact_type: Option<String>,
+
+    #[serde(default)]
+    pub config: Option<Descriptor>,
+
+    #[serde(default)]
+    pub layers: Option<Vec<Descriptor>>,
+
+    /// The blobs of an OCI artifact manifest, which has neither config nor layers
+    #[serde(default, rename = "blobs")]
+    pub artifact_blobs: Option<Vec<Descriptor>>,
+
+    /// The manifests of an image index
+    #[serde(default)]
+    pub manifests: Option<Vec<Descriptor>>,
+
+    /// The manifest this one refers to, e.g. a signature or an SBOM of an image
+    #[serde(default)]
+    pub subject: Option<Descriptor>,
+
+    #[serde(default)]
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+impl Manifest {
+
+    /// Parse the manifest JSON
+    pub fn parse(data: &[u8]) -> Result<Manifest, serde_json::Error> {
+        serde_json::from_slice(data)
+    }
+
+    /// The artifact type as defined by the referrers API: the `artifactType` field,
+    /// falling back to the config media type
+    pub fn effective_artifact_type(&self) -> Option<String> {
+        self.artifact_type.clone()
+            .or_else(|| self.config.as_ref().map(|config| config.media_type.clone()))
+    }
+
+    /// The first image index entry built for the given platform
+    pub fn platform_manifest(&self, platform: &Platform) -> Option<&Descriptor> {
+        self.manifests.as_ref()?.iter()
+            .find(|descriptor| descriptor.platform.as_ref().is_some_and(|p| platform.matches(p)))
+    }
+
+    /// The blobs a manifest is made of: its config and its layers, or the blobs of an artifact manifest.
+    /// Whatever their media types, e.g. the chart of a Helm chart or the document of an SBOM
+    pub fn blobs(&self) -> Vec<&Descriptor> {
+        self.config.iter()
+            .chain(self.layers.iter().flatten())
+            .chain(self.artifact_blobs.iter().flatten())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::registry::manifest::{Manifest, Platform};
+
+    #[test]
+    fn manifest_subject_test() {
+        let manifest = r#"{
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": {
+                "mediaType": "application/vnd.dev.cosign.artifact.sig.v1+json",
+                "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+                "size": 2
+            },
+            "layers": [],
+            "subject": {
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "digest": "sha256:05c6e08f1d9fdafa03147fcb8f82f124c76d2f70e3d989dc8aadb5e7d7450bec",
+                "size": 1234
+            },
+            "annotations": { "org.opencontainers.image.created": "2023-10-01T00:00:00Z" }
+        }"#;
+
+        let manifest = Manifest::parse(manifest.as_bytes()).expect("Failed to parse manifest");
+        let subject = manifest.subject.clone().expect("Missing subject");
+        assert_eq!("sha256:05c6e08f1d9fdafa03147fcb8f82f124c76d2f70e3d989dc8aadb5e7d7450bec", subject.digest.to_string());
+        assert_eq!(Some("application/vnd.dev.cosign.artifact.sig.v1+json".to_string()), manifest.effective_artifact_type());
+    }
+
+    #[test]
+    fn artifact_manifest_test() {
+        let chart = r#"{
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": {
+                "mediaType": "application/vnd.cncf.helm.config.v1+json",
+                "digest": "sha256:1111111111111111111111111111111111111111111111111111111111111111",
+                "size": 117
+            },
+            "layers": [
+                {
+                    "mediaType": "application/vnd.cncf.helm.chart.content.v1.tar+gzip",
+                    "digest": "sha256:2222222222222222222222222222222222222222222222222222222222222222",
+                    "size": 3624
+                }
+            ]
+        }"#;
+
+        let chart = Manifest::parse(chart.as_bytes()).expect("Failed to parse Helm chart");
+        let blobs = chart.blobs().into_iter().map(|blob| blob.media_type.as_str()).collect::<Vec<&str>>();
+        assert_eq!(vec!["application/vnd.cncf.helm.config.v1+json", "application/vnd.cncf.helm.chart.content.v1.tar+gzip"], blobs);
+        assert_eq!(Some("application/vnd.cncf.helm.config.v1+json".to_string()), chart.effective_artifact_type());
+
+        let sbom = r#"{
+            "mediaType": "application/vnd.oci.artifact.manifest.v1+json",
+            "artifactType": "application/spdx+json",
+            "blobs": [
+                {
+                    "mediaType": "application/spdx+json",
+                    "digest": "sha256:3333333333333333333333333333333333333333333333333333333333333333",
+                    "size": 8192
+                }
+            ],
+            "subject": {
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "digest": "sha256:05c6e08f1d9fdafa03147fcb8f82f124c76d2f70e3d989dc8aadb5e7d7450bec",
+                "size": 1234
+            }
+        }"#;
+
+        let sbom = Manifest::parse(sbom.as_bytes()).expect("Failed to parse SBOM");
+        let blobs = sbom.blobs().into_iter().map(|blob| blob.digest.to_string()).collect::<Vec<String>>();
+        assert_eq!(vec!["sha256:3333333333333333333333333333333333333333333333333333333333333333"], blobs);
+        assert_eq!(Some("application/spdx+json".to_string()), sbom.effective_artifact_type());
+        assert!(sbom.subject.is_some());
+    }
+
+    #[test]
+    fn platform_manifest_test() {
+        let index = r#"{
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.index.v1+json",
+            "manifests": [
+                {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "digest": "sha256:1111111111111111111111111111111111111111111111111111111111111111",
+                    "size": 100,
+                    "platform": { "architecture": "amd64", "os": "linux" }
+                },
+                {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "digest": "sha256:2222222222222222222222222222222222222222222222222222222222222222",
+                    "size": 100,
+                    "platform": { "architecture": "arm", "os": "linux", "variant": "v7" }
+                },
+                {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "digest": "sha256:3333333333333333333333333333333333333333333333333333333333333333",
+                    "size": 100,
+                    "platform": { "architecture": "arm64", "os": "linux", "variant": "v8" }
+                }
+            ]
+        }"#;
+
+        let index = Manifest::parse(index.as_bytes()).expect("Failed to parse index");
+
+        let amd64 = index.platform_manifest(&Platform::parse("linux/amd64").unwrap()).expect("Missing linux/amd64");
+        assert!(amd64.digest.to_string().ends_with("1111"));
+
+        let arm64 = index.platform_manifest(&Platform::parse("linux/arm64").unwrap()).expect("Missing linux/arm64");
+        assert!(arm64.digest.to_string().ends_with("3333"));
+
+        assert!(index.platform_manifest(&Platform::parse("linux/arm/v6").unwrap()).is_none());
+        assert!(index.platform_manifest(&Platform::parse("windows/amd64").unwrap()).is_none());
+        assert!(Platform::parse("linux").is_none());
+    }
+}
@@ -1,4 +1,5 @@
 // SPDX-License-Identifier: Apache-2.0
 pub mod digest;
+pub mod manifest;
 pub mod repository;
 pub mod repository_error;
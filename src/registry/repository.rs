@@ -10,12 +10,17 @@ use lazy_static::lazy_static;
 use regex::Regex;
 
 use serde::{Deserialize, Serialize};
+use crate::config::repository_names::RepositoryNamesConfig;
 use crate::error::error_kind::ErrorKind;
 use crate::error::registry::RegistryError;
 use crate::registry::digest::{Digest, DigestAlgorithm};
 
 lazy_static! {
-    static ref REGEX_COMPONENT: Regex = Regex::new(r"^[a-z0-9]+(?:[._-][a-z0-9]+)*").unwrap();
+    /// A whole path component of the repository name
+    static ref REGEX_COMPONENT: Regex = Regex::new(r"^[a-z0-9]+(?:[._-][a-z0-9]+)*$").unwrap();
+
+    /// The start of a tag, the rest of it is left to upstream
+    static ref REGEX_TAG_START: Regex = Regex::new(r"^[a-z0-9]+(?:[._-][a-z0-9]+)*").unwrap();
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -41,7 +46,12 @@ impl Repository {
     /// New repository with reference
     pub fn new_with_reference(name: &str, reference: &str) -> Result<Repository, RegistryError> {
         // parse the name(space)
-        let mut repository = Repository::new(name)?;
+        Repository::new(name)?.with_reference(reference)
+    }
+
+    /// The repository with the reference, a tag or a digest
+    pub fn with_reference(self, reference: &str) -> Result<Repository, RegistryError> {
+        let mut repository = self;
 
         // set the reference
         repository.reference = reference.to_string();
@@ -51,7 +61,7 @@ impl Repository {
             reference.starts_with(&DigestAlgorithm::Sha512.to_string())){
             repository.digest = Some(Digest::parse(reference)?);
 
-        } else if !REGEX_COMPONENT.is_match(reference) {
+        } else if !REGEX_TAG_START.is_match(reference) {
             return Err(RegistryError::new(ErrorKind::RegistryDigestInvalid).with_error(format!(
                 "Repository reference/tag is invalid: {}",
                 &reference
@@ -81,6 +91,14 @@ impl Repository {
 
         // verify now that each component is valid
         for component in &components {
+            // a leading, trailing or doubled `/`
+            if component.is_empty() {
+                return Err(RegistryError::new(ErrorKind::RegistryNameInvalid).with_error(format!(
+                    "Repository name has an empty component: {}",
+                    &name
+                )));
+            }
+
             // if it does not match then return an error!
             if !REGEX_COMPONENT.is_match(component) {
                 return Err(RegistryError::new(ErrorKind::RegistryNameInvalid).with_error(format!(
@@ -97,10 +115,40 @@ impl Repository {
             digest: None
         })
     }
+
+    /// New repository whose name is within the limits of config.yaml repository_names, for the names sent by the clients.
+    /// The limits are checked before the name is parsed, so that a pathological name is rejected without being matched
+    pub fn new_with_limits(name: &str, limits: &RepositoryNamesConfig) -> Result<Repository, RegistryError> {
+        check_name_limits(name, limits)?;
+        Repository::new(name)
+    }
+}
+
+/// Check the components of the name against the limits, stopping at the first one over them.
+/// A valid component is ASCII, its length in bytes is its number of characters
+fn check_name_limits(name: &str, limits: &RepositoryNamesConfig) -> Result<(), RegistryError> {
+    for (index, component) in name.split('/').enumerate() {
+        if index >= limits.max_components {
+            return Err(RegistryError::new(ErrorKind::RegistryNameInvalid).with_error(format!(
+                "Repository name has more than {} components: {}",
+                limits.max_components, name
+            )));
+        }
+
+        if component.len() > limits.max_component_length {
+            return Err(RegistryError::new(ErrorKind::RegistryNameInvalid).with_error(format!(
+                "Repository name has a component longer than {} characters: {}",
+                limits.max_component_length, name
+            )));
+        }
+    }
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod test {
+    use crate::config::repository_names::{RepositoryNamesConfig, DEFAULT_MAX_COMPONENTS, DEFAULT_MAX_COMPONENT_LENGTH};
 
     #[test]
     fn repository_no_tag_test() {
@@ -202,6 +250,43 @@ mod test {
         );
     }
 
+    #[test]
+    fn repository_empty_component_test() {
+        for repo_name in ["a//b", "a/", "a/b/", ""] {
+            assert!(super::Repository::new(repo_name).is_err(), "{} has an empty component", repo_name);
+        }
+    }
+
+    #[test]
+    fn repository_component_anchored_test() {
+        for repo_name in ["library/nginx!", "library/nginx-", "library/Nginx", "library/nginx:latest"] {
+            assert!(super::Repository::new(repo_name).is_err(), "{} has an invalid component", repo_name);
+        }
+    }
+
+    #[test]
+    fn repository_name_limits_test() {
+        let limits = RepositoryNamesConfig { max_components: 3, max_component_length: 1 };
+        let check = |name: &str| super::Repository::new_with_limits(name, &limits);
+        assert!(check("a/b/c").is_ok());
+        assert!(check("a/b/c/d").is_err());
+        assert!(check("a/bb/c").is_err());
+
+        // Rejected for the limits before the invalid components are even parsed
+        assert!(check("A/B/C/D").unwrap_err().to_string().contains("more than 3 components"));
+        assert!(check("a/BB").unwrap_err().to_string().contains("longer than 1 characters"));
+        assert!(check("A").unwrap_err().to_string().contains("component is invalid"));
+
+        // The default limits are generous, yet bounded
+        let limits = RepositoryNamesConfig::default();
+        let deep = vec!["a"; DEFAULT_MAX_COMPONENTS + 1].join("/");
+        assert!(super::Repository::new(&deep).is_ok());
+        assert!(super::Repository::new_with_limits(&deep, &limits).is_err());
+        let long = format!("library/{}", "a".repeat(DEFAULT_MAX_COMPONENT_LENGTH + 1));
+        assert!(super::Repository::new_with_limits(&long, &limits).is_err());
+        assert!(super::Repository::new_with_limits("library/nginx", &limits).is_ok());
+    }
+
     #[test]
     fn repository_complex_test() {
         let repo_name = String::from("lib/crane/reg/test/amd64/nginx");
@@ -221,14 +306,6 @@ mod test {
     fn repository_complex_with_space_test() {
         let repo_name = String::from("lib/crane/reg/test rust/amd64/nginx");
         let repo = super::Repository::new(&repo_name);
-        assert!(repo.is_ok(), "complex repo should be parsed fine");
-        let repo = repo.unwrap();
-        assert_eq!(6, repo.components.len());
-        assert_eq!("lib", repo.components[0]);
-        assert_eq!("crane", repo.components[1]);
-        assert_eq!("reg", repo.components[2]);
-        assert_eq!("test rust", repo.components[3]);
-        assert_eq!("amd64", repo.components[4]);
-        assert_eq!("nginx", repo.components[5]);
+        assert!(repo.is_err(), "a component can't contain a space");
     }
 }
\ No newline at end of file
@@ -0,0 +1,104 @@
+// SPDX-License-Identifier: Apache-2.0
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use parking_lot::Mutex;
+use crate::config::app::EvictionReadPolicy;
+
+/// Outcome of a blob eviction
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Eviction {
+    /// The blob was removed from the storage
+    Removed,
+
+    /// The blob is being served to a client and was left in place
+    Skipped,
+
+    /// The blob is being served to a client and will be removed once the last reader is done
+    Deferred,
+}
+
+#[derive(Default)]
+struct Reads {
+    /// Number of clients currently reading a blob file
+    readers: HashMap<PathBuf, usize>,
+
+    /// Blob files to remove as soon as their last reader is done
+    pending: HashSet<PathBuf>,
+}
+
+/// Keeps track of the blobs which are currently being served to the clients,
+/// so that an eviction never removes a blob in the middle of a stream
+#[derive(Clone, Default)]
+pub struct ActiveReads {
+    reads: Arc<Mutex<Reads>>,
+}
+
+impl ActiveReads {
+
+    /// Register a new reader for the blob file, the read is tracked until the guard is dropped
+    pub fn acquire(&self, path: PathBuf) -> ReadGuard {
+        *self.reads.lock().readers.entry(path.clone()).or_default() += 1;
+
+        ReadGuard {
+            reads: self.clone(),
+            path,
+        }
+    }
+
+    /// Remove the blob file, unless it has active readers in which case the policy decides
+    /// whether the eviction is skipped or deferred until the last reader is done
+    pub fn evict(&self, path: PathBuf, policy: &EvictionReadPolicy) -> std::io::Result<Eviction> {
+        // Keep the lock while removing the file, so that no reader can start in between
+        let mut reads = self.reads.lock();
+
+        if reads.readers.contains_key(&path) {
+            return match policy {
+                EvictionReadPolicy::Skip => Ok(Eviction::Skipped),
+                EvictionReadPolicy::Defer => {
+                    reads.pending.insert(path);
+                    Ok(Eviction::Deferred)
+                }
+            };
+        }
+
+        std::fs::remove_file(path)?;
+        Ok(Eviction::Removed)
+    }
+
+    /// Release a reader and run any deferred eviction
+    fn release(&self, path: &PathBuf) {
+        let mut reads = self.reads.lock();
+
+        let remaining = match reads.readers.get_mut(path) {
+            Some(count) => {
+                *count -= 1;
+                *count
+            }
+            None => return,
+        };
+
+        if remaining > 0 {
+            return;
+        }
+
+        reads.readers.remove(path);
+        if reads.pending.remove(path) {
+            if let Err(e) = std::fs::remove_file(path) {
+                tracing::error!("Failed to remove deferred blob eviction {:?}: {}", path, e.to_string());
+            }
+        }
+    }
+}
+
+/// Keeps a blob marked as being read until dropped
+pub struct ReadGuard {
+    reads: ActiveReads,
+    path: PathBuf,
+}
+
+impl Drop for ReadGuard {
+    fn drop(&mut self) {
+        self.reads.release(&self.path);
+    }
+}
@@ -0,0 +1,134 @@
+// SPDX-License-Identifier: Apache-2.0
+use std::sync::Arc;
+use crate::handlers::command::blob::service::ManifestService;
+use crate::registry::digest::Digest;
+use crate::registry::manifest::Manifest;
+use crate::registry::repository::Repository;
+use crate::repository::filesystem::FilesystemStorage;
+
+/// The digests a stored manifest keeps in the cache: the manifest itself, its config and its layers.
+/// A manifest which is not an OCI/Docker v2 JSON manifest only references itself
+pub fn referenced_digests(manifest: &Digest, data: &[u8]) -> Vec<Digest> {
+    let blobs = Manifest::parse(data)
+        .map(|parsed| parsed.blobs().into_iter().map(|blob| blob.digest.clone()).collect())
+        .unwrap_or_else(|_| Vec::new());
+
+    std::iter::once(manifest.clone()).chain(blobs).collect()
+}
+
+/// Remove a digest which is not referenced anymore from the storages, returning from how many it was removed
+pub async fn unlink(storages: &[FilesystemStorage], manifests: &ManifestService, digest: &Digest) -> usize {
+    let mut removed = 0;
+    for storage in storages {
+        match storage.driver().remove(digest).await {
+            Ok(true) => removed += 1,
+            Ok(false) => {}
+            Err(e) => tracing::error!("failed to remove unreferenced blob {}: {}", digest, e.to_string()),
+        }
+    }
+    if let Some(storage) = storages.first().filter(|_| removed > 0) {
+        forget_type(storage, manifests, digest).await;
+    }
+    removed
+}
+
+/// Forget the media type of a removed blob, unless the storage folder of another upstream still holds it
+pub async fn forget_type(storage: &FilesystemStorage, manifests: &ManifestService, digest: &Digest) {
+    if storage.storages().iter().any(|storage| storage.driver().is_stored(digest)) {
+        return;
+    }
+    if let Err(e) = manifests.delete_blob_type(digest).await {
+        tracing::error!("failed to forget the media type of blob {}: {}", digest, e);
+    }
+}
+
+/// Record the blob references of the manifests indexed before they were tracked,
+/// so that a purge does not remove what another container image still uses.
+/// Nothing is done once any reference was recorded
+pub async fn backfill(storage: Arc<FilesystemStorage>, manifests: Arc<ManifestService>) {
+    match manifests.has_no_blob_refs().await {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(e) => {
+            tracing::error!("Failed to look up the blob references: {}", e);
+            return;
+        }
+    }
+
+    let records = match manifests.list_all().await {
+        Ok(records) => records,
+        Err(e) => {
+            tracing::error!("Failed to load the indexed manifests: {}", e);
+            return;
+        }
+    };
+
+    let storages = storage.storages();
+    let mut recorded = 0;
+    for record in records {
+        let Some(digest) = record.reference else { continue };
+        let Ok(repository) = Repository::new_with_reference(&record.name, &record.tag) else { continue };
+
+        // The manifest is in the storage of the upstream it was pulled from
+        let mut data = None;
+        for storage in &storages {
+            if let Ok(read) = storage.driver().content(&digest).await {
+                data = Some(read);
+                break;
+            }
+        }
+        let Some(data) = data else { continue };
+
+        // Owned by the upstream the tag was pulled from, '' when it was indexed before the upstream was tracked
+        match manifests.reference_blobs(&record.upstream, &repository, &digest, &referenced_digests(&digest, &data)).await {
+            Ok(_) => recorded += 1,
+            Err(e) => tracing::error!("Failed to record the blob references of {}:{}: {}", record.name, record.tag, e),
+        }
+    }
+
+    if recorded > 0 {
+        tracing::info!("Recorded the blob references of {} indexed manifests", recorded);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use crate::config::app::AppConfig;
+    use crate::db::db_blob_refs::DBBlobRefs;
+    use crate::db::db_manifests::DBManifests;
+    use crate::db::pool::DBPool;
+    use crate::handlers::command::blob::service::ManifestService;
+    use crate::registry::digest::Digest;
+    use crate::registry::repository::Repository;
+    use crate::repository::blob_refs::{backfill, referenced_digests};
+    use crate::repository::filesystem::FilesystemStorage;
+
+    #[tokio::test]
+    async fn backfill_test() {
+        let folder = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(folder.path().join("sha256")).unwrap();
+        let storage = Arc::new(FilesystemStorage::new(AppConfig::with_storage_folder(folder.path().to_str().unwrap())));
+        let pool = DBPool::default().await;
+        DBManifests::create_table(&pool).await;
+        DBBlobRefs::create_table(&pool).await;
+        let manifests = ManifestService::from_pool(pool);
+
+        // A manifest indexed before the blob references were tracked
+        let layer = Digest::parse(&format!("sha256:{}", "a".repeat(64))).unwrap();
+        let manifest = format!(r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","layers":[{{"mediaType":"application/vnd.oci.image.layer.v1.tar+gzip","digest":"{}","size":1}}]}}"#, layer);
+        let digest = Digest::parse(&format!("sha256:{}", "b".repeat(64))).unwrap();
+        std::fs::write(storage.digest_path(&digest), &manifest).unwrap();
+        let repository = Repository::new_with_reference("library/nginx", "latest").unwrap();
+        manifests.persist("localhost", &repository, digest.clone(), manifest.len() as i32, &"application/vnd.oci.image.manifest.v1+json".parse().unwrap(), None).await.unwrap();
+        assert_eq!(vec![digest.clone(), layer.clone()], referenced_digests(&digest, manifest.as_bytes()));
+
+        backfill(storage, manifests.clone()).await;
+        assert!(!manifests.has_no_blob_refs().await.unwrap());
+
+        // Releasing the tag releases both the manifest and its layer
+        let mut released = manifests.release_blobs("localhost", &repository, &digest).await.unwrap();
+        released.sort_by_key(|digest| digest.to_string());
+        assert_eq!(vec![layer, digest], released);
+    }
+}
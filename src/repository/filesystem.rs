@@ -1,24 +1,61 @@
 // SPDX-License-Identifier: Apache-2.0
+use std::collections::HashSet;
+use std::os::unix::fs::MetadataExt;
 use std::path::PathBuf;
 use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 use async_trait::async_trait;
+use bytes::Bytes;
 use tokio::fs::{File, OpenOptions};
 use tokio::io::{AsyncRead, AsyncWrite};
-use crate::driver::RepositoryTrait;
+use crate::config::app::AppConfig;
+use crate::config::driver::StorageDriver;
+use crate::driver::{RepositoryTrait, StoredContent};
 use crate::error::error_kind::ErrorKind;
 use crate::error::registry::RegistryError;
+use crate::metrics;
+use crate::registry::digest::{Digest, DigestAlgorithm};
 use crate::registry::repository::Repository;
+use crate::repository::active_reads::{ActiveReads, Eviction, ReadGuard};
+use crate::repository::memory::InMemoryStorage;
+
+/// Suffix of the files blobs are written into before they are verified
+const TMP_SUFFIX: &str = "_tmp";
+
+/// Makes the tmp file of each persistence attempt unique within the process
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A blob found in the storage folder
+pub struct StoredBlob {
+    pub digest: Digest,
+    pub path: PathBuf,
+    pub size: u64,
+    pub last_access: SystemTime,
+
+    /// The device and the inode of the file, shared by the hard links of a deduplicated content
+    pub file_id: (u64, u64),
+}
 
 #[derive(Clone)]
 pub struct FilesystemStorage {
-    app_config: crate::config::app::AppConfig
+    app_config: Arc<AppConfig>,
+
+    /// Folder the blobs are stored into: the storage folder, or the one of an upstream
+    folder: PathBuf,
+
+    /// Blobs currently being served to the clients
+    active_reads: ActiveReads,
+
+    /// Where the blobs are stored instead of the folder, with the memory driver, see `driver`
+    memory: Option<InMemoryStorage>,
 }
 
 #[async_trait]
 impl RepositoryTrait for FilesystemStorage {
 
-    async fn persist(&self, repo: Repository) -> Result<Pin<Box<dyn AsyncWrite>>, RegistryError> {
-
+    async fn persist(&self, repo: Repository) -> Result<Pin<Box<dyn AsyncWrite + Send>>, RegistryError> {
         // Get the blob path
         let blob_path = self.blob_path(repo);
 
@@ -30,7 +67,7 @@ impl RepositoryTrait for FilesystemStorage {
 
     }
 
-    async fn read(&self, repo: Repository) -> Result<Pin<Box<dyn AsyncRead>>, RegistryError> {
+    async fn read(&self, repo: Repository) -> Result<Pin<Box<dyn AsyncRead + Send>>, RegistryError> {
         // Get the blob path
         let blob_path = self.blob_path(repo);
 
@@ -40,34 +77,240 @@ impl RepositoryTrait for FilesystemStorage {
         // Box it and pin it
         Ok(Box::pin(blob_file))
     }
+
+    async fn open(&self, repo: Repository) -> Result<StoredContent, RegistryError> {
+        let file = actix_files::NamedFile::open_async(self.blob_path(repo)).await
+            .map_err(|e| RegistryError::new(ErrorKind::NotFound).with_error(e.to_string()))?;
+        Ok(StoredContent::File(Box::new(file)))
+    }
+
+    fn is_stored(&self, digest: &Digest) -> bool {
+        self.digest_path(digest).exists()
+    }
+
+    async fn content(&self, digest: &Digest) -> std::io::Result<Bytes> {
+        tokio::fs::read(self.digest_path(digest)).await.map(Bytes::from)
+    }
+
+    async fn remove(&self, digest: &Digest) -> std::io::Result<bool> {
+        let path = self.digest_path(digest);
+        let Ok(metadata) = tokio::fs::metadata(&path).await else { return Ok(false) };
+
+        // Same as an eviction, a client might still be reading it
+        match self.evict(path)? {
+            Eviction::Removed => {
+                metrics::CACHE_DISK_BYTES.sub(metadata.len() as i64);
+                metrics::CACHE_BLOB_COUNT.dec();
+                Ok(true)
+            }
+            Eviction::Deferred | Eviction::Skipped => Ok(false),
+        }
+    }
+
+    fn usage(&self) -> std::io::Result<(u64, u64)> {
+        self.unique_usage()
+    }
 }
 
 impl FilesystemStorage {
 
     /// New instance of the FilesystemStorage
-    pub fn new(app_config: crate::config::app::AppConfig) -> FilesystemStorage {
+    pub fn new(app_config: AppConfig) -> FilesystemStorage {
         FilesystemStorage {
-            app_config
+            folder: PathBuf::from(app_config.storage.folder.to_string()),
+            memory: (app_config.storage.driver == StorageDriver::Memory).then(|| InMemoryStorage::new(app_config.storage.memory_max_bytes)),
+            app_config: Arc::new(app_config),
+            active_reads: Default::default(),
         }
     }
 
+    /// The backend the blobs are stored into, as selected by storage->driver: this storage folder,
+    /// or the memory of the process shared by all the upstreams
+    pub fn driver(&self) -> &(dyn RepositoryTrait + Send + Sync) {
+        match &self.memory {
+            Some(memory) => memory,
+            None => self,
+        }
+    }
+
+    /// The storage of the blobs coming from the upstream of the given host.
+    /// Upstreams without their own storage folder share the main storage folder.
+    pub fn for_upstream(&self, host: &str) -> FilesystemStorage {
+        let folder = self.app_config.upstreams.iter()
+            .find(|upstream| upstream.host == host)
+            .and_then(|upstream| upstream.storage_folder.as_ref())
+            .map(|folder| self.root().join(folder))
+            .unwrap_or_else(|| self.root());
+
+        FilesystemStorage {
+            app_config: self.app_config.clone(),
+            folder,
+            active_reads: self.active_reads.clone(),
+            memory: self.memory.clone(),
+        }
+    }
+
+    /// The main storage together with the ones of the upstreams with their own storage folder
+    pub fn storages(&self) -> Vec<FilesystemStorage> {
+        let mut folders = HashSet::new();
+        std::iter::once(self.for_upstream(""))
+            .chain(self.app_config.upstreams.iter().map(|upstream| self.for_upstream(&upstream.host)))
+            .filter(|storage| folders.insert(storage.folder()))
+            .collect()
+    }
+
     /// Build the local blob path
     pub fn blob_path(&self, repo: Repository) -> PathBuf {
         // Extract the digest
         let digest = repo.digest.unwrap();
 
         // Build the path where to store the data
-        PathBuf::from(self.app_config.storage.folder.to_string()).join(digest.algo.to_string()).join(digest.hash)
+        self.digest_path(&digest)
+    }
+
+    /// Build the local blob path for a digest
+    pub fn digest_path(&self, digest: &Digest) -> PathBuf {
+        self.folder.join(digest.algo.to_string()).join(&digest.hash)
+    }
 
+    /// Mark the blob as being served to a client until the guard is dropped
+    pub fn acquire_read(&self, digest: &Digest) -> ReadGuard {
+        self.active_reads.acquire(self.digest_path(digest))
     }
 
+    /// Remove a blob file from the storage, taking into account the clients which are currently reading it.
+    /// The path is the one of `blobs`, which walks the storage folders of all the upstreams.
+    pub fn evict(&self, path: PathBuf) -> std::io::Result<Eviction> {
+        self.active_reads.evict(path, &self.app_config.storage.eviction_read_policy)
+    }
+
+    /// Build the path of the tmp file a blob is written into, unique per call so that concurrent
+    /// persistences of the same blob never write into the same file. It is in the spool folder when there is one
     pub fn blob_path_tmp(&self, repo: Repository) -> PathBuf {
         // Extract the digest
         let digest = repo.digest.unwrap();
 
         // Build the path where to store the data
-        PathBuf::from(self.app_config.storage.folder.to_string()).join(digest.algo.to_string()).join(format!("{}_tmp", digest.hash))
+        let attempt = TMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+        self.spool().join(digest.algo.to_string()).join(format!("{}_{}_{}{}", digest.hash, std::process::id(), attempt, TMP_SUFFIX))
+    }
+
+    /// Folder the blobs are written into until they are verified: storage->spool_dir, or the storage folder
+    fn spool(&self) -> PathBuf {
+        self.app_config.storage.spool_dir.as_ref().map(PathBuf::from).unwrap_or_else(|| self.folder.clone())
+    }
+
+    /// Walk the storage folder and return the total amount of bytes and the number of blobs stored.
+    /// Temporary files of in-flight writes are not accounted for.
+    pub fn disk_usage(&self) -> std::io::Result<(u64, u64)> {
+        let blobs = self.blobs()?;
+        Ok((blobs.iter().map(|blob| blob.size).sum(), blobs.len() as u64))
+    }
+
+    /// Walk the storage folders and return the amount of bytes and the number of the unique blobs stored:
+    /// a digest stored in the folders of several upstreams, or a content hard linked under several digests, counts once
+    pub fn unique_usage(&self) -> std::io::Result<(u64, u64)> {
+        let mut digests = HashSet::new();
+        let mut files = HashSet::new();
+        let (mut bytes, mut count) = (0, 0);
+        for blob in self.blobs()? {
+            // Both are recorded, whichever was seen first
+            let new_digest = digests.insert(blob.digest);
+            let new_file = files.insert(blob.file_id);
+            if new_digest && new_file {
+                bytes += blob.size;
+                count += 1;
+            }
+        }
+        Ok((bytes, count))
+    }
+
+    /// Walk the storage folder, including the upstream ones, and return all the stored blobs.
+    /// Temporary files of in-flight writes are skipped.
+    pub fn blobs(&self) -> std::io::Result<Vec<StoredBlob>> {
+        let mut blobs = Vec::new();
+
+        let mut folders = vec![self.root()];
+        for folder in self.app_config.upstreams.iter().filter_map(|upstream| upstream.storage_folder.as_ref()) {
+            let folder = self.root().join(folder);
+            if !folders.contains(&folder) {
+                folders.push(folder);
+            }
+        }
+
+        for (folder, algo) in folders.iter().flat_map(|folder| [(folder, DigestAlgorithm::Sha256), (folder, DigestAlgorithm::Sha512)]) {
+            let algo_folder = folder.join(algo.to_string());
+
+            // Nothing was stored yet for this algorithm
+            if !algo_folder.is_dir() {
+                continue;
+            }
+
+            let mut folders = vec![algo_folder];
+            while let Some(folder) = folders.pop() {
+                for entry in std::fs::read_dir(folder)? {
+                    // Files can be renamed or removed while we are walking the folder
+                    let Ok(entry) = entry else { continue };
+                    let Ok(metadata) = entry.metadata() else { continue };
+
+                    if metadata.is_dir() {
+                        folders.push(entry.path());
+                        continue;
+                    }
+
+                    let hash = entry.file_name().to_string_lossy().to_string();
+                    if hash.ends_with(TMP_SUFFIX) {
+                        continue;
+                    }
+
+                    blobs.push(StoredBlob {
+                        digest: Digest { algo, hash },
+                        path: entry.path(),
+                        size: metadata.len(),
+                        // Not every filesystem tracks the access time
+                        last_access: metadata.accessed().or_else(|_| metadata.modified()).unwrap_or(UNIX_EPOCH),
+                        file_id: (metadata.dev(), metadata.ino()),
+                    });
+                }
+            }
+        }
+
+        Ok(blobs)
+    }
+
+    /// Create the storage folders, including the upstream ones and the spool folder, and their digest algorithm folders
+    /// when missing, and make sure the blobs can be written into them. The tmp files left over by a previous run,
+    /// crashed or stopped while writing, are removed: nothing is being written yet
+    pub fn create_folders(&self) -> std::io::Result<()> {
+        let mut folders = self.storages().iter().map(|storage| storage.folder()).collect::<Vec<_>>();
+        if let Some(spool_dir) = &self.app_config.storage.spool_dir {
+            folders.push(PathBuf::from(spool_dir));
+        }
+
+        for root in folders {
+            for algo in [DigestAlgorithm::Sha256, DigestAlgorithm::Sha512] {
+                let folder = root.join(algo.to_string());
+                std::fs::create_dir_all(&folder)?;
+
+                // Nothing tells whether a folder is writable better than writing into it
+                let probe = folder.join(format!(".pier-cache-check-{}", std::process::id()));
+                std::fs::write(&probe, b"")?;
+                std::fs::remove_file(&probe)?;
 
+                remove_tmp_files(&folder)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The folder where the blobs are stored
+    pub fn folder(&self) -> PathBuf {
+        self.folder.clone()
+    }
+
+    /// The main storage folder, which contains the upstream ones
+    fn root(&self) -> PathBuf {
+        PathBuf::from(self.app_config.storage.folder.to_string())
     }
 
     /// Get an async read File handle
@@ -96,4 +339,235 @@ impl FilesystemStorage {
 
     }
 
-}
\ No newline at end of file
+}
+
+/// Remove the tmp files of the blobs from a digest algorithm folder
+fn remove_tmp_files(folder: &PathBuf) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(folder)? {
+        let entry = entry?;
+        if !entry.file_name().to_string_lossy().ends_with(TMP_SUFFIX) {
+            continue;
+        }
+
+        tracing::info!("Removing {}, left over by a previous run", entry.path().display());
+        if let Err(e) = std::fs::remove_file(entry.path()) {
+            tracing::warn!("Failed to remove {}: {}", entry.path().display(), e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use crate::config::app::{AppConfig, EvictionReadPolicy, UpstreamConfig};
+    use crate::registry::digest::Digest;
+    use crate::registry::repository::Repository;
+    use crate::repository::active_reads::Eviction;
+    use crate::repository::filesystem::FilesystemStorage;
+
+    /// Storage with a single blob stored in it
+    fn storage_with_blob(folder: &tempfile::TempDir, policy: EvictionReadPolicy) -> (FilesystemStorage, Digest) {
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.storage.eviction_read_policy = policy;
+        let storage = FilesystemStorage::new(config);
+
+        let digest = Digest::parse("sha256:05c6e08f1d9fdafa03147fcb8f82f124c76d2f70e3d989dc8aadb5e7d7450bec").unwrap();
+        let path = storage.digest_path(&digest);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, vec![7u8; 64 * 1024]).unwrap();
+
+        (storage, digest)
+    }
+
+    #[tokio::test]
+    async fn evict_skips_blob_being_served_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let (storage, digest) = storage_with_blob(&folder, EvictionReadPolicy::Skip);
+
+        // Start serving the blob
+        let guard = storage.acquire_read(&digest);
+        let mut file = std::fs::File::open(storage.digest_path(&digest)).unwrap();
+        let mut chunk = vec![0u8; 1024];
+        file.read_exact(&mut chunk).unwrap();
+
+        // Evict it concurrently while the stream is still open
+        let evicting = storage.clone();
+        let evicted_digest = digest.clone();
+        let eviction = tokio::spawn(async move { evicting.evict(evicting.digest_path(&evicted_digest)) }).await.unwrap().unwrap();
+        assert_eq!(Eviction::Skipped, eviction);
+        assert!(storage.digest_path(&digest).exists());
+
+        // The rest of the stream is still served
+        let mut rest = Vec::new();
+        file.read_to_end(&mut rest).unwrap();
+        assert_eq!(63 * 1024, rest.len());
+
+        // Once the stream is done the blob can be evicted
+        drop(guard);
+        assert_eq!(Eviction::Removed, storage.evict(storage.digest_path(&digest)).unwrap());
+        assert!(!storage.digest_path(&digest).exists());
+    }
+
+    #[tokio::test]
+    async fn evict_deferred_until_last_reader_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let (storage, digest) = storage_with_blob(&folder, EvictionReadPolicy::Defer);
+
+        let first = storage.acquire_read(&digest);
+        let second = storage.acquire_read(&digest);
+
+        assert_eq!(Eviction::Deferred, storage.evict(storage.digest_path(&digest)).unwrap());
+        assert!(storage.digest_path(&digest).exists());
+
+        // A reader finishing on another task does not remove the blob while another one is active
+        tokio::spawn(async move { drop(first) }).await.unwrap();
+        assert!(storage.digest_path(&digest).exists());
+
+        // The last reader removes it
+        drop(second);
+        assert!(!storage.digest_path(&digest).exists());
+    }
+
+    #[test]
+    fn upstream_storage_folder_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        for (host, storage_folder) in [("docker.local", None), ("private.local", Some("private".to_string()))] {
+            config.upstreams.push(UpstreamConfig { storage_folder, ..UpstreamConfig::for_test(host, &format!("https://{}", host)) });
+        }
+        let storage = FilesystemStorage::new(config);
+
+        // The same digest is stored once per upstream storage folder
+        let digest = Digest::parse("sha256:05c6e08f1d9fdafa03147fcb8f82f124c76d2f70e3d989dc8aadb5e7d7450bec").unwrap();
+        let shared = storage.for_upstream("docker.local").digest_path(&digest);
+        let private = storage.for_upstream("private.local").digest_path(&digest);
+        assert_eq!(storage.digest_path(&digest), shared);
+        assert_eq!(folder.path().join("private").join("sha256").join(&digest.hash), private);
+
+        for path in [&shared, &private] {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"blob").unwrap();
+        }
+
+        let mut paths = storage.blobs().unwrap().into_iter().map(|blob| blob.path).collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(vec![private.clone(), shared.clone()], paths);
+
+        // Evicting the blob of one upstream leaves the other one in place
+        assert_eq!(Eviction::Removed, storage.evict(private.clone()).unwrap());
+        assert!(!private.exists());
+        assert!(shared.exists());
+    }
+
+    #[test]
+    fn blob_path_tmp_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let (storage, digest) = storage_with_blob(&folder, EvictionReadPolicy::Skip);
+        let repository = Repository::new_with_reference("library/nginx", &digest.to_string()).unwrap();
+
+        // Each attempt writes into its own file, next to the final one
+        let first = storage.blob_path_tmp(repository.clone());
+        let second = storage.blob_path_tmp(repository);
+        assert_ne!(first, second);
+        assert_eq!(storage.digest_path(&digest).parent(), first.parent());
+
+        // The blobs being written are not accounted for
+        std::fs::write(&first, b"partial").unwrap();
+        let blobs = storage.blobs().unwrap();
+        assert_eq!(1, blobs.len());
+        assert_eq!(storage.digest_path(&digest), blobs[0].path);
+
+        // Or in the spool folder, whatever the upstream
+        let spool = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.storage.spool_dir = Some(spool.path().to_str().unwrap().to_string());
+        let storage = FilesystemStorage::new(config).for_upstream("private.local");
+        let repository = Repository::new_with_reference("library/nginx", &digest.to_string()).unwrap();
+        assert_eq!(Some(spool.path().join("sha256").as_path()), storage.blob_path_tmp(repository).parent());
+    }
+
+    #[test]
+    fn disk_usage_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let (storage, digest) = storage_with_blob(&folder, EvictionReadPolicy::Skip);
+        assert_eq!((64 * 1024, 1), storage.disk_usage().unwrap());
+
+        // Every blob is accounted for, the blobs being written are not
+        let other = Digest::parse("sha256:9bc9c096713a6e47ca1b4a0d354ea3f2a1f67669c9a2456352d28481a6ce2fbe").unwrap();
+        std::fs::write(storage.digest_path(&other), b"blob").unwrap();
+        let repository = Repository::new_with_reference("library/nginx", &digest.to_string()).unwrap();
+        std::fs::write(storage.blob_path_tmp(repository), b"partial").unwrap();
+        assert_eq!((64 * 1024 + 4, 2), storage.disk_usage().unwrap());
+
+        // An empty storage folder
+        let empty = tempfile::tempdir().unwrap();
+        let storage = FilesystemStorage::new(AppConfig::with_storage_folder(empty.path().to_str().unwrap()));
+        assert_eq!((0, 0), storage.disk_usage().unwrap());
+    }
+
+    #[test]
+    fn unique_usage_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let mut config = AppConfig::with_storage_folder(folder.path().to_str().unwrap());
+        config.upstreams.push(UpstreamConfig { storage_folder: Some("private".to_string()), ..UpstreamConfig::for_test("private.local", "https://registry.private.local") });
+        let storage = FilesystemStorage::new(config);
+        storage.create_folders().unwrap();
+        let private = storage.for_upstream("private.local");
+
+        // The same digest in the folders of two upstreams
+        let digest = Digest::parse("sha256:9bc9c096713a6e47ca1b4a0d354ea3f2a1f67669c9a2456352d28481a6ce2fbe").unwrap();
+        std::fs::write(storage.digest_path(&digest), b"blob").unwrap();
+        std::fs::write(private.digest_path(&digest), b"blob").unwrap();
+
+        // The same content hard linked under another digest
+        let sha512 = Digest::parse(&format!("sha512:{}", "a".repeat(128))).unwrap();
+        std::fs::hard_link(storage.digest_path(&digest), storage.digest_path(&sha512)).unwrap();
+
+        assert_eq!((12, 3), storage.disk_usage().unwrap());
+        assert_eq!((4, 1), storage.unique_usage().unwrap());
+
+        let other = Digest::parse(&format!("sha256:{}", "b".repeat(64))).unwrap();
+        std::fs::write(private.digest_path(&other), b"other blob").unwrap();
+        assert_eq!((14, 2), storage.unique_usage().unwrap());
+    }
+
+    #[test]
+    fn create_folders_test() {
+        let folder = tempfile::tempdir().unwrap();
+        let root = folder.path().join("missing").join("cache");
+        let mut config = AppConfig::with_storage_folder(root.to_str().unwrap());
+        config.upstreams.push(UpstreamConfig { storage_folder: Some("private".to_string()), ..UpstreamConfig::for_test("private.local", "https://private.local") });
+
+        // Created, and left alone once they exist
+        let storage = FilesystemStorage::new(config.clone());
+        for _ in 0..2 {
+            storage.create_folders().unwrap();
+            for folder in [root.clone(), root.join("private")] {
+                assert!(folder.join("sha256").is_dir());
+                assert!(folder.join("sha512").is_dir());
+            }
+        }
+        assert!(storage.blobs().unwrap().is_empty());
+
+        // The tmp files left over are removed, in the storage folders and in the spool, the blobs are left alone
+        let spool = folder.path().join("spool");
+        config.storage.spool_dir = Some(spool.to_str().unwrap().to_string());
+        let storage = FilesystemStorage::new(config.clone());
+        storage.create_folders().unwrap();
+        let blob = root.join("sha256").join("a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4");
+        let leftovers = [root.join("sha256").join("a3ed_1_0_tmp"), root.join("private").join("sha512").join("a3ed_1_1_tmp"), spool.join("sha256").join("a3ed_1_2_tmp")];
+        for file in leftovers.iter().chain([&blob]) {
+            std::fs::write(file, b"blob").unwrap();
+        }
+        storage.create_folders().unwrap();
+        assert!(leftovers.iter().all(|file| !file.exists()));
+        assert!(blob.exists());
+
+        // A file in the way of the storage folder
+        let file = folder.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        config.storage.folder = file.join("cache").to_str().unwrap().to_string();
+        assert!(FilesystemStorage::new(config).create_folders().is_err());
+    }
+}
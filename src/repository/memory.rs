@@ -0,0 +1,210 @@
+// SPDX-License-Identifier: Apache-2.0
+use std::collections::{HashMap, VecDeque};
+use std::io::Cursor;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use parking_lot::Mutex;
+use tokio::io::{AsyncRead, AsyncWrite};
+use crate::driver::{RepositoryTrait, StoredContent};
+use crate::error::error_kind::ErrorKind;
+use crate::error::registry::RegistryError;
+use crate::registry::digest::Digest;
+use crate::registry::repository::Repository;
+
+/// The stored blobs, in the order they were stored
+#[derive(Default)]
+struct Blobs {
+    data: HashMap<Digest, Bytes>,
+    order: VecDeque<Digest>,
+
+    /// Bytes of all the stored blobs
+    size: u64,
+}
+
+impl Blobs {
+
+    fn remove(&mut self, digest: &Digest) -> Option<Bytes> {
+        let data = self.data.remove(digest)?;
+        self.order.retain(|stored| stored != digest);
+        self.size -= data.len() as u64;
+        Some(data)
+    }
+}
+
+/// Storage of the blobs in the memory of the process, up to `max_bytes`.
+/// The blobs stored the longest ago are evicted to make room for a new one
+#[derive(Clone)]
+pub struct InMemoryStorage {
+    blobs: Arc<Mutex<Blobs>>,
+    max_bytes: u64,
+}
+
+#[async_trait]
+impl RepositoryTrait for InMemoryStorage {
+
+    async fn persist(&self, repo: Repository) -> Result<Pin<Box<dyn AsyncWrite + Send>>, RegistryError> {
+        let digest = repo.digest.ok_or_else(|| RegistryError::new(ErrorKind::RegistryDigestInvalid).with_error(format!("{} is not a digest", repo.reference)))?;
+
+        Ok(Box::pin(MemoryBlobWriter {
+            storage: self.clone(),
+            digest,
+            data: BytesMut::new(),
+        }))
+    }
+
+    async fn read(&self, repo: Repository) -> Result<Pin<Box<dyn AsyncRead + Send>>, RegistryError> {
+        let data = repo.digest.as_ref()
+            .and_then(|digest| self.get(digest))
+            .ok_or_else(|| RegistryError::new(ErrorKind::NotFound).with_error(format!("{} is not stored", repo.reference)))?;
+
+        Ok(Box::pin(Cursor::new(data)))
+    }
+
+    async fn open(&self, repo: Repository) -> Result<StoredContent, RegistryError> {
+        let data = repo.digest.as_ref()
+            .and_then(|digest| self.get(digest))
+            .ok_or_else(|| RegistryError::new(ErrorKind::NotFound).with_error(format!("{} is not stored", repo.reference)))?;
+
+        Ok(StoredContent::Bytes(data))
+    }
+
+    fn is_stored(&self, digest: &Digest) -> bool {
+        self.blobs.lock().data.contains_key(digest)
+    }
+
+    async fn content(&self, digest: &Digest) -> std::io::Result<Bytes> {
+        self.get(digest).ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} is not stored", digest)))
+    }
+
+    async fn remove(&self, digest: &Digest) -> std::io::Result<bool> {
+        Ok(self.blobs.lock().remove(digest).is_some())
+    }
+
+    fn usage(&self) -> std::io::Result<(u64, u64)> {
+        let blobs = self.blobs.lock();
+        Ok((blobs.size, blobs.data.len() as u64))
+    }
+}
+
+impl InMemoryStorage {
+
+    pub fn new(max_bytes: u64) -> Self {
+        InMemoryStorage {
+            blobs: Default::default(),
+            max_bytes,
+        }
+    }
+
+    /// The content of a blob, if it is stored
+    pub fn get(&self, digest: &Digest) -> Option<Bytes> {
+        self.blobs.lock().data.get(digest).cloned()
+    }
+
+    /// Store a blob, replacing the one stored under the same digest, after evicting as many blobs as it needs room
+    pub fn insert(&self, digest: Digest, data: Bytes) -> std::io::Result<()> {
+        if data.len() as u64 > self.max_bytes {
+            return Err(std::io::Error::other(format!("blob of {} bytes exceeds storage->memory_max_bytes", data.len())));
+        }
+
+        let mut blobs = self.blobs.lock();
+        blobs.remove(&digest);
+        while blobs.size + data.len() as u64 > self.max_bytes {
+            let Some(oldest) = blobs.order.front().cloned() else { break };
+            blobs.remove(&oldest);
+            tracing::info!("Evicted blob {} from the memory storage", oldest);
+        }
+
+        blobs.size += data.len() as u64;
+        blobs.order.push_back(digest.clone());
+        blobs.data.insert(digest, data);
+        Ok(())
+    }
+
+}
+
+/// A blob being written into the memory storage, it is stored as a whole once the writer is shut down.
+/// Dropping the writer before leaves nothing behind, e.g. for a blob whose digest did not match
+struct MemoryBlobWriter {
+    storage: InMemoryStorage,
+    digest: Digest,
+    data: BytesMut,
+}
+
+impl AsyncWrite for MemoryBlobWriter {
+
+    fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        if (self.data.len() + buf.len()) as u64 > self.storage.max_bytes {
+            return Poll::Ready(Err(std::io::Error::other("blob exceeds storage->memory_max_bytes")));
+        }
+        self.data.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let data = std::mem::take(&mut self.data).freeze();
+        Poll::Ready(self.storage.insert(self.digest.clone(), data))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use crate::driver::RepositoryTrait;
+    use crate::registry::digest::{Digest, DigestAlgorithm};
+    use crate::registry::repository::Repository;
+    use crate::repository::memory::InMemoryStorage;
+
+    fn repository(data: &[u8]) -> Repository {
+        Repository::new_with_reference("library/nginx", &Digest::hash_bytes(DigestAlgorithm::Sha256, data).to_string()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn memory_storage_test() {
+        let storage = InMemoryStorage::new(20);
+        let first = repository(b"first blob");
+        assert!(storage.read(first.clone()).await.is_err());
+
+        // Only stored once the writer is shut down
+        let mut writer = storage.persist(first.clone()).await.unwrap();
+        writer.write_all(b"first ").await.unwrap();
+        writer.write_all(b"blob").await.unwrap();
+        assert!(!storage.is_stored(first.digest.as_ref().unwrap()));
+        writer.shutdown().await.unwrap();
+
+        let mut data = Vec::new();
+        storage.read(first.clone()).await.unwrap().read_to_end(&mut data).await.unwrap();
+        assert_eq!(b"first blob".to_vec(), data);
+        assert_eq!((10, 1), storage.usage().unwrap());
+
+        // Dropped before being shut down
+        let dropped = repository(b"dropped");
+        let mut writer = storage.persist(dropped.clone()).await.unwrap();
+        writer.write_all(b"dropped").await.unwrap();
+        drop(writer);
+        assert!(storage.read(dropped).await.is_err());
+
+        // The oldest blob makes room for the new one
+        let second = repository(b"second blob");
+        let mut writer = storage.persist(second.clone()).await.unwrap();
+        writer.write_all(b"second blob").await.unwrap();
+        writer.shutdown().await.unwrap();
+        assert!(!storage.is_stored(first.digest.as_ref().unwrap()));
+        assert!(storage.is_stored(second.digest.as_ref().unwrap()));
+        assert_eq!((11, 1), storage.usage().unwrap());
+
+        // Never larger than the cap
+        let mut writer = storage.persist(repository(b"larger than the whole storage")).await.unwrap();
+        assert!(writer.write_all(b"larger than the whole storage").await.is_err());
+        assert!(storage.is_stored(second.digest.as_ref().unwrap()));
+
+        assert!(storage.remove(second.digest.as_ref().unwrap()).await.unwrap());
+        assert_eq!((0, 0), storage.usage().unwrap());
+    }
+}
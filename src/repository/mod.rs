@@ -1,2 +1,5 @@
 // SPDX-License-Identifier: Apache-2.0
+pub mod active_reads;
 pub mod filesystem;
+pub mod memory;
+pub mod blob_refs;
@@ -0,0 +1,81 @@
+// SPDX-License-Identifier: Apache-2.0
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{Sampler, Tracer};
+use opentelemetry_sdk::Resource;
+use tracing::Subscriber;
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+use crate::config::telemetry::TelemetryConfig;
+use crate::registry::repository::Repository;
+
+/// The layer exporting the spans to the OTLP endpoint of the collector, none when it is not configured.
+/// The `traceparent` of the client requests is continued, and sent along to upstream
+pub fn layer<S>(config: &TelemetryConfig) -> Result<Option<OpenTelemetryLayer<S, Tracer>>, String>
+    where S: Subscriber + for<'span> LookupSpan<'span>
+{
+    let Some(endpoint) = &config.otlp_endpoint else { return Ok(None) };
+
+    let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(config.sample_ratio)));
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().http().with_endpoint(endpoint))
+        .with_trace_config(opentelemetry_sdk::trace::config()
+            .with_sampler(sampler)
+            .with_resource(Resource::new(vec![KeyValue::new("service.name", config.service_name.clone())])))
+        .install_batch(opentelemetry_sdk::runtime::Tokio)
+        .map_err(|e| format!("Failed to export the traces to {}: {}", endpoint, e))?;
+
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+
+/// Export the spans not sent yet
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// The trace context sent by the client, if any
+pub fn extract(headers: &actix_web::http::header::HeaderMap) -> opentelemetry::Context {
+    opentelemetry::global::get_text_map_propagator(|propagator| propagator.extract(&ClientHeaders(headers)))
+}
+
+/// Continue the trace of the current span in the upstream request
+pub fn inject(headers: &mut reqwest::header::HeaderMap) {
+    let context = tracing::Span::current().context();
+    opentelemetry::global::get_text_map_propagator(|propagator| propagator.inject_context(&context, &mut UpstreamHeaders(headers)));
+}
+
+/// Record the container image, and the digest when pulled by digest, on the span of the request
+pub fn record_repository(repository: &Repository) {
+    let span = tracing::Span::current();
+    span.record("repository", repository.name.as_str());
+    if let Some(digest) = &repository.digest {
+        span.record("digest", digest.to_string().as_str());
+    }
+}
+
+struct ClientHeaders<'a>(&'a actix_web::http::header::HeaderMap);
+
+impl Extractor for ClientHeaders<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+struct UpstreamHeaders<'a>(&'a mut reqwest::header::HeaderMap);
+
+impl Injector for UpstreamHeaders<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (reqwest::header::HeaderName::from_bytes(key.as_bytes()), reqwest::header::HeaderValue::from_str(&value)) {
+            self.0.insert(name, value);
+        }
+    }
+}